@@ -7,7 +7,7 @@ extern crate libax;
 
 use libax::{
     hv::{
-        HyperCraftHalImpl, PerCpu, VM,
+        host_cpus, HyperCraftHalImpl, VM,
     },
     info,
 };
@@ -25,18 +25,19 @@ fn main(hart_id: usize) {
     println!("Hello, hv!");
     println!("into main {}", hart_id);
 
-    let mut p = PerCpu::<HyperCraftHalImpl>::new(hart_id);
-    p.hardware_enable().unwrap();
-    let vmcs_revision_id = p.get_vmcs_revision_id();
+    // VMX is enabled on all CPUs at boot
+    let vmcs_revision_id = host_cpus::vmcs_revision_id();
 
     for id in 0..NUM_VM {
         thread::spawn(move || {
+            // the VMCS of the vCPU stays loaded on the boot CPU
+            thread::set_current_affinity(Some(hart_id));
             println!("Hello, task {}! id = {:?}", id, thread::current().id());
             let gpm = x64::setup_gpm(id).unwrap();
             info!("{:#x?}", gpm);
 
             println!("Create VM{}...",id);
-            let mut vm = VM::<HyperCraftHalImpl>::new(id);
+            let mut vm = VM::<HyperCraftHalImpl>::new(id).unwrap();
             
             println!("VM {} add vcpu {}...", vm.get_vm_id(), 0);
             let vcpu_id = vm.add_vcpu(vmcs_revision_id, x64::BIOS_ENTRY, gpm.nest_page_table_root()).unwrap();
@@ -57,8 +58,6 @@ fn main(hart_id: usize) {
         thread::yield_now();
     }
     println!("Task yielding tests run OK!");
-    
-    p.hardware_disable().unwrap();
 
     return;
 
@@ -32,5 +32,44 @@ for i in range(0,d['vm_count']):
     merged_file.write(struct.pack('<Q', d['vm'+str(i)]['io_apic']))
     merged_file.write(struct.pack('<Q', d['vm'+str(i)]['HPET']))
     merged_file.write(struct.pack('<Q', d['vm'+str(i)]['local_apic']))
+    # populate RAM on demand with at most this many bytes, 0 to allocate up front
+    merged_file.write(struct.pack('<Q', d['vm'+str(i)].get('memory_cap', 0)))
+    # optional memory layout, a list of {gpa, size, kind: ram/rom}
+    regions = d['vm'+str(i)].get('regions', [])
+    merged_file.write(struct.pack('<Q', len(regions)))
+    for r in regions:
+        merged_file.write(struct.pack('<Q', r['gpa']))
+        merged_file.write(struct.pack('<Q', r['size']))
+        merged_file.write(struct.pack('<Q', {'ram': 0, 'rom': 1}[r['kind']]))
+    # optional I/O ports accessed by the guest directly, a list of {port, count}
+    io_ports = d['vm'+str(i)].get('io_ports', [])
+    merged_file.write(struct.pack('<Q', len(io_ports)))
+    for p in io_ports:
+        merged_file.write(struct.pack('<Q', p['port']))
+        merged_file.write(struct.pack('<Q', p['count']))
+
+    # optional memory shared with the VMs using the same name, a list of
+    # {name, gpa, size, vector}, where the vector is injected by the doorbell
+    shared_memory = d['vm'+str(i)].get('shared_memory', [])
+    merged_file.write(struct.pack('<Q', len(shared_memory)))
+    for m in shared_memory:
+        merged_file.write(struct.pack('<8s', m['name'].encode()))
+        merged_file.write(struct.pack('<Q', m['gpa']))
+        merged_file.write(struct.pack('<Q', m['size']))
+        merged_file.write(struct.pack('<Q', m['vector']))
+    # optional virtio-net device on the switch of the VMs, with a MAC address
+    # like "52:54:00:12:34:56", and the vector of its interrupt
+    net = d['vm'+str(i)].get('net')
+    mac = int(net['mac'].replace(':', ''), 16) if net else 0
+    merged_file.write(struct.pack('<Q', mac))
+    merged_file.write(struct.pack('<Q', net['vector'] if net else 0))
+    # optional physical CPU of each vCPU, the boot CPU for those not listed
+    cpus = d['vm'+str(i)].get('cpus', [])
+    merged_file.write(struct.pack('<Q', len(cpus)))
+    for c in cpus:
+        merged_file.write(struct.pack('<Q', c))
+    # optional record/replay of the inputs of the first boot, with the replay feature
+    replay = d['vm'+str(i)].get('replay')
+    merged_file.write(struct.pack('<Q', {None: 0, 'record': 1, 'replay': 2}[replay]))
 
 merged_file.close()
\ No newline at end of file
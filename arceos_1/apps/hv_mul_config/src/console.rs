@@ -0,0 +1,278 @@
+//! The management console, entered by typing `Ctrl-A c` on the host console.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use libax::hv::console::{self, OutputMode};
+use libax::hv::{demand_paging, exit_stats, host_cpus, vm_control, Error};
+use libax::thread;
+
+use crate::x64::ConfigFile;
+
+const LF: u8 = b'\n';
+const CR: u8 = b'\r';
+const DL: u8 = b'\x7f';
+const BS: u8 = b'\x08';
+
+const MAX_CMD_LEN: usize = 256;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type CmdHandler = fn(&[ConfigFile], &str);
+
+const CMD_TABLE: &[(&str, CmdHandler)] = &[
+    ("attach", do_attach),
+    ("destroy", do_destroy),
+    ("dump", do_dump),
+    ("exit", do_exit),
+    ("help", do_help),
+    ("list", do_list),
+    ("mem", do_mem),
+    ("move", do_move),
+    ("output", do_output),
+    ("pause", do_pause),
+    ("reset", do_reset),
+    ("resume", do_resume),
+    ("stats", do_stats),
+];
+
+/// Runs the management console in a new task.
+pub fn spawn(configs: Arc<Vec<ConfigFile>>) {
+    thread::spawn(move || loop {
+        while !console::manager_active() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        println!();
+        println!("[hv] management console, type `help` for commands");
+        run_shell(&configs);
+    });
+}
+
+fn print_prompt() {
+    print!("hv> ");
+}
+
+/// Reads and runs commands until the console is left.
+fn run_shell(configs: &[ConfigFile]) {
+    let mut buf = [0; MAX_CMD_LEN];
+    let mut cursor = 0;
+    print_prompt();
+    while console::manager_active() {
+        let Some(c) = console::manager_getchar() else {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+        match c {
+            CR | LF => {
+                println!();
+                if cursor > 0 {
+                    run_cmd(configs, &buf[..cursor]);
+                    cursor = 0;
+                }
+                if console::manager_active() {
+                    print_prompt();
+                }
+            }
+            BS | DL => {
+                if cursor > 0 {
+                    print!("{} {}", BS as char, BS as char);
+                    cursor -= 1;
+                }
+            }
+            0..=31 => {}
+            c => {
+                if cursor < MAX_CMD_LEN - 1 {
+                    print!("{}", c as char);
+                    buf[cursor] = c;
+                    cursor += 1;
+                }
+            }
+        }
+    }
+}
+
+fn run_cmd(configs: &[ConfigFile], line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim();
+    let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+    match CMD_TABLE.iter().find(|(name, _)| *name == cmd) {
+        Some((_, handler)) => handler(configs, args.trim()),
+        None => println!("{}: command not found", cmd),
+    }
+}
+
+/// Parses the VM id argument of `cmd`.
+fn parse_vm_id(configs: &[ConfigFile], cmd: &str, args: &str) -> Option<usize> {
+    match args.parse::<usize>() {
+        Ok(id) if id < configs.len() => Some(id),
+        _ => {
+            println!("{}: expected a VM id below {}", cmd, configs.len());
+            None
+        }
+    }
+}
+
+fn print_result(cmd: &str, id: usize, res: Result<(), Error>) {
+    match res {
+        Ok(()) => println!("VM {}: {} requested", id, cmd),
+        Err(Error::BadState) => println!("{}: VM {} is {:?}", cmd, id, vm_control::status(id)),
+        Err(err) => println!("{}: VM {}: {:?}", cmd, id, err),
+    }
+}
+
+fn do_help(_configs: &[ConfigFile], _args: &str) {
+    println!("Available commands:");
+    println!("  list           list VMs and their states");
+    println!("  pause <id>     pause a VM");
+    println!("  resume <id>    resume a paused VM");
+    println!("  reset <id>     restart a VM from scratch");
+    println!("  destroy <id>   stop a VM for good");
+    println!("  dump <id>      write a core file of a VM");
+    println!("  stats <id> [trace|clear]");
+    println!("                 show VM-exit statistics, the exit trace, or clear them");
+    println!("  mem <id>       show memory usage of a VM");
+    println!("  move <id> <cpu>");
+    println!("                 move the vcpu of a VM to another physical CPU");
+    println!("  output [focused|shared]");
+    println!("                 show only the focused VM's output, or all of it");
+    println!("  attach <id>    give a VM the console focus, and leave the console");
+    println!("  exit           leave the console");
+    println!("Hotkeys: Ctrl-A c comes back here, Ctrl-A n focuses the next VM,");
+    println!("Ctrl-A 0-9 focuses the given VM, Ctrl-A s toggles the output mode.");
+}
+
+fn do_list(configs: &[ConfigFile], _args: &str) {
+    let focus = console::focus();
+    println!("  ID  STATUS       VCPUS  MEMORY");
+    for (id, config) in configs.iter().enumerate() {
+        println!(
+            "{} {:>3}  {:<12} {:>5}  {} KiB",
+            if id == focus { '*' } else { ' ' },
+            id,
+            alloc::format!("{:?}", vm_control::status(id)),
+            vm_control::vcpu_count(id),
+            config.memory / 1024
+        );
+    }
+    println!("(* has the console focus)");
+}
+
+fn do_pause(configs: &[ConfigFile], args: &str) {
+    if let Some(id) = parse_vm_id(configs, "pause", args) {
+        print_result("pause", id, vm_control::pause(id));
+    }
+}
+
+fn do_resume(configs: &[ConfigFile], args: &str) {
+    if let Some(id) = parse_vm_id(configs, "resume", args) {
+        print_result("resume", id, vm_control::resume(id));
+    }
+}
+
+fn do_reset(configs: &[ConfigFile], args: &str) {
+    if let Some(id) = parse_vm_id(configs, "reset", args) {
+        print_result("reset", id, vm_control::reset(id));
+    }
+}
+
+fn do_destroy(configs: &[ConfigFile], args: &str) {
+    if let Some(id) = parse_vm_id(configs, "destroy", args) {
+        print_result("destroy", id, vm_control::destroy(id));
+    }
+}
+
+fn do_dump(configs: &[ConfigFile], args: &str) {
+    let Some(id) = parse_vm_id(configs, "dump", args) else {
+        return;
+    };
+    #[cfg(feature = "coredump")]
+    match crate::coredump::dump(id, vm_control::vcpu_count(id)) {
+        Ok(path) => println!("VM {} dumped to {}", id, path),
+        Err(Error::NotFound) => println!("dump: VM {} is not running", id),
+        Err(Error::BadState) => println!("dump: VM {} is {:?}", id, vm_control::status(id)),
+        Err(err) => println!("dump: VM {}: {:?}", id, err),
+    }
+    #[cfg(not(feature = "coredump"))]
+    println!("dump: VM {}: core files need the \"coredump\" feature", id);
+}
+
+fn do_stats(configs: &[ConfigFile], args: &str) {
+    let (id, sub) = args.split_once(' ').unwrap_or((args, ""));
+    let Some(id) = parse_vm_id(configs, "stats", id) else {
+        return;
+    };
+    let mut report = String::new();
+    match sub.trim() {
+        "" => exit_stats::dump(id, &mut report).unwrap(),
+        "trace" => exit_stats::dump_trace(id, &mut report).unwrap(),
+        "clear" => exit_stats::reset(id),
+        _ => {
+            println!("stats: unknown argument {:?}", sub);
+            return;
+        }
+    }
+    print!("{}", report);
+}
+
+fn do_mem(configs: &[ConfigFile], args: &str) {
+    let Some(id) = parse_vm_id(configs, "mem", args) else {
+        return;
+    };
+    let config = &configs[id];
+    println!("VM {}: {} KiB of guest memory", id, config.memory / 1024);
+    if config.memory_cap != 0 {
+        let populated = demand_paging::populated_pages(id) * 4;
+        println!(
+            "  {} KiB populated on demand, capped at {} KiB",
+            populated,
+            config.memory_cap / 1024
+        );
+        println!(
+            "  {} KiB asked back by the balloon",
+            demand_paging::balloon_target(id) * 4
+        );
+    } else {
+        println!("  all allocated at creation");
+    }
+}
+
+fn do_move(configs: &[ConfigFile], args: &str) {
+    let (id, cpu) = args.split_once(' ').unwrap_or((args, ""));
+    let Some(id) = parse_vm_id(configs, "move", id) else {
+        return;
+    };
+    let Ok(cpu_id) = cpu.trim().parse::<usize>() else {
+        println!("move: expected a CPU id");
+        return;
+    };
+    match host_cpus::migrate(id, 0, cpu_id) {
+        Err(Error::InvalidParam) => println!("move: CPU {} cannot run vcpus", cpu_id),
+        Err(Error::NotFound) => println!("move: VM {} is not running", id),
+        res => print_result("move", id, res),
+    }
+}
+
+fn do_attach(configs: &[ConfigFile], args: &str) {
+    if let Some(id) = parse_vm_id(configs, "attach", args) {
+        console::set_focus(id);
+        console::leave_manager();
+    }
+}
+
+fn do_output(_configs: &[ConfigFile], args: &str) {
+    match args {
+        "" => {}
+        "focused" => console::set_output_mode(OutputMode::Focused),
+        "shared" => console::set_output_mode(OutputMode::Shared),
+        _ => {
+            println!("output: expected `focused` or `shared`");
+            return;
+        }
+    }
+    println!("output mode: {:?}", console::output_mode());
+}
+
+fn do_exit(_configs: &[ConfigFile], _args: &str) {
+    console::leave_manager();
+}
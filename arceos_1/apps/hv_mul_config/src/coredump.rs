@@ -0,0 +1,112 @@
+//! Writing VMs to ELF core files, when they crash or from the console.
+//!
+//! The core file of VM `id` is written to `/vm{id}.core`, and is loaded in GDB
+//! with the symbols of the guest kernel, e.g. `gdb vmlinux vm0.core`. Guest
+//! memory is found at its guest physical addresses, and also at
+//! `HV_COREDUMP_VOFFSET` (in hex) above them if set, for kernels mapping their
+//! memory at a fixed offset.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use libax::fs::File;
+use libax::hv::{
+    core_file_headers, crash, snapshot, vm_control, Error, HyperCraftHal, HyperCraftHalImpl,
+    Result as HyperResult, VcpuState, CORE_FILE_ALIGN,
+};
+use libax::io::{self, Write};
+use libax::sync::spin::SpinNoIrq;
+
+use crate::x64::GuestPhysMemorySet;
+
+/// Signal reported by the vCPUs of crashed VMs.
+const SIGSEGV: u32 = 11;
+/// Signal reported by the vCPUs of VMs dumped from the console.
+const SIGSTOP: u32 = 19;
+
+/// Guest memory of the running VMs.
+static GUEST_MEMORY: SpinNoIrq<BTreeMap<usize, Arc<GuestPhysMemorySet>>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+fn io_err(err: io::Error) -> Error {
+    warn!("core file I/O failed: {:?}", err);
+    Error::Internal
+}
+
+fn virt_offset() -> Option<u64> {
+    option_env!("HV_COREDUMP_VOFFSET")
+        .map(|offset| u64::from_str_radix(offset.trim_start_matches("0x"), 16).unwrap())
+}
+
+/// Makes the VM dumpable from the console while it runs.
+pub fn register(vm_id: usize, gpm: Arc<GuestPhysMemorySet>) {
+    GUEST_MEMORY.lock().insert(vm_id, gpm);
+}
+
+/// Called after the vCPUs of the VM returned from `run`. Writes its core file
+/// if it crashed.
+pub fn finish(vm_id: usize) {
+    let Some(gpm) = GUEST_MEMORY.lock().remove(&vm_id) else {
+        return;
+    };
+    let vcpus = crash::take(vm_id);
+    if vcpus.is_empty() {
+        return;
+    }
+    let path = format!("/vm{}.core", vm_id);
+    match write_file(&vcpus, SIGSEGV, &gpm, &path) {
+        Ok(()) => println!("VM{} crashed, core dumped to {}", vm_id, path),
+        Err(err) => warn!("failed to dump VM {}: {:?}", vm_id, err),
+    }
+}
+
+/// Pauses the running VM, writes its core file, then lets it run again.
+/// Returns the path of the file.
+pub fn dump(vm_id: usize, vcpu_count: usize) -> HyperResult<String> {
+    let gpm = GUEST_MEMORY.lock().get(&vm_id).cloned().ok_or(Error::NotFound)?;
+    // The vCPUs of a paused VM only wake up to be resumed, they would never
+    // save their states.
+    if vm_control::status(vm_id) != vm_control::VmStatus::Running {
+        return Err(Error::BadState);
+    }
+    let path = format!("/vm{}.core", vm_id);
+    snapshot::request(vm_id, vcpu_count)?;
+    let res = snapshot::wait_captured(vm_id).and_then(|state| {
+        let vcpus: Vec<_> = state.vcpus.into_iter().enumerate().collect();
+        write_file(&vcpus, SIGSTOP, &gpm, &path)
+    });
+    snapshot::resume(vm_id);
+    res.map(|_| path)
+}
+
+fn write_file(
+    vcpus: &[(usize, VcpuState)],
+    signal: u32,
+    gpm: &GuestPhysMemorySet,
+    path: &str,
+) -> HyperResult {
+    let regions = gpm.memory_regions();
+    let headers = core_file_headers(vcpus, signal, &regions, virt_offset());
+    let mut file = File::create(path).map_err(io_err)?;
+    file.write_all(&headers).map_err(io_err)?;
+
+    let page_size = HyperCraftHalImpl::PAGE_SIZE;
+    let mut page = vec![0u8; page_size];
+    for (start, size) in regions {
+        for gpa in (start..start + size).step_by(page_size) {
+            // Pages not populated yet read as zeros.
+            if !gpm.read_page(gpa, &mut page)? {
+                page.fill(0);
+            }
+            let len = page_size.min(start + size - gpa);
+            file.write_all(&page[..len]).map_err(io_err)?;
+        }
+        let padding = (CORE_FILE_ALIGN - size % CORE_FILE_ALIGN) % CORE_FILE_ALIGN;
+        file.write_all(&vec![0u8; padding]).map_err(io_err)?;
+    }
+    Ok(())
+}
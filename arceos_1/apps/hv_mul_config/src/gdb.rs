@@ -0,0 +1,89 @@
+//! Debugging a VM with GDB, over COM2 or a TCP connection.
+//!
+//! `HV_GDB=serial` serves GDB on COM2 at 115200 baud, and `HV_GDB=tcp:PORT`
+//! (with the `gdb-tcp` feature) waits for GDB to connect to `PORT`, e.g. with
+//! `target remote 10.0.2.15:PORT`. `HV_GDB_VM` selects the VM, VM 0 by
+//! default. The VM is stopped as soon as GDB is attached.
+
+use libax::hv::{gdbstub, Error, Result as HyperResult};
+#[cfg(feature = "gdb-tcp")]
+use libax::{
+    io::{self, prelude::*},
+    net::{IpAddr, Ipv4Addr, TcpListener, TcpStream},
+};
+
+/// Serves GDB for the VM `vm_id` with `vcpu_count` vCPUs, if it's selected by
+/// `HV_GDB_VM`. Blocks until GDB detaches.
+pub fn serve(vm_id: usize, vcpu_count: usize) {
+    let Some(transport) = option_env!("HV_GDB") else {
+        return;
+    };
+    let selected = option_env!("HV_GDB_VM").map_or(0, |id| id.parse().unwrap());
+    if vm_id != selected {
+        return;
+    }
+    let res = if transport == "serial" {
+        let mut conn = gdbstub::SerialConnection::new(gdbstub::SerialConnection::COM2, 115200);
+        info!("waiting for GDB on COM2...");
+        gdbstub::serve(vm_id, vcpu_count, &mut conn)
+    } else if let Some(port) = transport.strip_prefix("tcp:") {
+        serve_tcp(vm_id, vcpu_count, port.parse().unwrap())
+    } else {
+        warn!("unknown GDB transport {:?}", transport);
+        Err(Error::InvalidParam)
+    };
+    if let Err(err) = res {
+        warn!("GDB stub for VM {} failed: {:?}", vm_id, err);
+    }
+}
+
+#[cfg(feature = "gdb-tcp")]
+struct TcpConnection(TcpStream);
+
+#[cfg(feature = "gdb-tcp")]
+fn io_err(err: io::Error) -> Error {
+    warn!("GDB connection failed: {:?}", err);
+    Error::Internal
+}
+
+#[cfg(feature = "gdb-tcp")]
+impl gdbstub::GdbConnection for TcpConnection {
+    fn try_read(&mut self) -> HyperResult<Option<u8>> {
+        let mut buf = [0];
+        match self.0.read(&mut buf) {
+            Ok(0) => Err(Error::Internal),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(io::Error::WouldBlock) => Ok(None),
+            Err(err) => Err(io_err(err)),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> HyperResult {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            match self.0.write(buf) {
+                Ok(n) => buf = &buf[n..],
+                Err(io::Error::WouldBlock) => libax::thread::yield_now(),
+                Err(err) => return Err(io_err(err)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "gdb-tcp")]
+fn serve_tcp(vm_id: usize, vcpu_count: usize, port: u16) -> HyperResult {
+    let addr = IpAddr::from(Ipv4Addr::new(0, 0, 0, 0));
+    let mut listener = TcpListener::bind((addr, port).into()).map_err(io_err)?;
+    info!("waiting for GDB on TCP port {}...", port);
+    let (mut stream, peer) = listener.accept().map_err(io_err)?;
+    info!("GDB connected from {}", peer);
+    stream.set_nonblocking(true);
+    gdbstub::serve(vm_id, vcpu_count, &mut TcpConnection(stream))
+}
+
+#[cfg(not(feature = "gdb-tcp"))]
+fn serve_tcp(_vm_id: usize, _vcpu_count: usize, _port: u16) -> HyperResult {
+    warn!("GDB over TCP needs the `gdb-tcp` feature");
+    Err(Error::NotSupported)
+}
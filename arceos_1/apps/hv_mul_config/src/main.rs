@@ -5,10 +5,13 @@ extern crate alloc;
 #[macro_use]
 extern crate libax;
 
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use libax::{
     hv::{
-        HyperCraftHalImpl, PerCpu, VM, HostPhysAddr,
+        exit_stats, host_cpus, io_passthrough, shmem, vm_control, HyperCraftHalImpl, VM,
+        HostPhysAddr,
     },
     info,
 };
@@ -16,53 +19,147 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use libax::thread;
 
 
+mod console;
+#[cfg(feature = "coredump")]
+mod coredump;
+mod gdb;
 mod x64;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+#[cfg(feature = "replay")]
+mod replay;
 
 static FINISHED_TASKS: AtomicUsize = AtomicUsize::new(0);
+/// Highest exit code of the VMs, the exit status of the hypervisor.
+static EXIT_STATUS: AtomicUsize = AtomicUsize::new(0);
 const CONFIG_START: HostPhysAddr = 0x5001000;
-const VM_CONFIG_SIZE: usize = 6;
 
 #[no_mangle]
 fn main(hart_id: usize) {
     println!("Hello, hv!");
     println!("into main {}", hart_id);
 
-    let mut p = PerCpu::<HyperCraftHalImpl>::new(hart_id);
-    p.hardware_enable().unwrap();
-    let vmcs_revision_id = p.get_vmcs_revision_id();
+    // VMX is enabled on all CPUs at boot
+    let vmcs_revision_id = host_cpus::vmcs_revision_id();
 
-    let num_vm_ptr = CONFIG_START as usize as *const usize;
-    let num_vm = unsafe { num_vm_ptr.read_volatile() };
-    let vms_config: Vec<usize>  =  unsafe { core::slice::from_raw_parts(num_vm_ptr.add(1), num_vm * VM_CONFIG_SIZE).to_vec()};
+    // config: num_vm, then for each VM: id, memory, vcpu_count, io_apic, HPET,
+    // local_apic, memory_cap, region_count, region_count * (gpa, size, kind),
+    // io_port_count, io_port_count * (port, count), shmem_count, and
+    // shmem_count * (name, gpa, size, vector), with names of up to 8 bytes,
+    // net_mac (0 for no NIC), net_vector, cpu_count, cpu_count * cpu, and
+    // replay (0 for none, 1 to record, 2 to replay)
+    let mut config_ptr = CONFIG_START as usize as *const usize;
+    let mut next_config = || unsafe {
+        let value = config_ptr.read_volatile();
+        config_ptr = config_ptr.add(1);
+        value
+    };
+    let num_vm = next_config();
+    let mut vms_config = Vec::new();
+    for _ in 0..num_vm {
+        let mut vm_config = x64::ConfigFile {
+            id: next_config(),
+            memory: next_config(),
+            vcpu_count: next_config(),
+            io_apic: next_config(),
+            HPET: next_config(),
+            local_apic: next_config(),
+            memory_cap: next_config(),
+            regions: Vec::new(),
+            io_ports: Vec::new(),
+            shared_memory: Vec::new(),
+            net_mac: [0; 6],
+            net_vector: 0,
+            cpus: Vec::new(),
+            replay: 0,
+        };
+        for _ in 0..next_config() {
+            vm_config.regions.push(x64::GuestMemoryConfig {
+                gpa: next_config(),
+                size: next_config(),
+                kind: match next_config() {
+                    0 => x64::GuestMemoryKind::Ram,
+                    _ => x64::GuestMemoryKind::Rom,
+                },
+            });
+        }
+        for _ in 0..next_config() {
+            let port = next_config();
+            let count = next_config();
+            // the range may end at port 0xffff, but not beyond
+            if count == 0 || port.saturating_add(count) > u16::MAX as usize + 1 {
+                panic!("VM{} I/O ports {:#x} + {:#x} out of range", vm_config.id, port, count);
+            }
+            vm_config.io_ports.push(port as u16..=(port + count - 1) as u16);
+        }
+        for _ in 0..next_config() {
+            let name = next_config().to_le_bytes();
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            vm_config.shared_memory.push(x64::SharedMemoryConfig {
+                name: String::from_utf8_lossy(&name[..len]).into_owned(),
+                gpa: next_config(),
+                size: next_config(),
+                vector: next_config() as u8,
+            });
+        }
+        // the MAC address is stored big-endian in the low 48 bits
+        vm_config.net_mac.copy_from_slice(&next_config().to_be_bytes()[2..]);
+        vm_config.net_vector = next_config() as u8;
+        for _ in 0..next_config() {
+            vm_config.cpus.push(next_config());
+        }
+        vm_config.replay = next_config();
+        if let Err(err) = vm_config.validate() {
+            panic!("VM{} config is invalid: {:?}", vm_config.id, err);
+        }
+        vms_config.push(vm_config);
+    }
+
+    // the host is reachable from the VMs at its IP address
+    #[cfg(feature = "net-uplink")]
+    libax::hv::vswitch::enable_uplink();
+
+    let vms_config = Arc::new(vms_config);
+    console::spawn(vms_config.clone());
 
     for id in 0..num_vm {
-        let vm_config = x64::ConfigFile {
-            id: vms_config[id*VM_CONFIG_SIZE],
-            memory: vms_config[id*VM_CONFIG_SIZE+1],
-            vcpu_count: vms_config[id*VM_CONFIG_SIZE+2],
-            io_apic: vms_config[id*VM_CONFIG_SIZE+3],
-            HPET: vms_config[id*VM_CONFIG_SIZE+4],
-            local_apic: vms_config[id*VM_CONFIG_SIZE+5],
-        };
+        let vms_config = vms_config.clone();
         thread::spawn(move || {
-            println!("Hello, task {}! id = {:?}", id, thread::current().id());
-            let gpm = x64::setup_gpm(id, vm_config).unwrap();
-            info!("{:#x?}", gpm);
-
-            println!("Create VM{}...",id);
-            let mut vm = VM::<HyperCraftHalImpl>::new(id);
-            
-            println!("VM {} add vcpu {}...", vm.get_vm_id(), 0);
-            let vcpu_id = vm.add_vcpu(vmcs_revision_id, x64::BIOS_ENTRY, gpm.nest_page_table_root()).unwrap();
-
-            let vcpu = vm.get_vcpu(vcpu_id).unwrap();
-            println!("Running vcpu {}...", vcpu.get_vcpu_id());
-            vcpu.run();
-
-            let _order = FINISHED_TASKS.fetch_add(1, Ordering::Relaxed);
-            if option_env!("SMP") == Some("1") {
-                assert!(_order == id); // FIFO scheduler
+            // The VM has one vCPU, pinned to its CPU for its VMCS to stay
+            // loaded there. VMs not saying where share the boot CPU.
+            let cpu_id = vms_config[id].cpus.first().copied().unwrap_or(hart_id);
+            if !host_cpus::is_enabled(cpu_id) || !thread::set_current_affinity(Some(cpu_id)) {
+                panic!("VM{} cannot run on CPU {}", id, cpu_id);
             }
+            println!("Hello, task {}! id = {:?}, cpu = {}", id, thread::current().id(), cpu_id);
+            if let Some(secs) = option_env!("HV_EXIT_STATS_EVERY") {
+                let secs = secs.parse().unwrap();
+                thread::spawn(move || loop {
+                    thread::sleep(core::time::Duration::from_secs(secs));
+                    let mut report = alloc::string::String::new();
+                    exit_stats::dump(id, &mut report).unwrap();
+                    print!("{}", report);
+                });
+            }
+
+            // run the VM again each time it's reset from the console or by the guest
+            let mut first_boot = true;
+            loop {
+                run_vm(id, &vms_config[id], vmcs_revision_id, first_boot);
+                if !vm_control::finish(id) {
+                    break;
+                }
+                println!("Resetting VM{}...", id);
+                first_boot = false;
+            }
+            exit_stats::unregister(id);
+            io_passthrough::release(id);
+            shmem::detach(id);
+            let exit_code = vm_control::exit_code(id);
+            println!("VM{} destroyed, exit code {:#x}", id, exit_code);
+
+            EXIT_STATUS.fetch_max(exit_code as usize, Ordering::Relaxed);
+            FINISHED_TASKS.fetch_add(1, Ordering::Relaxed);
         });
     }
 
@@ -71,9 +168,86 @@ fn main(hart_id: usize) {
         thread::yield_now();
     }
     println!("Task yielding tests run OK!");
-    
-    p.hardware_disable().unwrap();
 
-    return;
+    // QEMU exits with a non-zero status if any guest failed, for test runs
+    let status = EXIT_STATUS.load(Ordering::Relaxed).min(u8::MAX as usize);
+    thread::exit(status as i32);
+}
+
+/// Creates the VM and runs it until it's reset or destroyed.
+fn run_vm(id: usize, vm_config: &x64::ConfigFile, vmcs_revision_id: u32, first_boot: bool) {
+    let gpm = Arc::new(x64::setup_gpm(id, vm_config.clone()).unwrap());
+    info!("{:#x?}", gpm);
 
+    println!("Create VM{}...",id);
+    let mut vm = VM::<HyperCraftHalImpl>::new(id).unwrap();
+    for ports in &vm_config.io_ports {
+        if let Err(err) = io_passthrough::assign(&mut vm, ports.clone()) {
+            panic!("VM{} cannot own I/O ports {:#x?}: {:?}", id, ports, err);
+        }
+    }
+
+    println!("VM {} add vcpu {}...", vm.get_vm_id(), 0);
+    let vcpu_id = vm.add_vcpu(vmcs_revision_id, x64::BIOS_ENTRY, gpm.nest_page_table_root()).unwrap();
+    vm_control::register(id, 1).unwrap();
+
+    #[cfg(feature = "snapshot")]
+    if first_boot {
+        // continue from the snapshot saved by a previous run, if any
+        let path = alloc::format!("/vm{}.snap", id);
+        if let Err(err) = snapshot::restore(&mut vm, &gpm, &path) {
+            // Its state may be half restored, so only this VM fails.
+            warn!("VM{} cannot be restored from {}: {:?}", id, path, err);
+            return;
+        }
+        // save a snapshot after the VM has run for some seconds
+        if let Some(secs) = option_env!("HV_SNAPSHOT_AFTER") {
+            let secs = secs.parse().unwrap();
+            let gpm = gpm.clone();
+            thread::spawn(move || {
+                thread::sleep(core::time::Duration::from_secs(secs));
+                if let Err(err) = snapshot::save(id, vm_control::vcpu_count(id), &gpm, &path) {
+                    warn!("failed to save VM {}: {:?}", id, err);
+                }
+            });
+        }
+    }
+
+    // count VM exits, and trace the last $HV_EXIT_TRACE ones
+    let trace_capacity = option_env!("HV_EXIT_TRACE").map_or(0, |n| n.parse().unwrap());
+    exit_stats::register(vm.get_vcpu(vcpu_id).unwrap(), trace_capacity);
+
+    // attach GDB if asked to, the VM stops once the vcpu runs
+    if first_boot && option_env!("HV_GDB").is_some() {
+        thread::spawn(move || gdb::serve(id, 1));
+    }
+
+    #[cfg(feature = "coredump")]
+    coredump::register(id, gpm.clone());
+
+    let vcpu = vm.get_vcpu(vcpu_id).unwrap();
+    // record or replay the inputs of the first boot, see src/replay.rs
+    #[cfg(feature = "replay")]
+    let replay_mode = replay::mode(vm_config.replay)
+        .filter(|_| first_boot)
+        .filter(|&mode| match replay::start(id, vcpu, mode) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("VM {} runs without {:?}: {:?}", id, mode, err);
+                false
+            }
+        });
+    println!("Running vcpu {}...", vcpu.get_vcpu_id());
+    // the console may move it to another CPU
+    host_cpus::attach_vcpu(vcpu);
+    vcpu.run();
+    host_cpus::detach_vcpu(vcpu);
+    #[cfg(feature = "coredump")]
+    coredump::finish(id);
+    #[cfg(feature = "replay")]
+    if let Some(mode) = replay_mode {
+        if let Err(err) = replay::finish(id, vcpu, mode) {
+            warn!("failed to save the inputs of VM {}: {:?}", id, err);
+        }
+    }
 }
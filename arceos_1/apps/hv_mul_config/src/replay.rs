@@ -0,0 +1,86 @@
+//! Recording the non-deterministic inputs of VMs to files, and replaying them.
+//!
+//! With `replay: record` in the config of a VM, the port I/O reads, MSR reads,
+//! `RDTSC` and `CPUID` results, and the interrupts injected into vCPU 0 are
+//! logged while the VM runs, and written to `/vm{id}.replay` when it's reset
+//! or destroyed. With `replay: replay`, they are read from that file and fed
+//! back to the guest instead of asking the devices, so the same execution is
+//! repeated.
+//!
+//! Inputs are positioned by the branches retired by the guest, counted with a
+//! performance counter: VMs run without recording or replaying if the
+//! processor can't count them.
+//!
+//! Limitations: only the first boot is recorded, and data written into guest
+//! memory by devices (virtio-net, shared memory) or by other VMs is not logged,
+//! so guests using them diverge. The replay then goes on with live inputs.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use libax::fs::File;
+use libax::hv::{Error, HyperCraftHalImpl, ReplayLog, Result as HyperResult, VCpu};
+use libax::io::{self, Read, Write};
+
+/// What to do with the inputs of the VMs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+/// The mode set by the `replay` field of the config of a VM, if any.
+pub fn mode(setting: usize) -> Option<Mode> {
+    match setting {
+        0 => None,
+        1 => Some(Mode::Record),
+        2 => Some(Mode::Replay),
+        other => {
+            warn!("unknown replay mode {}, ignored", other);
+            None
+        }
+    }
+}
+
+fn io_err(err: io::Error) -> Error {
+    warn!("replay file I/O failed: {:?}", err);
+    Error::Internal
+}
+
+fn path(vm_id: usize) -> alloc::string::String {
+    format!("/vm{}.replay", vm_id)
+}
+
+/// Gives the vCPU a log to record into, or the log of the VM to replay. It
+/// must be called right before the vCPU runs.
+pub fn start(vm_id: usize, vcpu: &mut VCpu<HyperCraftHalImpl>, mode: Mode) -> HyperResult {
+    let log = match mode {
+        Mode::Record => ReplayLog::recording(),
+        Mode::Replay => {
+            let mut data = Vec::new();
+            File::open(&path(vm_id))
+                .and_then(|mut file| file.read_to_end(&mut data))
+                .map_err(io_err)?;
+            let log = ReplayLog::replaying(&data)?;
+            info!("VM {} replaying {} inputs", vm_id, log.len());
+            log
+        }
+    };
+    vcpu.set_replay_log(Some(log))
+}
+
+/// Stops recording or replaying once the vCPU has returned, and writes the
+/// recorded inputs to the file of the VM.
+pub fn finish(vm_id: usize, vcpu: &mut VCpu<HyperCraftHalImpl>, mode: Mode) -> HyperResult {
+    let Some(log) = vcpu.take_replay_log() else {
+        return Ok(());
+    };
+    if mode == Mode::Record {
+        let mut file = File::create(&path(vm_id)).map_err(io_err)?;
+        file.write_all(&log.to_bytes()).map_err(io_err)?;
+        info!("VM {} recorded {} inputs to {}", vm_id, log.len(), path(vm_id));
+    } else if log.is_replaying() {
+        info!("VM {} stopped with {} inputs not replayed", vm_id, log.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Saving VMs to snapshot files, and restoring them at boot.
+//!
+//! A snapshot file starts with a header: the magic, the format version, and
+//! the length-prefixed states of the virtual clock, the vCPUs and the emulated
+//! devices. It's followed by each guest memory region: its start, its size,
+//! then for each page a flag telling if the page is zero, and the page content
+//! if it's not.
+//!
+//! Limitations: the VM must be restored with the same configuration, memory
+//! layout and guest images as when it was saved. Host devices passed through
+//! to the guest are not saved, nor is memory shared with other VMs: restoring
+//! a VM must not overwrite what its peers wrote since.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use libax::fs::File;
+use libax::hv::{
+    snapshot, Error, HyperCraftHal, HyperCraftHalImpl, Result as HyperResult,
+    StateReader, StateWriter, VcpuState, VM,
+};
+use libax::io::{self, Read, Write};
+
+use crate::x64::GuestPhysMemorySet;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"HVSNAP\0\0";
+const SNAPSHOT_VERSION: u32 = 1;
+
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+
+fn io_err(err: io::Error) -> Error {
+    warn!("snapshot file I/O failed: {:?}", err);
+    Error::Internal
+}
+
+fn read_u64(file: &mut File) -> HyperResult<u64> {
+    let mut buf = [0; 8];
+    file.read_exact(&mut buf).map_err(io_err)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Pauses the first `vcpu_count` vCPUs of the VM, saves it to the file at
+/// `path`, then lets the VM run again.
+pub fn save(vm_id: usize, vcpu_count: usize, gpm: &GuestPhysMemorySet, path: &str) -> HyperResult {
+    snapshot::request(vm_id, vcpu_count)?;
+    let res = snapshot::wait_captured(vm_id).and_then(|state| {
+        let mut header = StateWriter::new();
+        header.put_u64(state.clock_ns);
+        header.put_u32(state.vcpus.len() as u32);
+        for vcpu in &state.vcpus {
+            vcpu.encode(&mut header);
+        }
+        header.put_bytes(&state.devices);
+        write_file(vm_id, gpm, header.as_bytes(), path)
+    });
+    snapshot::resume(vm_id);
+    res
+}
+
+fn write_file(vm_id: usize, gpm: &GuestPhysMemorySet, header: &[u8], path: &str) -> HyperResult {
+    let mut file = File::create(path).map_err(io_err)?;
+    let mut w = StateWriter::new();
+    w.put_u32(SNAPSHOT_VERSION);
+    w.put_bytes(header);
+    file.write_all(SNAPSHOT_MAGIC).map_err(io_err)?;
+    file.write_all(w.as_bytes()).map_err(io_err)?;
+
+    let page_size = HyperCraftHalImpl::PAGE_SIZE;
+    let mut page = vec![0u8; page_size];
+    let regions = gpm.private_memory_regions();
+    file.write_all(&(regions.len() as u64).to_le_bytes()).map_err(io_err)?;
+    for (start, size) in regions {
+        file.write_all(&(start as u64).to_le_bytes()).map_err(io_err)?;
+        file.write_all(&(size as u64).to_le_bytes()).map_err(io_err)?;
+        for gpa in (start..start + size).step_by(page_size) {
+            if gpm.read_page(gpa, &mut page)? && page.iter().any(|&b| b != 0) {
+                file.write_all(&[PAGE_DATA]).map_err(io_err)?;
+                file.write_all(&page).map_err(io_err)?;
+            } else {
+                file.write_all(&[PAGE_ZERO]).map_err(io_err)?;
+            }
+        }
+    }
+    info!("VM {} saved to {}", vm_id, path);
+    Ok(())
+}
+
+/// Restores the VM from the file at `path` if it exists, and returns whether
+/// it does. The vCPUs must have been added but not run yet. The VMCS of vCPU 0
+/// is left current.
+pub fn restore(vm: &mut VM<HyperCraftHalImpl>, gpm: &GuestPhysMemorySet, path: &str) -> HyperResult<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(io::Error::NotFound) => return Ok(false),
+        Err(err) => return Err(io_err(err)),
+    };
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic).map_err(io_err)?;
+    let mut version = [0; 4];
+    file.read_exact(&mut version).map_err(io_err)?;
+    if &magic != SNAPSHOT_MAGIC || u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+        warn!("{} is not a snapshot of a supported version", path);
+        return Err(Error::DecodeError);
+    }
+    // The header follows the magic, the version and its own length, and can
+    // not be longer than the rest of the file.
+    let file_len = file.metadata().map_err(io_err)?.len();
+    let header_len = read_u64(&mut file)?;
+    if header_len > file_len.saturating_sub(SNAPSHOT_MAGIC.len() as u64 + 12) {
+        warn!("{} is truncated", path);
+        return Err(Error::DecodeError);
+    }
+    let mut header = vec![0u8; header_len as usize];
+    file.read_exact(&mut header).map_err(io_err)?;
+
+    let mut r = StateReader::new(&header);
+    let clock_ns = r.get_u64()?;
+    let mut vcpus = Vec::new();
+    for _ in 0..r.get_u32()? {
+        vcpus.push(VcpuState::decode(&mut r)?);
+    }
+    let devices = r.get_bytes()?;
+    if r.remaining() != 0 {
+        return Err(Error::DecodeError);
+    }
+
+    vm.clock().set_now_ns(clock_ns)?;
+    for (vcpu_id, state) in vcpus.iter().enumerate().rev() {
+        let vcpu = vm.get_vcpu(vcpu_id)?;
+        vcpu.load_vmcs()?;
+        vcpu.restore_state(state)?;
+    }
+    snapshot::restore_devices(vm.get_vm_id(), devices)?;
+    restore_memory(gpm, &mut file)?;
+    info!("VM {} restored from {}", vm.get_vm_id(), path);
+    Ok(true)
+}
+
+fn restore_memory(gpm: &GuestPhysMemorySet, file: &mut File) -> HyperResult {
+    let page_size = HyperCraftHalImpl::PAGE_SIZE;
+    let mut page = vec![0u8; page_size];
+    let regions = gpm.private_memory_regions();
+    if read_u64(file)? != regions.len() as u64 {
+        return Err(Error::DecodeError);
+    }
+    for (start, size) in regions {
+        if read_u64(file)? != start as u64 || read_u64(file)? != size as u64 {
+            warn!("memory layout differs from the snapshot at GPA {:#x}", start);
+            return Err(Error::DecodeError);
+        }
+        for gpa in (start..start + size).step_by(page_size) {
+            let mut flag = [0];
+            file.read_exact(&mut flag).map_err(io_err)?;
+            match flag[0] {
+                PAGE_ZERO => gpm.write_page(gpa, None)?,
+                PAGE_DATA => {
+                    file.read_exact(&mut page).map_err(io_err)?;
+                    gpm.write_page(gpa, Some(&page))?;
+                }
+                _ => return Err(Error::DecodeError),
+            }
+        }
+    }
+    Ok(())
+}
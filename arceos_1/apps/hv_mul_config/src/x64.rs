@@ -1,8 +1,12 @@
 use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
+use core::ops::RangeInclusive;
 
-use libax::hv::{HyperCraftHal, HyperCraftHalImpl, GuestPhysAddr, HostPhysAddr, phys_to_virt, virt_to_phys, Result as HyperResult, Error, GuestPageTable, GuestPageTableTrait};
+use libax::hv::{HyperCraftHal, HyperCraftHalImpl, GuestPhysAddr, HostPhysAddr, HostVirtAddr, phys_to_virt, virt_to_phys, Result as HyperResult, Error, GuestPageTable, GuestPageTableTrait, global_allocator, acpi, demand_paging, set_hpet_enabled, shmem, vswitch};
+use libax::sync::spin::SpinNoIrq;
 
 use page_table_entry::MappingFlags;
 
@@ -16,7 +20,13 @@ pub const GUEST_IMAGE_SIZE: usize = 0x10_0000; // 1M
 pub const GUEST_PHYS_MEMORY_BASE: GuestPhysAddr = 0;
 pub const BIOS_ENTRY: GuestPhysAddr = 0x8000;
 pub const GUEST_ENTRY: GuestPhysAddr = 0x20_0000;
-pub const GUEST_PHYS_MEMORY_SIZE: usize = 0x100_0000; // 16M
+
+// guest RAM beyond 3G is placed above 4G, leaving a hole for MMIO devices
+pub const GUEST_LOW_MEMORY_LIMIT: GuestPhysAddr = 0xc000_0000;
+pub const GUEST_HIGH_MEMORY_BASE: GuestPhysAddr = 0x1_0000_0000;
+// host memory backing guest RAM is aligned like the guest addresses up to 2M,
+// so that the EPT can use huge pages
+const GUEST_MEMORY_ALIGN: usize = 0x20_0000;
 
 pub const MAX_VMS: usize = 2;
 
@@ -27,6 +37,10 @@ pub const fn is_aligned(addr: usize) -> bool {
 #[derive(Debug)]
 enum Mapper {
     Offset(usize),
+    // like `Offset`, for memory shared with other VMs
+    Shared(usize),
+    // populated on first touch, with the VM id
+    Demand(usize),
 }
 
 #[derive(Debug)]
@@ -54,7 +68,7 @@ impl MapRegion {
         assert!(is_aligned(start_gpa));
         assert!(is_aligned(start_hpa));
         assert!(is_aligned(size));
-        let offset = start_gpa - start_hpa;
+        let offset = start_gpa.wrapping_sub(start_hpa);
         Self {
             start: start_gpa,
             size,
@@ -63,6 +77,29 @@ impl MapRegion {
         }
     }
 
+    pub fn new_shared(
+        start_gpa: GuestPhysAddr,
+        start_hpa: HostPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> Self {
+        Self {
+            mapper: Mapper::Shared(start_gpa.wrapping_sub(start_hpa)),
+            ..Self::new_offset(start_gpa, start_hpa, size, flags)
+        }
+    }
+
+    pub fn new_demand(start_gpa: GuestPhysAddr, size: usize, flags: MappingFlags, vm_id: usize) -> Self {
+        assert!(is_aligned(start_gpa));
+        assert!(is_aligned(size));
+        Self {
+            start: start_gpa,
+            size,
+            flags,
+            mapper: Mapper::Demand(vm_id),
+        }
+    }
+
     fn is_overlap_with(&self, other: &Self) -> bool {
         let s0 = self.start;
         let e0 = s0 + self.size;
@@ -71,31 +108,40 @@ impl MapRegion {
         !(e0 <= s1 || e1 <= s0)
     }
 
-    fn target(&self, gpa: GuestPhysAddr) -> HostPhysAddr {
+    fn target(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
         match self.mapper {
-            Mapper::Offset(off) => gpa.wrapping_sub(off),
+            Mapper::Offset(off) | Mapper::Shared(off) => Ok(gpa.wrapping_sub(off)),
+            Mapper::Demand(vm_id) => demand_paging::populate(vm_id, gpa),
         }
     }
 
-    fn map_to(&self, npt: &mut GuestPageTable) -> HyperResult {
-        let mut start = self.start;
-        let end = start + self.size;
-        while start < end {
-            let target = self.target(start);
-            npt.map(start, target, self.flags)?;
-            start += HyperCraftHalImpl::PAGE_SIZE;
+    // like `target`, but `None` if the page is not populated yet
+    fn lookup(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        match self.mapper {
+            Mapper::Offset(off) | Mapper::Shared(off) => Some(gpa.wrapping_sub(off)),
+            Mapper::Demand(vm_id) => demand_paging::lookup(vm_id, gpa),
+        }
+    }
+
+    fn contains(&self, gpa: GuestPhysAddr, size: usize) -> bool {
+        self.start <= gpa && gpa + size <= self.start + self.size
+    }
+
+    fn map_to(&self, npt: &SpinNoIrq<GuestPageTable>) -> HyperResult {
+        match self.mapper {
+            // huge pages are used where the alignment allows
+            Mapper::Offset(off) | Mapper::Shared(off) => npt.lock().map_region(self.start, self.start.wrapping_sub(off), self.size, self.flags),
+            // not under the EPT lock, `demand_paging::populate` takes it after its own
+            Mapper::Demand(vm_id) => demand_paging::add_region(vm_id, self.start, self.size, self.flags),
         }
-        Ok(())
     }
 
     fn unmap_to(&self, npt: &mut GuestPageTable) -> HyperResult {
-        let mut start = self.start;
-        let end = start + self.size;
-        while start < end {
-            npt.unmap(start)?;
-            start += HyperCraftHalImpl::PAGE_SIZE;
+        match self.mapper {
+            Mapper::Offset(_) | Mapper::Shared(_) => npt.unmap_region(self.start, self.size),
+            // populated pages are freed by `demand_paging::release`
+            Mapper::Demand(_) => Ok(()),
         }
-        Ok(())
     }
 }
 
@@ -118,19 +164,45 @@ impl From<GuestMemoryRegion> for MapRegion {
 
 pub struct GuestPhysMemorySet {
     regions: BTreeMap<GuestPhysAddr, MapRegion>,
-    npt: GuestPageTable,
+    // shared with the EPT violation handler for demand paging
+    npt: Arc<SpinNoIrq<GuestPageTable>>,
+    // host pages allocated for guest memory: (start vaddr, number of pages)
+    backing: Vec<(HostVirtAddr, usize)>,
+    // VM id if RAM is populated on demand
+    demand_paging_vm: Option<usize>,
+    // VM id if the virtio-net device accesses the memory
+    virtio_net_vm: Option<usize>,
 }
 
 impl GuestPhysMemorySet {
     pub fn new() -> HyperResult<Self> {
         Ok(Self {
-            npt: GuestPageTable::new()?,
+            npt: Arc::new(SpinNoIrq::new(GuestPageTable::new()?)),
             regions: BTreeMap::new(),
+            backing: Vec::new(),
+            demand_paging_vm: None,
+            virtio_net_vm: None,
         })
     }
 
     pub fn nest_page_table_root(&self) -> HostPhysAddr {
-        self.npt.root_paddr().into()
+        self.npt.lock().root_paddr().into()
+    }
+
+    /// Populates RAM allocated by later [`GuestPhysMemorySet::alloc_region`]
+    /// calls on first touch, with at most `max_size` bytes of host memory.
+    pub fn enable_demand_paging(&mut self, vm_id: usize, max_size: usize) {
+        let max_pages = max_size / HyperCraftHalImpl::PAGE_SIZE;
+        demand_paging::init(vm_id, self.npt.clone(), max_pages);
+        self.demand_paging_vm = Some(vm_id);
+    }
+
+    /// Connects the virtio-net device of the VM to the switch, with the MAC
+    /// address `mac` and the interrupt `vector`.
+    pub fn enable_virtio_net(&mut self, vm_id: usize, mac: [u8; 6], vector: u8) -> HyperResult {
+        vswitch::attach(vm_id, mac, vector, self.npt.clone())?;
+        self.virtio_net_vm = Some(vm_id);
+        Ok(())
     }
 
     fn test_free_area(&self, other: &MapRegion) -> bool {
@@ -160,22 +232,143 @@ impl GuestPhysMemorySet {
             );
             return Err(Error::InvalidParam);
         }
-        region.map_to(&mut self.npt)?;
+        region.map_to(&self.npt)?;
         self.regions.insert(region.start, region);
         Ok(())
     }
 
+    /// Allocates zeroed host memory for the guest region `[gpa, gpa + size)`
+    /// and maps it with `flags`.
+    pub fn alloc_region(&mut self, gpa: GuestPhysAddr, size: usize, flags: MappingFlags) -> HyperResult {
+        if let Some(vm_id) = self.demand_paging_vm {
+            return self.map_region(MapRegion::new_demand(gpa, size, flags, vm_id));
+        }
+        let page_size = HyperCraftHalImpl::PAGE_SIZE;
+        let (align, offset) = if size >= GUEST_MEMORY_ALIGN {
+            (GUEST_MEMORY_ALIGN, gpa & (GUEST_MEMORY_ALIGN - 1))
+        } else {
+            (page_size, 0)
+        };
+        let num_pages = (offset + size + page_size - 1) / page_size;
+        let base = global_allocator()
+            .alloc_pages(num_pages, align)
+            .map_err(|_| Error::NoMemory)?;
+        unsafe { core::ptr::write_bytes(base as *mut u8, 0, num_pages * page_size) };
+        self.backing.push((base, num_pages));
+        let hpa: HostPhysAddr = virt_to_phys((base + offset).into()).into();
+        self.map_region(MapRegion::new_offset(gpa, hpa, size, flags))
+    }
+
+    /// Copies `size` bytes at host physical address `hpa` to the guest memory
+    /// at `gpa`, which must be inside one region.
+    pub fn load_image(&self, hpa: HostPhysAddr, gpa: GuestPhysAddr, size: usize) -> HyperResult {
+        trace!("loading to guest memory: host {:#x} to guest {:#x}, size {:#x}", hpa, gpa, size);
+        let src = usize::from(phys_to_virt(hpa.into())) as *const u8;
+        self.write_bytes(gpa, unsafe { core::slice::from_raw_parts(src, size) })
+    }
+
+    /// Copies `data` to the guest memory at `gpa`, which must be inside one
+    /// region.
+    pub fn write_bytes(&self, gpa: GuestPhysAddr, data: &[u8]) -> HyperResult {
+        let size = data.len();
+        let region = match self.regions.range(..=gpa).last() {
+            Some((_, region)) if region.contains(gpa, size) => region,
+            _ => return Err(Error::InvalidParam),
+        };
+        // copy page by page, as demand-paged memory is not contiguous
+        let page_size = HyperCraftHalImpl::PAGE_SIZE;
+        let mut offset = 0;
+        while offset < size {
+            let len = (page_size - (gpa + offset) % page_size).min(size - offset);
+            let dst = usize::from(phys_to_virt(region.target(gpa + offset)?.into())) as *mut u8;
+            unsafe { core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), dst, len) };
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Start and size of the regions backed by guest memory, not by devices.
+    #[allow(dead_code)]
+    pub fn memory_regions(&self) -> Vec<(GuestPhysAddr, usize)> {
+        self.regions
+            .values()
+            .filter(|r| !r.flags.contains(MappingFlags::DEVICE))
+            .map(|r| (r.start, r.size))
+            .collect()
+    }
+
+    /// Like [`GuestPhysMemorySet::memory_regions`], without the memory shared
+    /// with other VMs.
+    #[allow(dead_code)]
+    pub fn private_memory_regions(&self) -> Vec<(GuestPhysAddr, usize)> {
+        self.regions
+            .values()
+            .filter(|r| !r.flags.contains(MappingFlags::DEVICE) && !matches!(r.mapper, Mapper::Shared(_)))
+            .map(|r| (r.start, r.size))
+            .collect()
+    }
+
+    fn page_region(&self, gpa: GuestPhysAddr) -> HyperResult<&MapRegion> {
+        match self.regions.range(..=gpa).last() {
+            Some((_, region)) if region.contains(gpa, HyperCraftHalImpl::PAGE_SIZE) => Ok(region),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Copies the guest page at `gpa` to `buf`. Returns `false` without
+    /// touching `buf` if the page is not populated yet, so it reads as zeros.
+    #[allow(dead_code)]
+    pub fn read_page(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<bool> {
+        let Some(hpa) = self.page_region(gpa)?.lookup(gpa) else {
+            return Ok(false);
+        };
+        let page_size = HyperCraftHalImpl::PAGE_SIZE;
+        let src = usize::from(phys_to_virt(hpa.into())) as *const u8;
+        buf[..page_size].copy_from_slice(unsafe { core::slice::from_raw_parts(src, page_size) });
+        Ok(true)
+    }
+
+    /// Overwrites the guest page at `gpa` with `data`, or with zeros if
+    /// `data` is `None`, in which case a page not populated yet is left as is.
+    #[allow(dead_code)]
+    pub fn write_page(&self, gpa: GuestPhysAddr, data: Option<&[u8]>) -> HyperResult {
+        let region = self.page_region(gpa)?;
+        let page_size = HyperCraftHalImpl::PAGE_SIZE;
+        match data {
+            Some(data) => {
+                let dst = usize::from(phys_to_virt(region.target(gpa)?.into())) as *mut u8;
+                unsafe { core::slice::from_raw_parts_mut(dst, page_size) }.copy_from_slice(&data[..page_size]);
+            }
+            None => {
+                if let Some(hpa) = region.lookup(gpa) {
+                    let dst = usize::from(phys_to_virt(hpa.into())) as *mut u8;
+                    unsafe { core::ptr::write_bytes(dst, 0, page_size) };
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
+        if let Some(vm_id) = self.virtio_net_vm.take() {
+            vswitch::detach(vm_id);
+        }
         for region in self.regions.values() {
-            region.unmap_to(&mut self.npt).unwrap();
+            region.unmap_to(&mut self.npt.lock()).unwrap();
         }
         self.regions.clear();
+        if let Some(vm_id) = self.demand_paging_vm.take() {
+            demand_paging::release(vm_id);
+        }
     }
 }
 
 impl Drop for GuestPhysMemorySet {
     fn drop(&mut self) {
         self.clear();
+        for &(base, num_pages) in self.backing.iter() {
+            global_allocator().dealloc_pages(base, num_pages);
+        }
     }
 }
 
@@ -194,48 +387,37 @@ pub(super) struct AlignedMemory<const LEN: usize>([u8; LEN]);
 
 
 
-pub(super) static mut GUEST_PHYS_MEMORY: [AlignedMemory<GUEST_PHYS_MEMORY_SIZE>;MAX_VMS]=
-    [AlignedMemory([0; GUEST_PHYS_MEMORY_SIZE]);MAX_VMS];
 pub(super) static mut IO_PHYS_MEMORY: [AlignedMemory<0x1000>;MAX_VMS]=
     [AlignedMemory([0; 0x1000]);MAX_VMS];
 // pub(super) static mut HPET_PHYS_MEMORY: [AlignedMemory<0x1000>;MAX_VMS]=
 //     [AlignedMemory([0; 0x1000]);MAX_VMS];
 pub(super) static mut LAPIC_PHYS_MEMORY: [AlignedMemory<0x1000>;MAX_VMS]=
     [AlignedMemory([0; 0x1000]);MAX_VMS];
-// pub(super) static mut GUEST_PHYS_MEMORY_0: AlignedMemory<GUEST_PHYS_MEMORY_SIZE>=
-//     AlignedMemory([0; GUEST_PHYS_MEMORY_SIZE]);
-// pub(super) static mut GUEST_PHYS_MEMORY_1: AlignedMemory<GUEST_PHYS_MEMORY_SIZE>=
-//     AlignedMemory([0; GUEST_PHYS_MEMORY_SIZE]);
-
-fn gpa_as_mut_ptr(guest_paddr: GuestPhysAddr, id: usize) -> *mut u8 {
-    let offset = unsafe { &GUEST_PHYS_MEMORY[id] as *const _ as usize };
-    let host_vaddr = guest_paddr + offset;
-    host_vaddr as *mut u8
-    // if id == 0 {
-    //     let offset = unsafe { &GUEST_PHYS_MEMORY_0 as *const _ as usize };
-    //     let host_vaddr = guest_paddr + offset;
-    //     host_vaddr as *mut u8
-    // } else {
-    //     // id = 1
-    //     let offset = unsafe { &GUEST_PHYS_MEMORY_1 as *const _ as usize };
-    //     let host_vaddr = guest_paddr + offset;
-    //     host_vaddr as *mut u8
-    // }
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GuestMemoryKind {
+    Ram = 0,
+    // read-only, e.g. firmware
+    Rom = 1,
 }
 
-#[cfg(target_arch = "x86_64")]
-fn load_guest_image(hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize, id: usize) {
-    let image_ptr = usize::from(phys_to_virt(hpa.into())) as *const u8;
-    let image = unsafe { core::slice::from_raw_parts(image_ptr, size) };
-
-    trace!("loading to guest memory: host {:#x} to guest {:#x}, size {:#x}", image_ptr as usize, load_gpa, size);
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GuestMemoryConfig {
+    pub gpa: GuestPhysAddr,
+    pub size: usize,
+    pub kind: GuestMemoryKind,
+}
 
-    unsafe {
-        core::slice::from_raw_parts_mut(gpa_as_mut_ptr(load_gpa, id), size).copy_from_slice(image)
-    }
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SharedMemoryConfig {
+    // VMs with the same name share the region
+    pub name: String,
+    pub gpa: GuestPhysAddr,
+    pub size: usize,
+    // injected when a peer rings the doorbell of the region
+    pub vector: u8,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ConfigFile {
     pub id: usize,
     pub memory: usize,
@@ -243,30 +425,123 @@ pub struct ConfigFile {
     pub io_apic: usize,
     pub HPET: usize,
     pub local_apic: usize,
+    // if non-zero, RAM is populated on first touch, up to this many bytes
+    pub memory_cap: usize,
+    // explicit memory layout, `memory` is split around the MMIO hole if empty
+    pub regions: Vec<GuestMemoryConfig>,
+    // I/O ports accessed by the guest directly, owned by this VM only
+    pub io_ports: Vec<RangeInclusive<u16>>,
+    // memory shared with other VMs, numbered in this order for the doorbell
+    pub shared_memory: Vec<SharedMemoryConfig>,
+    // MAC address of the virtio-net device, no device if zero
+    pub net_mac: [u8; 6],
+    // interrupt vector of the virtio-net device
+    pub net_vector: u8,
+    // the physical CPU each vCPU is pinned to, by vCPU ID
+    pub cpus: Vec<usize>,
+    // 1 to record the inputs of the first boot, 2 to replay them, see replay.rs
+    pub replay: usize,
+}
+
+impl ConfigFile {
+    pub fn memory_regions(&self) -> Vec<GuestMemoryConfig> {
+        if !self.regions.is_empty() {
+            return self.regions.clone();
+        }
+        let low_size = self.memory.min(GUEST_LOW_MEMORY_LIMIT - GUEST_PHYS_MEMORY_BASE);
+        let mut regions = Vec::new();
+        regions.push(GuestMemoryConfig {
+            gpa: GUEST_PHYS_MEMORY_BASE,
+            size: low_size,
+            kind: GuestMemoryKind::Ram,
+        });
+        if self.memory > low_size {
+            regions.push(GuestMemoryConfig {
+                gpa: GUEST_HIGH_MEMORY_BASE,
+                size: self.memory - low_size,
+                kind: GuestMemoryKind::Ram,
+            });
+        }
+        regions
+    }
+
+    /// Checks that the VM has a single vCPU, as guests can not start other
+    /// processors, that the memory regions, including the shared ones, are
+    /// non-empty and page-aligned, as they are mapped page by page, and that
+    /// one of them holds the ACPI tables.
+    pub fn validate(&self) -> HyperResult {
+        if self.vcpu_count != 1 || self.cpus.len() > 1 {
+            warn!(
+                "VM{} has {} vCPUs pinned to CPUs {:?}, only one vCPU is supported",
+                self.id, self.vcpu_count, self.cpus
+            );
+            return Err(Error::InvalidParam);
+        }
+        let shared = self.shared_memory.iter().map(|r| (r.gpa, r.size));
+        let regions = self.memory_regions().into_iter().map(|r| (r.gpa, r.size));
+        for (gpa, size) in regions.chain(shared) {
+            if size == 0 || !is_aligned(gpa) || !is_aligned(size) {
+                warn!("VM{} memory region {:#x} + {:#x} is not page-aligned", self.id, gpa, size);
+                return Err(Error::InvalidParam);
+            }
+        }
+        let acpi_start = acpi::ACPI_TABLES_GPA;
+        let acpi_end = acpi_start + acpi::ACPI_TABLES_MAX_SIZE;
+        let regions = self.memory_regions();
+        if !regions.iter().any(|r| r.gpa <= acpi_start && acpi_end <= r.gpa + r.size) {
+            warn!(
+                "VM{} has no memory region for the ACPI tables at {:#x}..{:#x}",
+                self.id, acpi_start, acpi_end
+            );
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
 pub fn setup_gpm(id: usize, config_file: ConfigFile) -> HyperResult<GuestPhysMemorySet> {
-    // copy BIOS and guest images
+    // create nested page table and allocate RAM and ROM
+    let mut gpm = GuestPhysMemorySet::new()?;
+    if config_file.memory_cap != 0 {
+        gpm.enable_demand_paging(id, config_file.memory_cap);
+    }
+    for r in config_file.memory_regions() {
+        let flags = match r.kind {
+            GuestMemoryKind::Ram => MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+            GuestMemoryKind::Rom => MappingFlags::READ | MappingFlags::EXECUTE,
+        };
+        gpm.alloc_region(r.gpa, r.size, flags)?;
+    }
 
-    use libax::hv::HostVirtAddr;
-    load_guest_image(BIOS_PADDR, BIOS_ENTRY, BIOS_SIZE, id);
-    load_guest_image(GUEST_IMAGE_PADDR, GUEST_ENTRY, GUEST_IMAGE_SIZE, id);
+    // map the same host pages in all VMs sharing a region
+    for r in config_file.shared_memory.iter() {
+        let hpa = shmem::attach(id, &r.name, r.size, r.vector)?;
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        gpm.map_region(MapRegion::new_shared(r.gpa, hpa, r.size, flags))?;
+    }
 
-    // create nested page table and add mapping
-    let mut gpm = GuestPhysMemorySet::new()?;
-    // let hpa_base:usize = virt_to_phys((gpa_as_mut_ptr(GUEST_PHYS_MEMORY_BASE + id * GUEST_PHYS_MEMORY_SIZE) as HostVirtAddr).into()).into();
-    // let hpa_base = 0x26_8000;
-    // info!("hpa_base {:x}",hpa_base);
-    let mut guest_memory_regions = Vec::new();
-    guest_memory_regions.push(GuestMemoryRegion {
-        // RAM
-        gpa: GUEST_PHYS_MEMORY_BASE,
-        hpa: virt_to_phys((gpa_as_mut_ptr(GUEST_PHYS_MEMORY_BASE, id) as HostVirtAddr).into()).into(),
-        // hpa: hpa_base,
-        size: config_file.memory,
-        flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
+    if config_file.net_mac != [0; 6] {
+        gpm.enable_virtio_net(id, config_file.net_mac, config_file.net_vector)?;
+    }
+
+    // copy BIOS and guest images
+    gpm.load_image(BIOS_PADDR, BIOS_ENTRY, BIOS_SIZE)?;
+    gpm.load_image(GUEST_IMAGE_PADDR, GUEST_ENTRY, GUEST_IMAGE_SIZE)?;
+
+    // describe the devices below to the guest
+    let tables = acpi::build_tables(&acpi::AcpiConfig {
+        // `validate` checked that it's the number of vCPUs created
+        cpu_count: config_file.vcpu_count,
+        local_apic: config_file.local_apic != 0,
+        io_apic: config_file.io_apic != 0,
+        hpet: config_file.HPET != 0,
     });
+    gpm.write_bytes(acpi::ACPI_TABLES_GPA, &tables)?;
+    // the HPET is emulated, on the virtual clock of the VM
+    set_hpet_enabled(id, config_file.HPET != 0);
+
+    let mut guest_memory_regions = Vec::new();
     if config_file.io_apic != 0 {
         guest_memory_regions.push(GuestMemoryRegion {
             // IO APIC
@@ -276,15 +551,6 @@ pub fn setup_gpm(id: usize, config_file: ConfigFile) -> HyperResult<GuestPhysMem
             flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
         });
     }
-    if config_file.HPET != 0 {
-        guest_memory_regions.push(GuestMemoryRegion {
-            // HPET
-            gpa: 0xfed0_0000,
-            hpa: 0xfed0_0000,
-            size: 0x1000,
-            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-        });
-    }
     if config_file.local_apic != 0 {
         guest_memory_regions.push(GuestMemoryRegion {
             // Local APIC
@@ -0,0 +1,106 @@
+//! Counting the branches retired by a guest with the first general-purpose
+//! performance counter, to position replayed inputs.
+//!
+//! The counter only counts in the guest: on Intel processors, VM entries and
+//! exits load IA32_PERF_GLOBAL_CTRL, and on AMD ones, it's set guest-only. It
+//! is reset before each VM entry and read after each VM exit, as other vCPUs
+//! may use it in between. Guest accesses to it are intercepted and ignored.
+
+use raw_cpuid::cpuid;
+
+use super::detect::{extension, Extension};
+use super::hw::{Hw, HwAccess};
+
+/// MSRs and event of the counter.
+struct Counter {
+    event_select: u32,
+    counter: u32,
+    /// Other MSRs giving access to the counter.
+    other_msrs: &'static [u32],
+    event: u64,
+}
+
+/// IA32_PERFEVTSEL0 and IA32_PMC0, also accessed by IA32_A_PMC0 and
+/// IA32_PERF_GLOBAL_CTRL. The architectural "branch instruction retired"
+/// event, counted at all privilege levels and enabled. (SDM Vol. 3B, Section 20.2.1)
+const INTEL_COUNTER: Counter = Counter {
+    event_select: 0x186,
+    counter: 0xc1,
+    other_msrs: &[0x4c1, PERF_GLOBAL_CTRL],
+    event: 0xc4 | 1 << 16 | 1 << 17 | 1 << 22,
+};
+
+/// PerfEvtSel0 and PerfCtr0, also accessed by PERF_CTL0 and PERF_CTR0 of the
+/// core performance counter extensions. The "retired branch instructions"
+/// event, counted at all privilege levels, enabled, and in the guest only.
+/// (APM Vol. 2, Section 13.2.1)
+const AMD_COUNTER: Counter = Counter {
+    event_select: 0xc001_0000,
+    counter: 0xc001_0004,
+    other_msrs: &[0xc001_0200, 0xc001_0201],
+    event: 0xc2 | 1 << 16 | 1 << 17 | 1 << 22 | 1 << 40,
+};
+
+/// IA32_PERF_GLOBAL_CTRL.
+const PERF_GLOBAL_CTRL: u32 = 0x38f;
+
+/// Bits of the counter, the least implemented by processors.
+const COUNTER_MASK: u64 = (1 << 40) - 1;
+
+/// The IA32_PERF_GLOBAL_CTRL value in the guest, with only the counter
+/// enabled.
+pub const GUEST_PERF_GLOBAL_CTRL: u64 = 1;
+
+fn counter() -> &'static Counter {
+    match extension() {
+        Extension::Vmx => &INTEL_COUNTER,
+        Extension::Svm => &AMD_COUNTER,
+    }
+}
+
+/// Whether the processor can count the branches retired by guests.
+pub fn is_supported() -> bool {
+    if extension() == Extension::Svm {
+        return true;
+    }
+    // Architectural performance monitoring version 2, for IA32_PERF_GLOBAL_CTRL,
+    // with at least one counter and the event available. (SDM Vol. 2A, CPUID)
+    let res = cpuid!(0xa);
+    let version = res.eax & 0xff;
+    let counters = (res.eax >> 8) & 0xff;
+    let events = res.eax >> 24;
+    version >= 2 && counters >= 1 && events > 5 && res.ebx & (1 << 5) == 0
+}
+
+/// Whether the guest accesses the counter with the MSR `msr`.
+pub fn is_counter_msr(msr: u32) -> bool {
+    let c = counter();
+    msr == c.event_select || msr == c.counter || c.other_msrs.contains(&msr)
+}
+
+/// The MSRs of the counter, to be intercepted.
+pub fn counter_msrs() -> impl Iterator<Item = u32> {
+    let c = counter();
+    [c.event_select, c.counter].into_iter().chain(c.other_msrs.iter().copied())
+}
+
+/// Starts counting from zero, right before a VM entry.
+pub fn reset() {
+    let c = counter();
+    unsafe {
+        if extension() == Extension::Vmx {
+            // The host value loaded at VM exits, which is not loaded yet
+            // before the first VM entry.
+            Hw::wrmsr(PERF_GLOBAL_CTRL, 0);
+        }
+        Hw::wrmsr(c.event_select, 0);
+        Hw::wrmsr(c.counter, 0);
+        Hw::wrmsr(c.event_select, c.event);
+    }
+}
+
+/// Returns the branches retired by the guest since [`reset`], right after a
+/// VM exit.
+pub fn read() -> u64 {
+    Hw::rdmsr(counter().counter) & COUNTER_MASK
+}
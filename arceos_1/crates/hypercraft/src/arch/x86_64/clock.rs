@@ -0,0 +1,196 @@
+use core::marker::PhantomData;
+use spinlock::SpinNoIrq;
+
+use crate::{HyperCraftHal, HyperError, HyperResult};
+
+/// Fixed-point TSC multiplier which means a ratio of 1.0. (SDM Vol. 3C, Section 25.3)
+pub const TSC_MULTIPLIER_ONE: u64 = 1 << 48;
+
+struct ClockState {
+    /// Virtual time at the origin, non-zero if restored from a snapshot.
+    base_ns: u64,
+    /// Total host time in nanoseconds during which the clock was stopped.
+    stopped_ns: u64,
+    /// Host time at which the clock was stopped, valid if `stop_depth > 0`.
+    stopped_at: u64,
+    /// Number of reasons why the clock is stopped.
+    stop_depth: usize,
+    /// Number of vCPUs that are scheduled on physical CPUs.
+    running_vcpus: usize,
+    /// Bumped on every restart, so vCPUs know their TSC offset is stale.
+    generation: u64,
+}
+
+/// The virtual time of a VM.
+///
+/// It starts from zero when the first vCPU of the VM runs, and does not
+/// advance while the VM is paused or all its vCPUs are descheduled. The guest
+/// TSC and the virtual local APIC timers are all derived from it, so they are
+/// always coherent with each other.
+pub struct VirtClock<H: HyperCraftHal> {
+    origin_ns: u64,
+    origin_tsc: u64,
+    host_tsc_mhz: u64,
+    guest_tsc_mhz: u64,
+    state: SpinNoIrq<ClockState>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> VirtClock<H> {
+    /// Creates a new clock starting from now, whose guest TSC runs at
+    /// `guest_tsc_mhz`, or at the host TSC frequency if it's `None`.
+    pub fn new(guest_tsc_mhz: Option<u64>) -> Self {
+        let host_tsc_mhz = H::nanos_to_ticks(1_000);
+        let origin_ns = H::current_time_nanos();
+        Self {
+            origin_ns,
+            origin_tsc: unsafe { core::arch::x86_64::_rdtsc() },
+            host_tsc_mhz,
+            guest_tsc_mhz: guest_tsc_mhz.unwrap_or(host_tsc_mhz),
+            // stopped until a vCPU runs
+            state: SpinNoIrq::new(ClockState {
+                base_ns: 0,
+                stopped_ns: 0,
+                stopped_at: origin_ns,
+                stop_depth: 1,
+                running_vcpus: 0,
+                generation: 0,
+            }),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The guest TSC frequency in MHz.
+    pub const fn guest_tsc_mhz(&self) -> u64 {
+        self.guest_tsc_mhz
+    }
+
+    /// Whether the guest TSC runs at a different frequency from the host TSC.
+    pub const fn need_scaling(&self) -> bool {
+        self.guest_tsc_mhz != self.host_tsc_mhz
+    }
+
+    /// Stops the clock. Calls can be nested, the clock restarts after the
+    /// same number of [`VirtClock::resume`] calls.
+    pub fn pause(&self) {
+        Self::stop(&mut self.state.lock());
+    }
+
+    /// Restarts the clock stopped by [`VirtClock::pause`].
+    pub fn resume(&self) {
+        Self::restart(&mut self.state.lock());
+    }
+
+    /// Called when a vCPU of the VM is scheduled on a physical CPU.
+    pub fn vcpu_scheduled(&self) {
+        let mut state = self.state.lock();
+        if state.running_vcpus == 0 {
+            Self::restart(&mut state);
+        }
+        state.running_vcpus += 1;
+    }
+
+    /// Called when a vCPU of the VM gives up its physical CPU. The clock
+    /// stops if no vCPU is left running.
+    pub fn vcpu_descheduled(&self) {
+        let mut state = self.state.lock();
+        assert!(state.running_vcpus > 0);
+        state.running_vcpus -= 1;
+        if state.running_vcpus == 0 {
+            Self::stop(&mut state);
+        }
+    }
+
+    /// Whether the clock is stopped.
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().stop_depth > 0
+    }
+
+    /// Generation number of the clock, changed whenever the clock restarts.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().generation
+    }
+
+    /// Current virtual time in nanoseconds.
+    pub fn now_ns(&self) -> u64 {
+        let state = self.state.lock();
+        let host_ns = if state.stop_depth > 0 {
+            state.stopped_at
+        } else {
+            H::current_time_nanos()
+        };
+        state.base_ns + host_ns - self.origin_ns - state.stopped_ns
+    }
+
+    /// Sets the current virtual time, e.g., to continue the time of a VM
+    /// restored from a snapshot. The clock must be stopped.
+    pub fn set_now_ns(&self, ns: u64) -> HyperResult {
+        let mut state = self.state.lock();
+        if state.stop_depth == 0 {
+            return Err(HyperError::BadState);
+        }
+        // forget the time elapsed so far
+        state.stopped_ns = state.stopped_at - self.origin_ns;
+        state.base_ns = ns;
+        Ok(())
+    }
+
+    fn stop(state: &mut ClockState) {
+        if state.stop_depth == 0 {
+            state.stopped_at = H::current_time_nanos();
+        }
+        state.stop_depth += 1;
+    }
+
+    fn restart(state: &mut ClockState) {
+        assert!(state.stop_depth > 0, "resume a running clock");
+        state.stop_depth -= 1;
+        if state.stop_depth == 0 {
+            state.stopped_ns += H::current_time_nanos() - state.stopped_at;
+            state.generation += 1;
+        }
+    }
+
+    /// Converts a virtual time to the host time, assuming the clock is running.
+    pub fn to_host_ns(&self, virt_ns: u64) -> u64 {
+        let state = self.state.lock();
+        virt_ns.saturating_sub(state.base_ns) + self.origin_ns + state.stopped_ns
+    }
+
+    /// Current guest TSC value.
+    pub fn guest_tsc(&self) -> u64 {
+        self.ns_to_guest_tsc(self.now_ns())
+    }
+
+    /// Converts a virtual time in nanoseconds to a guest TSC value.
+    pub fn ns_to_guest_tsc(&self, ns: u64) -> u64 {
+        (ns as u128 * self.guest_tsc_mhz as u128 / 1_000) as u64
+    }
+
+    /// Converts a guest TSC value to a virtual time in nanoseconds.
+    pub fn guest_tsc_to_ns(&self, tsc: u64) -> u64 {
+        (tsc as u128 * 1_000 / self.guest_tsc_mhz as u128) as u64
+    }
+
+    /// The TSC multiplier makes the guest TSC run at `guest_tsc_mhz`.
+    pub fn tsc_multiplier(&self) -> u64 {
+        ((self.guest_tsc_mhz as u128 * TSC_MULTIPLIER_ONE as u128) / self.host_tsc_mhz as u128)
+            as u64
+    }
+
+    /// The TSC offset to be added to the (scaled) host TSC, such that the
+    /// guest reads the virtual time in guest TSC ticks.
+    pub fn tsc_offset(&self, scaling: bool) -> u64 {
+        let (base_ns, stopped_ns) = {
+            let state = self.state.lock();
+            (state.base_ns, state.stopped_ns)
+        };
+        let hidden_ticks = self.origin_tsc + H::nanos_to_ticks(stopped_ns);
+        let hidden_ticks = if scaling {
+            ((hidden_ticks as u128 * self.tsc_multiplier() as u128) >> 48) as u64
+        } else {
+            hidden_ticks
+        };
+        hidden_ticks.wrapping_neg().wrapping_add(self.ns_to_guest_tsc(base_ns))
+    }
+}
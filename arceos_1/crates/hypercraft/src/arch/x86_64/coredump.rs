@@ -0,0 +1,192 @@
+//! ELF core files of VMs, which GDB loads with the symbols of the guest
+//! kernel: a `PT_NOTE` segment with the registers of each vCPU, then one
+//! `PT_LOAD` segment for each guest memory region.
+//!
+//! Guest physical addresses are used as virtual ones, unless the kernel maps
+//! the memory at a fixed offset, in which case each region is also loaded at
+//! its address in that mapping.
+
+use alloc::vec::Vec;
+
+use super::vcpu_state::VcpuState;
+use super::core_reg_fields;
+use crate::GuestPhysAddr;
+
+/// Alignment of the memory contents in core files.
+pub const CORE_FILE_ALIGN: usize = 0x1000;
+
+const ELF_HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+/// Size of `struct elf_prstatus` on x86_64 Linux.
+const PRSTATUS_LEN: usize = 336;
+/// Offset of the registers in `struct elf_prstatus`.
+const PRSTATUS_REGS_OFFSET: usize = 112;
+/// Size of the legacy region of the XSAVE area, which is the FXSAVE format of
+/// `NT_FPREGSET`.
+const FXSAVE_LEN: usize = 512;
+
+/// Guest fields of [`VcpuState::guest_fields`] holding the registers of
+/// `NT_PRSTATUS` which are not general-purpose.
+pub(crate) struct CoreRegFields {
+    pub rip: u32,
+    pub rflags: u32,
+    pub rsp: u32,
+    pub cs: u32,
+    pub ss: u32,
+    pub ds: u32,
+    pub es: u32,
+    pub fs: u32,
+    pub gs: u32,
+    pub fs_base: u32,
+    pub gs_base: u32,
+}
+
+/// Builds the headers of the core file of a VM, and its notes, padded to
+/// [`CORE_FILE_ALIGN`]. The contents of `regions` must follow in the file, in
+/// this order.
+///
+/// `vcpus` are the states of the vCPUs with their IDs, which are the thread
+/// IDs seen by GDB plus 1. `signal` is the signal they report, e.g., 11
+/// (`SIGSEGV`) if the VM crashed. If `virt_offset` is given, the regions are
+/// also loaded at virtual addresses this far from their guest physical ones.
+pub fn core_file_headers(
+    vcpus: &[(usize, VcpuState)],
+    signal: u32,
+    regions: &[(GuestPhysAddr, usize)],
+    virt_offset: Option<u64>,
+) -> Vec<u8> {
+    let mut notes = Vec::new();
+    for (vcpu_id, state) in vcpus {
+        add_note(&mut notes, NT_PRSTATUS, &prstatus(*vcpu_id, state, signal));
+        if let Some(fxsave) = state.xsave_area.get(..FXSAVE_LEN) {
+            add_note(&mut notes, NT_FPREGSET, fxsave);
+        }
+    }
+
+    let loads_per_region = if virt_offset.is_some() { 2 } else { 1 };
+    let phnum = 1 + regions.len() * loads_per_region;
+    let notes_offset = ELF_HEADER_LEN + phnum * PROGRAM_HEADER_LEN;
+    let headers_len = align_up(notes_offset + notes.len());
+
+    let mut buf = Vec::with_capacity(headers_len);
+    elf_header(&mut buf, phnum as u16);
+    program_header(&mut buf, PT_NOTE, 0, notes_offset, 0, 0, notes.len());
+    let flags = PF_R | PF_W | PF_X;
+    let mut offset = headers_len;
+    for &(gpa, size) in regions {
+        let paddr = gpa as u64;
+        program_header(&mut buf, PT_LOAD, flags, offset, paddr, paddr, size);
+        if let Some(virt_offset) = virt_offset {
+            let vaddr = paddr.wrapping_add(virt_offset);
+            program_header(&mut buf, PT_LOAD, flags, offset, vaddr, paddr, size);
+        }
+        offset += align_up(size);
+    }
+    buf.extend_from_slice(&notes);
+    buf.resize(headers_len, 0);
+    buf
+}
+
+fn align_up(len: usize) -> usize {
+    (len + CORE_FILE_ALIGN - 1) & !(CORE_FILE_ALIGN - 1)
+}
+
+fn elf_header(buf: &mut Vec<u8>, phnum: u16) {
+    buf.extend_from_slice(b"\x7fELF");
+    buf.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little endian, version 1, System V
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&ET_CORE.to_le_bytes());
+    buf.extend_from_slice(&EM_X86_64.to_le_bytes());
+    buf.extend_from_slice(&1u32.to_le_bytes()); // version
+    buf.extend_from_slice(&0u64.to_le_bytes()); // entry
+    buf.extend_from_slice(&(ELF_HEADER_LEN as u64).to_le_bytes()); // program headers
+    buf.extend_from_slice(&0u64.to_le_bytes()); // no section headers
+    buf.extend_from_slice(&0u32.to_le_bytes()); // flags
+    buf.extend_from_slice(&(ELF_HEADER_LEN as u16).to_le_bytes());
+    buf.extend_from_slice(&(PROGRAM_HEADER_LEN as u16).to_le_bytes());
+    buf.extend_from_slice(&phnum.to_le_bytes());
+    buf.extend_from_slice(&[0; 6]); // section header size, count and names
+}
+
+fn program_header(
+    buf: &mut Vec<u8>,
+    p_type: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: u64,
+    paddr: u64,
+    size: usize,
+) {
+    let align = if p_type == PT_LOAD { CORE_FILE_ALIGN } else { 1 };
+    buf.extend_from_slice(&p_type.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&(offset as u64).to_le_bytes());
+    buf.extend_from_slice(&vaddr.to_le_bytes());
+    buf.extend_from_slice(&paddr.to_le_bytes());
+    buf.extend_from_slice(&(size as u64).to_le_bytes()); // in the file
+    buf.extend_from_slice(&(size as u64).to_le_bytes()); // in memory
+    buf.extend_from_slice(&(align as u64).to_le_bytes());
+}
+
+fn add_note(buf: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+    const NAME: &[u8] = b"CORE\0";
+    buf.extend_from_slice(&(NAME.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&n_type.to_le_bytes());
+    buf.extend_from_slice(NAME);
+    buf.resize((buf.len() + 3) & !3, 0);
+    buf.extend_from_slice(desc);
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+/// `struct elf_prstatus` of the vCPU, as a thread of ID `vcpu_id + 1`.
+fn prstatus(vcpu_id: usize, state: &VcpuState, signal: u32) -> [u8; PRSTATUS_LEN] {
+    let field = |field: u32| {
+        state
+            .guest_fields
+            .iter()
+            .find(|&&(f, _)| f == field)
+            .map_or(0, |&(_, value)| value)
+    };
+    // With SVM, a selector shares its word with the segment attributes.
+    let selector = |f: u32| field(f) & 0xffff;
+    let f = core_reg_fields();
+    let r = &state.regs;
+    // `struct user_regs_struct`
+    let regs = [
+        r.r15, r.r14, r.r13, r.r12, r.rbp, r.rbx, r.r11, r.r10, r.r9, r.r8, r.rax, r.rcx, r.rdx,
+        r.rsi, r.rdi, u64::MAX, // orig_rax: not in a system call
+        field(f.rip),
+        selector(f.cs),
+        field(f.rflags),
+        field(f.rsp),
+        selector(f.ss),
+        field(f.fs_base),
+        field(f.gs_base),
+        selector(f.ds),
+        selector(f.es),
+        selector(f.fs),
+        selector(f.gs),
+    ];
+
+    let mut buf = [0; PRSTATUS_LEN];
+    buf[0..4].copy_from_slice(&signal.to_le_bytes()); // si_signo
+    buf[12..14].copy_from_slice(&(signal as u16).to_le_bytes()); // pr_cursig
+    buf[32..36].copy_from_slice(&(vcpu_id as u32 + 1).to_le_bytes()); // pr_pid
+    for (i, reg) in regs.iter().enumerate() {
+        let offset = PRSTATUS_REGS_OFFSET + i * 8;
+        buf[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+    let fpvalid = state.xsave_area.len() >= FXSAVE_LEN;
+    buf[328..332].copy_from_slice(&(fpvalid as u32).to_le_bytes()); // pr_fpvalid
+    buf
+}
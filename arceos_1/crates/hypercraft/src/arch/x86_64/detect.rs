@@ -0,0 +1,40 @@
+use spin::Once;
+
+use super::{svm, vmx};
+
+/// Hardware virtualization extension used to run guests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Extension {
+    /// Intel VT-x.
+    Vmx,
+    /// AMD-V.
+    Svm,
+}
+
+static EXTENSION: Once<Option<Extension>> = Once::new();
+
+/// Detects the extension supported by the processor, `None` if neither is.
+fn detect() -> Option<Extension> {
+    if vmx::has_hardware_support() {
+        Some(Extension::Vmx)
+    } else if svm::has_hardware_support() {
+        Some(Extension::Svm)
+    } else {
+        None
+    }
+}
+
+/// The extension supported by the processor, if any, detected on first use.
+pub(crate) fn supported_extension() -> Option<Extension> {
+    // The in-memory processor of hosted tests only implements VMX.
+    if cfg!(any(test, feature = "mock")) {
+        return Some(Extension::Vmx);
+    }
+    *EXTENSION.call_once(detect)
+}
+
+/// The extension used to run guests. Only called once
+/// [`init_hv_runtime`](super::init_hv_runtime) has checked there is one.
+pub(crate) fn extension() -> Extension {
+    supported_extension().expect("no hardware virtualization extension")
+}
@@ -1,13 +1,58 @@
-use page_table_entry::x86_64::EPTEntry;
-use page_table::{PagingMetaData, PageTable64};
+use bit_field::BitField;
+use page_table::PageSize;
 
-pub struct ExtendedPageTableMetadata;
+use super::guest_memory::{GuestMemoryError, GuestMemoryResult, PTE_ADDR_MASK};
+use super::msr::Msr;
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal};
 
-impl PagingMetaData for ExtendedPageTableMetadata {
-    const LEVELS: usize = 4;
-    const PA_MAX_BITS: usize = 52;
-    const VA_MAX_BITS: usize = 52;
+// EPT entry bits. (SDM Vol. 3C, Section 28.3.2)
+const EPTE_RWX: u64 = 0b111;
+const EPTE_WRITE: u64 = 1 << 1;
+const EPTE_HUGE: u64 = 1 << 7;
+
+/// The largest page size that EPT entries can map, 2 MiB or 1 GiB pages are
+/// not supported by all processors. (SDM Vol. 3D, Appendix A.10)
+pub fn ept_max_page_size() -> PageSize {
+    let cap = Msr::IA32_VMX_EPT_VPID_CAP.read();
+    if cap.get_bit(17) {
+        PageSize::Size1G
+    } else if cap.get_bit(16) {
+        PageSize::Size2M
+    } else {
+        PageSize::Size4K
+    }
+}
+
+/// Whether the processor supports accessed and dirty flags for EPT, so dirty
+/// guest pages can be found without write-protecting them. (SDM Vol. 3D,
+/// Appendix A.10)
+pub fn ept_ad_supported() -> bool {
+    Msr::IA32_VMX_EPT_VPID_CAP.read().get_bit(21)
 }
 
-/// The VMX extended page table. (SDM Vol. 3C, Section 28.3)
-pub type ExtendedPageTable<I> = PageTable64<ExtendedPageTableMetadata, EPTEntry, I>;
+/// Translates `gpa` through the EPT whose pointer is `eptp`, checking that all
+/// levels permit writes if `write` is true. (SDM Vol. 3C, Section 28.3.2)
+pub(super) fn ept_translate<H: HyperCraftHal>(
+    eptp: u64,
+    gpa: GuestPhysAddr,
+    write: bool,
+) -> GuestMemoryResult<HostPhysAddr> {
+    let mut level = eptp.get_bits(3..6) as usize + 1;
+    let mut table = (eptp & PTE_ADDR_MASK) as HostPhysAddr;
+    loop {
+        let index = gpa.get_bits(12 + (level - 1) * 9..21 + (level - 1) * 9);
+        let entry = unsafe { (H::phys_to_virt(table) as *const u64).add(index).read_volatile() };
+        if entry & EPTE_RWX == 0 {
+            return Err(GuestMemoryError::NotBacked(gpa));
+        }
+        if write && entry & EPTE_WRITE == 0 {
+            return Err(GuestMemoryError::ReadOnly(gpa));
+        }
+        if level == 1 || (level <= 3 && entry & EPTE_HUGE != 0) {
+            let page_mask = (1usize << (12 + (level - 1) * 9)) - 1;
+            return Ok(((entry & PTE_ADDR_MASK) as usize & !page_mask) | (gpa & page_mask));
+        }
+        table = (entry & PTE_ADDR_MASK) as HostPhysAddr;
+        level -= 1;
+    }
+}
@@ -0,0 +1,79 @@
+numeric_enum_macro::numeric_enum! {
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+/// Reasons of VM exits, the same with VMX and SVM.
+///
+/// The VMX exits and SVM #VMEXITs are converted to them by the vCPU, and the
+/// hardware one is kept in [`VmExitInfo::hw_reason`].
+pub enum VmExitReason {
+    /// An exception intercepted by [`VCpu::set_exception_exiting`], or an NMI
+    /// with VMX. See [`VCpu::interrupt_exit_info`].
+    ///
+    /// [`VCpu::set_exception_exiting`]: super::VCpu::set_exception_exiting
+    /// [`VCpu::interrupt_exit_info`]: super::VCpu::interrupt_exit_info
+    EXCEPTION_NMI = 0,
+    /// A physical interrupt. It's taken by the host before the exit is
+    /// handled with SVM, including NMIs and SMIs.
+    EXTERNAL_INTERRUPT = 1,
+    /// The guest is shut down by a triple fault.
+    TRIPLE_FAULT = 2,
+    /// An INIT signal.
+    INIT = 3,
+    /// A start-up IPI.
+    SIPI = 4,
+    /// The guest can take the virtual interrupts again, see
+    /// [`VCpu::set_interrupt_window`](super::VCpu::set_interrupt_window).
+    INTERRUPT_WINDOW = 5,
+    /// `CPUID`.
+    CPUID = 6,
+    /// `HLT`.
+    HLT = 7,
+    /// `RDTSC`.
+    RDTSC = 8,
+    /// `RDTSCP`.
+    RDTSCP = 9,
+    /// A hypercall, by `VMCALL` or `VMMCALL`.
+    VMCALL = 10,
+    /// An instruction of the virtualization extension, other than hypercalls,
+    /// which guests can not use.
+    VIRTUALIZATION_INSTRUCTION = 11,
+    /// An access to a control register.
+    CR_ACCESS = 12,
+    /// `IN`, `OUT`, `INS` or `OUTS`.
+    IO_INSTRUCTION = 13,
+    /// `RDMSR`.
+    MSR_READ = 14,
+    /// `WRMSR`.
+    MSR_WRITE = 15,
+    /// The guest executed one instruction, see
+    /// [`VCpu::set_monitor_trap`](super::VCpu::set_monitor_trap).
+    MONITOR_TRAP_FLAG = 16,
+    /// `PAUSE` in a spin loop.
+    PAUSE_INSTRUCTION = 17,
+    /// A fault of the nested page table: an EPT violation with VMX.
+    NESTED_PAGE_FAULT = 18,
+    /// The vCPU used up its time slice.
+    PREEMPTION_TIMER = 19,
+    /// `XSETBV`.
+    XSETBV = 20,
+    /// Any other VM exit, see [`VmExitInfo::hw_reason`].
+    OTHER = 21,
+}
+}
+
+/// Basic information about a VM exit.
+#[derive(Debug)]
+pub struct VmExitInfo {
+    /// Whether the VM entry failed, instead of the guest exiting.
+    pub entry_failure: bool,
+    /// Exit reason.
+    pub exit_reason: VmExitReason,
+    /// The basic exit reason of VMX, or the exit code of SVM.
+    pub hw_reason: u64,
+    /// For VM exits resulting from instruction execution, the length in bytes
+    /// of the instruction whose execution led to the VM exit.
+    pub exit_instruction_length: u32,
+    /// Guest `RIP` where the VM exit occurs.
+    pub guest_rip: usize,
+}
@@ -0,0 +1,175 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use spinlock::SpinNoIrq;
+
+use super::exit::VmExitReason;
+
+/// Number of exit reasons, which are all below it.
+const NUM_EXIT_REASONS: usize = VmExitReason::OTHER as usize + 1;
+
+/// A VM exit recorded in the trace.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitTraceEntry {
+    /// Host time of the VM exit, in nanoseconds.
+    pub time_ns: u64,
+    /// Exit reason.
+    pub reason: VmExitReason,
+    /// Exit qualification, as VMX reports it.
+    pub qualification: usize,
+    /// Guest `RIP` where the VM exit occurs.
+    pub rip: usize,
+    /// Time spent in the exit handler, in nanoseconds.
+    pub handler_ns: u64,
+}
+
+/// Counters of the VM exits of a vCPU, or of several vCPUs summed up.
+#[derive(Debug, Clone, Default)]
+pub struct ExitStatsSummary {
+    /// Total number of VM exits.
+    pub exits: u64,
+    /// Host time spent in the guest, in nanoseconds.
+    pub guest_ns: u64,
+    /// Host time spent in exit handlers, in nanoseconds, including time the
+    /// vCPU yielded to other tasks.
+    pub handler_ns: u64,
+    /// Number of VM exits by reason, for the reasons that occurred.
+    pub reasons: Vec<(VmExitReason, u64)>,
+    /// Number of I/O instruction exits by port.
+    pub io_ports: Vec<(u16, u64)>,
+    /// Number of `RDMSR` and `WRMSR` exits by MSR.
+    pub msrs: Vec<(u32, u64)>,
+}
+
+impl ExitStatsSummary {
+    /// Adds the counters of `other` to this one.
+    pub fn merge(&mut self, other: &Self) {
+        fn add_counts<K: PartialEq + Copy>(dst: &mut Vec<(K, u64)>, src: &[(K, u64)]) {
+            for &(key, n) in src {
+                match dst.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, count)) => *count += n,
+                    None => dst.push((key, n)),
+                }
+            }
+        }
+        self.exits += other.exits;
+        self.guest_ns += other.guest_ns;
+        self.handler_ns += other.handler_ns;
+        add_counts(&mut self.reasons, &other.reasons);
+        add_counts(&mut self.io_ports, &other.io_ports);
+        add_counts(&mut self.msrs, &other.msrs);
+        self.reasons.sort_by_key(|&(r, _)| r as u32);
+        self.io_ports.sort_by_key(|&(port, _)| port);
+        self.msrs.sort_by_key(|&(msr, _)| msr);
+    }
+}
+
+struct ExitCounters {
+    exits: u64,
+    guest_ns: u64,
+    handler_ns: u64,
+    reasons: [u64; NUM_EXIT_REASONS],
+    io_ports: BTreeMap<u16, u64>,
+    msrs: BTreeMap<u32, u64>,
+    /// The most recent VM exits, if tracing is enabled.
+    trace: VecDeque<ExitTraceEntry>,
+    trace_capacity: usize,
+}
+
+/// Statistics of the VM exits of a vCPU, and an optional trace of the recent
+/// ones.
+///
+/// They are updated by the vCPU on every VM exit, and can be read from other
+/// tasks while it's running.
+pub struct ExitStats {
+    inner: SpinNoIrq<ExitCounters>,
+}
+
+impl ExitStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: SpinNoIrq::new(ExitCounters {
+                exits: 0,
+                guest_ns: 0,
+                handler_ns: 0,
+                reasons: [0; NUM_EXIT_REASONS],
+                io_ports: BTreeMap::new(),
+                msrs: BTreeMap::new(),
+                trace: VecDeque::new(),
+                trace_capacity: 0,
+            }),
+        }
+    }
+
+    /// Records a VM exit handled in `handler_ns` after `guest_ns` in the guest.
+    /// `port` and `msr` are the accessed I/O port or MSR, if any.
+    pub(crate) fn record(
+        &self,
+        entry: ExitTraceEntry,
+        guest_ns: u64,
+        port: Option<u16>,
+        msr: Option<u32>,
+    ) {
+        let mut inner = self.inner.lock();
+        inner.exits += 1;
+        inner.guest_ns += guest_ns;
+        inner.handler_ns += entry.handler_ns;
+        inner.reasons[entry.reason as usize] += 1;
+        if let Some(port) = port {
+            *inner.io_ports.entry(port).or_default() += 1;
+        }
+        if let Some(msr) = msr {
+            *inner.msrs.entry(msr).or_default() += 1;
+        }
+        if inner.trace_capacity > 0 {
+            if inner.trace.len() == inner.trace_capacity {
+                inner.trace.pop_front();
+            }
+            inner.trace.push_back(entry);
+        }
+    }
+
+    /// Keeps a trace of the most recent `capacity` VM exits, or stops tracing
+    /// if it's 0. The current trace is dropped.
+    pub fn set_trace_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock();
+        inner.trace = VecDeque::with_capacity(capacity);
+        inner.trace_capacity = capacity;
+    }
+
+    /// Returns the traced VM exits, from the oldest to the most recent.
+    pub fn trace(&self) -> Vec<ExitTraceEntry> {
+        self.inner.lock().trace.iter().copied().collect()
+    }
+
+    /// Returns the current counters.
+    pub fn summary(&self) -> ExitStatsSummary {
+        let inner = self.inner.lock();
+        ExitStatsSummary {
+            exits: inner.exits,
+            guest_ns: inner.guest_ns,
+            handler_ns: inner.handler_ns,
+            reasons: inner
+                .reasons
+                .iter()
+                .enumerate()
+                .filter(|&(_, &n)| n > 0)
+                .map(|(r, &n)| (VmExitReason::try_from(r as u32).unwrap(), n))
+                .collect(),
+            io_ports: inner.io_ports.iter().map(|(&k, &v)| (k, v)).collect(),
+            msrs: inner.msrs.iter().map(|(&k, &v)| (k, v)).collect(),
+        }
+    }
+
+    /// Clears the counters and the trace.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock();
+        inner.exits = 0;
+        inner.guest_ns = 0;
+        inner.handler_ns = 0;
+        inner.reasons = [0; NUM_EXIT_REASONS];
+        inner.io_ports.clear();
+        inner.msrs.clear();
+        inner.trace.clear();
+    }
+}
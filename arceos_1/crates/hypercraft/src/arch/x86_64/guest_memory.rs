@@ -0,0 +1,506 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use bit_field::BitField;
+use page_table_entry::MappingFlags;
+
+use crate::memory::PAGE_SIZE_4K;
+use crate::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult};
+
+const CR0_PG: usize = 1 << 31;
+const CR0_WP: usize = 1 << 16;
+const CR4_PSE: usize = 1 << 4;
+const CR4_PAE: usize = 1 << 5;
+const CR4_LA57: usize = 1 << 12;
+const CR4_SMEP: usize = 1 << 20;
+const CR4_SMAP: usize = 1 << 21;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
+const RFLAGS_AC: usize = 1 << 18;
+
+// Guest page table entry bits. (SDM Vol. 3A, Section 4.3-4.5)
+const PTE_P: u64 = 1 << 0;
+const PTE_RW: u64 = 1 << 1;
+const PTE_US: u64 = 1 << 2;
+const PTE_A: u64 = 1 << 5;
+const PTE_D: u64 = 1 << 6;
+const PTE_PS: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+pub(super) const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+bitflags::bitflags! {
+    /// Page-fault error code pushed by the guest `#PF` handler. (SDM Vol. 3A, Section 4.7)
+    pub struct PageFaultErrorCode: u32 {
+        /// The fault was caused by a page-level protection violation.
+        const PRESENT = 1 << 0;
+        /// The access causing the fault was a write.
+        const WRITE = 1 << 1;
+        /// A user-mode access caused the fault.
+        const USER = 1 << 2;
+        /// A reserved bit was set in some paging-structure entry.
+        const RESERVED = 1 << 3;
+        /// The fault was caused by an instruction fetch.
+        const INSTRUCTION_FETCH = 1 << 4;
+    }
+}
+
+/// A guest page fault that should be reflected to the guest as `#PF`.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestPageFault {
+    /// Faulting guest virtual address, which goes to the guest `CR2`.
+    pub vaddr: GuestVirtAddr,
+    /// Error code of the page fault.
+    pub error_code: PageFaultErrorCode,
+}
+
+/// The error type of guest memory accesses.
+#[derive(Debug)]
+pub enum GuestMemoryError {
+    /// The guest page tables do not permit the access, inject it with
+    /// [`GuestMemoryAccessor::inject_page_fault`].
+    PageFault(GuestPageFault),
+    /// The guest physical address is not backed by host memory in the nested
+    /// page table (e.g., it's an emulated MMIO region).
+    NotBacked(GuestPhysAddr),
+    /// The guest physical address is mapped read-only in the nested page
+    /// table, and the access was a write.
+    ReadOnly(GuestPhysAddr),
+    /// Other hypervisor errors.
+    Hyper(HyperError),
+}
+
+impl From<HyperError> for GuestMemoryError {
+    fn from(err: HyperError) -> Self {
+        Self::Hyper(err)
+    }
+}
+
+impl From<x86::vmx::VmFail> for GuestMemoryError {
+    fn from(err: x86::vmx::VmFail) -> Self {
+        Self::Hyper(err.into())
+    }
+}
+
+/// Result type of guest memory accesses.
+pub type GuestMemoryResult<T = ()> = Result<T, GuestMemoryError>;
+
+/// Accesses guest memory by guest virtual address, by walking the guest page
+/// tables (in the paging mode given by the guest `CR0`/`CR4`/`EFER`) and the
+/// nested page table of the VM.
+///
+/// The VMCS of the vCPU must be loaded on the current CPU.
+pub trait GuestMemoryAccessor {
+    /// Translates a guest physical address to a host physical address through
+    /// the nested page table.
+    fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr) -> GuestMemoryResult<HostPhysAddr>;
+
+    /// Translates a guest virtual address to a guest physical address, checking
+    /// that the guest page tables permit `access` (`READ`, `WRITE` or `EXECUTE`)
+    /// at the current privilege level.
+    fn guest_virt_to_phys(
+        &self,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> GuestMemoryResult<GuestPhysAddr>;
+
+    /// Reads `buf.len()` bytes of guest memory starting at `gva`.
+    fn copy_from_guest(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> GuestMemoryResult;
+
+    /// Writes `buf` to guest memory starting at `gva`.
+    fn copy_to_guest(&self, gva: GuestVirtAddr, buf: &[u8]) -> GuestMemoryResult;
+
+    /// Fetches up to `buf.len()` instruction bytes at guest `RIP`.
+    fn fetch_guest_instruction(&self, buf: &mut [u8]) -> GuestMemoryResult;
+
+    /// Reads guest memory at `gva` for a debugger, regardless of the access
+    /// rights in the guest page tables, and without setting their accessed
+    /// flags.
+    fn debug_read(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> GuestMemoryResult;
+
+    /// Writes guest memory at `gva` for a debugger, e.g., to insert
+    /// breakpoints into read-only code.
+    fn debug_write(&self, gva: GuestVirtAddr, buf: &[u8]) -> GuestMemoryResult;
+
+    /// Reflects a page fault returned by the other methods to the guest.
+    fn inject_page_fault(&mut self, fault: GuestPageFault);
+}
+
+/// What the guest memory accessor needs from a vCPU of the VMX or SVM backend.
+pub trait GuestPagingBackend {
+    /// The HAL to access host physical memory.
+    type Hal: HyperCraftHal;
+
+    /// Reads the guest registers which control the paging mode.
+    fn paging_context(&self) -> HyperResult<GuestPagingContext>;
+
+    /// Translates `gpa` through the nested page table of the vCPU, checking
+    /// that it's writable if `write` is true.
+    fn nested_translate(&self, gpa: GuestPhysAddr, write: bool) -> GuestMemoryResult<HostPhysAddr>;
+
+    /// Linear address of the guest `RIP`.
+    fn linear_rip(&self) -> HyperResult<usize>;
+
+    /// Injects `#PF` with the guest `CR2` set to the faulting address.
+    fn inject_page_fault_event(&mut self, fault: GuestPageFault);
+
+    /// PDPTE `index` of PAE paging. They are read from guest memory at `CR3`
+    /// unless the processor caches them.
+    fn pae_pdpte(&self, ctx: &GuestPagingContext, index: usize) -> GuestMemoryResult<u64> {
+        let gpa = (ctx.cr3 & 0xffff_ffe0) + index * 8;
+        let hpa = self.nested_translate(gpa, false)?;
+        Ok(unsafe { (Self::Hal::phys_to_virt(hpa) as *const u64).read_volatile() })
+    }
+}
+
+impl<V: GuestPagingBackend> GuestMemoryAccessor for V {
+    fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr) -> GuestMemoryResult<HostPhysAddr> {
+        self.nested_translate(gpa, false)
+    }
+
+    fn guest_virt_to_phys(
+        &self,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> GuestMemoryResult<GuestPhysAddr> {
+        let ctx = self.paging_context()?;
+        walk_guest_page_table(self, &ctx, gva, access)
+    }
+
+    fn copy_from_guest(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> GuestMemoryResult {
+        let ctx = self.paging_context()?;
+        copy_guest_pages(self, &ctx, gva, buf.len(), MappingFlags::READ, |offset, ptr, len| unsafe {
+            core::ptr::copy_nonoverlapping(ptr, buf[offset..].as_mut_ptr(), len)
+        })
+    }
+
+    fn copy_to_guest(&self, gva: GuestVirtAddr, buf: &[u8]) -> GuestMemoryResult {
+        let ctx = self.paging_context()?;
+        copy_guest_pages(self, &ctx, gva, buf.len(), MappingFlags::WRITE, |offset, ptr, len| unsafe {
+            core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), ptr, len)
+        })
+    }
+
+    fn fetch_guest_instruction(&self, buf: &mut [u8]) -> GuestMemoryResult {
+        let ctx = self.paging_context()?;
+        let rip = self.linear_rip()?;
+        copy_guest_pages(self, &ctx, rip, buf.len(), MappingFlags::EXECUTE, |offset, ptr, len| unsafe {
+            core::ptr::copy_nonoverlapping(ptr, buf[offset..].as_mut_ptr(), len)
+        })
+    }
+
+    fn debug_read(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> GuestMemoryResult {
+        let ctx = self.paging_context()?.for_debugger();
+        copy_guest_pages(self, &ctx, gva, buf.len(), MappingFlags::READ, |offset, ptr, len| unsafe {
+            core::ptr::copy_nonoverlapping(ptr, buf[offset..].as_mut_ptr(), len)
+        })
+    }
+
+    fn debug_write(&self, gva: GuestVirtAddr, buf: &[u8]) -> GuestMemoryResult {
+        let ctx = self.paging_context()?.for_debugger();
+        copy_guest_pages(self, &ctx, gva, buf.len(), MappingFlags::WRITE, |offset, ptr, len| unsafe {
+            core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), ptr, len)
+        })
+    }
+
+    fn inject_page_fault(&mut self, fault: GuestPageFault) {
+        self.inject_page_fault_event(fault);
+    }
+}
+
+/// Snapshot of the guest registers which control the paging mode.
+pub struct GuestPagingContext {
+    pub cr0: usize,
+    pub cr3: usize,
+    pub cr4: usize,
+    pub efer: u64,
+    /// Whether the guest runs at CPL 3.
+    pub user: bool,
+    pub rflags: usize,
+    /// Accesses by a debugger skip the permission checks and do not update
+    /// the accessed and dirty flags.
+    pub debugger: bool,
+}
+
+impl GuestPagingContext {
+    fn for_debugger(self) -> Self {
+        Self {
+            debugger: true,
+            ..self
+        }
+    }
+
+    fn paging_levels(&self) -> usize {
+        if self.cr0 & CR0_PG == 0 {
+            0
+        } else if self.cr4 & CR4_PAE == 0 {
+            2
+        } else if self.efer & EFER_LMA == 0 {
+            3
+        } else if self.cr4 & CR4_LA57 == 0 {
+            4
+        } else {
+            5
+        }
+    }
+}
+
+/// MAXPHYADDR, the physical address width of the processor, which is also
+/// the one of the guests. (SDM Vol. 3A, Section 4.1.4)
+fn max_phys_addr_bits() -> u32 {
+    static BITS: AtomicU32 = AtomicU32::new(0);
+    let mut bits = BITS.load(Ordering::Relaxed);
+    if bits == 0 {
+        bits = raw_cpuid::CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map_or(36, |info| info.physical_address_bits() as u32);
+        BITS.store(bits, Ordering::Relaxed);
+    }
+    bits
+}
+
+/// Bits which must be zero in a present guest paging-structure entry at
+/// `level`, where level 1 is the last-level page table. (SDM Vol. 3A, Section
+/// 4.3-4.5)
+fn reserved_bits(ctx: &GuestPagingContext, level: usize, entry_size: usize, entry: u64) -> u64 {
+    let phys_bits = max_phys_addr_bits();
+    let huge = entry & PTE_PS != 0;
+    if entry_size == 4 {
+        // Only 4M pages have reserved bits: bit 21, and the PSE-36 address bits
+        // 13..21 beyond MAXPHYADDR.
+        return if level == 2 && huge && ctx.cr4 & CR4_PSE != 0 {
+            (1 << 22) - (1 << (13 + phys_bits.min(40) - 32))
+        } else {
+            0
+        };
+    }
+    let mut reserved = PTE_ADDR_MASK & !((1 << phys_bits) - 1);
+    if ctx.efer & EFER_NXE == 0 {
+        reserved |= PTE_NX;
+    }
+    match level {
+        // PML5Es and PML4Es can not map pages.
+        4 | 5 => reserved |= PTE_PS,
+        // The address bits of 1G and 2M pages below the page size, except PAT.
+        3 if huge => reserved |= 0x3fff_e000,
+        2 if huge => reserved |= 0x1f_e000,
+        _ => {}
+    }
+    reserved
+}
+
+/// Atomically replaces the guest paging-structure entry at `entry_hpa` with
+/// `new` if it still holds `old`, and returns whether it did.
+fn update_entry<H: HyperCraftHal>(
+    entry_hpa: HostPhysAddr,
+    entry_size: usize,
+    old: u64,
+    new: u64,
+) -> bool {
+    let ptr = H::phys_to_virt(entry_hpa);
+    unsafe {
+        if entry_size == 4 {
+            (*(ptr as *const AtomicU32))
+                .compare_exchange(old as u32, new as u32, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        } else {
+            (*(ptr as *const AtomicU64))
+                .compare_exchange(old, new, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        }
+    }
+}
+
+/// Walks the guest page tables. (SDM Vol. 3A, Section 4.3-4.5)
+fn walk_guest_page_table<V: GuestPagingBackend + ?Sized>(
+    vcpu: &V,
+    ctx: &GuestPagingContext,
+    gva: GuestVirtAddr,
+    access: MappingFlags,
+) -> GuestMemoryResult<GuestPhysAddr> {
+    loop {
+        if let Some(paddr) = try_walk_guest_page_table(vcpu, ctx, gva, access)? {
+            return Ok(paddr);
+        }
+    }
+}
+
+/// Walks the guest page tables once, or returns `None` if an entry changed
+/// before its accessed or dirty flag could be set, e.g., by another vCPU.
+fn try_walk_guest_page_table<V: GuestPagingBackend + ?Sized>(
+    vcpu: &V,
+    ctx: &GuestPagingContext,
+    gva: GuestVirtAddr,
+    access: MappingFlags,
+) -> GuestMemoryResult<Option<GuestPhysAddr>> {
+    let levels = ctx.paging_levels();
+    if levels == 0 {
+        return Ok(Some(gva));
+    }
+
+    let write = access.contains(MappingFlags::WRITE);
+    let fetch = access.contains(MappingFlags::EXECUTE);
+    let mut error_code = PageFaultErrorCode::empty();
+    if write {
+        error_code |= PageFaultErrorCode::WRITE;
+    }
+    if ctx.user {
+        error_code |= PageFaultErrorCode::USER;
+    }
+    if fetch && (ctx.efer & EFER_NXE != 0 || ctx.cr4 & CR4_SMEP != 0) {
+        error_code |= PageFaultErrorCode::INSTRUCTION_FETCH;
+    }
+    let fault = |error_code| {
+        GuestMemoryError::PageFault(GuestPageFault {
+            vaddr: gva,
+            error_code,
+        })
+    };
+
+    if levels >= 4 {
+        let va_bits = if levels == 5 { 57 } else { 48 };
+        let high = (gva as isize) >> (va_bits - 1);
+        if high != 0 && high != -1 {
+            // Non-canonical addresses raise #GP instead of #PF.
+            return Err(GuestMemoryError::Hyper(HyperError::OutOfRange));
+        }
+    }
+
+    // Accumulated access rights along the walk.
+    let mut writable = true;
+    let mut user = true;
+    let mut executable = true;
+    let mut entries: [(GuestPhysAddr, u64); 5] = [(0, 0); 5];
+    let mut depth = 0;
+
+    // `(table, level, entry size)` where level 1 is the last-level page table.
+    let (mut table, mut level, entry_size) = match levels {
+        2 => (ctx.cr3 & 0xffff_f000, 2, 4),
+        3 => {
+            let pdpte = vcpu.pae_pdpte(ctx, gva.get_bits(30..32))?;
+            if pdpte & PTE_P == 0 {
+                return Err(fault(error_code));
+            }
+            ((pdpte & PTE_ADDR_MASK) as usize, 2, 8)
+        }
+        _ => (ctx.cr3 & PTE_ADDR_MASK as usize, levels, 8),
+    };
+
+    let paddr = loop {
+        let index = if entry_size == 4 {
+            gva.get_bits(12 + (level - 1) * 10..22 + (level - 1) * 10)
+        } else {
+            gva.get_bits(12 + (level - 1) * 9..21 + (level - 1) * 9)
+        };
+        let entry_gpa = table + index * entry_size;
+        let entry_hpa = vcpu.nested_translate(entry_gpa, false)?;
+        let entry = unsafe {
+            if entry_size == 4 {
+                (V::Hal::phys_to_virt(entry_hpa) as *const u32).read_volatile() as u64
+            } else {
+                (V::Hal::phys_to_virt(entry_hpa) as *const u64).read_volatile()
+            }
+        };
+        if entry & PTE_P == 0 {
+            return Err(fault(error_code));
+        }
+        if entry & reserved_bits(ctx, level, entry_size, entry) != 0 {
+            let reserved = PageFaultErrorCode::PRESENT | PageFaultErrorCode::RESERVED;
+            return Err(fault(error_code | reserved));
+        }
+        writable &= entry & PTE_RW != 0;
+        user &= entry & PTE_US != 0;
+        if entry_size == 8 && ctx.efer & EFER_NXE != 0 && entry & PTE_NX != 0 {
+            executable = false;
+        }
+        entries[depth] = (entry_hpa, entry);
+        depth += 1;
+
+        let is_leaf = level == 1
+            || (entry & PTE_PS != 0
+                && (level == 2 && (entry_size == 8 || ctx.cr4 & CR4_PSE != 0)
+                    || level == 3 && levels >= 4));
+        if is_leaf {
+            let page_shift = 12 + (level - 1) * if entry_size == 4 { 10 } else { 9 };
+            let offset = gva & ((1 << page_shift) - 1);
+            let base = if level == 1 {
+                (entry & PTE_ADDR_MASK) as usize
+            } else if entry_size == 4 {
+                // PSE-36: bits 13..21 of a 4M PDE hold the physical address bits 32..40.
+                (entry as usize & 0xffc0_0000) | ((entry.get_bits(13..21) as usize) << 32)
+            } else {
+                (entry & PTE_ADDR_MASK) as usize & !((1 << page_shift) - 1)
+            };
+            break base | offset;
+        }
+        table = (entry & PTE_ADDR_MASK) as usize;
+        if entry_size == 4 {
+            table &= 0xffff_f000;
+        }
+        level -= 1;
+    };
+
+    if ctx.debugger {
+        return Ok(Some(paddr));
+    }
+
+    // Check the access rights. (SDM Vol. 3A, Section 4.6)
+    let protection = error_code | PageFaultErrorCode::PRESENT;
+    let wp = ctx.cr0 & CR0_WP != 0;
+    if ctx.user {
+        if !user || (write && !writable) || (fetch && !executable) {
+            return Err(fault(protection));
+        }
+    } else {
+        let smap = ctx.cr4 & CR4_SMAP != 0 && ctx.rflags & RFLAGS_AC == 0;
+        if (write && !writable && wp)
+            || (fetch && !executable)
+            || (fetch && user && ctx.cr4 & CR4_SMEP != 0)
+            || (!fetch && user && smap)
+        {
+            return Err(fault(protection));
+        }
+    }
+
+    // Update the accessed and dirty flags as the processor would do, without
+    // losing the changes made to the entries since they were read.
+    for (i, &(entry_hpa, entry)) in entries[..depth].iter().enumerate() {
+        let mut new_entry = entry | PTE_A;
+        if write && i == depth - 1 {
+            new_entry |= PTE_D;
+        }
+        if new_entry != entry && !update_entry::<V::Hal>(entry_hpa, entry_size, entry, new_entry) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(paddr))
+}
+
+/// Calls `f(offset, host_ptr, len)` on each piece of the guest virtual
+/// range `[gva, gva + size)` which lies in one guest page.
+fn copy_guest_pages<V, F>(
+    vcpu: &V,
+    ctx: &GuestPagingContext,
+    gva: GuestVirtAddr,
+    size: usize,
+    access: MappingFlags,
+    mut f: F,
+) -> GuestMemoryResult
+where
+    V: GuestPagingBackend + ?Sized,
+    F: FnMut(usize, *mut u8, usize),
+{
+    let mut offset = 0;
+    while offset < size {
+        let vaddr = gva.wrapping_add(offset);
+        let len = (PAGE_SIZE_4K - (vaddr & (PAGE_SIZE_4K - 1))).min(size - offset);
+        let gpa = walk_guest_page_table(vcpu, ctx, vaddr, access)?;
+        // Debuggers may write to read-only guest memory, e.g., to insert breakpoints.
+        let write = access.contains(MappingFlags::WRITE) && !ctx.debugger;
+        let hpa = vcpu.nested_translate(gpa, write)?;
+        f(offset, V::Hal::phys_to_virt(hpa) as *mut u8, len);
+        offset += len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,294 @@
+use super::*;
+use crate::arch::ept::ept_translate;
+use crate::arch::mock::MockHal;
+use crate::arch::npt::npt_translate;
+use crate::HostVirtAddr;
+
+const GUEST_PAGES: usize = 8;
+const PML4: GuestPhysAddr = 0x1000;
+const PDPT: GuestPhysAddr = 0x2000;
+const PD: GuestPhysAddr = 0x3000;
+const PT: GuestPhysAddr = 0x4000;
+const DATA: GuestPhysAddr = 0x5000;
+/// Mapped through the entries 0, 0, 1, 2 of the tables above.
+const GVA: GuestVirtAddr = 0x20_2010;
+const TABLE: u64 = PTE_P | PTE_RW;
+
+/// A vCPU whose guest physical memory is a few host pages, where the guest
+/// page tables map [`GVA`] to [`DATA`].
+struct TestGuest {
+    mem: HostVirtAddr,
+    cr0: usize,
+    cr4: usize,
+    efer: u64,
+    user: bool,
+    /// A page mapped read-only in the nested page table.
+    read_only: Option<GuestPhysAddr>,
+}
+
+impl TestGuest {
+    /// A guest in 4-level paging mode.
+    fn new() -> Self {
+        let guest = Self {
+            mem: MockHal::alloc_pages(GUEST_PAGES).unwrap(),
+            cr0: CR0_PG | CR0_WP | 1,
+            cr4: CR4_PAE,
+            efer: EFER_LMA | EFER_NXE,
+            user: false,
+            read_only: None,
+        };
+        guest.set_entry(PML4, 0, PDPT as u64 | TABLE);
+        guest.set_entry(PDPT, 0, PD as u64 | TABLE);
+        guest.set_entry(PD, 1, PT as u64 | TABLE);
+        guest.set_entry(PT, 2, DATA as u64 | TABLE);
+        guest
+    }
+
+    fn ptr(&self, gpa: GuestPhysAddr) -> *mut u8 {
+        assert!(gpa < GUEST_PAGES * PAGE_SIZE_4K);
+        (self.mem + gpa) as *mut u8
+    }
+
+    fn entry(&self, table: GuestPhysAddr, index: usize) -> u64 {
+        unsafe { (self.ptr(table) as *const u64).add(index).read() }
+    }
+
+    fn set_entry(&self, table: GuestPhysAddr, index: usize, entry: u64) {
+        unsafe { (self.ptr(table) as *mut u64).add(index).write(entry) }
+    }
+
+    fn error_code(&self, gva: GuestVirtAddr, access: MappingFlags) -> Option<PageFaultErrorCode> {
+        match self.guest_virt_to_phys(gva, access) {
+            Err(GuestMemoryError::PageFault(fault)) => {
+                assert_eq!(fault.vaddr, gva);
+                Some(fault.error_code)
+            }
+            Ok(_) => None,
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+    }
+}
+
+impl Drop for TestGuest {
+    fn drop(&mut self) {
+        MockHal::dealloc_pages(self.mem, GUEST_PAGES);
+    }
+}
+
+impl GuestPagingBackend for TestGuest {
+    type Hal = MockHal;
+
+    fn paging_context(&self) -> HyperResult<GuestPagingContext> {
+        Ok(GuestPagingContext {
+            cr0: self.cr0,
+            cr3: PML4,
+            cr4: self.cr4,
+            efer: self.efer,
+            user: self.user,
+            rflags: 0x2,
+            debugger: false,
+        })
+    }
+
+    fn nested_translate(&self, gpa: GuestPhysAddr, write: bool) -> GuestMemoryResult<HostPhysAddr> {
+        if gpa >= GUEST_PAGES * PAGE_SIZE_4K {
+            Err(GuestMemoryError::NotBacked(gpa))
+        } else if write && self.read_only == Some(gpa & !(PAGE_SIZE_4K - 1)) {
+            Err(GuestMemoryError::ReadOnly(gpa))
+        } else {
+            Ok(self.ptr(gpa) as HostPhysAddr)
+        }
+    }
+
+    fn linear_rip(&self) -> HyperResult<usize> {
+        Ok(GVA)
+    }
+
+    fn inject_page_fault_event(&mut self, _fault: GuestPageFault) {}
+}
+
+#[test]
+fn walk_sets_accessed_and_dirty_flags() {
+    let guest = TestGuest::new();
+    assert_eq!(
+        guest.guest_virt_to_phys(GVA, MappingFlags::READ).unwrap(),
+        DATA + 0x10
+    );
+    let walked = [(PML4, 0), (PDPT, 0), (PD, 1), (PT, 2)];
+    for (table, index) in walked {
+        assert_eq!(guest.entry(table, index) & (PTE_A | PTE_D), PTE_A);
+    }
+
+    guest.copy_to_guest(GVA, b"data").unwrap();
+    let mut buf = [0; 4];
+    guest.copy_from_guest(GVA, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    assert_eq!(unsafe { *guest.ptr(DATA + 0x10) }, b'd');
+    // Only the last-level entry maps the written page.
+    for (table, index) in &walked[..3] {
+        assert_eq!(guest.entry(*table, *index) & PTE_D, 0);
+    }
+    assert_eq!(guest.entry(PT, 2) & PTE_D, PTE_D);
+
+    // Debugger accesses leave the flags alone.
+    guest.set_entry(PT, 2, DATA as u64 | TABLE);
+    guest.debug_write(GVA, b"dbg").unwrap();
+    assert_eq!(guest.entry(PT, 2), DATA as u64 | TABLE);
+}
+
+#[test]
+fn write_protected_pages() {
+    let mut guest = TestGuest::new();
+    guest.set_entry(PT, 2, DATA as u64 | PTE_P);
+    assert_eq!(guest.error_code(GVA, MappingFlags::READ), None);
+    assert_eq!(
+        guest.error_code(GVA, MappingFlags::WRITE),
+        Some(PageFaultErrorCode::PRESENT | PageFaultErrorCode::WRITE)
+    );
+    assert_eq!(guest.entry(PT, 2) & PTE_D, 0);
+
+    // Supervisor writes ignore the R/W flags without CR0.WP.
+    guest.cr0 &= !CR0_WP;
+    guest.copy_to_guest(GVA, b"data").unwrap();
+    // But not user ones, nor accesses to supervisor pages.
+    guest.user = true;
+    let user_fault = PageFaultErrorCode::PRESENT | PageFaultErrorCode::USER;
+    assert_eq!(guest.error_code(GVA, MappingFlags::READ), Some(user_fault));
+}
+
+#[test]
+fn reserved_bits_fault() {
+    let mut guest = TestGuest::new();
+    let reserved = Some(PageFaultErrorCode::PRESENT | PageFaultErrorCode::RESERVED);
+
+    // PML4Es can not map pages.
+    guest.set_entry(PML4, 0, PDPT as u64 | TABLE | PTE_PS);
+    assert_eq!(guest.error_code(GVA, MappingFlags::READ), reserved);
+    assert_eq!(guest.entry(PML4, 0) & PTE_A, 0);
+    guest.set_entry(PML4, 0, PDPT as u64 | TABLE);
+
+    // The execute-disable flag without EFER.NXE.
+    guest.set_entry(PT, 2, DATA as u64 | TABLE | PTE_NX);
+    assert_eq!(guest.error_code(GVA, MappingFlags::READ), None);
+    guest.efer &= !EFER_NXE;
+    assert_eq!(guest.error_code(GVA, MappingFlags::READ), reserved);
+    guest.set_entry(PT, 2, DATA as u64 | TABLE);
+
+    // The address bits of a 2M page below 2M.
+    guest.set_entry(PD, 1, 0x20_0000 | TABLE | PTE_PS);
+    assert_eq!(
+        guest.guest_virt_to_phys(GVA, MappingFlags::READ).unwrap(),
+        GVA
+    );
+    guest.set_entry(PD, 1, 0x20_2000 | TABLE | PTE_PS);
+    assert_eq!(guest.error_code(GVA, MappingFlags::READ), reserved);
+    guest.set_entry(PD, 1, PT as u64 | TABLE);
+
+    // Addresses beyond MAXPHYADDR.
+    if max_phys_addr_bits() < 52 {
+        guest.set_entry(PT, 2, DATA as u64 | TABLE | (1 << 51));
+        let write_reserved = reserved.map(|code| code | PageFaultErrorCode::WRITE);
+        assert_eq!(guest.error_code(GVA, MappingFlags::WRITE), write_reserved);
+    }
+}
+
+#[test]
+fn pse_pages_of_32bit_paging() {
+    let mut guest = TestGuest::new();
+    guest.cr4 = CR4_PSE;
+    guest.efer = 0;
+    // The 4-byte PDE 1 at `PML4` maps the 4M page at 4M.
+    let gva = 0x40_1234;
+    unsafe {
+        (guest.ptr(PML4) as *mut u32)
+            .add(1)
+            .write(0x40_0000 | TABLE as u32 | PTE_PS as u32)
+    };
+    assert_eq!(
+        guest.guest_virt_to_phys(gva, MappingFlags::READ).unwrap(),
+        gva
+    );
+    let pde = unsafe { (guest.ptr(PML4) as *const u32).add(1).read() };
+    assert_eq!(pde & PTE_A as u32, PTE_A as u32);
+
+    unsafe { (guest.ptr(PML4) as *mut u32).add(1).write(pde | (1 << 21)) };
+    assert_eq!(
+        guest.error_code(gva, MappingFlags::READ),
+        Some(PageFaultErrorCode::PRESENT | PageFaultErrorCode::RESERVED)
+    );
+}
+
+#[test]
+fn read_only_guest_physical_pages() {
+    let mut guest = TestGuest::new();
+    guest.read_only = Some(DATA);
+    let mut buf = [0; 4];
+    guest.copy_from_guest(GVA, &mut buf).unwrap();
+    assert!(matches!(
+        guest.copy_to_guest(GVA, b"data"),
+        Err(GuestMemoryError::ReadOnly(gpa)) if gpa == DATA + 0x10
+    ));
+    assert_eq!(unsafe { *guest.ptr(DATA + 0x10) }, 0);
+    // Debuggers may still insert breakpoints.
+    guest.debug_write(GVA, &[0xcc]).unwrap();
+    assert_eq!(unsafe { *guest.ptr(DATA + 0x10) }, 0xcc);
+}
+
+#[test]
+fn nested_translate_checks_writes() {
+    // EPT entries with R/W/X, and NPT ones with P/RW/US, have the same layout.
+    const RWX: u64 = 0b111;
+    const RX: u64 = 0b101;
+    let tables = MockHal::alloc_pages(4).unwrap();
+    let hpa = 0x1234_5000;
+    let set_entry = |table: usize, index: usize, entry: u64| unsafe {
+        ((tables + table * PAGE_SIZE_4K) as *mut u64)
+            .add(index)
+            .write(entry)
+    };
+    for table in 0..3 {
+        set_entry(table, 0, (tables + (table + 1) * PAGE_SIZE_4K) as u64 | RWX);
+    }
+    set_entry(3, 5, hpa | RX);
+    // A 4-level EPT, with the page-walk length minus one in bits 3..6.
+    let eptp = tables as u64 | (3 << 3);
+    let ncr3 = tables as u64;
+
+    let gpa = 0x5678;
+    assert_eq!(
+        ept_translate::<MockHal>(eptp, gpa, false).unwrap(),
+        hpa as usize + 0x678
+    );
+    assert_eq!(
+        npt_translate::<MockHal>(ncr3, gpa, false).unwrap(),
+        hpa as usize + 0x678
+    );
+    assert!(matches!(
+        ept_translate::<MockHal>(eptp, gpa, true),
+        Err(GuestMemoryError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        npt_translate::<MockHal>(ncr3, gpa, true),
+        Err(GuestMemoryError::ReadOnly(_))
+    ));
+
+    set_entry(3, 5, hpa | RWX);
+    assert!(ept_translate::<MockHal>(eptp, gpa, true).is_ok());
+    assert!(npt_translate::<MockHal>(ncr3, gpa, true).is_ok());
+    // All levels must permit writes.
+    set_entry(1, 0, (tables + 2 * PAGE_SIZE_4K) as u64 | RX);
+    assert!(matches!(
+        ept_translate::<MockHal>(eptp, gpa, true),
+        Err(GuestMemoryError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        npt_translate::<MockHal>(ncr3, gpa, true),
+        Err(GuestMemoryError::ReadOnly(_))
+    ));
+    assert!(matches!(
+        ept_translate::<MockHal>(eptp, 0x7000, false),
+        Err(GuestMemoryError::NotBacked(_))
+    ));
+
+    MockHal::dealloc_pages(tables, 4);
+}
@@ -0,0 +1,187 @@
+//! Accesses of the vCPUs to the processor: VMCS fields, MSRs, extended states
+//! and the host states saved into the VMCS.
+//!
+//! They all go through [`Hw`], which executes the instructions, or is the
+//! in-memory machine of [`super::mock`] in hosted unit tests, so that the VM
+//! logic can be exercised with `cargo test`.
+
+use bit_field::BitField;
+use core::arch::asm;
+use x86::bits64::vmx;
+use x86::dtables::{self, DescriptorTablePointer};
+use x86::segmentation::SegmentSelector;
+use x86::vmx::Result as VmxResult;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+
+/// Host states loaded by the processor on VM exits, which are not MSRs.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HostState {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub es: u16,
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+    pub fs: u16,
+    pub gs: u16,
+    pub tr: u16,
+    pub tr_base: u64,
+    pub gdtr_base: u64,
+    pub idtr_base: u64,
+}
+
+/// Operations of the processor used by the vCPUs.
+pub(crate) trait HwAccess {
+    /// Reads a field of the current VMCS.
+    fn vmread(field: u32) -> VmxResult<u64>;
+    /// Writes a field of the current VMCS.
+    fn vmwrite(field: u32, value: u64) -> VmxResult<()>;
+    /// Makes the VMCS at `paddr` inactive and not launched.
+    fn vmclear(paddr: u64) -> VmxResult<()>;
+    /// Makes the VMCS at `paddr` current.
+    fn vmptrld(paddr: u64) -> VmxResult<()>;
+    /// Invalidates the mappings derived from the EPT pointer `eptp`.
+    fn invept_single_context(eptp: u64) -> VmxResult<()>;
+    /// Reads a MSR.
+    fn rdmsr(msr: u32) -> u64;
+    /// Writes a MSR.
+    ///
+    /// # Safety
+    ///
+    /// The write must have no unsafe side effects.
+    unsafe fn wrmsr(msr: u32, value: u64);
+    /// Reads `CR2`, which holds the guest value around VM entries and exits.
+    fn read_cr2() -> u64;
+    /// Loads the guest `CR2` before a VM entry.
+    fn write_cr2(value: u64);
+    /// Loads `XCR0`, the extended state components enabled.
+    ///
+    /// # Safety
+    ///
+    /// `xcr0` must be valid, and supported by the processor.
+    unsafe fn xsetbv(xcr0: u64);
+    /// Saves the extended state components in `mask` and enabled in `XCR0`
+    /// into the XSAVE area at `area`, with XSAVE.
+    ///
+    /// # Safety
+    ///
+    /// `area` must be a writable XSAVE area, aligned to 64 bytes.
+    unsafe fn xsave(area: *mut u8, mask: u64);
+    /// Loads the extended state components in `mask` and enabled in `XCR0`
+    /// from the XSAVE area at `area`, with XRSTOR.
+    ///
+    /// # Safety
+    ///
+    /// `area` must be a valid XSAVE area, aligned to 64 bytes.
+    unsafe fn xrstor(area: *const u8, mask: u64);
+    /// Reads the current host states.
+    fn host_state() -> HostState;
+}
+
+/// The processor itself.
+pub(crate) struct Hardware;
+
+impl HwAccess for Hardware {
+    fn vmread(field: u32) -> VmxResult<u64> {
+        unsafe { vmx::vmread(field) }
+    }
+
+    fn vmwrite(field: u32, value: u64) -> VmxResult<()> {
+        unsafe { vmx::vmwrite(field, value) }
+    }
+
+    fn vmclear(paddr: u64) -> VmxResult<()> {
+        unsafe { vmx::vmclear(paddr) }
+    }
+
+    fn vmptrld(paddr: u64) -> VmxResult<()> {
+        unsafe { vmx::vmptrld(paddr) }
+    }
+
+    fn invept_single_context(eptp: u64) -> VmxResult<()> {
+        use super::vmx::{invept, InvEptType};
+        unsafe { invept(InvEptType::SingleContext, eptp) }
+    }
+
+    fn rdmsr(msr: u32) -> u64 {
+        unsafe { x86::msr::rdmsr(msr) }
+    }
+
+    unsafe fn wrmsr(msr: u32, value: u64) {
+        x86::msr::wrmsr(msr, value)
+    }
+
+    fn read_cr2() -> u64 {
+        unsafe { x86::controlregs::cr2() as u64 }
+    }
+
+    fn write_cr2(value: u64) {
+        unsafe { x86::controlregs::cr2_write(value) }
+    }
+
+    unsafe fn xsetbv(xcr0: u64) {
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") xcr0 as u32,
+            in("edx") (xcr0 >> 32) as u32,
+        );
+    }
+
+    unsafe fn xsave(area: *mut u8, mask: u64) {
+        asm!("xsave64 [{}]", in(reg) area, in("eax") mask as u32, in("edx") (mask >> 32) as u32);
+    }
+
+    unsafe fn xrstor(area: *const u8, mask: u64) {
+        asm!("xrstor64 [{}]", in(reg) area, in("eax") mask as u32, in("edx") (mask >> 32) as u32);
+    }
+
+    fn host_state() -> HostState {
+        let tr = unsafe { x86::task::tr() };
+        let mut gdtp = DescriptorTablePointer::<u64>::default();
+        let mut idtp = DescriptorTablePointer::<u64>::default();
+        unsafe {
+            dtables::sgdt(&mut gdtp);
+            dtables::sidt(&mut idtp);
+        }
+        HostState {
+            cr0: Cr0::read_raw(),
+            cr3: Cr3::read_raw().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+            es: x86::segmentation::es().bits(),
+            cs: x86::segmentation::cs().bits(),
+            ss: x86::segmentation::ss().bits(),
+            ds: x86::segmentation::ds().bits(),
+            fs: x86::segmentation::fs().bits(),
+            gs: x86::segmentation::gs().bits(),
+            tr: tr.bits(),
+            tr_base: get_tr_base(tr, &gdtp),
+            gdtr_base: gdtp.base as u64,
+            idtr_base: idtp.base as u64,
+        }
+    }
+}
+
+fn get_tr_base(tr: SegmentSelector, gdt: &DescriptorTablePointer<u64>) -> u64 {
+    let index = tr.index() as usize;
+    let table_len = (gdt.limit as usize + 1) / core::mem::size_of::<u64>();
+    let table = unsafe { core::slice::from_raw_parts(gdt.base, table_len) };
+    let entry = table[index];
+    if entry & (1 << 47) != 0 {
+        // present
+        let base_low = entry.get_bits(16..40) | entry.get_bits(56..64) << 24;
+        let base_high = table[index + 1] & 0xffff_ffff;
+        base_low | base_high << 32
+    } else {
+        // no present
+        0
+    }
+}
+
+/// The processor accessed by the vCPUs.
+#[cfg(not(any(test, feature = "mock")))]
+pub(crate) type Hw = Hardware;
+/// The processor accessed by the vCPUs.
+#[cfg(any(test, feature = "mock"))]
+pub(crate) type Hw = super::mock::InMemoryHw;
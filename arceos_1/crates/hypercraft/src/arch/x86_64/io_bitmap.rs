@@ -0,0 +1,46 @@
+use core::ops::RangeInclusive;
+
+use crate::arch::memory::ContiguousFrames;
+use crate::{HostPhysAddr, HyperCraftHal, HyperResult};
+
+/// I/O bitmap of a VM, one bit per port to intercept its accesses.
+///
+/// The layout is shared by VMX, as I/O bitmaps A (ports `0..0x8000`) and B
+/// (ports `0x8000..0x10000`) in the first two pages, and SVM, as the I/O
+/// permission map whose third page covers multi-byte accesses at port
+/// `0xffff`. (SDM Vol. 3C, Section 24.6.4; APM Vol. 2, Section 15.10.1)
+#[derive(Debug)]
+pub struct IoBitmap<H: HyperCraftHal> {
+    frames: ContiguousFrames<H>,
+}
+
+impl<H: HyperCraftHal> IoBitmap<H> {
+    pub fn intercept_all() -> HyperResult<Self> {
+        Ok(Self {
+            frames: ContiguousFrames::alloc(3, u8::MAX)?,
+        })
+    }
+
+    /// Physical address of the first page, i.e., I/O bitmap A or the I/O
+    /// permission map.
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frames.phys_addr()
+    }
+
+    /// Physical address of the second page, i.e., I/O bitmap B.
+    pub fn phys_addr_b(&self) -> HostPhysAddr {
+        self.frames.phys_addr() + H::PAGE_SIZE
+    }
+
+    pub fn set_intercept(&mut self, ports: RangeInclusive<u16>, intercept: bool) {
+        let bitmap = self.frames.as_mut_slice();
+        for port in ports {
+            let byte = &mut bitmap[port as usize / 8];
+            if intercept {
+                *byte |= 1 << (port % 8);
+            } else {
+                *byte &= !(1 << (port % 8));
+            }
+        }
+    }
+}
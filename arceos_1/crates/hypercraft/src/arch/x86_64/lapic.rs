@@ -1,6 +1,7 @@
+use alloc::sync::Arc;
 use bit_field::BitField;
-use core::marker::PhantomData;
 
+use super::clock::VirtClock;
 use crate::{HyperCraftHal, HyperResult, HyperError};
 
 const APIC_FREQ_MHZ: u64 = 1000; // 1000 MHz
@@ -19,25 +20,46 @@ pub enum TimerMode {
     TscDeadline = 0b10,
 }
 
+/// Saved state of an [`ApicTimer`], all times are in the virtual time of the VM.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApicTimerState {
+    /// LVT Timer Register.
+    pub lvt_timer: u32,
+    /// Shift of the divider, decoded from the Divide Configuration Register.
+    pub divide_shift: u8,
+    /// Initial Count Register.
+    pub initial_count: u32,
+    /// Time at which the count was last reloaded.
+    pub last_start_ns: u64,
+    /// Time of the next expiry, zero if not armed.
+    pub deadline_ns: u64,
+    /// IA32_TSC_DEADLINE MSR.
+    pub deadline_tsc: u64,
+}
+
 /// A virtual local APIC timer. (SDM Vol. 3C, Section 10.5.4)
+///
+/// All times are in the virtual time of the VM.
 pub struct ApicTimer<H: HyperCraftHal> {
     lvt_timer_bits: u32,
     divide_shift: u8,
     initial_count: u32,
     last_start_ns: u64,
     deadline_ns: u64,
-    _phantom: PhantomData<H>,
+    deadline_tsc: u64,
+    clock: Arc<VirtClock<H>>,
 }
 
 impl<H: HyperCraftHal> ApicTimer<H> {
-    pub(crate) const fn new() -> Self {
+    pub(crate) fn new(clock: Arc<VirtClock<H>>) -> Self {
         Self {
             lvt_timer_bits: 0x1_0000, // masked
             divide_shift: 0,
             initial_count: 0,
             last_start_ns: 0,
             deadline_ns: 0,
-            _phantom: PhantomData,
+            deadline_tsc: 0,
+            clock,
         }
     }
 
@@ -45,11 +67,12 @@ impl<H: HyperCraftHal> ApicTimer<H> {
     pub fn check_interrupt(&mut self) -> bool {
         if self.deadline_ns == 0 {
             false
-        } else if H::current_time_nanos() >= self.deadline_ns {
+        } else if self.clock.now_ns() >= self.deadline_ns {
             if self.is_periodic() {
                 self.deadline_ns += self.interval_ns();
             } else {
                 self.deadline_ns = 0;
+                self.deadline_tsc = 0;
             }
             !self.is_masked()
         } else {
@@ -57,6 +80,23 @@ impl<H: HyperCraftHal> ApicTimer<H> {
         }
     }
 
+    /// The virtual time in nanoseconds at which the next unmasked timer
+    /// interrupt will be generated, or `None` if the timer is not armed.
+    pub const fn deadline_ns(&self) -> Option<u64> {
+        if self.deadline_ns == 0 || self.is_masked() {
+            None
+        } else {
+            Some(self.deadline_ns)
+        }
+    }
+
+    /// Whether the virtual time has reached [`ApicTimer::deadline_ns`], so
+    /// that a halted vCPU must not wait for the timer.
+    pub fn is_due(&self) -> bool {
+        self.deadline_ns()
+            .map_or(false, |deadline_ns| self.clock.now_ns() >= deadline_ns)
+    }
+
     /// Whether the timer interrupt is masked.
     pub const fn is_masked(&self) -> bool {
         self.lvt_timer_bits & (1 << 16) != 0
@@ -68,6 +108,12 @@ impl<H: HyperCraftHal> ApicTimer<H> {
         timer_mode == TimerMode::Periodic as _
     }
 
+    /// Whether the timer mode is TSC-deadline.
+    pub const fn is_tsc_deadline(&self) -> bool {
+        let timer_mode = (self.lvt_timer_bits >> 17) & 0b11;
+        timer_mode == TimerMode::TscDeadline as _
+    }
+
     /// The timer interrupt vector number.
     pub const fn vector(&self) -> u8 {
         (self.lvt_timer_bits & 0xff) as u8
@@ -89,9 +135,22 @@ impl<H: HyperCraftHal> ApicTimer<H> {
         self.initial_count
     }
 
+    /// IA32_TSC_DEADLINE MSR, reads zero if not in TSC-deadline mode or the
+    /// timer has fired. (SDM Vol. 3A, Section 10.5.4.1)
+    pub const fn tsc_deadline(&self) -> u64 {
+        if self.is_tsc_deadline() {
+            self.deadline_tsc
+        } else {
+            0
+        }
+    }
+
     /// Current Count Register.
     pub fn current_counter(&self) -> u32 {
-        let elapsed_ns = H::current_time_nanos() - self.last_start_ns;
+        if self.is_tsc_deadline() {
+            return 0;
+        }
+        let elapsed_ns = self.clock.now_ns() - self.last_start_ns;
         let elapsed_cycles = (elapsed_ns / APIC_CYCLE_NANOS) >> self.divide_shift;
         if self.is_periodic() {
             self.initial_count - (elapsed_cycles % self.initial_count as u64) as u32
@@ -105,28 +164,83 @@ impl<H: HyperCraftHal> ApicTimer<H> {
     /// Set LVT Timer Register.
     pub fn set_lvt_timer(&mut self, bits: u32) -> HyperResult {
         let timer_mode = bits.get_bits(17..19);
-        if timer_mode == TimerMode::TscDeadline as _ {
-            return Err(HyperError::NotSupported); // TSC deadline mode was not supported
-        } else if timer_mode == 0b11 {
+        if timer_mode == 0b11 {
             return Err(HyperError::InvalidParam); // reserved
         }
+        let was_tsc_deadline = self.is_tsc_deadline();
         self.lvt_timer_bits = bits;
-        self.start_timer();
+        if self.is_tsc_deadline() {
+            if !was_tsc_deadline {
+                // switching to TSC-deadline mode disarms the timer
+                self.deadline_ns = 0;
+                self.deadline_tsc = 0;
+            }
+        } else {
+            self.deadline_tsc = 0;
+            self.start_timer();
+        }
         Ok(())
     }
 
     /// Set Initial Count Register.
     pub fn set_initial_count(&mut self, initial: u32) -> HyperResult {
+        if self.is_tsc_deadline() {
+            return Ok(()); // ignored in TSC-deadline mode
+        }
         self.initial_count = initial;
         self.start_timer();
         Ok(())
     }
 
+    /// Set IA32_TSC_DEADLINE MSR, writing zero disarms the timer.
+    pub fn set_tsc_deadline(&mut self, deadline_tsc: u64) -> HyperResult {
+        if !self.is_tsc_deadline() {
+            return Ok(()); // ignored in other modes
+        }
+        self.deadline_tsc = deadline_tsc;
+        if deadline_tsc == 0 {
+            self.deadline_ns = 0;
+        } else {
+            // a deadline in the past fires immediately
+            self.deadline_ns = self.clock.guest_tsc_to_ns(deadline_tsc).max(1);
+        }
+        Ok(())
+    }
+
     /// Set Divide Configuration Register.
     pub fn set_divide(&mut self, dcr: u32) -> HyperResult {
         let shift = (dcr & 0b11) | ((dcr & 0b1000) >> 1);
         self.divide_shift = (shift + 1) as u8 & 0b111;
-        self.start_timer();
+        if !self.is_tsc_deadline() {
+            self.start_timer();
+        }
+        Ok(())
+    }
+
+    /// Saves the timer registers and the next expiry.
+    pub const fn save_state(&self) -> ApicTimerState {
+        ApicTimerState {
+            lvt_timer: self.lvt_timer_bits,
+            divide_shift: self.divide_shift,
+            initial_count: self.initial_count,
+            last_start_ns: self.last_start_ns,
+            deadline_ns: self.deadline_ns,
+            deadline_tsc: self.deadline_tsc,
+        }
+    }
+
+    /// Restores the state saved by [`ApicTimer::save_state`], the virtual
+    /// clock must have been restored to the saved time as well.
+    pub fn restore_state(&mut self, state: &ApicTimerState) -> HyperResult {
+        if state.lvt_timer.get_bits(17..19) == 0b11 || state.divide_shift > 7 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.lvt_timer_bits = state.lvt_timer;
+        self.divide_shift = state.divide_shift;
+        self.initial_count = state.initial_count;
+        self.last_start_ns = state.last_start_ns;
+        self.deadline_ns = state.deadline_ns;
+        self.deadline_tsc = state.deadline_tsc;
         Ok(())
     }
 
@@ -136,7 +250,7 @@ impl<H: HyperCraftHal> ApicTimer<H> {
 
     fn start_timer(&mut self) {
         if self.initial_count != 0 {
-            self.last_start_ns = H::current_time_nanos();
+            self.last_start_ns = self.clock.now_ns();
             self.deadline_ns = self.last_start_ns + self.interval_ns();
         } else {
             self.deadline_ns = 0;
@@ -70,4 +70,43 @@ impl<H: HyperCraftHal> Drop for PhysFrame<H> {
             trace!("dropped physframe {:#018x}", self.start_paddr);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Physically contiguous 4K pages, deallocated automatically on drop.
+#[derive(Debug)]
+pub struct ContiguousFrames<H: HyperCraftHal> {
+    start_vaddr: usize,
+    num_pages: usize,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> ContiguousFrames<H> {
+    pub fn alloc(num_pages: usize, byte: u8) -> HyperResult<Self> {
+        let start_vaddr = H::alloc_pages(num_pages).ok_or(HyperError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(start_vaddr as *mut u8, byte, num_pages * H::PAGE_SIZE) };
+        Ok(Self {
+            start_vaddr,
+            num_pages,
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        H::virt_to_phys(self.start_vaddr)
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.start_vaddr as *mut u8,
+                self.num_pages * H::PAGE_SIZE,
+            )
+        }
+    }
+}
+
+impl<H: HyperCraftHal> Drop for ContiguousFrames<H> {
+    fn drop(&mut self) {
+        H::dealloc_pages(self.start_vaddr, self.num_pages);
+    }
+}
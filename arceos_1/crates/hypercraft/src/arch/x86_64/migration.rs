@@ -0,0 +1,24 @@
+/// Functions to call as the task running a vCPU moves between physical CPUs,
+/// see [`VCpu::migration_hooks`].
+///
+/// [`VCpu::migration_hooks`]: super::VCpu::migration_hooks
+#[derive(Debug, Clone, Copy)]
+pub struct MigrationHooks {
+    /// Called with [`MigrationHooks::arg`] on the CPU the task leaves, after
+    /// its last VM exit there and before the task runs anywhere else.
+    pub leave_cpu: fn(usize),
+    /// Called with [`MigrationHooks::arg`] on the CPU the task arrives at,
+    /// before the vCPU is used there.
+    pub enter_cpu: fn(usize),
+    /// The argument of the hooks.
+    pub arg: usize,
+}
+
+impl MigrationHooks {
+    /// Hooks doing nothing, for vCPUs not tied to a physical CPU.
+    pub const NONE: Self = Self {
+        leave_cpu: |_| {},
+        enter_cpu: |_| {},
+        arg: 0,
+    };
+}
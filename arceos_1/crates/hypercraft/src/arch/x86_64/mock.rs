@@ -0,0 +1,280 @@
+//! An in-memory processor and a mock [`HyperCraftHal`], to run the vCPU code
+//! in hosted unit tests (`cargo test`, or the `mock` feature for the tests of
+//! other crates).
+//!
+//! VMCS fields and MSRs are kept in memory, and the host time only advances
+//! when asked to. Extended states are saved and loaded by the processor
+//! running the tests, limited to the components enabled in the in-memory
+//! `XCR0`. All states are per thread, as the test harness runs tests
+//! in parallel.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::thread_local;
+
+use x86::vmx::{Result as VmxResult, VmFail};
+
+use super::hw::{Hardware, HostState, HwAccess};
+use super::{VCpu, VmExitReason};
+use crate::memory::PAGE_SIZE_4K;
+use crate::{HostPhysAddr, HostVirtAddr, HyperCraftHal, HyperResult};
+
+/// The TSC frequency of the mock host.
+pub const MOCK_TSC_MHZ: u64 = 2000;
+
+/// The host time at which each test starts, in nanoseconds.
+pub const MOCK_START_NS: u64 = 1_000_000_000;
+
+struct Machine {
+    // the fields of each VMCS, by its physical address
+    vmcs: BTreeMap<u64, BTreeMap<u32, u64>>,
+    current_vmcs: Option<u64>,
+    msrs: BTreeMap<u32, u64>,
+    cr2: u64,
+    xcr0: u64,
+    invept_count: usize,
+}
+
+impl Machine {
+    fn new() -> Self {
+        // All VMX controls can be either 0 or 1, PE, NE and PG are fixed to 1
+        // in CR0, and VMXE in CR4.
+        let any_control = 0xffff_ffff_0000_0000;
+        let msrs = [
+            (0x480, 1),                     // IA32_VMX_BASIC: revision 1
+            (0x481, any_control),           // IA32_VMX_PINBASED_CTLS
+            (0x482, any_control),           // IA32_VMX_PROCBASED_CTLS
+            (0x483, any_control),           // IA32_VMX_EXIT_CTLS
+            (0x484, any_control),           // IA32_VMX_ENTRY_CTLS
+            (0x486, 0x8000_0021),           // IA32_VMX_CR0_FIXED0
+            (0x487, 0xffff_ffff),           // IA32_VMX_CR0_FIXED1
+            (0x488, 0x2000),                // IA32_VMX_CR4_FIXED0
+            (0x489, 0x00ff_ffff),           // IA32_VMX_CR4_FIXED1
+            (0x48b, any_control),           // IA32_VMX_PROCBASED_CTLS2
+            (0x48c, 0x0023_4040),           // IA32_VMX_EPT_VPID_CAP: 4-level, WB, 2M, 1G, A/D
+            (0x48d, any_control),           // IA32_VMX_TRUE_PINBASED_CTLS
+            (0x48e, any_control),           // IA32_VMX_TRUE_PROCBASED_CTLS
+            (0x48f, any_control),           // IA32_VMX_TRUE_EXIT_CTLS
+            (0x490, any_control),           // IA32_VMX_TRUE_ENTRY_CTLS
+            (0x277, 0x0007_0406_0007_0406), // IA32_PAT
+            (0xc000_0080, 0xd01),           // IA32_EFER: SCE, LME, LMA, NXE
+        ];
+        Self {
+            vmcs: BTreeMap::new(),
+            current_vmcs: None,
+            msrs: msrs.into_iter().collect(),
+            cr2: 0,
+            xcr0: 1, // x87 only, as at reset
+            invept_count: 0,
+        }
+    }
+
+    fn current_fields(&mut self) -> VmxResult<&mut BTreeMap<u32, u64>> {
+        let paddr = self.current_vmcs.ok_or(VmFail::VmFailInvalid)?;
+        Ok(self.vmcs.entry(paddr).or_default())
+    }
+}
+
+thread_local! {
+    static MACHINE: RefCell<Machine> = RefCell::new(Machine::new());
+    static NOW_NS: Cell<u64> = Cell::new(MOCK_START_NS);
+    static EXITS: RefCell<Vec<VmExitReason>> = RefCell::new(Vec::new());
+    static VCPU_TIMERS: RefCell<BTreeMap<(usize, usize), u64>> = RefCell::new(BTreeMap::new());
+}
+
+/// A processor whose VMCSs and MSRs are kept in memory.
+///
+/// VMCS fields read zero until written, read-only fields included, so tests
+/// can write the exit information before calling the exit handlers.
+pub struct InMemoryHw;
+
+impl InMemoryHw {
+    /// Reads a field of the current VMCS, by its encoding.
+    pub fn read_field(field: u32) -> u64 {
+        <Self as HwAccess>::vmread(field).expect("no current VMCS")
+    }
+
+    /// Writes a field of the current VMCS, by its encoding.
+    pub fn write_field(field: u32, value: u64) {
+        <Self as HwAccess>::vmwrite(field, value).expect("no current VMCS")
+    }
+
+    /// The physical address of the current VMCS.
+    pub fn current_vmcs() -> Option<u64> {
+        MACHINE.with(|m| m.borrow().current_vmcs)
+    }
+
+    /// Reads a MSR, zero if never written.
+    pub fn msr(msr: u32) -> u64 {
+        <Self as HwAccess>::rdmsr(msr)
+    }
+
+    /// Writes a MSR, e.g., to change the VMX capabilities before creating a
+    /// vCPU.
+    pub fn set_msr(msr: u32, value: u64) {
+        MACHINE.with(|m| m.borrow_mut().msrs.insert(msr, value));
+    }
+
+    /// The `CR2` register.
+    pub fn cr2() -> u64 {
+        <Self as HwAccess>::read_cr2()
+    }
+
+    /// Writes the `CR2` register, e.g., as a guest page fault would.
+    pub fn set_cr2(value: u64) {
+        <Self as HwAccess>::write_cr2(value)
+    }
+
+    /// The `XCR0` register.
+    pub fn xcr0() -> u64 {
+        MACHINE.with(|m| m.borrow().xcr0)
+    }
+
+    /// Number of INVEPT instructions executed.
+    pub fn invept_count() -> usize {
+        MACHINE.with(|m| m.borrow().invept_count)
+    }
+}
+
+impl HwAccess for InMemoryHw {
+    fn vmread(field: u32) -> VmxResult<u64> {
+        MACHINE.with(|m| Ok(m.borrow_mut().current_fields()?.get(&field).copied().unwrap_or(0)))
+    }
+
+    fn vmwrite(field: u32, value: u64) -> VmxResult<()> {
+        MACHINE.with(|m| {
+            m.borrow_mut().current_fields()?.insert(field, value);
+            Ok(())
+        })
+    }
+
+    fn vmclear(paddr: u64) -> VmxResult<()> {
+        MACHINE.with(|m| {
+            let mut m = m.borrow_mut();
+            if m.current_vmcs == Some(paddr) {
+                m.current_vmcs = None;
+            }
+            Ok(())
+        })
+    }
+
+    fn vmptrld(paddr: u64) -> VmxResult<()> {
+        MACHINE.with(|m| m.borrow_mut().current_vmcs = Some(paddr));
+        Ok(())
+    }
+
+    fn invept_single_context(_eptp: u64) -> VmxResult<()> {
+        MACHINE.with(|m| m.borrow_mut().invept_count += 1);
+        Ok(())
+    }
+
+    fn rdmsr(msr: u32) -> u64 {
+        MACHINE.with(|m| m.borrow().msrs.get(&msr).copied().unwrap_or(0))
+    }
+
+    unsafe fn wrmsr(msr: u32, value: u64) {
+        Self::set_msr(msr, value)
+    }
+
+    fn read_cr2() -> u64 {
+        MACHINE.with(|m| m.borrow().cr2)
+    }
+
+    fn write_cr2(value: u64) {
+        MACHINE.with(|m| m.borrow_mut().cr2 = value)
+    }
+
+    unsafe fn xsetbv(xcr0: u64) {
+        MACHINE.with(|m| m.borrow_mut().xcr0 = xcr0)
+    }
+
+    unsafe fn xsave(area: *mut u8, mask: u64) {
+        Hardware::xsave(area, mask & Self::xcr0())
+    }
+
+    unsafe fn xrstor(area: *const u8, mask: u64) {
+        Hardware::xrstor(area, mask & Self::xcr0())
+    }
+
+    fn host_state() -> HostState {
+        HostState {
+            cr0: 0x8005_0033,
+            cr4: 0x2020,
+            cs: 0x8,
+            ss: 0x10,
+            tr: 0x18,
+            ..Default::default()
+        }
+    }
+}
+
+/// A [`HyperCraftHal`] allocating pages from the heap, with physical
+/// addresses equal to virtual ones, and a host time set by the tests.
+///
+/// VM exits not handled by the vCPU itself are only recorded, see
+/// [`MockHal::take_exits`].
+pub struct MockHal;
+
+impl MockHal {
+    /// Advances the host time by `ns` nanoseconds.
+    pub fn advance_ns(ns: u64) {
+        NOW_NS.with(|now| now.set(now.get() + ns));
+    }
+
+    /// Takes the reasons of the VM exits passed to
+    /// [`HyperCraftHal::vmexit_handler`] so far.
+    pub fn take_exits() -> Vec<VmExitReason> {
+        EXITS.with(|exits| exits.take())
+    }
+
+    /// The host time at which the timer of the vCPU is armed, if any.
+    pub fn vcpu_timer(vm_id: usize, vcpu_id: usize) -> Option<u64> {
+        VCPU_TIMERS.with(|timers| timers.borrow().get(&(vm_id, vcpu_id)).copied())
+    }
+
+    fn layout(num_pages: usize) -> Layout {
+        Layout::from_size_align(num_pages * PAGE_SIZE_4K, PAGE_SIZE_4K).unwrap()
+    }
+}
+
+impl HyperCraftHal for MockHal {
+    fn alloc_pages(num_pages: usize) -> Option<HostVirtAddr> {
+        let ptr = unsafe { alloc_zeroed(Self::layout(num_pages)) };
+        (!ptr.is_null()).then_some(ptr as HostVirtAddr)
+    }
+
+    fn dealloc_pages(va: HostVirtAddr, num_pages: usize) {
+        unsafe { dealloc(va as *mut u8, Self::layout(num_pages)) }
+    }
+
+    fn phys_to_virt(pa: HostPhysAddr) -> HostVirtAddr {
+        pa
+    }
+
+    fn virt_to_phys(va: HostVirtAddr) -> HostPhysAddr {
+        va
+    }
+
+    fn vmexit_handler(vcpu: &mut VCpu<Self>) -> HyperResult {
+        let exit_info = vcpu.exit_info()?;
+        EXITS.with(|exits| exits.borrow_mut().push(exit_info.exit_reason));
+        Ok(())
+    }
+
+    fn current_time_nanos() -> u64 {
+        NOW_NS.with(|now| now.get())
+    }
+
+    fn nanos_to_ticks(nanos: u64) -> u64 {
+        nanos * MOCK_TSC_MHZ / 1_000
+    }
+
+    fn set_vcpu_timer(vm_id: usize, vcpu_id: usize, deadline_ns: Option<u64>) {
+        VCPU_TIMERS.with(|timers| match deadline_ns {
+            Some(ns) => timers.borrow_mut().insert((vm_id, vcpu_id), ns),
+            None => timers.borrow_mut().remove(&(vm_id, vcpu_id)),
+        });
+    }
+}
@@ -3,31 +3,66 @@ mod regs;
 
 // Codes in this module come mainly from https://github.com/rcore-os/RVM-Tutorial
 
+mod branch_counter;
+mod clock;
+mod coredump;
+mod detect;
 mod ept;
+mod exit;
+mod exit_stats;
+mod guest_memory;
+mod hw;
+mod io_bitmap;
 mod lapic;
 mod memory;
+mod migration;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 mod msr;
+mod nested_paging;
+mod npt;
+mod replay;
+mod vcpu;
+mod vcpu_state;
+mod vm;
 mod vmx;
+mod svm;
 mod percpu;
+mod xstate;
+#[cfg(test)]
+mod tests;
 
 use crate::{GuestPageTableTrait, HyperCraftHal};
 use page_table::PagingIf;
 
+use detect::{extension, supported_extension, Extension};
+
 /// Initialize the hypervisor runtime.
 pub fn init_hv_runtime() {
-    if !vmx::has_hardware_support() {
-        panic!("VMX not supported");
+    match supported_extension() {
+        Some(ext) => info!("Hardware virtualization extension: {:?}", ext),
+        None => panic!("Neither VMX nor SVM is supported"),
     }
 }
 
-/// Nested page table define.
-pub use ept::ExtendedPageTable as NestedPageTable;
-
-/// VCpu define.
-pub use vmx::VmxVcpu as VCpu;
+pub use nested_paging::{ept_ad_supported, ept_max_page_size, flush_ept, NestedPageTable};
+pub use vcpu::VCpu;
 pub use percpu::PerCpu;
-pub use vmx::{VmxExitReason, VmxExitInfo};
-pub use vmx::VM;
+pub use clock::VirtClock;
+pub use coredump::{core_file_headers, CORE_FILE_ALIGN};
+pub use xstate::xstate_cpuid;
+pub use exit::{VmExitInfo, VmExitReason};
+pub use exit_stats::{ExitStats, ExitStatsSummary, ExitTraceEntry};
+pub use vmx::VmxExitReason;
+pub use vcpu_state::VcpuState;
+pub use replay::{ReplayInput, ReplayLog};
+pub use lapic::ApicTimerState;
+pub use memory::NestedPageFaultInfo;
+pub use migration::MigrationHooks;
+pub use vm::VM;
+pub use guest_memory::{
+    GuestMemoryAccessor, GuestMemoryError, GuestMemoryResult, GuestPageFault, PageFaultErrorCode,
+};
 
 ////// Following are things to be implemented
 
@@ -39,14 +74,28 @@ impl<H: HyperCraftHal> VCpu<H> {
     }
 }
 
+/// Guest-state fields saved in [`VcpuState::guest_fields`] by the vCPUs of
+/// the detected extension.
+pub(crate) fn saved_guest_fields() -> &'static [u32] {
+    match extension() {
+        Extension::Vmx => vmx::SAVED_GUEST_FIELDS,
+        Extension::Svm => svm::SAVED_GUEST_FIELDS,
+    }
+}
+
+/// Fields of the registers of core files, for the detected extension.
+pub(crate) fn core_reg_fields() -> &'static coredump::CoreRegFields {
+    match extension() {
+        Extension::Vmx => &vmx::CORE_REG_FIELDS,
+        Extension::Svm => &svm::CORE_REG_FIELDS,
+    }
+}
+
 // /// VM define.
 // pub struct VM<H: HyperCraftHal> {
 //     _marker: core::marker::PhantomData<H>,
 // }
 
-/// VM exit information.
-pub struct VmExitInfo {}
-
 /// General purpose register index.
 pub enum GprIndex {}
 
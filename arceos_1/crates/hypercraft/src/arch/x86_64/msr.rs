@@ -1,6 +1,7 @@
 use bit_field::BitField;
 use bitflags::bitflags;
-use x86::msr::{rdmsr, wrmsr};
+
+use super::hw::{Hw, HwAccess};
 
 /// X86 model-specific registers. (SDM Vol. 4)
 #[repr(u32)]
@@ -37,13 +38,17 @@ pub enum Msr {
     IA32_FS_BASE = 0xc000_0100,
     IA32_GS_BASE = 0xc000_0101,
     IA32_KERNEL_GSBASE = 0xc000_0102,
+    IA32_TSC_AUX = 0xc000_0103,
+
+    VM_CR = 0xc001_0114,
+    VM_HSAVE_PA = 0xc001_0117,
 }
 
 impl Msr {
     /// Read 64 bits msr register.
     #[inline(always)]
     pub fn read(self) -> u64 {
-        unsafe { rdmsr(self as _) }
+        Hw::rdmsr(self as _)
     }
 
     /// Write 64 bits to msr register.
@@ -54,7 +59,7 @@ impl Msr {
     /// effects.
     #[inline(always)]
     pub unsafe fn write(self, value: u64) {
-        wrmsr(self as _, value)
+        Hw::wrmsr(self as _, value)
     }
 }
 
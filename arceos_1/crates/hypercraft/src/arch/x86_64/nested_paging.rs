@@ -0,0 +1,165 @@
+use core::fmt::{Debug, Formatter, Result};
+use core::mem::transmute;
+
+use memory_addr::PhysAddr;
+use page_table::{MappingFlags, PageSize, PagingMetaData, PageTable64};
+use page_table_entry::x86_64::{EPTEntry, NPTEntry};
+use page_table_entry::GenericPTE;
+
+use super::detect::{extension, Extension};
+use super::{ept, npt, vmx};
+use crate::{HostPhysAddr, HyperResult};
+
+pub struct NestedPageTableMetadata;
+
+impl PagingMetaData for NestedPageTableMetadata {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 52;
+    const VA_MAX_BITS: usize = 52;
+}
+
+/// An entry of [`NestedPageTable`]: an EPT entry with VMX, or a nested page
+/// table entry with SVM, chosen by the extension detected at runtime.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct NestedPageEntry(u64);
+
+impl NestedPageEntry {
+    fn from_ept(entry: EPTEntry) -> Self {
+        unsafe { transmute(entry) }
+    }
+
+    fn from_npt(entry: NPTEntry) -> Self {
+        unsafe { transmute(entry) }
+    }
+
+    fn ept(self) -> EPTEntry {
+        unsafe { transmute(self) }
+    }
+
+    fn npt(self) -> NPTEntry {
+        unsafe { transmute(self) }
+    }
+}
+
+/// Evaluates `$body` with the entry as the one of the detected extension.
+macro_rules! with_entry {
+    ($entry:expr, |$e:ident| $body:expr) => {
+        match extension() {
+            Extension::Vmx => {
+                let $e = $entry.ept();
+                $body
+            }
+            Extension::Svm => {
+                let $e = $entry.npt();
+                $body
+            }
+        }
+    };
+}
+
+/// Like [`with_entry`], but writes the changed entry back.
+macro_rules! update_entry {
+    ($entry:expr, |$e:ident| $body:expr) => {
+        match extension() {
+            Extension::Vmx => {
+                let mut $e = $entry.ept();
+                $body;
+                *$entry = Self::from_ept($e);
+            }
+            Extension::Svm => {
+                let mut $e = $entry.npt();
+                $body;
+                *$entry = Self::from_npt($e);
+            }
+        }
+    };
+}
+
+impl GenericPTE for NestedPageEntry {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        match extension() {
+            Extension::Vmx => Self::from_ept(EPTEntry::new_page(paddr, flags, is_huge)),
+            Extension::Svm => Self::from_npt(NPTEntry::new_page(paddr, flags, is_huge)),
+        }
+    }
+
+    fn new_table(paddr: PhysAddr) -> Self {
+        match extension() {
+            Extension::Vmx => Self::from_ept(EPTEntry::new_table(paddr)),
+            Extension::Svm => Self::from_npt(NPTEntry::new_table(paddr)),
+        }
+    }
+
+    fn paddr(&self) -> PhysAddr {
+        with_entry!(self, |e| e.paddr())
+    }
+
+    fn flags(&self) -> MappingFlags {
+        with_entry!(self, |e| e.flags())
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_present(&self) -> bool {
+        with_entry!(self, |e| e.is_present())
+    }
+
+    fn is_huge(&self) -> bool {
+        with_entry!(self, |e| e.is_huge())
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn is_dirty(&self) -> bool {
+        with_entry!(self, |e| e.is_dirty())
+    }
+
+    fn clear_dirty(&mut self) {
+        update_entry!(self, |e| e.clear_dirty())
+    }
+
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        update_entry!(self, |e| e.set_flags(flags, is_huge))
+    }
+}
+
+impl Debug for NestedPageEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        with_entry!(self, |e| e.fmt(f))
+    }
+}
+
+/// The nested page table: the extended page table with VMX (SDM Vol. 3C,
+/// Section 28.3), or the nested page table with SVM (APM Vol. 2, Section 15.25).
+pub type NestedPageTable<I> = PageTable64<NestedPageTableMetadata, NestedPageEntry, I>;
+
+/// The largest page size that nested page table entries can map.
+pub fn ept_max_page_size() -> PageSize {
+    match extension() {
+        Extension::Vmx => ept::ept_max_page_size(),
+        Extension::Svm => npt::npt_max_page_size(),
+    }
+}
+
+/// Whether the processor sets the accessed and dirty flags of the nested page
+/// table, so dirty guest pages can be found without write-protecting them.
+pub fn ept_ad_supported() -> bool {
+    match extension() {
+        Extension::Vmx => ept::ept_ad_supported(),
+        Extension::Svm => npt::npt_ad_supported(),
+    }
+}
+
+/// Invalidates the guest-physical mappings derived from the nested page table
+/// rooted at `pml4_paddr` on the current CPU.
+pub fn flush_ept(pml4_paddr: HostPhysAddr) -> HyperResult {
+    match extension() {
+        Extension::Vmx => vmx::flush_ept(pml4_paddr),
+        Extension::Svm => npt::flush_npt(pml4_paddr),
+    }
+}
@@ -0,0 +1,64 @@
+use bit_field::BitField;
+use page_table::PageSize;
+use raw_cpuid::CpuId;
+
+use super::guest_memory::{GuestMemoryError, GuestMemoryResult, PTE_ADDR_MASK};
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperResult};
+
+// Nested page table entry bits, the same as the host ones. (APM Vol. 2, Section 5.3)
+const NPTE_P: u64 = 1 << 0;
+const NPTE_RW: u64 = 1 << 1;
+const NPTE_PS: u64 = 1 << 7;
+
+/// The largest page size that nested page table entries can map, 1 GiB pages
+/// are not supported by all processors.
+pub fn npt_max_page_size() -> PageSize {
+    let has_1g = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |f| f.has_1gib_pages());
+    if has_1g {
+        PageSize::Size1G
+    } else {
+        PageSize::Size2M
+    }
+}
+
+/// Whether the processor sets the accessed and dirty flags of the nested page
+/// table, which it always does. (APM Vol. 2, Section 15.25.5)
+pub fn npt_ad_supported() -> bool {
+    true
+}
+
+/// Invalidates the guest-physical mappings derived from the nested page table
+/// rooted at `pml4_paddr` on the current CPU. Nothing to do, since the guest
+/// TLB entries are flushed on every VMRUN.
+pub fn flush_npt(_pml4_paddr: HostPhysAddr) -> HyperResult {
+    Ok(())
+}
+
+/// Translates `gpa` through the nested page table rooted at `ncr3`, checking
+/// that all levels permit writes if `write` is true.
+pub(super) fn npt_translate<H: HyperCraftHal>(
+    ncr3: u64,
+    gpa: GuestPhysAddr,
+    write: bool,
+) -> GuestMemoryResult<HostPhysAddr> {
+    let mut level = 4;
+    let mut table = (ncr3 & PTE_ADDR_MASK) as HostPhysAddr;
+    loop {
+        let index = gpa.get_bits(12 + (level - 1) * 9..21 + (level - 1) * 9);
+        let entry = unsafe { (H::phys_to_virt(table) as *const u64).add(index).read_volatile() };
+        if entry & NPTE_P == 0 {
+            return Err(GuestMemoryError::NotBacked(gpa));
+        }
+        if write && entry & NPTE_RW == 0 {
+            return Err(GuestMemoryError::ReadOnly(gpa));
+        }
+        if level == 1 || (level <= 3 && entry & NPTE_PS != 0) {
+            let page_mask = (1usize << (12 + (level - 1) * 9)) - 1;
+            return Ok(((entry & PTE_ADDR_MASK) as usize & !page_mask) | (gpa & page_mask));
+        }
+        table = (entry & PTE_ADDR_MASK) as HostPhysAddr;
+        level -= 1;
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{HyperCraftHal, HostPhysAddr, GuestPhysAddr};
 use crate::{HyperResult, HyperError};
+use crate::arch::detect::{extension, Extension};
+use crate::arch::svm::SvmPerCpuState;
 use crate::arch::vmx::VmxPerCpuState;
 
 use super::VCpu;
@@ -7,15 +9,15 @@ use super::VCpu;
 /// Host per-CPU states to run the guest. All methods must be called on the corresponding CPU.
 pub struct PerCpu<H: HyperCraftHal> {
     cpu_id: usize,
-    arch: VmxPerCpuState<H>,
+    arch: ArchPerCpuState<H>,
 }
 
 impl<H: HyperCraftHal> PerCpu<H> {
     /// Create an uninitialized instance.
     pub fn new(cpu_id: usize) -> Self {
         Self {
-            cpu_id: cpu_id,
-            arch: VmxPerCpuState::new(),
+            cpu_id,
+            arch: ArchPerCpuState::new(),
         }
     }
 
@@ -28,7 +30,7 @@ impl<H: HyperCraftHal> PerCpu<H> {
     pub fn hardware_enable(&mut self) -> HyperResult {
         match self.arch.hardware_enable() {
             Ok(_) => {
-                info!("VMX enabled on cpu {}.", self.cpu_id);
+                info!("{} enabled on cpu {}.", self.arch.extension(), self.cpu_id);
                 Ok(())
             },
             e @ Err(_) => {
@@ -41,7 +43,7 @@ impl<H: HyperCraftHal> PerCpu<H> {
     pub fn hardware_disable(&mut self) -> HyperResult {
         match self.arch.hardware_disable() {
             Ok(_) => {
-                info!("VMX disabled on cpu {}.", self.cpu_id);
+                info!("{} disabled on cpu {}.", self.arch.extension(), self.cpu_id);
                 Ok(())
             },
             e @ Err(_) => {
@@ -51,7 +53,7 @@ impl<H: HyperCraftHal> PerCpu<H> {
     }
     /// get vmcs_revision_id
     pub fn get_vmcs_revision_id(&self) -> u32 {
-        self.arch.vmcs_revision_id
+        self.arch.vmcs_revision_id()
     }
 }
 
@@ -63,3 +65,49 @@ impl<H: HyperCraftHal> Drop for PerCpu<H> {
     }
 }
 
+/// The per-CPU state of the extension detected at runtime.
+enum ArchPerCpuState<H: HyperCraftHal> {
+    Vmx(VmxPerCpuState<H>),
+    Svm(SvmPerCpuState<H>),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $state:ident => $body:expr) => {
+        match $self {
+            ArchPerCpuState::Vmx($state) => $body,
+            ArchPerCpuState::Svm($state) => $body,
+        }
+    };
+}
+
+impl<H: HyperCraftHal> ArchPerCpuState<H> {
+    fn new() -> Self {
+        match extension() {
+            Extension::Vmx => Self::Vmx(VmxPerCpuState::new()),
+            Extension::Svm => Self::Svm(SvmPerCpuState::new()),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Vmx(_) => VmxPerCpuState::<H>::EXTENSION,
+            Self::Svm(_) => SvmPerCpuState::<H>::EXTENSION,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        dispatch!(self, state => state.is_enabled())
+    }
+
+    fn hardware_enable(&mut self) -> HyperResult {
+        dispatch!(self, state => state.hardware_enable())
+    }
+
+    fn hardware_disable(&mut self) -> HyperResult {
+        dispatch!(self, state => state.hardware_disable())
+    }
+
+    fn vmcs_revision_id(&self) -> u32 {
+        dispatch!(self, state => state.vmcs_revision_id)
+    }
+}
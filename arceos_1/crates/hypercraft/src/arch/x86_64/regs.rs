@@ -20,6 +20,56 @@ pub struct GeneralRegisters {
     pub r15: u64,
 }
 
+impl GeneralRegisters {
+    /// Returns the register of `index` in the instruction encoding order
+    /// (`RAX`, `RCX`, `RDX`, `RBX`, `RSP`, `RBP`, ...). `RSP` is in the VMCS
+    /// and can not be accessed here.
+    pub fn get_reg_of_index(&self, index: u8) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => panic!("Illegal index of GeneralRegisters {}", index),
+        }
+    }
+
+    /// Sets the register of `index` in the instruction encoding order.
+    /// `RSP` is in the VMCS and can not be accessed here.
+    pub fn set_reg_of_index(&mut self, index: u8, value: u64) {
+        match index {
+            0 => self.rax = value,
+            1 => self.rcx = value,
+            2 => self.rdx = value,
+            3 => self.rbx = value,
+            5 => self.rbp = value,
+            6 => self.rsi = value,
+            7 => self.rdi = value,
+            8 => self.r8 = value,
+            9 => self.r9 = value,
+            10 => self.r10 = value,
+            11 => self.r11 = value,
+            12 => self.r12 = value,
+            13 => self.r13 = value,
+            14 => self.r14 = value,
+            15 => self.r15 = value,
+            _ => panic!("Illegal index of GeneralRegisters {}", index),
+        }
+    }
+}
+
+// Used by the VMX entry and exit paths only.
 macro_rules! save_regs_to_stack {
     () => {
         "
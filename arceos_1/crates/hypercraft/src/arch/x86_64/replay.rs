@@ -0,0 +1,360 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::exit::VmExitReason;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::{HyperError, HyperResult};
+
+const INPUT_PORT_READ: u8 = 0;
+const INPUT_MSR_READ: u8 = 1;
+const INPUT_TSC: u8 = 2;
+const INPUT_CPUID: u8 = 3;
+const INPUT_INTERRUPT: u8 = 4;
+const INPUT_MMIO_READ: u8 = 5;
+
+/// A non-deterministic input delivered to the guest at a VM exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayInput {
+    /// The value read by an `IN` instruction from `port`.
+    PortRead {
+        /// The I/O port.
+        port: u16,
+        /// The value read, zero-extended.
+        value: u32,
+    },
+    /// The value read by `RDMSR` from `msr`.
+    MsrRead {
+        /// The MSR index.
+        msr: u32,
+        /// The value read.
+        value: u64,
+    },
+    /// The guest TSC read by `RDTSC` or `RDTSCP`.
+    Tsc {
+        /// The TSC value.
+        value: u64,
+    },
+    /// The result of `CPUID` with `EAX = leaf` and `ECX = subleaf`.
+    Cpuid {
+        /// The leaf in `EAX`.
+        leaf: u32,
+        /// The subleaf in `ECX`.
+        subleaf: u32,
+        /// `EAX`, `EBX`, `ECX` and `EDX`.
+        regs: [u32; 4],
+    },
+    /// An external interrupt injected to the guest.
+    Interrupt {
+        /// The interrupt vector.
+        vector: u8,
+    },
+    /// The value read from an emulated MMIO register at `gpa`.
+    MmioRead {
+        /// The guest physical address.
+        gpa: u64,
+        /// The value read, zero-extended.
+        value: u64,
+    },
+}
+
+impl ReplayInput {
+    /// Whether both inputs come from the same source, regardless of the values.
+    fn same_source(&self, other: &Self) -> bool {
+        use ReplayInput::*;
+        match (self, other) {
+            (PortRead { port: a, .. }, PortRead { port: b, .. }) => a == b,
+            (MsrRead { msr: a, .. }, MsrRead { msr: b, .. }) => a == b,
+            (Tsc { .. }, Tsc { .. }) => true,
+            (
+                Cpuid { leaf: a, subleaf: sa, .. },
+                Cpuid { leaf: b, subleaf: sb, .. },
+            ) => a == b && sa == sb,
+            (Interrupt { .. }, Interrupt { .. }) => true,
+            (MmioRead { gpa: a, .. }, MmioRead { gpa: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+
+    fn encode(&self, w: &mut StateWriter) {
+        match *self {
+            Self::PortRead { port, value } => {
+                w.put_u8(INPUT_PORT_READ);
+                w.put_u16(port);
+                w.put_u32(value);
+            }
+            Self::MsrRead { msr, value } => {
+                w.put_u8(INPUT_MSR_READ);
+                w.put_u32(msr);
+                w.put_u64(value);
+            }
+            Self::Tsc { value } => {
+                w.put_u8(INPUT_TSC);
+                w.put_u64(value);
+            }
+            Self::Cpuid { leaf, subleaf, regs } => {
+                w.put_u8(INPUT_CPUID);
+                w.put_u32(leaf);
+                w.put_u32(subleaf);
+                for reg in regs {
+                    w.put_u32(reg);
+                }
+            }
+            Self::Interrupt { vector } => {
+                w.put_u8(INPUT_INTERRUPT);
+                w.put_u8(vector);
+            }
+            Self::MmioRead { gpa, value } => {
+                w.put_u8(INPUT_MMIO_READ);
+                w.put_u64(gpa);
+                w.put_u64(value);
+            }
+        }
+    }
+
+    fn decode(r: &mut StateReader) -> HyperResult<Self> {
+        Ok(match r.get_u8()? {
+            INPUT_PORT_READ => Self::PortRead {
+                port: r.get_u16()?,
+                value: r.get_u32()?,
+            },
+            INPUT_MSR_READ => Self::MsrRead {
+                msr: r.get_u32()?,
+                value: r.get_u64()?,
+            },
+            INPUT_TSC => Self::Tsc {
+                value: r.get_u64()?,
+            },
+            INPUT_CPUID => Self::Cpuid {
+                leaf: r.get_u32()?,
+                subleaf: r.get_u32()?,
+                regs: [r.get_u32()?, r.get_u32()?, r.get_u32()?, r.get_u32()?],
+            },
+            INPUT_INTERRUPT => Self::Interrupt { vector: r.get_u8()? },
+            INPUT_MMIO_READ => Self::MmioRead {
+                gpa: r.get_u64()?,
+                value: r.get_u64()?,
+            },
+            _ => return Err(HyperError::DecodeError),
+        })
+    }
+}
+
+/// Where an input is delivered in the guest execution: after `exits` VM
+/// exits caused by guest instructions and `branches` retired branches, at
+/// `rip` with `rcx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    exits: u64,
+    branches: u64,
+    rip: u64,
+    rcx: u64,
+}
+
+impl Position {
+    /// Whether the guest execution is past this position, at `other`.
+    fn is_before(&self, other: &Self) -> bool {
+        (self.exits, self.branches) < (other.exits, other.branches)
+    }
+}
+
+/// What to do for the next recorded interrupt before a VM entry.
+pub(crate) enum DueInterrupt {
+    /// No interrupt to inject here.
+    None,
+    /// Inject the interrupt now.
+    Inject(u8),
+    /// The interrupt is due later in the current stretch of guest
+    /// instructions, single-step the guest until it reaches the position.
+    Step,
+}
+
+/// Non-deterministic inputs of a vCPU, logged when recording and fed back
+/// when replaying.
+///
+/// Inputs are positioned by the number of branches retired by the guest,
+/// its `RIP` and its `RCX`, which tell apart the executions of an instruction,
+/// including the iterations of `REP` string instructions. The number of VM
+/// exits caused by guest instructions (I/O, `CPUID`, `RDTSC`, MSR accesses,
+/// ...) also only depends on the guest execution: interrupts recorded after
+/// the last such exit are replayed by single-stepping the guest from there to
+/// their position.
+///
+/// Once the guest takes another path than when recording, or the log is used
+/// up, the vCPU goes on with live inputs.
+pub struct ReplayLog {
+    replaying: bool,
+    entries: VecDeque<(Position, ReplayInput)>,
+    exits: u64,
+    branches: u64,
+}
+
+impl ReplayLog {
+    /// Creates an empty log to record inputs into.
+    pub fn recording() -> Self {
+        Self {
+            replaying: false,
+            entries: VecDeque::new(),
+            exits: 0,
+            branches: 0,
+        }
+    }
+
+    /// Loads a log saved by [`ReplayLog::to_bytes`] to replay it.
+    pub fn replaying(data: &[u8]) -> HyperResult<Self> {
+        let mut r = StateReader::new(data);
+        let mut entries = VecDeque::new();
+        for _ in 0..r.get_u64()? {
+            let position = Position {
+                exits: r.get_u64()?,
+                branches: r.get_u64()?,
+                rip: r.get_u64()?,
+                rcx: r.get_u64()?,
+            };
+            entries.push_back((position, ReplayInput::decode(&mut r)?));
+        }
+        if r.remaining() != 0 {
+            return Err(HyperError::DecodeError);
+        }
+        Ok(Self {
+            replaying: true,
+            entries,
+            exits: 0,
+            branches: 0,
+        })
+    }
+
+    /// Encodes the recorded inputs.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.put_u64(self.entries.len() as u64);
+        for (position, input) in self.entries.iter() {
+            w.put_u64(position.exits);
+            w.put_u64(position.branches);
+            w.put_u64(position.rip);
+            w.put_u64(position.rcx);
+            input.encode(&mut w);
+        }
+        w.into_bytes()
+    }
+
+    /// Whether inputs still come from the log.
+    pub fn is_replaying(&self) -> bool {
+        self.replaying
+    }
+
+    /// Number of inputs recorded, or not replayed yet.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the log has no inputs.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn stop_replaying(&mut self, reason: &str) {
+        warn!(
+            "replay stopped after {} exits and {} branches: {}, {} inputs left",
+            self.exits,
+            self.branches,
+            reason,
+            self.entries.len()
+        );
+        self.replaying = false;
+        self.entries.clear();
+    }
+
+    /// Counts the branches retired by the guest before the current VM exit,
+    /// and the VM exit if it's caused by a guest instruction.
+    pub(crate) fn count_exit(&mut self, reason: VmExitReason, branches: u64) {
+        self.branches += branches;
+        use VmExitReason::*;
+        if matches!(
+            reason,
+            CPUID | HLT | RDTSC | RDTSCP | IO_INSTRUCTION | MSR_READ | MSR_WRITE | XSETBV
+                | CR_ACCESS | VMCALL
+        ) {
+            self.exits += 1;
+        }
+    }
+
+    fn position(&self, rip: u64, rcx: u64) -> Position {
+        Position {
+            exits: self.exits,
+            branches: self.branches,
+            rip,
+            rcx,
+        }
+    }
+
+    /// Returns the input at `rip` and `rcx` of the current VM exit: the logged
+    /// one when replaying, or the one given by `live`, which is logged when
+    /// recording.
+    pub(crate) fn input<F>(
+        &mut self,
+        rip: u64,
+        rcx: u64,
+        expected: ReplayInput,
+        live: F,
+    ) -> HyperResult<ReplayInput>
+    where
+        F: FnOnce() -> HyperResult<ReplayInput>,
+    {
+        let position = self.position(rip, rcx);
+        if self.replaying {
+            match self.entries.front() {
+                Some(&(pos, input)) if pos == position && input.same_source(&expected) => {
+                    self.entries.pop_front();
+                    return Ok(input);
+                }
+                Some(_) => self.stop_replaying("guest diverged"),
+                None => self.stop_replaying("end of log"),
+            }
+            return live();
+        }
+        let input = live()?;
+        self.entries.push_back((position, input));
+        Ok(input)
+    }
+
+    /// Logs the interrupt injected at `rip` and `rcx`, when recording.
+    pub(crate) fn record_interrupt(&mut self, rip: u64, rcx: u64, vector: u8) {
+        if !self.replaying {
+            let position = self.position(rip, rcx);
+            self.entries.push_back((position, ReplayInput::Interrupt { vector }));
+        }
+    }
+
+    /// Whether a recorded interrupt is due at the current VM exit, e.g., to
+    /// wake up the halted guest.
+    pub(crate) fn has_due_interrupt(&self) -> bool {
+        self.replaying
+            && matches!(
+                self.entries.front(),
+                Some((pos, ReplayInput::Interrupt { .. })) if pos.exits <= self.exits
+            )
+    }
+
+    /// Takes the next recorded interrupt if the guest has reached its position
+    /// at `rip` and `rcx`.
+    pub(crate) fn due_interrupt(&mut self, rip: u64, rcx: u64) -> DueInterrupt {
+        if !self.replaying {
+            return DueInterrupt::None;
+        }
+        let Some(&(pos, ReplayInput::Interrupt { vector })) = self.entries.front() else {
+            return DueInterrupt::None;
+        };
+        if pos.exits > self.exits {
+            return DueInterrupt::None;
+        }
+        let current = self.position(rip, rcx);
+        if current.is_before(&pos) || (current != pos && !pos.is_before(&current)) {
+            return DueInterrupt::Step;
+        }
+        if current != pos {
+            warn!("replayed interrupt {:#x} late, the guest missed {:#x}", vector, pos.rip);
+        }
+        self.entries.pop_front();
+        DueInterrupt::Inject(vector)
+    }
+}
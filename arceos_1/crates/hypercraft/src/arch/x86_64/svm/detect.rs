@@ -0,0 +1,14 @@
+use raw_cpuid::CpuId;
+
+/// Checks if AMD-V (svm) with nested paging and next-RIP saving is supported
+/// by our hardware.
+pub fn has_hardware_support() -> bool {
+    let cpuid = CpuId::new();
+    let has_svm = cpuid
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |f| f.has_svm());
+    has_svm
+        && cpuid
+            .get_svm_info()
+            .map_or(false, |svm| svm.has_nested_paging() && svm.has_nrip())
+}
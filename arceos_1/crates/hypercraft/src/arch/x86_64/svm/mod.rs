@@ -0,0 +1,10 @@
+mod detect;
+mod percpu;
+mod region;
+mod vcpu;
+mod vmcb;
+
+pub use detect::has_hardware_support;
+pub use percpu::SvmPerCpuState;
+pub use vcpu::SvmVcpu;
+pub(crate) use vcpu::{CORE_REG_FIELDS, SAVED_GUEST_FIELDS};
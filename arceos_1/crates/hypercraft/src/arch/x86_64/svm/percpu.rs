@@ -0,0 +1,71 @@
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+
+use crate::{HyperCraftHal, HyperError, HyperResult};
+use crate::arch::memory::PhysFrame;
+use crate::arch::msr::Msr;
+use super::detect::has_hardware_support;
+
+/// `VM_CR.SVMDIS`: SVM is disabled by the firmware. (APM Vol. 2, Section 15.30.1)
+const VM_CR_SVMDIS: u64 = 1 << 4;
+
+/// State per svm physical cpu.
+pub struct SvmPerCpuState<H: HyperCraftHal> {
+    /// Always 0, there are no VMCB revisions.
+    pub vmcs_revision_id: u32,
+    /// Where VMRUN saves the host state. (APM Vol. 2, Section 15.30.4)
+    hsave_area: PhysFrame<H>,
+}
+
+impl<H: HyperCraftHal> SvmPerCpuState<H> {
+    /// Name of the hardware virtualization extension.
+    pub const EXTENSION: &'static str = "SVM";
+
+    pub const fn new() -> Self {
+        Self {
+            vmcs_revision_id: 0,
+            hsave_area: unsafe { PhysFrame::uninit() },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        Efer::read().contains(EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE)
+    }
+
+    pub fn hardware_enable(&mut self) -> HyperResult {
+        if !has_hardware_support() {
+            return Err(HyperError::NotSupported);
+        }
+        if self.is_enabled() {
+            return Err(HyperError::Disabled);
+        }
+        if Msr::VM_CR.read() & VM_CR_SVMDIS != 0 {
+            return Err(HyperError::NotSupported);
+        }
+        self.hsave_area = PhysFrame::alloc_zero()?;
+
+        unsafe {
+            Msr::VM_HSAVE_PA.write(self.hsave_area.start_paddr() as u64);
+            // Enable XSAVE and XCR0 to switch extended FPU states of guests.
+            if crate::arch::xstate::has_xsave() {
+                Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            }
+            // Enable SVM using the SVME bit.
+            Efer::update(|efer| efer.insert(EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE));
+        }
+        Ok(())
+    }
+
+    pub fn hardware_disable(&mut self) -> HyperResult {
+        if !self.is_enabled() {
+            return Err(HyperError::BadState);
+        }
+
+        unsafe {
+            Efer::update(|efer| efer.remove(EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE));
+            Msr::VM_HSAVE_PA.write(0);
+        }
+        self.hsave_area = unsafe { PhysFrame::uninit() };
+        Ok(())
+    }
+}
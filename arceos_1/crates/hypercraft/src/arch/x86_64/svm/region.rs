@@ -0,0 +1,45 @@
+use crate::arch::memory::ContiguousFrames;
+use crate::{HostPhysAddr, HyperCraftHal, HyperResult};
+
+/// MSR permission map in 8K size. (APM Vol. 2, Section 15.11)
+#[derive(Debug)]
+pub struct MsrPermissionMap<H: HyperCraftHal> {
+    frames: ContiguousFrames<H>,
+}
+
+impl<H: HyperCraftHal> MsrPermissionMap<H> {
+    pub fn passthrough_all() -> HyperResult<Self> {
+        Ok(Self {
+            frames: ContiguousFrames::alloc(2, 0)?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frames.phys_addr()
+    }
+
+    fn set_intercept(&mut self, msr: u32, is_write: bool, intercept: bool) {
+        // Each MSR takes two bits, for reads and writes.
+        let base = match msr {
+            0..=0x1fff => 0,
+            0xc000_0000..=0xc000_1fff => 0x800,
+            0xc001_0000..=0xc001_1fff => 0x1000,
+            _ => unreachable!(),
+        };
+        let bit = (msr & 0x1fff) as usize * 2 + is_write as usize;
+        let byte = &mut self.frames.as_mut_slice()[base + bit / 8];
+        if intercept {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+
+    pub fn set_read_intercept(&mut self, msr: u32, intercept: bool) {
+        self.set_intercept(msr, false, intercept);
+    }
+
+    pub fn set_write_intercept(&mut self, msr: u32, intercept: bool) {
+        self.set_intercept(msr, true, intercept);
+    }
+}
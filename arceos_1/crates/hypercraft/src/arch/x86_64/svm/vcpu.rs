@@ -0,0 +1,1017 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::{Debug, Formatter, Result};
+
+use bit_field::BitField;
+use raw_cpuid::CpuId;
+use x86_64::registers::control::Cr0Flags;
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::rflags::RFlags;
+
+use super::region::MsrPermissionMap;
+use crate::arch::branch_counter;
+use super::vmcb::{
+    event_injection, InterceptMisc1, InterceptMisc2, SvmExitCode, TlbControl, VIntrFlags, Vmcb,
+    VmcbControl16, VmcbControl32, VmcbControl64, VmcbSave16, VmcbSave32, VmcbSave64, VmcbSave8,
+    EXIT_CODE_EXCEPTION_BASE,
+};
+use crate::arch::exit::{VmExitInfo, VmExitReason};
+use crate::arch::guest_memory::{GuestMemoryResult, GuestPagingContext};
+use crate::arch::io_bitmap::IoBitmap;
+use crate::arch::npt::npt_translate;
+use crate::arch::vmx::{
+    VmxCrAccessInfo, VmxCrAccessType, VmxInterruptInfo, VmxInterruptionType, VmxIoExitInfo,
+};
+use crate::arch::{msr::Msr, memory::NestedPageFaultInfo, regs::GeneralRegisters};
+use crate::arch::migration::MigrationHooks;
+use crate::arch::clock::VirtClock;
+use crate::arch::coredump::CoreRegFields;
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// Host time a vCPU runs before an external interrupt is reported as a
+/// preemption timer exit, which SVM does not have.
+const TIME_SLICE_NS: u64 = 10_000_000;
+/// Number of PAUSEs a guest may execute in a spin loop before a VM exit occurs.
+const PAUSE_FILTER_COUNT: u16 = 3000;
+/// ASID shared by all guests, whose TLB entries are flushed on every VMRUN.
+const GUEST_ASID: u32 = 1;
+
+/// `DR6.BS`: the debug exception is caused by single-stepping.
+const DR6_BS: u64 = 1 << 14;
+/// `VM_CR.LOCK` and `VM_CR.SVMDIS` seen by guests, so they never enable SVM.
+const GUEST_VM_CR: u64 = (1 << 3) | (1 << 4);
+
+/// Offsets of the 8-byte words of the VMCB saved in [`VcpuState::guest_fields`].
+/// A word may hold several fields, e.g., the selector, attributes and limit of
+/// a segment. Control registers are saved separately.
+pub(crate) const SAVED_GUEST_FIELDS: &[u32] = &[
+    VmcbSave16::ES_SELECTOR as u32,
+    VmcbSave64::ES_BASE as u32,
+    VmcbSave16::CS_SELECTOR as u32,
+    VmcbSave64::CS_BASE as u32,
+    VmcbSave16::SS_SELECTOR as u32,
+    VmcbSave64::SS_BASE as u32,
+    VmcbSave16::DS_SELECTOR as u32,
+    VmcbSave64::DS_BASE as u32,
+    VmcbSave16::FS_SELECTOR as u32,
+    VmcbSave64::FS_BASE as u32,
+    VmcbSave16::GS_SELECTOR as u32,
+    VmcbSave64::GS_BASE as u32,
+    VmcbSave16::GDTR_SELECTOR as u32,
+    VmcbSave64::GDTR_BASE as u32,
+    VmcbSave16::LDTR_SELECTOR as u32,
+    VmcbSave64::LDTR_BASE as u32,
+    VmcbSave16::IDTR_SELECTOR as u32,
+    VmcbSave64::IDTR_BASE as u32,
+    VmcbSave16::TR_SELECTOR as u32,
+    VmcbSave64::TR_BASE as u32,
+    VmcbSave8::CPL as u32,
+    VmcbSave64::EFER as u32,
+    VmcbSave64::DR7 as u32,
+    VmcbSave64::DR6 as u32,
+    VmcbSave64::RFLAGS as u32,
+    VmcbSave64::RIP as u32,
+    VmcbSave64::RSP as u32,
+    VmcbSave64::STAR as u32,
+    VmcbSave64::LSTAR as u32,
+    VmcbSave64::CSTAR as u32,
+    VmcbSave64::SFMASK as u32,
+    VmcbSave64::KERNEL_GS_BASE as u32,
+    VmcbSave64::SYSENTER_CS as u32,
+    VmcbSave64::SYSENTER_ESP as u32,
+    VmcbSave64::SYSENTER_EIP as u32,
+    VmcbSave64::G_PAT as u32,
+    VmcbSave64::DBGCTL as u32,
+    VmcbControl64::INTERRUPT_SHADOW as u32,
+];
+
+/// Offsets of the VMCB words holding the registers of core files.
+pub(crate) const CORE_REG_FIELDS: CoreRegFields = CoreRegFields {
+    rip: VmcbSave64::RIP as u32,
+    rflags: VmcbSave64::RFLAGS as u32,
+    rsp: VmcbSave64::RSP as u32,
+    cs: VmcbSave16::CS_SELECTOR as u32,
+    ss: VmcbSave16::SS_SELECTOR as u32,
+    ds: VmcbSave16::DS_SELECTOR as u32,
+    es: VmcbSave16::ES_SELECTOR as u32,
+    fs: VmcbSave16::FS_SELECTOR as u32,
+    gs: VmcbSave16::GS_SELECTOR as u32,
+    fs_base: VmcbSave64::FS_BASE as u32,
+    gs_base: VmcbSave64::GS_BASE as u32,
+};
+
+
+/// The SVM part of a [`VCpu`](crate::arch::VCpu): the guest state in the
+/// VMCB, and the guest registers it does not hold.
+pub struct SvmVcpu<H: HyperCraftHal> {
+    guest_regs: GeneralRegisters,
+    vmcb: Vmcb<H>,
+    /// Host states not switched by VMRUN (e.g., `FS`, `GS` and `TR`), which
+    /// are saved and loaded with VMSAVE and VMLOAD.
+    host_vmcb: Vmcb<H>,
+    msr_pm: MsrPermissionMap<H>,
+    /// I/O permission map, shared by all vCPUs of the VM.
+    io_bitmap: Arc<IoBitmap<H>>,
+    rdtsc_exiting: bool,
+    /// The event whose delivery was interrupted by the last VM exit, to be
+    /// injected again before any other.
+    interrupted_event: Option<u64>,
+    /// Exceptions intercepted by [`SvmVcpu::set_exception_exiting`].
+    exception_bitmap: u32,
+    /// Whether the guest is single-stepped by [`SvmVcpu::set_monitor_trap`].
+    monitor_trap: bool,
+    /// Whether the guest `RFLAGS.TF` is set by us for single-stepping.
+    trap_flag_set: bool,
+    /// The reason of the last VM exit, `None` if the exit code is unknown.
+    exit_reason: Option<VmExitReason>,
+    exit_code: u64,
+    exit_instruction_length: u32,
+    /// The qualification VMX would report for the last VM exit.
+    exit_qualification: usize,
+    exit_rip: usize,
+    entry_failure: bool,
+    /// Host time the vCPU got the physical CPU.
+    slice_start_ns: u64,
+}
+
+impl<H: HyperCraftHal> SvmVcpu<H> {
+    pub(crate) fn new(
+        entry: GuestPhysAddr,
+        npt_root: HostPhysAddr,
+        clock: &VirtClock<H>,
+        io_bitmap: Arc<IoBitmap<H>>,
+    ) -> HyperResult<Self> {
+        let mut vcpu = Self {
+            guest_regs: GeneralRegisters::default(),
+            vmcb: Vmcb::new()?,
+            host_vmcb: Vmcb::new()?,
+            msr_pm: MsrPermissionMap::passthrough_all()?,
+            io_bitmap,
+            rdtsc_exiting: false,
+            interrupted_event: None,
+            exception_bitmap: 0,
+            monitor_trap: false,
+            trap_flag_set: false,
+            exit_reason: None,
+            exit_code: 0,
+            exit_instruction_length: 0,
+            exit_qualification: 0,
+            exit_rip: 0,
+            entry_failure: false,
+            slice_start_ns: 0,
+        };
+        vcpu.setup_msr_permission_map();
+        vcpu.setup_vmcb_guest(entry)?;
+        vcpu.setup_vmcb_control(npt_root, clock);
+        info!("[HV] created SvmVcpu(vmcb: {:#x})", vcpu.vmcb.phys_addr());
+        Ok(vcpu)
+    }
+
+    /// Starts the first time slice of [`VCpu::run`](crate::arch::VCpu::run).
+    pub(crate) fn start(&mut self) -> HyperResult {
+        self.slice_start_ns = H::current_time_nanos();
+        Ok(())
+    }
+
+    /// Loads the guest `RAX`, which VMRUN takes from the VMCB, and sets the
+    /// trap flag again while single-stepping, right before a VM entry.
+    pub(crate) fn prepare_entry(&mut self) {
+        VmcbSave64::RAX.write(&mut self.vmcb, self.guest_regs.rax);
+        self.update_trap_flag();
+    }
+
+    /// Enters the guest, and returns at the next #VMEXIT.
+    pub(crate) unsafe fn enter(&mut self) {
+        svm_run(
+            &mut self.guest_regs,
+            self.vmcb.phys_addr(),
+            self.host_vmcb.phys_addr(),
+        )
+    }
+
+    /// Saves the guest `RAX` and the interrupted event, and decodes the
+    /// #VMEXIT right after it.
+    pub(crate) fn exited(&mut self) {
+        self.guest_regs.rax = VmcbSave64::RAX.read(&self.vmcb);
+        let int_info = VmcbControl64::EXIT_INT_INFO.read(&self.vmcb);
+        self.interrupted_event = int_info.get_bit(31).then_some(int_info);
+        VmcbControl64::EVENT_INJ.write(&mut self.vmcb, 0);
+        self.decode_exit();
+    }
+
+    /// Basic information about VM exits. Returns
+    /// [`HyperError::NotSupported`] for unknown exit codes.
+    pub(crate) fn exit_info(&self) -> HyperResult<VmExitInfo> {
+        let exit_reason = self.exit_reason.ok_or(HyperError::NotSupported)?;
+        Ok(VmExitInfo {
+            entry_failure: self.entry_failure,
+            exit_reason,
+            hw_reason: self.exit_code,
+            exit_instruction_length: self.exit_instruction_length,
+            guest_rip: self.exit_rip,
+        })
+    }
+
+    /// Information for VM exits due to intercepted exceptions. External
+    /// interrupts are taken by the host itself, so the information is never
+    /// valid for them.
+    pub(crate) fn interrupt_exit_info(&self) -> HyperResult<VmxInterruptInfo> {
+        let vector = self.exit_code.wrapping_sub(EXIT_CODE_EXCEPTION_BASE);
+        if vector >= 32 {
+            return Ok(VmxInterruptInfo {
+                vector: 0,
+                int_type: VmxInterruptionType::External,
+                err_code: None,
+                valid: false,
+            });
+        }
+        let vector = vector as u8;
+        let err_code = VmxInterruptionType::vector_has_error_code(vector)
+            .then(|| VmcbControl64::EXIT_INFO1.read(&self.vmcb) as u32);
+        Ok(VmxInterruptInfo::from(vector, err_code))
+    }
+
+    /// Information for VM exits due to I/O instructions.
+    pub(crate) fn io_exit_info(&self) -> HyperResult<VmxIoExitInfo> {
+        // APM Vol. 2, Section 15.10.2, Figure 15-2
+        let info = VmcbControl64::EXIT_INFO1.read(&self.vmcb);
+        Ok(VmxIoExitInfo {
+            access_size: info.get_bits(4..7) as u8,
+            is_in: info.get_bit(0),
+            is_string: info.get_bit(2),
+            is_repeat: info.get_bit(3),
+            port: info.get_bits(16..32) as u16,
+        })
+    }
+
+    /// Information for VM exits due to nested page table faults.
+    pub(crate) fn nested_page_fault_info(&self) -> HyperResult<NestedPageFaultInfo> {
+        use page_table::MappingFlags;
+        // APM Vol. 2, Section 15.25.6
+        let info = VmcbControl64::EXIT_INFO1.read(&self.vmcb);
+        let access_flags = if info.get_bit(4) {
+            MappingFlags::EXECUTE
+        } else if info.get_bit(1) {
+            MappingFlags::WRITE
+        } else {
+            MappingFlags::READ
+        };
+        Ok(NestedPageFaultInfo {
+            access_flags,
+            fault_guest_paddr: VmcbControl64::EXIT_INFO2.read(&self.vmcb) as usize,
+        })
+    }
+
+    /// Exit qualification of the last VM exit, converted to what VMX would
+    /// report for the same exit reason. (SDM Vol. 3C, Section 27.2.1)
+    pub(crate) fn exit_qualification(&self) -> HyperResult<usize> {
+        Ok(self.exit_qualification)
+    }
+
+    /// The MOV to `CR3` of the last VM exit, as VMX reports it. The decode
+    /// assists give the source register.
+    pub(crate) fn cr_access_info(&self) -> HyperResult<VmxCrAccessInfo> {
+        let info = VmcbControl64::EXIT_INFO1.read(&self.vmcb);
+        if !info.get_bit(63) {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(VmxCrAccessInfo {
+            cr_number: 3,
+            access_type: VmxCrAccessType::MovToCr,
+            lmsw_memory_operand: false,
+            gpr: info.get_bits(0..4) as u8,
+            lmsw_source_data: 0,
+        })
+    }
+
+    pub(crate) fn regs(&self) -> &GeneralRegisters {
+        &self.guest_regs
+    }
+
+    pub(crate) fn regs_mut(&mut self) -> &mut GeneralRegisters {
+        &mut self.guest_regs
+    }
+
+    pub(crate) fn cr(&self, cr_idx: usize) -> usize {
+        (match cr_idx {
+            0 => VmcbSave64::CR0.read(&self.vmcb),
+            3 => VmcbSave64::CR3.read(&self.vmcb),
+            4 => VmcbSave64::CR4.read(&self.vmcb),
+            _ => unreachable!(),
+        }) as usize
+    }
+
+    pub(crate) fn set_cr(&mut self, cr_idx: usize, val: u64) -> HyperResult {
+        match cr_idx {
+            0 => {
+                VmcbSave64::CR0.write(&mut self.vmcb, val);
+                self.update_long_mode(val & Cr0Flags::PAGING.bits() != 0);
+            }
+            3 => VmcbSave64::CR3.write(&mut self.vmcb, val),
+            4 => VmcbSave64::CR4.write(&mut self.vmcb, val),
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    /// Loads `value` into `CR3` for the guest, the only control register
+    /// whose loads are intercepted. The processor checks the others itself.
+    pub(crate) fn emulate_cr_load(&mut self, cr_idx: usize, value: u64) -> HyperResult<bool> {
+        self.set_cr(cr_idx, value)?;
+        Ok(true)
+    }
+
+    /// CR3 load exiting needs the decode assists to find the source register.
+    pub(crate) fn set_cr3_load_exiting(&mut self, enable: bool) -> HyperResult {
+        let decode_assists = CpuId::new()
+            .get_svm_info()
+            .map_or(false, |svm| svm.has_decode_assists());
+        if enable && !decode_assists {
+            return Err(HyperError::NotSupported);
+        }
+        let mut intercepts = VmcbControl32::INTERCEPT_CR.read(&self.vmcb);
+        intercepts.set_bit(16 + 3, enable);
+        VmcbControl32::INTERCEPT_CR.write(&mut self.vmcb, intercepts);
+        Ok(())
+    }
+
+    pub(crate) fn stack_pointer(&self) -> usize {
+        VmcbSave64::RSP.read(&self.vmcb) as usize
+    }
+
+    pub(crate) fn set_stack_pointer(&mut self, rsp: usize) {
+        VmcbSave64::RSP.write(&mut self.vmcb, rsp as u64)
+    }
+
+    pub(crate) fn rip(&self) -> usize {
+        VmcbSave64::RIP.read(&self.vmcb) as usize
+    }
+
+    pub(crate) fn set_rip(&mut self, rip: usize) -> HyperResult {
+        VmcbSave64::RIP.write(&mut self.vmcb, rip as u64);
+        Ok(())
+    }
+
+    /// Guest `RFLAGS`, without the trap flag set for single-stepping.
+    pub(crate) fn rflags(&self) -> usize {
+        let rflags = VmcbSave64::RFLAGS.read(&self.vmcb);
+        if self.trap_flag_set {
+            (rflags & !RFlags::TRAP_FLAG.bits()) as usize
+        } else {
+            rflags as usize
+        }
+    }
+
+    pub(crate) fn set_rflags(&mut self, rflags: usize) -> HyperResult {
+        let mut rflags = rflags as u64;
+        if self.trap_flag_set {
+            rflags |= RFlags::TRAP_FLAG.bits();
+        }
+        VmcbSave64::RFLAGS.write(&mut self.vmcb, rflags);
+        Ok(())
+    }
+
+    pub(crate) fn segment_selectors(&self) -> HyperResult<[u16; 6]> {
+        Ok([
+            VmcbSave16::CS_SELECTOR.read(&self.vmcb),
+            VmcbSave16::SS_SELECTOR.read(&self.vmcb),
+            VmcbSave16::DS_SELECTOR.read(&self.vmcb),
+            VmcbSave16::ES_SELECTOR.read(&self.vmcb),
+            VmcbSave16::FS_SELECTOR.read(&self.vmcb),
+            VmcbSave16::GS_SELECTOR.read(&self.vmcb),
+        ])
+    }
+
+    pub(crate) fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
+        let rip = VmcbSave64::RIP.read(&self.vmcb);
+        VmcbSave64::RIP.write(&mut self.vmcb, rip + instr_len as u64);
+        Ok(())
+    }
+
+    pub(crate) fn cr2(&self) -> u64 {
+        VmcbSave64::CR2.read(&self.vmcb)
+    }
+
+    pub(crate) fn set_cr2(&mut self, cr2: u64) {
+        VmcbSave64::CR2.write(&mut self.vmcb, cr2);
+    }
+
+    pub(crate) fn dr7(&self) -> HyperResult<usize> {
+        Ok(VmcbSave64::DR7.read(&self.vmcb) as usize)
+    }
+
+    pub(crate) fn set_dr7(&mut self, dr7: usize) -> HyperResult {
+        VmcbSave64::DR7.write(&mut self.vmcb, dr7 as u64);
+        Ok(())
+    }
+
+    /// If enabled, a VM exit occurs once the guest can take interrupts, with
+    /// a virtual interrupt pending. (APM Vol. 2, Section 15.21.4)
+    pub(crate) fn set_interrupt_window(&mut self, enable: bool) -> HyperResult {
+        let mut v_intr = VIntrFlags::from_bits_truncate(VmcbControl64::V_INTR.read(&self.vmcb));
+        v_intr.set(VIntrFlags::V_IRQ | VIntrFlags::V_IGN_TPR, enable);
+        VmcbControl64::V_INTR.write(&mut self.vmcb, v_intr.bits());
+        let mut intercepts =
+            InterceptMisc1::from_bits_truncate(VmcbControl32::INTERCEPT_MISC1.read(&self.vmcb));
+        intercepts.set(InterceptMisc1::VINTR, enable);
+        VmcbControl32::INTERCEPT_MISC1.write(&mut self.vmcb, intercepts.bits());
+        Ok(())
+    }
+
+    /// Single-steps the guest with `RFLAGS.TF` and intercepts the debug
+    /// exceptions, which are reported as monitor trap flag exits.
+    pub(crate) fn set_monitor_trap(&mut self, enable: bool) -> HyperResult {
+        self.monitor_trap = enable;
+        if !enable && self.trap_flag_set {
+            let rflags = VmcbSave64::RFLAGS.read(&self.vmcb);
+            VmcbSave64::RFLAGS.write(&mut self.vmcb, rflags & !RFlags::TRAP_FLAG.bits());
+            self.trap_flag_set = false;
+        }
+        self.update_trap_flag();
+        self.update_exception_intercepts();
+        Ok(())
+    }
+
+    pub(crate) fn set_exception_exiting(&mut self, vector: u8, enable: bool) -> HyperResult {
+        self.exception_bitmap.set_bit(vector as usize, enable);
+        self.update_exception_intercepts();
+        Ok(())
+    }
+
+    /// Whether guest exceptions of `vector` cause VM exits, other than for
+    /// single-stepping.
+    pub(crate) fn exception_exiting(&self, vector: u8) -> bool {
+        vector < 32 && self.exception_bitmap.get_bit(vector as usize)
+    }
+
+    /// Makes `RDTSC` and `RDTSCP` cause VM exits, if they don't already.
+    pub(crate) fn enable_rdtsc_exiting(&mut self) -> HyperResult {
+        if !self.rdtsc_exiting {
+            let misc1 = VmcbControl32::INTERCEPT_MISC1.read(&self.vmcb);
+            let misc2 = VmcbControl32::INTERCEPT_MISC2.read(&self.vmcb);
+            VmcbControl32::INTERCEPT_MISC1
+                .write(&mut self.vmcb, misc1 | InterceptMisc1::RDTSC.bits());
+            VmcbControl32::INTERCEPT_MISC2
+                .write(&mut self.vmcb, misc2 | InterceptMisc2::RDTSCP.bits());
+            self.rdtsc_exiting = true;
+        }
+        Ok(())
+    }
+
+    /// Hides the branch counter from the guest. The counter only counts in
+    /// the guest, as it's configured with the guest-only bit.
+    pub(crate) fn enable_branch_counting(&mut self) -> HyperResult {
+        for msr in branch_counter::counter_msrs() {
+            self.msr_pm.set_read_intercept(msr, true);
+            self.msr_pm.set_write_intercept(msr, true);
+        }
+        Ok(())
+    }
+
+    /// Reads the MSRs which reveal SVM: `EFER.SVME` is always set for VMRUN
+    /// but hidden from the guest, which can not enable SVM. Returns `None`
+    /// for other MSRs.
+    pub(crate) fn read_hidden_msr(&self, msr: u32) -> Option<u64> {
+        if msr == Msr::IA32_EFER as u32 {
+            let svme = EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits();
+            Some(VmcbSave64::EFER.read(&self.vmcb) & !svme)
+        } else if msr == Msr::VM_CR as u32 {
+            Some(GUEST_VM_CR)
+        } else if msr == Msr::VM_HSAVE_PA as u32 {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Writes the MSRs which reveal SVM, and returns whether the write is
+    /// valid, or `None` for other MSRs.
+    pub(crate) fn write_hidden_msr(&mut self, msr: u32, value: u64) -> Option<bool> {
+        if msr == Msr::IA32_EFER as u32 {
+            let svme = EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits();
+            if value & svme != 0 {
+                return Some(false);
+            }
+            // EFER.LMA is read-only.
+            let efer = VmcbSave64::EFER.read(&self.vmcb);
+            let lma = EferFlags::LONG_MODE_ACTIVE.bits();
+            VmcbSave64::EFER.write(&mut self.vmcb, (value & !lma) | (efer & lma) | svme);
+            Some(true)
+        } else if msr == Msr::VM_CR as u32 || msr == Msr::VM_HSAVE_PA as u32 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Refresh the TSC offset after the virtual clock has been restarted.
+    pub(crate) fn set_tsc_offset(&mut self, clock: &VirtClock<H>) -> HyperResult {
+        if !self.rdtsc_exiting {
+            VmcbControl64::TSC_OFFSET.write(&mut self.vmcb, clock.tsc_offset(false));
+        }
+        Ok(())
+    }
+
+    /// Whether the guest interrupts are blocked.
+    pub(crate) fn allow_interrupt(&self) -> bool {
+        let rflags = VmcbSave64::RFLAGS.read(&self.vmcb);
+        let shadow = VmcbControl64::INTERRUPT_SHADOW.read(&self.vmcb);
+        rflags & RFlags::INTERRUPT_FLAG.bits() != 0 && !shadow.get_bit(0)
+    }
+
+    /// Injects the event whose delivery was interrupted by the last VM exit,
+    /// if any, which goes before any other. Returns whether there is one.
+    pub(crate) fn reinject_interrupted_event(&mut self) -> bool {
+        match self.interrupted_event.take() {
+            Some(event) => {
+                VmcbControl64::EVENT_INJ.write(&mut self.vmcb, event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Injects the event at the next VM entry, and loads `cr2` for a page
+    /// fault.
+    pub(crate) fn inject(&mut self, vector: u8, err_code: Option<u32>, cr2: Option<usize>) -> HyperResult {
+        if let Some(cr2) = cr2 {
+            VmcbSave64::CR2.write(&mut self.vmcb, cr2 as u64);
+        }
+        let int_type = VmxInterruptionType::from_vector(vector);
+        let event_type = match int_type {
+            VmxInterruptionType::External => 0,
+            VmxInterruptionType::NMI => 2,
+            _ => 3,
+        };
+        if int_type == VmxInterruptionType::SoftException {
+            // Injected exceptions are not traps, so the guest returns
+            // after the one-byte INT3 or INTO instead of running it again.
+            self.advance_rip(1)?;
+        }
+        let err_code = VmxInterruptionType::vector_has_error_code(vector)
+            .then(|| err_code.unwrap_or(0));
+        let event = event_injection(vector, event_type, err_code);
+        VmcbControl64::EVENT_INJ.write(&mut self.vmcb, event);
+        Ok(())
+    }
+
+    /// Reads the VMCB words of [`SAVED_GUEST_FIELDS`].
+    pub(crate) fn save_guest_fields(&self) -> HyperResult<Vec<(u32, u64)>> {
+        Ok(SAVED_GUEST_FIELDS
+            .iter()
+            .map(|&offset| (offset, self.vmcb.read_word(offset)))
+            .collect())
+    }
+
+    /// Writes the VMCB words saved by [`SvmVcpu::save_guest_fields`].
+    pub(crate) fn restore_guest_fields(&mut self, guest_fields: &[(u32, u64)]) -> HyperResult {
+        for &(offset, value) in guest_fields {
+            if !SAVED_GUEST_FIELDS.contains(&offset) {
+                return Err(HyperError::InvalidParam);
+            }
+            self.vmcb.write_word(offset, value);
+        }
+        // VMRUN fails if the guest EFER.SVME is cleared.
+        let efer = VmcbSave64::EFER.read(&self.vmcb);
+        VmcbSave64::EFER.write(
+            &mut self.vmcb,
+            efer | EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits(),
+        );
+        self.interrupted_event = None;
+        Ok(())
+    }
+
+    /// There is no current VMCB to load with SVM, only a new time slice to
+    /// start.
+    pub(crate) fn load(&mut self) -> HyperResult {
+        self.slice_start_ns = H::current_time_nanos();
+        Ok(())
+    }
+
+    /// None are needed with SVM: `VMRUN` saves the host states into the
+    /// host save area of the current CPU, and the VMCB is not cached by it.
+    pub(crate) fn migration_hooks(&self) -> MigrationHooks {
+        MigrationHooks::NONE
+    }
+
+    pub(crate) fn paging_context(&self) -> HyperResult<GuestPagingContext> {
+        Ok(GuestPagingContext {
+            cr0: self.cr(0),
+            cr3: self.cr(3),
+            cr4: self.cr(4),
+            efer: VmcbSave64::EFER.read(&self.vmcb),
+            user: VmcbSave8::CPL.read(&self.vmcb) == 3,
+            rflags: self.rflags(),
+            debugger: false,
+        })
+    }
+
+    pub(crate) fn nested_translate(
+        &self,
+        gpa: GuestPhysAddr,
+        write: bool,
+    ) -> GuestMemoryResult<HostPhysAddr> {
+        npt_translate::<H>(VmcbControl64::N_CR3.read(&self.vmcb), gpa, write)
+    }
+
+    pub(crate) fn linear_rip(&self) -> HyperResult<usize> {
+        Ok((VmcbSave64::CS_BASE.read(&self.vmcb) + VmcbSave64::RIP.read(&self.vmcb)) as usize)
+    }
+}
+
+// Implementation of private methods
+impl<H: HyperCraftHal> SvmVcpu<H> {
+    fn setup_msr_permission_map(&mut self) {
+        // Intercept IA32_APIC_BASE MSR accesses
+        let msr = x86::msr::IA32_APIC_BASE;
+        self.msr_pm.set_read_intercept(msr, true);
+        self.msr_pm.set_write_intercept(msr, true);
+        // Intercept IA32_TSC_DEADLINE MSR accesses
+        let msr = x86::msr::IA32_TSC_DEADLINE;
+        self.msr_pm.set_read_intercept(msr, true);
+        self.msr_pm.set_write_intercept(msr, true);
+        // Intercept all x2APIC MSR accesses
+        for msr in 0x800..=0x83f {
+            self.msr_pm.set_read_intercept(msr, true);
+            self.msr_pm.set_write_intercept(msr, true);
+        }
+        // Hide SVM from guests.
+        for msr in [Msr::IA32_EFER, Msr::VM_CR, Msr::VM_HSAVE_PA] {
+            self.msr_pm.set_read_intercept(msr as u32, true);
+            self.msr_pm.set_write_intercept(msr as u32, true);
+        }
+    }
+
+    fn setup_vmcb_guest(&mut self, entry: GuestPhysAddr) -> HyperResult {
+        macro_rules! set_guest_segment {
+            ($seg: ident, $attrib: expr) => {{
+                concat_idents!($seg, _SELECTOR).write(&mut self.vmcb, 0);
+                concat_idents!($seg, _ATTRIB).write(&mut self.vmcb, $attrib);
+                concat_idents!($seg, _LIMIT).write(&mut self.vmcb, 0xffff);
+                concat_idents!($seg, _BASE).write(&mut self.vmcb, 0);
+            }};
+        }
+
+        {
+            use VmcbSave16::*;
+            use VmcbSave32::*;
+            use VmcbSave64::*;
+            set_guest_segment!(ES, 0x93); // 16-bit, present, data, read/write, accessed
+            set_guest_segment!(CS, 0x9b); // 16-bit, present, code, exec/read, accessed
+            set_guest_segment!(SS, 0x93);
+            set_guest_segment!(DS, 0x93);
+            set_guest_segment!(FS, 0x93);
+            set_guest_segment!(GS, 0x93);
+            set_guest_segment!(TR, 0x8b); // present, system, 32-bit TSS busy
+            set_guest_segment!(LDTR, 0x82); // present, system, LDT
+        }
+
+        VmcbSave64::GDTR_BASE.write(&mut self.vmcb, 0);
+        VmcbSave32::GDTR_LIMIT.write(&mut self.vmcb, 0xffff);
+        VmcbSave64::IDTR_BASE.write(&mut self.vmcb, 0);
+        VmcbSave32::IDTR_LIMIT.write(&mut self.vmcb, 0xffff);
+        VmcbSave8::CPL.write(&mut self.vmcb, 0);
+
+        VmcbSave64::DR6.write(&mut self.vmcb, 0xffff_0ff0);
+        VmcbSave64::DR7.write(&mut self.vmcb, 0x400);
+        VmcbSave64::RSP.write(&mut self.vmcb, 0);
+        VmcbSave64::RIP.write(&mut self.vmcb, entry as u64);
+        VmcbSave64::RFLAGS.write(&mut self.vmcb, 0x2);
+        VmcbSave64::SYSENTER_CS.write(&mut self.vmcb, 0);
+        VmcbSave64::SYSENTER_ESP.write(&mut self.vmcb, 0);
+        VmcbSave64::SYSENTER_EIP.write(&mut self.vmcb, 0);
+        VmcbSave64::DBGCTL.write(&mut self.vmcb, 0);
+        VmcbSave64::G_PAT.write(&mut self.vmcb, Msr::IA32_PAT.read());
+        VmcbSave64::EFER.write(
+            &mut self.vmcb,
+            EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits(),
+        );
+
+        self.set_cr(0, (Cr0Flags::EXTENSION_TYPE | Cr0Flags::NUMERIC_ERROR).bits())?;
+        self.set_cr(3, 0)?;
+        self.set_cr(4, 0)?;
+        Ok(())
+    }
+
+    fn setup_vmcb_control(&mut self, npt_root: HostPhysAddr, clock: &VirtClock<H>) {
+        let svm_info = CpuId::new().get_svm_info();
+        let has_pause_filter = svm_info.as_ref().map_or(false, |svm| svm.has_pause_filter());
+        let has_flush_by_asid = svm_info.as_ref().map_or(false, |svm| svm.has_flush_by_asid());
+
+        // The TSC ratio MSR is shared by all guests on the CPU, so RDTSC(P) is
+        // emulated if the guest TSC frequency differs.
+        self.rdtsc_exiting = clock.need_scaling();
+
+        // Intercept physical interrupts, NMIs, CPUID, HLT, I/O ports in the bitmap,
+        // MSRs in the permission map, shutdowns and the SVM instructions.
+        let mut misc1 = InterceptMisc1::INTR
+            | InterceptMisc1::NMI
+            | InterceptMisc1::CPUID
+            | InterceptMisc1::HLT
+            | InterceptMisc1::IOIO_PROT
+            | InterceptMisc1::MSR_PROT
+            | InterceptMisc1::SHUTDOWN;
+        let mut misc2 = InterceptMisc2::VMRUN
+            | InterceptMisc2::VMMCALL
+            | InterceptMisc2::VMLOAD
+            | InterceptMisc2::VMSAVE
+            | InterceptMisc2::STGI
+            | InterceptMisc2::CLGI
+            | InterceptMisc2::SKINIT
+            | InterceptMisc2::XSETBV;
+        if self.rdtsc_exiting {
+            misc1 |= InterceptMisc1::RDTSC;
+            misc2 |= InterceptMisc2::RDTSCP;
+        } else {
+            VmcbControl64::TSC_OFFSET.write(&mut self.vmcb, clock.tsc_offset(false));
+        }
+        if has_pause_filter {
+            misc1 |= InterceptMisc1::PAUSE;
+            VmcbControl16::PAUSE_FILTER_COUNT.write(&mut self.vmcb, PAUSE_FILTER_COUNT);
+        }
+        VmcbControl32::INTERCEPT_CR.write(&mut self.vmcb, 0);
+        VmcbControl32::INTERCEPT_DR.write(&mut self.vmcb, 0);
+        VmcbControl32::INTERCEPT_EXCEPTIONS.write(&mut self.vmcb, 0);
+        VmcbControl32::INTERCEPT_MISC1.write(&mut self.vmcb, misc1.bits());
+        VmcbControl32::INTERCEPT_MISC2.write(&mut self.vmcb, misc2.bits());
+
+        VmcbControl64::IOPM_BASE_PA.write(&mut self.vmcb, self.io_bitmap.phys_addr() as u64);
+        VmcbControl64::MSRPM_BASE_PA.write(&mut self.vmcb, self.msr_pm.phys_addr() as u64);
+
+        // All guests share one ASID, as the nested page tables of the VMs
+        // differ anyway, and flush their TLB entries on every VMRUN.
+        VmcbControl32::GUEST_ASID.write(&mut self.vmcb, GUEST_ASID);
+        let tlb_control = if has_flush_by_asid {
+            TlbControl::FlushGuest
+        } else {
+            TlbControl::FlushAll
+        };
+        VmcbControl32::TLB_CONTROL.write(&mut self.vmcb, tlb_control as u32);
+
+        VmcbControl64::V_INTR.write(&mut self.vmcb, VIntrFlags::V_INTR_MASKING.bits());
+        VmcbControl64::NP_ENABLE.write(&mut self.vmcb, 1);
+        VmcbControl64::N_CR3.write(&mut self.vmcb, npt_root as u64);
+    }
+
+
+    /// Set `EFER.LMA` when the guest enables paging with `EFER.LME` set, and
+    /// clear it when it disables paging. The processor does it on guest
+    /// writes to `CR0`, but not on ours.
+    fn update_long_mode(&mut self, paging: bool) {
+        let efer = VmcbSave64::EFER.read(&self.vmcb);
+        let lme = efer & EferFlags::LONG_MODE_ENABLE.bits() != 0;
+        let lma = EferFlags::LONG_MODE_ACTIVE.bits();
+        VmcbSave64::EFER.write(
+            &mut self.vmcb,
+            if lme && paging { efer | lma } else { efer & !lma },
+        );
+    }
+
+    /// Intercept the exceptions asked by [`SvmVcpu::set_exception_exiting`],
+    /// and `#DB` for single-stepping.
+    fn update_exception_intercepts(&mut self) {
+        let mut bitmap = self.exception_bitmap;
+        if self.monitor_trap {
+            bitmap.set_bit(x86::irq::DEBUG_VECTOR as usize, true);
+        }
+        VmcbControl32::INTERCEPT_EXCEPTIONS.write(&mut self.vmcb, bitmap);
+    }
+
+    /// Set the guest `RFLAGS.TF` again while single-stepping, in case the
+    /// guest cleared it (e.g., by IRET).
+    fn update_trap_flag(&mut self) {
+        let rflags = VmcbSave64::RFLAGS.read(&self.vmcb);
+        if self.monitor_trap && rflags & RFlags::TRAP_FLAG.bits() == 0 {
+            VmcbSave64::RFLAGS.write(&mut self.vmcb, rflags | RFlags::TRAP_FLAG.bits());
+            self.trap_flag_set = true;
+        }
+    }
+
+    /// Converts the #VMEXIT to the exit reason, and the qualification and
+    /// instruction length VMX would report. (APM Vol. 2, Appendix C)
+    ///
+    /// Physical interrupts are taken by the host here, as SVM does not
+    /// acknowledge them on VM exits. Once the time slice of the vCPU is used
+    /// up, the exit is reported as a preemption timer exit instead, so the
+    /// vCPU yields.
+    fn decode_exit(&mut self) {
+        let code = VmcbControl64::EXIT_CODE.read(&self.vmcb);
+        let info1 = VmcbControl64::EXIT_INFO1.read(&self.vmcb);
+        let info2 = VmcbControl64::EXIT_INFO2.read(&self.vmcb);
+        let rip = VmcbSave64::RIP.read(&self.vmcb);
+        let next_rip = VmcbControl64::NEXT_RIP.read(&self.vmcb);
+        let instr_len = next_rip.wrapping_sub(rip) as u32;
+        self.exit_code = code;
+        self.exit_rip = rip as usize;
+        self.entry_failure = false;
+        self.exit_qualification = info1 as usize;
+        self.exit_instruction_length = 0;
+
+        let vector = code.wrapping_sub(EXIT_CODE_EXCEPTION_BASE);
+        if vector < 32 {
+            let dr6 = VmcbSave64::DR6.read(&self.vmcb);
+            self.exit_reason = Some(VmExitReason::EXCEPTION_NMI);
+            if vector == x86::irq::DEBUG_VECTOR as u64 {
+                self.exit_qualification = dr6 as usize;
+                if self.monitor_trap && dr6 & DR6_BS != 0 {
+                    // The guest never sees the debug exception.
+                    VmcbSave64::DR6.write(&mut self.vmcb, dr6 & !DR6_BS);
+                    self.exit_reason = Some(VmExitReason::MONITOR_TRAP_FLAG);
+                    self.exit_qualification = 0;
+                }
+            } else if vector == x86::irq::PAGE_FAULT_VECTOR as u64 {
+                self.exit_qualification = info2 as usize;
+            }
+            return;
+        }
+
+        let Ok(code) = SvmExitCode::try_from(code as u32) else {
+            warn!("Unknown #VMEXIT code {:#x}", code);
+            self.exit_reason = None;
+            return;
+        };
+        use SvmExitCode as Svm;
+        use VmExitReason as Exit;
+        let exit_reason = match code {
+            Svm::CR3_WRITE => {
+                // SDM Vol. 3C, Section 27.2.1, Table 27-3
+                self.exit_qualification = 3 | (info1.get_bits(0..4) as usize) << 8;
+                self.exit_instruction_length = instr_len;
+                Exit::CR_ACCESS
+            }
+            Svm::INTR | Svm::NMI | Svm::SMI => {
+                unsafe { asm!("sti", "nop", "cli") };
+                if H::current_time_nanos().saturating_sub(self.slice_start_ns) >= TIME_SLICE_NS {
+                    Exit::PREEMPTION_TIMER
+                } else {
+                    Exit::EXTERNAL_INTERRUPT
+                }
+            }
+            Svm::INIT => Exit::INIT,
+            Svm::VINTR => Exit::INTERRUPT_WINDOW,
+            Svm::SHUTDOWN => Exit::TRIPLE_FAULT,
+            Svm::IOIO => {
+                // SDM Vol. 3C, Section 27.2.1, Table 27-5
+                let mut qualification = info1.get_bits(16..32) << 16;
+                // The SZ8, SZ16 and SZ32 bits become the size minus one.
+                qualification |= match info1.get_bits(4..7) {
+                    0b001 => 0,
+                    0b010 => 1,
+                    0b100 => 3,
+                    sz => {
+                        warn!("Invalid IOIO access size bits {:#b}", sz);
+                        0
+                    }
+                };
+                qualification.set_bit(3, info1.get_bit(0));
+                qualification.set_bit(4, info1.get_bit(2));
+                qualification.set_bit(5, info1.get_bit(3));
+                self.exit_qualification = qualification as usize;
+                // EXITINFO2 holds the next RIP.
+                self.exit_instruction_length = info2.wrapping_sub(rip) as u32;
+                Exit::IO_INSTRUCTION
+            }
+            Svm::NPF => {
+                // SDM Vol. 3C, Section 27.2.1, Table 27-7
+                let mut qualification = 0;
+                qualification.set_bit(1, info1.get_bit(1));
+                qualification.set_bit(2, info1.get_bit(4));
+                qualification.set_bit(0, !info1.get_bit(1) && !info1.get_bit(4));
+                self.exit_qualification = qualification;
+                Exit::NESTED_PAGE_FAULT
+            }
+            Svm::INVALID => {
+                self.entry_failure = true;
+                Exit::OTHER
+            }
+            _ => {
+                self.exit_instruction_length = instr_len;
+                match code {
+                    Svm::RDTSC => Exit::RDTSC,
+                    Svm::RDTSCP => Exit::RDTSCP,
+                    Svm::CPUID => Exit::CPUID,
+                    Svm::PAUSE => Exit::PAUSE_INSTRUCTION,
+                    Svm::HLT => Exit::HLT,
+                    Svm::MSR if info1 == 0 => Exit::MSR_READ,
+                    Svm::MSR => Exit::MSR_WRITE,
+                    Svm::VMMCALL => Exit::VMCALL,
+                    Svm::XSETBV => Exit::XSETBV,
+                    Svm::VMRUN
+                    | Svm::VMLOAD
+                    | Svm::VMSAVE
+                    | Svm::STGI
+                    | Svm::CLGI
+                    | Svm::SKINIT => Exit::VIRTUALIZATION_INSTRUCTION,
+                    _ => Exit::OTHER,
+                }
+            }
+        };
+        self.exit_reason = Some(exit_reason);
+    }
+}
+
+/// Enters the guest with VMRUN, and returns on the next #VMEXIT. The guest
+/// state not switched by VMRUN is loaded from and saved to the guest VMCB
+/// with VMLOAD and VMSAVE, and the host one from and to `host_vmcb`.
+#[naked]
+unsafe extern "C" fn svm_run(
+    guest_regs: &mut GeneralRegisters,
+    vmcb: HostPhysAddr,
+    host_vmcb: HostPhysAddr,
+) {
+    asm!(
+        "push   rbp",                           // save host callee-saved registers
+        "push   rbx",
+        "push   r12",
+        "push   r13",
+        "push   r14",
+        "push   r15",
+        "push   rdi",                           // save &guest_regs
+        "push   rdx",                           // save host_vmcb
+        "clgi",                                 // no interrupts until the host state is back
+        "mov    rax, rdx",
+        "vmsave rax",
+        "mov    rax, rsi",                      // RAX holds the guest VMCB for VMLOAD/VMRUN/VMSAVE
+        "mov    rcx, [rdi + 8]",                // load guest registers except RAX and RSP
+        "mov    rdx, [rdi + 16]",
+        "mov    rbx, [rdi + 24]",
+        "mov    rbp, [rdi + 40]",
+        "mov    rsi, [rdi + 48]",
+        "mov    r8,  [rdi + 64]",
+        "mov    r9,  [rdi + 72]",
+        "mov    r10, [rdi + 80]",
+        "mov    r11, [rdi + 88]",
+        "mov    r12, [rdi + 96]",
+        "mov    r13, [rdi + 104]",
+        "mov    r14, [rdi + 112]",
+        "mov    r15, [rdi + 120]",
+        "mov    rdi, [rdi + 56]",
+        "vmload rax",
+        "sti",                                  // physical interrupts cause #VMEXIT with the host IF set
+        "vmrun  rax",
+        "vmsave rax",
+        "push   rdi",                           // save guest RDI, and get &guest_regs
+        "mov    rdi, [rsp + 16]",
+        "mov    [rdi + 8], rcx",                // save guest registers except RAX and RSP
+        "mov    [rdi + 16], rdx",
+        "mov    [rdi + 24], rbx",
+        "mov    [rdi + 40], rbp",
+        "mov    [rdi + 48], rsi",
+        "mov    [rdi + 64], r8",
+        "mov    [rdi + 72], r9",
+        "mov    [rdi + 80], r10",
+        "mov    [rdi + 88], r11",
+        "mov    [rdi + 96], r12",
+        "mov    [rdi + 104], r13",
+        "mov    [rdi + 112], r14",
+        "mov    [rdi + 120], r15",
+        "pop    rax",
+        "mov    [rdi + 56], rax",
+        "pop    rax",                           // load the host state
+        "vmload rax",
+        "cli",                                  // the pending interrupts are taken by exit handlers
+        "stgi",
+        "add    rsp, 8",
+        "pop    r15",                           // restore host callee-saved registers
+        "pop    r14",
+        "pop    r13",
+        "pop    r12",
+        "pop    rbx",
+        "pop    rbp",
+        "ret",
+        options(noreturn),
+    )
+}
+
+
+impl<H: HyperCraftHal> Drop for SvmVcpu<H> {
+    fn drop(&mut self) {
+        info!("[HV] dropped SvmVcpu(vmcb: {:#x})", self.vmcb.phys_addr());
+    }
+}
+
+impl<H: HyperCraftHal> Debug for SvmVcpu<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("SvmVcpu")
+            .field("guest_regs", &self.guest_regs)
+            .field("rip", &VmcbSave64::RIP.read(&self.vmcb))
+            .field("rsp", &VmcbSave64::RSP.read(&self.vmcb))
+            .field("rflags", &VmcbSave64::RFLAGS.read(&self.vmcb))
+            .field("cr0", &VmcbSave64::CR0.read(&self.vmcb))
+            .field("cr3", &VmcbSave64::CR3.read(&self.vmcb))
+            .field("cr4", &VmcbSave64::CR4.read(&self.vmcb))
+            .field("efer", &VmcbSave64::EFER.read(&self.vmcb))
+            .field("cs", &VmcbSave16::CS_SELECTOR.read(&self.vmcb))
+            .field("fs_base", &VmcbSave64::FS_BASE.read(&self.vmcb))
+            .field("gs_base", &VmcbSave64::GS_BASE.read(&self.vmcb))
+            .field("tss", &VmcbSave16::TR_SELECTOR.read(&self.vmcb))
+            .field("exit_code", &VmcbControl64::EXIT_CODE.read(&self.vmcb))
+            .field("exit_info1", &VmcbControl64::EXIT_INFO1.read(&self.vmcb))
+            .field("exit_info2", &VmcbControl64::EXIT_INFO2.read(&self.vmcb))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,45 @@
+use alloc::sync::Arc;
+
+use super::super::vmcb::{SvmExitCode, VmcbControl64};
+use super::SvmVcpu;
+use crate::arch::clock::VirtClock;
+use crate::arch::exit::VmExitReason;
+use crate::arch::io_bitmap::IoBitmap;
+use crate::arch::mock::MockHal;
+
+const ENTRY: u64 = 0x7c00;
+const COM1: u64 = 0x3f8;
+
+fn new_vcpu() -> SvmVcpu<MockHal> {
+    let clock = VirtClock::new(None);
+    let io_bitmap = Arc::new(IoBitmap::intercept_all().unwrap());
+    SvmVcpu::new(ENTRY as usize, 0x1000, &clock, io_bitmap).unwrap()
+}
+
+/// Fakes the #VMEXIT of a one-byte I/O instruction at [`ENTRY`], described
+/// by `info1`, and decodes it.
+fn fake_ioio_exit(vcpu: &mut SvmVcpu<MockHal>, info1: u64) {
+    VmcbControl64::EXIT_CODE.write(&mut vcpu.vmcb, SvmExitCode::IOIO as u64);
+    VmcbControl64::EXIT_INFO1.write(&mut vcpu.vmcb, info1);
+    VmcbControl64::EXIT_INFO2.write(&mut vcpu.vmcb, ENTRY + 1);
+    vcpu.exited();
+}
+
+#[test]
+fn ioio_access_sizes() {
+    let mut vcpu = new_vcpu();
+    // `IN` from COM1 with the SZ8, SZ16 or SZ32 bit set.
+    for (sz_bit, size) in [(4, 1), (5, 2), (6, 4)] {
+        fake_ioio_exit(&mut vcpu, (COM1 << 16) | (1 << sz_bit) | 1);
+        let exit_info = vcpu.exit_info().unwrap();
+        assert_eq!(exit_info.exit_reason, VmExitReason::IO_INSTRUCTION);
+        assert_eq!(exit_info.exit_instruction_length, 1);
+        // VMX encodes the size minus one, and the direction in bit 3.
+        let qualification = (COM1 << 16) | (1 << 3) | (size - 1);
+        assert_eq!(vcpu.exit_qualification().unwrap(), qualification as usize);
+        let io_info = vcpu.io_exit_info().unwrap();
+        assert_eq!(io_info.port, COM1 as u16);
+        assert_eq!(io_info.access_size, size as u8);
+        assert!(io_info.is_in);
+    }
+}
@@ -0,0 +1,316 @@
+use bit_field::BitField;
+use bitflags::bitflags;
+
+use crate::arch::memory::PhysFrame;
+use crate::{HostPhysAddr, HyperCraftHal, HyperResult};
+
+/// A VMCB in 4K size, with the control area followed by the state save area.
+/// (APM Vol. 2, Appendix B)
+#[derive(Debug)]
+pub struct Vmcb<H: HyperCraftHal> {
+    frame: PhysFrame<H>,
+}
+
+impl<H: HyperCraftHal> Vmcb<H> {
+    pub fn new() -> HyperResult<Self> {
+        Ok(Self {
+            frame: PhysFrame::alloc_zero()?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frame.start_paddr()
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { (self.frame.as_mut_ptr().add(offset) as *const T).read_volatile() }
+    }
+
+    fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { (self.frame.as_mut_ptr().add(offset) as *mut T).write_volatile(value) }
+    }
+
+    /// Reads the 8-byte word at `offset`, for saving the guest state.
+    pub fn read_word(&self, offset: u32) -> u64 {
+        self.read(offset as usize & !7)
+    }
+
+    /// Writes the 8-byte word at `offset`, for restoring the guest state.
+    pub fn write_word(&mut self, offset: u32, value: u64) {
+        self.write(offset as usize & !7, value)
+    }
+}
+
+macro_rules! vmcb_field {
+    ($type_name: ident, $ux: ty) => {
+        impl $type_name {
+            pub fn read<H: HyperCraftHal>(self, vmcb: &Vmcb<H>) -> $ux {
+                vmcb.read(self as usize)
+            }
+
+            pub fn write<H: HyperCraftHal>(self, vmcb: &mut Vmcb<H>, value: $ux) {
+                vmcb.write(self as usize, value)
+            }
+        }
+    };
+}
+
+/// 16-bit fields of the VMCB control area. (APM Vol. 2, Appendix B, Table B-1)
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbControl16 {
+    /// PAUSE filter threshold.
+    PAUSE_FILTER_THRESHOLD = 0x3c,
+    /// PAUSE filter count.
+    PAUSE_FILTER_COUNT = 0x3e,
+}
+vmcb_field!(VmcbControl16, u16);
+
+/// 32-bit fields of the VMCB control area.
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbControl32 {
+    /// Intercept reads (bits 0..16) and writes (bits 16..32) of `CR0`-`CR15`.
+    INTERCEPT_CR = 0x00,
+    /// Intercept reads (bits 0..16) and writes (bits 16..32) of `DR0`-`DR15`.
+    INTERCEPT_DR = 0x04,
+    /// Intercept exception vectors 0-31.
+    INTERCEPT_EXCEPTIONS = 0x08,
+    /// Intercepts in [`InterceptMisc1`].
+    INTERCEPT_MISC1 = 0x0c,
+    /// Intercepts in [`InterceptMisc2`].
+    INTERCEPT_MISC2 = 0x10,
+    /// Guest ASID.
+    GUEST_ASID = 0x58,
+    /// TLB control, one of [`TlbControl`].
+    TLB_CONTROL = 0x5c,
+}
+vmcb_field!(VmcbControl32, u32);
+
+/// 64-bit fields of the VMCB control area.
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbControl64 {
+    /// Physical base address of the I/O permission map.
+    IOPM_BASE_PA = 0x40,
+    /// Physical base address of the MSR permission map.
+    MSRPM_BASE_PA = 0x48,
+    /// TSC offset.
+    TSC_OFFSET = 0x50,
+    /// Virtual interrupt control, see [`VIntrFlags`].
+    V_INTR = 0x60,
+    /// Guest interrupt shadow (bit 0).
+    INTERRUPT_SHADOW = 0x68,
+    /// Exit code.
+    EXIT_CODE = 0x70,
+    /// Exit information 1.
+    EXIT_INFO1 = 0x78,
+    /// Exit information 2.
+    EXIT_INFO2 = 0x80,
+    /// Event whose delivery was interrupted by the #VMEXIT.
+    EXIT_INT_INFO = 0x88,
+    /// Enable nested paging (bit 0).
+    NP_ENABLE = 0x90,
+    /// Event injection.
+    EVENT_INJ = 0xa8,
+    /// Nested page table `CR3`.
+    N_CR3 = 0xb0,
+    /// VMCB clean bits.
+    CLEAN_BITS = 0xc0,
+    /// Next sequential instruction pointer.
+    NEXT_RIP = 0xc8,
+}
+vmcb_field!(VmcbControl64, u64);
+
+/// 8-bit fields of the VMCB state save area. (APM Vol. 2, Appendix B, Table B-2)
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbSave8 {
+    /// Current privilege level.
+    CPL = 0x4cb,
+}
+vmcb_field!(VmcbSave8, u8);
+
+/// 16-bit fields of the VMCB state save area.
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbSave16 {
+    ES_SELECTOR = 0x400,
+    ES_ATTRIB = 0x402,
+    CS_SELECTOR = 0x410,
+    CS_ATTRIB = 0x412,
+    SS_SELECTOR = 0x420,
+    SS_ATTRIB = 0x422,
+    DS_SELECTOR = 0x430,
+    DS_ATTRIB = 0x432,
+    FS_SELECTOR = 0x440,
+    FS_ATTRIB = 0x442,
+    GS_SELECTOR = 0x450,
+    GS_ATTRIB = 0x452,
+    GDTR_SELECTOR = 0x460,
+    LDTR_SELECTOR = 0x470,
+    LDTR_ATTRIB = 0x472,
+    IDTR_SELECTOR = 0x480,
+    TR_SELECTOR = 0x490,
+    TR_ATTRIB = 0x492,
+}
+vmcb_field!(VmcbSave16, u16);
+
+/// 32-bit fields of the VMCB state save area.
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbSave32 {
+    ES_LIMIT = 0x404,
+    CS_LIMIT = 0x414,
+    SS_LIMIT = 0x424,
+    DS_LIMIT = 0x434,
+    FS_LIMIT = 0x444,
+    GS_LIMIT = 0x454,
+    GDTR_LIMIT = 0x464,
+    LDTR_LIMIT = 0x474,
+    IDTR_LIMIT = 0x484,
+    TR_LIMIT = 0x494,
+}
+vmcb_field!(VmcbSave32, u32);
+
+/// 64-bit fields of the VMCB state save area.
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum VmcbSave64 {
+    ES_BASE = 0x408,
+    CS_BASE = 0x418,
+    SS_BASE = 0x428,
+    DS_BASE = 0x438,
+    FS_BASE = 0x448,
+    GS_BASE = 0x458,
+    GDTR_BASE = 0x468,
+    LDTR_BASE = 0x478,
+    IDTR_BASE = 0x488,
+    TR_BASE = 0x498,
+    EFER = 0x4d0,
+    CR4 = 0x548,
+    CR3 = 0x550,
+    CR0 = 0x558,
+    DR7 = 0x560,
+    DR6 = 0x568,
+    RFLAGS = 0x570,
+    RIP = 0x578,
+    RSP = 0x5d8,
+    RAX = 0x5f8,
+    STAR = 0x600,
+    LSTAR = 0x608,
+    CSTAR = 0x610,
+    SFMASK = 0x618,
+    KERNEL_GS_BASE = 0x620,
+    SYSENTER_CS = 0x628,
+    SYSENTER_ESP = 0x630,
+    SYSENTER_EIP = 0x638,
+    CR2 = 0x640,
+    G_PAT = 0x668,
+    DBGCTL = 0x670,
+}
+vmcb_field!(VmcbSave64, u64);
+
+bitflags! {
+    /// Intercepts in the VMCB control area at offset 0x0c.
+    pub struct InterceptMisc1: u32 {
+        const INTR = 1 << 0;
+        const NMI = 1 << 1;
+        const SMI = 1 << 2;
+        const INIT = 1 << 3;
+        const VINTR = 1 << 4;
+        const RDTSC = 1 << 14;
+        const CPUID = 1 << 18;
+        const PAUSE = 1 << 23;
+        const HLT = 1 << 24;
+        const IOIO_PROT = 1 << 27;
+        const MSR_PROT = 1 << 28;
+        const SHUTDOWN = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// Intercepts in the VMCB control area at offset 0x10.
+    pub struct InterceptMisc2: u32 {
+        const VMRUN = 1 << 0;
+        const VMMCALL = 1 << 1;
+        const VMLOAD = 1 << 2;
+        const VMSAVE = 1 << 3;
+        const STGI = 1 << 4;
+        const CLGI = 1 << 5;
+        const SKINIT = 1 << 6;
+        const RDTSCP = 1 << 7;
+        const XSETBV = 1 << 13;
+    }
+}
+
+bitflags! {
+    /// Virtual interrupt control. (APM Vol. 2, Section 15.21.1)
+    pub struct VIntrFlags: u64 {
+        /// A virtual interrupt is pending.
+        const V_IRQ = 1 << 8;
+        /// The virtual interrupt ignores the virtual TPR.
+        const V_IGN_TPR = 1 << 20;
+        /// The guest `RFLAGS.IF` only masks virtual interrupts, and the host
+        /// one masks physical interrupts.
+        const V_INTR_MASKING = 1 << 24;
+    }
+}
+
+/// Values of [`VmcbControl32::TLB_CONTROL`].
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+pub enum TlbControl {
+    /// Flush the entire TLB on VMRUN.
+    FlushAll = 1,
+    /// Flush the TLB entries of the guest ASID on VMRUN.
+    FlushGuest = 3,
+}
+
+numeric_enum_macro::numeric_enum! {
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+/// SVM exit codes, except for those of exceptions. (APM Vol. 2, Appendix C)
+pub enum SvmExitCode {
+    CR3_WRITE = 0x13,
+    INTR = 0x60,
+    NMI = 0x61,
+    SMI = 0x62,
+    INIT = 0x63,
+    VINTR = 0x64,
+    RDTSC = 0x6e,
+    CPUID = 0x72,
+    PAUSE = 0x77,
+    HLT = 0x78,
+    IOIO = 0x7b,
+    MSR = 0x7c,
+    SHUTDOWN = 0x7f,
+    VMRUN = 0x80,
+    VMMCALL = 0x81,
+    VMLOAD = 0x82,
+    VMSAVE = 0x83,
+    STGI = 0x84,
+    CLGI = 0x85,
+    SKINIT = 0x86,
+    RDTSCP = 0x87,
+    XSETBV = 0x8d,
+    NPF = 0x400,
+    INVALID = 0xffff_ffff,
+}
+}
+
+/// Exit codes of exceptions are this plus the vector.
+pub const EXIT_CODE_EXCEPTION_BASE: u64 = 0x40;
+
+/// Builds an `EVENTINJ` value. (APM Vol. 2, Section 15.20)
+pub fn event_injection(vector: u8, event_type: u8, err_code: Option<u32>) -> u64 {
+    let mut event = vector as u64;
+    event.set_bits(8..11, event_type as u64);
+    if let Some(err_code) = err_code {
+        event.set_bit(11, true);
+        event.set_bits(32..64, err_code as u64);
+    }
+    event.set_bit(31, true);
+    event
+}
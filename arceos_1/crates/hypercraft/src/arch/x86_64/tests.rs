@@ -0,0 +1,253 @@
+use alloc::sync::Arc;
+
+use super::clock::VirtClock;
+use super::coredump::{core_file_headers, CORE_FILE_ALIGN};
+use super::io_bitmap::IoBitmap;
+use super::lapic::ApicTimer;
+use super::mock::{MockHal, MOCK_TSC_MHZ};
+use super::replay::{DueInterrupt, ReplayInput, ReplayLog};
+use super::exit::VmExitReason;
+use super::vcpu_state::VcpuState;
+use super::{core_reg_fields, VCpu};
+use crate::{HyperError, VmCpus};
+
+const VECTOR: u32 = 0x40;
+const MASKED: u32 = 1 << 16;
+const PERIODIC: u32 = 0b01 << 17;
+const TSC_DEADLINE: u32 = 0b10 << 17;
+/// Divide Configuration Register value to divide by 1.
+const DIVIDE_BY_1: u32 = 0b1011;
+
+fn running_clock() -> Arc<VirtClock<MockHal>> {
+    let clock = Arc::new(VirtClock::new(None));
+    clock.vcpu_scheduled();
+    clock
+}
+
+fn timer(lvt_timer: u32) -> ApicTimer<MockHal> {
+    let mut timer = ApicTimer::new(running_clock());
+    timer.set_divide(DIVIDE_BY_1).unwrap();
+    timer.set_lvt_timer(lvt_timer).unwrap();
+    timer
+}
+
+#[test]
+fn clock_stops_while_paused() {
+    let clock = running_clock();
+    MockHal::advance_ns(1000);
+    assert_eq!(clock.now_ns(), 1000);
+
+    clock.pause();
+    MockHal::advance_ns(5000);
+    assert_eq!(clock.now_ns(), 1000);
+    clock.resume();
+    MockHal::advance_ns(500);
+    assert_eq!(clock.now_ns(), 1500);
+    assert_eq!(clock.guest_tsc(), 1500 * MOCK_TSC_MHZ / 1000);
+
+    clock.vcpu_descheduled();
+    assert!(clock.is_paused());
+    clock.set_now_ns(10_000).unwrap();
+    MockHal::advance_ns(700);
+    clock.vcpu_scheduled();
+    assert_eq!(clock.now_ns(), 10_000);
+}
+
+#[test]
+fn apic_timer_one_shot() {
+    let mut timer = timer(VECTOR);
+    timer.set_initial_count(1000).unwrap();
+    MockHal::advance_ns(999);
+    assert!(!timer.check_interrupt());
+    assert_eq!(timer.current_counter(), 1);
+
+    MockHal::advance_ns(1);
+    assert!(timer.check_interrupt());
+    assert_eq!(timer.vector(), VECTOR as u8);
+    // fires only once
+    MockHal::advance_ns(1000);
+    assert!(!timer.check_interrupt());
+    assert_eq!(timer.current_counter(), 0);
+    assert_eq!(timer.deadline_ns(), None);
+}
+
+#[test]
+fn apic_timer_periodic() {
+    let mut timer = timer(VECTOR | PERIODIC);
+    timer.set_initial_count(100).unwrap();
+    MockHal::advance_ns(250);
+    assert_eq!(timer.current_counter(), 50);
+    // one interrupt for each missed period
+    assert!(timer.check_interrupt());
+    assert!(timer.check_interrupt());
+    assert!(!timer.check_interrupt());
+    assert_eq!(timer.deadline_ns(), Some(300));
+}
+
+#[test]
+fn apic_timer_masked() {
+    let mut timer = timer(VECTOR | MASKED);
+    timer.set_initial_count(100).unwrap();
+    assert_eq!(timer.deadline_ns(), None);
+    MockHal::advance_ns(100);
+    assert!(!timer.check_interrupt());
+}
+
+#[test]
+fn apic_timer_tsc_deadline() {
+    let clock = running_clock();
+    let mut timer = ApicTimer::new(clock.clone());
+    timer.set_lvt_timer(VECTOR | TSC_DEADLINE).unwrap();
+    // the initial count is ignored in TSC-deadline mode
+    timer.set_initial_count(10).unwrap();
+    assert_eq!(timer.deadline_ns(), None);
+
+    // 2 microseconds from now
+    let deadline = clock.guest_tsc() + 2 * MOCK_TSC_MHZ;
+    timer.set_tsc_deadline(deadline).unwrap();
+    assert_eq!(timer.tsc_deadline(), deadline);
+    MockHal::advance_ns(1999);
+    assert!(!timer.check_interrupt());
+    MockHal::advance_ns(1);
+    assert!(timer.check_interrupt());
+    assert_eq!(timer.tsc_deadline(), 0);
+}
+
+#[test]
+fn apic_timer_save_restore() {
+    let mut timer = timer(VECTOR | PERIODIC);
+    timer.set_initial_count(100).unwrap();
+    let state = timer.save_state();
+
+    let mut restored = ApicTimer::new(running_clock());
+    restored.restore_state(&state).unwrap();
+    assert_eq!(restored.lvt_timer(), VECTOR | PERIODIC);
+    assert_eq!(restored.divide(), DIVIDE_BY_1);
+    assert_eq!(restored.deadline_ns(), timer.deadline_ns());
+
+    let mut bad = state;
+    bad.lvt_timer |= 0b11 << 17;
+    assert_eq!(restored.restore_state(&bad), Err(HyperError::InvalidParam));
+}
+
+#[test]
+fn replay_log_round_trip() {
+    let inputs = [
+        ReplayInput::PortRead { port: 0x3f8, value: 0x61 },
+        ReplayInput::Tsc { value: 123_456 },
+        ReplayInput::Cpuid {
+            leaf: 1,
+            subleaf: 0,
+            regs: [1, 2, 3, 4],
+        },
+        ReplayInput::MmioRead {
+            gpa: 0xfed0_00f0,
+            value: 0x1234_5678,
+        },
+    ];
+    let mut log = ReplayLog::recording();
+    for (rip, &input) in inputs.iter().enumerate() {
+        let res = log.input(rip as u64, 0, input, || Ok(input));
+        assert_eq!(res, Ok(input));
+    }
+    log.record_interrupt(0x1000, 0, 0x30);
+    assert_eq!(log.len(), 5);
+
+    let mut replay = ReplayLog::replaying(&log.to_bytes()).unwrap();
+    for (rip, &input) in inputs.iter().enumerate() {
+        let res = replay.input(rip as u64, 0, input, || panic!("live input while replaying"));
+        assert_eq!(res, Ok(input));
+    }
+    assert!(replay.has_due_interrupt());
+    assert!(replay.is_replaying());
+}
+
+#[test]
+fn replay_log_stops_on_divergence() {
+    let input = ReplayInput::PortRead { port: 0x60, value: 1 };
+    let mut log = ReplayLog::recording();
+    log.input(0, 0, input, || Ok(input)).unwrap();
+
+    let mut replay = ReplayLog::replaying(&log.to_bytes()).unwrap();
+    let other = ReplayInput::PortRead { port: 0x64, value: 2 };
+    assert_eq!(replay.input(0, 0, other, || Ok(other)), Ok(other));
+    assert!(!replay.is_replaying());
+    assert!(ReplayLog::replaying(&[1, 2, 3]).is_err());
+}
+
+#[test]
+fn replay_log_positions_interrupts_by_branches() {
+    let mut log = ReplayLog::recording();
+    log.count_exit(VmExitReason::EXTERNAL_INTERRUPT, 10);
+    log.record_interrupt(0x1000, 5, 0x30);
+
+    let mut replay = ReplayLog::replaying(&log.to_bytes()).unwrap();
+    replay.count_exit(VmExitReason::MONITOR_TRAP_FLAG, 4);
+    assert!(matches!(replay.due_interrupt(0x1000, 5), DueInterrupt::Step));
+    replay.count_exit(VmExitReason::MONITOR_TRAP_FLAG, 6);
+    // Another iteration of the same instruction.
+    assert!(matches!(replay.due_interrupt(0x1000, 6), DueInterrupt::Step));
+    assert!(matches!(replay.due_interrupt(0x1000, 5), DueInterrupt::Inject(0x30)));
+    assert!(replay.is_empty());
+}
+
+#[test]
+fn vm_cpus_bookkeeping() {
+    let clock = running_clock();
+    let io_bitmap = Arc::new(IoBitmap::intercept_all().unwrap());
+    let new_vcpu = |vcpu_id| VCpu::new(0, vcpu_id, 1, 0x7c00, 0x1000, clock.clone(), io_bitmap.clone());
+
+    let mut vcpus = VmCpus::<MockHal>::new();
+    vcpus.add_vcpu(new_vcpu(1).unwrap()).unwrap();
+    assert_eq!(vcpus.get_vcpu(1).unwrap().vcpu_id(), 1);
+    assert!(matches!(vcpus.get_vcpu(0), Err(HyperError::NotFound)));
+    assert!(matches!(vcpus.get_vcpu(100), Err(HyperError::NotFound)));
+    let too_many = new_vcpu(crate::vcpus::VM_CPUS_MAX).unwrap();
+    assert!(matches!(vcpus.add_vcpu(too_many), Err(HyperError::BadState)));
+}
+
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[test]
+fn core_file_layout() {
+    let mut state = VcpuState::default();
+    state.regs.rax = 0x1234;
+    state.guest_fields = alloc::vec![(core_reg_fields().rip, 0xffff_8000_0010_0000)];
+    let vcpus = [(0, state.clone()), (1, state)];
+    let regions = [(0, 0x9_f800), (0x10_0000, 0x100)];
+    let buf = core_file_headers(&vcpus, 11, &regions, Some(0xffff_8000_0000_0000));
+
+    assert_eq!(&buf[..4], b"\x7fELF");
+    assert_eq!(le_u16(&buf, 16), 4); // ET_CORE
+    assert_eq!(le_u16(&buf, 18), 62); // EM_X86_64
+    assert_eq!(le_u16(&buf, 56), 5); // one note and two loads per region
+    assert_eq!(buf.len() % CORE_FILE_ALIGN, 0);
+
+    // PT_NOTE, then NT_PRSTATUS of vCPU 0
+    let phdr = |i: usize| 64 + i * 56;
+    assert_eq!(le_u32(&buf, phdr(0)), 4);
+    let note = le_u64(&buf, phdr(0) + 8) as usize;
+    assert_eq!(le_u32(&buf, note + 8), 1);
+    let prstatus = note + 20;
+    assert_eq!(le_u32(&buf, prstatus), 11);
+    assert_eq!(le_u32(&buf, prstatus + 32), 1);
+    assert_eq!(le_u64(&buf, prstatus + 112 + 10 * 8), 0x1234);
+    assert_eq!(le_u64(&buf, prstatus + 112 + 16 * 8), 0xffff_8000_0010_0000);
+
+    // Regions follow the headers at aligned offsets, also at their virtual addresses.
+    assert_eq!(le_u32(&buf, phdr(1)), 1);
+    assert_eq!(le_u64(&buf, phdr(1) + 8), buf.len() as u64);
+    assert_eq!(le_u64(&buf, phdr(2) + 16), 0xffff_8000_0000_0000);
+    assert_eq!(le_u64(&buf, phdr(3) + 8), (buf.len() + 0xa_0000) as u64);
+    assert_eq!(le_u64(&buf, phdr(4) + 24), 0x10_0000);
+}
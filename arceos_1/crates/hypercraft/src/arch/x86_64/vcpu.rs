@@ -0,0 +1,876 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Formatter, Result};
+
+use bit_field::BitField;
+use x86::irq::{GENERAL_PROTECTION_FAULT_VECTOR, INVALID_OPCODE_VECTOR, PAGE_FAULT_VECTOR};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+
+use super::branch_counter;
+use super::clock::VirtClock;
+use super::detect::{extension, Extension};
+use super::exit::{VmExitInfo, VmExitReason};
+use super::exit_stats::{ExitStats, ExitTraceEntry};
+use super::guest_memory::{
+    GuestMemoryResult, GuestPageFault, GuestPagingBackend, GuestPagingContext,
+};
+use super::io_bitmap::IoBitmap;
+use super::lapic::ApicTimer;
+use super::memory::NestedPageFaultInfo;
+use super::migration::MigrationHooks;
+use super::regs::GeneralRegisters;
+use super::replay::{DueInterrupt, ReplayInput, ReplayLog};
+use super::svm::SvmVcpu;
+use super::vcpu_state::VcpuState;
+use super::vmx::{
+    VmxCrAccessType, VmxInterruptInfo, VmxInterruptionType, VmxIoExitInfo, VmxVcpu,
+};
+use super::xstate::XState;
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// The VMX or SVM part of a vCPU, for the extension detected at runtime.
+enum ArchVcpu<H: HyperCraftHal> {
+    Vmx(VmxVcpu<H>),
+    Svm(SvmVcpu<H>),
+}
+
+/// Evaluates `$body` with `$vcpu` bound to the VMX or SVM part.
+macro_rules! dispatch {
+    ($arch:expr, $vcpu:ident => $body:expr) => {
+        match $arch {
+            ArchVcpu::Vmx($vcpu) => $body,
+            ArchVcpu::Svm($vcpu) => $body,
+        }
+    };
+}
+
+/// A virtual CPU within a guest.
+///
+/// The events, timers, extended states, replay and statistics are handled
+/// here the same way with VMX and SVM, and the guest state is kept by the
+/// part of the extension, in the VMCS or VMCB.
+pub struct VCpu<H: HyperCraftHal> {
+    arch: ArchVcpu<H>,
+    apic_timer: ApicTimer<H>,
+    armed_timer_deadline: Option<u64>,
+    clock: Arc<VirtClock<H>>,
+    clock_generation: u64,
+    xstate: XState<H>,
+    pending_events: VecDeque<(u8, Option<u32>)>,
+    pending_cr2: Option<usize>,
+    /// `DR0`-`DR3` and `DR7` set by a debugger, overriding the guest ones.
+    debug_regs: Option<([usize; 4], usize)>,
+    /// Guest `DR0`-`DR3` and `DR7` saved while overridden by a debugger.
+    saved_debug_regs: Option<([usize; 4], usize)>,
+    exit_stats: Arc<ExitStats>,
+    /// Non-deterministic inputs recorded or replayed.
+    replay: Option<ReplayLog>,
+    /// Whether the monitor trap flag is set to replay an interrupt.
+    replay_stepping: bool,
+    /// Whether the branches retired by the guest are counted, to position
+    /// the replayed inputs.
+    branch_counting: bool,
+    /// Whether to return from `run` instead of entering the guest again.
+    stopping: bool,
+    /// Host time of the last VM entry.
+    entered_at_ns: u64,
+    vcpu_id: usize,
+    vm_id: usize,
+}
+
+impl<H: HyperCraftHal> VCpu<H> {
+    pub(crate) fn new(
+        vm_id: usize,
+        vcpu_id: usize,
+        vmcs_revision_id: u32,
+        entry: GuestPhysAddr,
+        npt_root: HostPhysAddr,
+        clock: Arc<VirtClock<H>>,
+        io_bitmap: Arc<IoBitmap<H>>,
+    ) -> HyperResult<Self> {
+        let arch = match extension() {
+            Extension::Vmx => ArchVcpu::Vmx(VmxVcpu::new(
+                vmcs_revision_id,
+                entry,
+                npt_root,
+                &clock,
+                io_bitmap,
+            )?),
+            Extension::Svm => ArchVcpu::Svm(SvmVcpu::new(entry, npt_root, &clock, io_bitmap)?),
+        };
+        Ok(Self {
+            arch,
+            apic_timer: ApicTimer::new(clock.clone()),
+            armed_timer_deadline: None,
+            clock_generation: clock.generation(),
+            clock,
+            xstate: XState::new()?,
+            pending_events: VecDeque::with_capacity(8),
+            pending_cr2: None,
+            debug_regs: None,
+            saved_debug_regs: None,
+            exit_stats: Arc::new(ExitStats::new()),
+            replay: None,
+            replay_stepping: false,
+            branch_counting: false,
+            stopping: false,
+            entered_at_ns: 0,
+            vcpu_id,
+            vm_id,
+        })
+    }
+
+    /// Run the guest until [`VCpu::stop`] is called by an exit handler.
+    /// The vCPU cannot run again afterwards.
+    pub fn run(&mut self) {
+        self.clock.vcpu_scheduled();
+        self.update_tsc_offset().unwrap();
+        self.xstate.restore();
+        self.load_debug_regs();
+        // Exit handlers run with interrupts disabled, as VMX exits clear
+        // RFLAGS.IF and SVM ones leave the interrupts pending.
+        let irq_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        dispatch!(&mut self.arch, vcpu => vcpu.start()).unwrap();
+        self.entered_at_ns = H::current_time_nanos();
+        loop {
+            self.prepare_entry();
+            unsafe { dispatch!(&mut self.arch, vcpu => vcpu.enter()) };
+            if !self.vmexit_handler() {
+                break;
+            }
+        }
+        if irq_enabled {
+            interrupts::enable();
+        }
+        if self.armed_timer_deadline.take().is_some() {
+            H::set_vcpu_timer(self.vm_id, self.vcpu_id, None);
+        }
+        self.clock.vcpu_descheduled();
+    }
+
+    /// Makes [`VCpu::run`] return once the current VM exit is handled,
+    /// instead of entering the guest again.
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+
+    /// Basic information about VM exits. Returns
+    /// [`HyperError::NotSupported`] if the vCPU does not know the exit.
+    pub fn exit_info(&self) -> HyperResult<VmExitInfo> {
+        dispatch!(&self.arch, vcpu => vcpu.exit_info())
+    }
+
+    /// Information for VM exits due to external interrupts or exceptions.
+    /// With SVM, the host takes external interrupts itself, so the
+    /// information is never valid for them.
+    pub fn interrupt_exit_info(&self) -> HyperResult<VmxInterruptInfo> {
+        dispatch!(&self.arch, vcpu => vcpu.interrupt_exit_info())
+    }
+
+    /// Information for VM exits due to I/O instructions.
+    pub fn io_exit_info(&self) -> HyperResult<VmxIoExitInfo> {
+        dispatch!(&self.arch, vcpu => vcpu.io_exit_info())
+    }
+
+    /// Information for VM exits due to nested page table faults.
+    pub fn nested_page_fault_info(&self) -> HyperResult<NestedPageFaultInfo> {
+        dispatch!(&self.arch, vcpu => vcpu.nested_page_fault_info())
+    }
+
+    /// Exit qualification of the last VM exit as VMX reports it, whose
+    /// meaning depends on the exit reason. (SDM Vol. 3C, Section 27.2.1)
+    pub fn exit_qualification(&self) -> HyperResult<usize> {
+        dispatch!(&self.arch, vcpu => vcpu.exit_qualification())
+    }
+
+    /// Guest general-purpose registers.
+    pub fn regs(&self) -> &GeneralRegisters {
+        dispatch!(&self.arch, vcpu => vcpu.regs())
+    }
+
+    /// Mutable reference of guest general-purpose registers.
+    pub fn regs_mut(&mut self) -> &mut GeneralRegisters {
+        dispatch!(&mut self.arch, vcpu => vcpu.regs_mut())
+    }
+
+    /// Guest control register `CR0`, `CR3` or `CR4` as seen by the guest.
+    pub fn cr(&self, cr_idx: usize) -> usize {
+        dispatch!(&self.arch, vcpu => vcpu.cr(cr_idx))
+    }
+
+    /// Set the guest control register `CR0`, `CR3` or `CR4` to the value the
+    /// guest will see, without checking it. `EFER.LMA` follows `CR0.PG`.
+    pub fn set_cr(&mut self, cr_idx: usize, val: u64) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_cr(cr_idx, val))
+    }
+
+    /// If enabled, guest loads of `CR3` cause VM exits and are reported to
+    /// [`HyperCraftHal::guest_cr3_loaded`].
+    pub fn set_cr3_load_exiting(&mut self, enable: bool) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_cr3_load_exiting(enable))
+    }
+
+    /// Guest stack pointer. (`RSP`)
+    pub fn stack_pointer(&self) -> usize {
+        dispatch!(&self.arch, vcpu => vcpu.stack_pointer())
+    }
+
+    /// Set guest stack pointer. (`RSP`)
+    pub fn set_stack_pointer(&mut self, rsp: usize) {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_stack_pointer(rsp))
+    }
+
+    /// Guest instruction pointer. (`RIP`)
+    pub fn rip(&self) -> usize {
+        dispatch!(&self.arch, vcpu => vcpu.rip())
+    }
+
+    /// Set guest instruction pointer. (`RIP`)
+    pub fn set_rip(&mut self, rip: usize) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_rip(rip))
+    }
+
+    /// Guest `RFLAGS`.
+    pub fn rflags(&self) -> usize {
+        dispatch!(&self.arch, vcpu => vcpu.rflags())
+    }
+
+    /// Set guest `RFLAGS`.
+    pub fn set_rflags(&mut self, rflags: usize) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_rflags(rflags))
+    }
+
+    /// Guest segment selectors, in the order of `CS`, `SS`, `DS`, `ES`, `FS`
+    /// and `GS`.
+    pub fn segment_selectors(&self) -> HyperResult<[u16; 6]> {
+        dispatch!(&self.arch, vcpu => vcpu.segment_selectors())
+    }
+
+    /// Advance guest `RIP` by `instr_len` bytes.
+    pub fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.advance_rip(instr_len))
+    }
+
+    /// Whether there are virtual interrupts or exceptions waiting to be injected.
+    pub fn has_pending_events(&self) -> bool {
+        !self.pending_events.is_empty()
+            || self.replay.as_ref().map_or(false, |log| log.has_due_interrupt())
+    }
+
+    /// Add a virtual interrupt or exception to the pending events list,
+    /// and try to inject it before later VM entries.
+    ///
+    /// Interrupts are dropped while replaying, the recorded ones are injected
+    /// instead.
+    pub fn inject_event(&mut self, vector: u8, err_code: Option<u32>) {
+        if vector >= 32 && self.replay.as_ref().map_or(false, |log| log.is_replaying()) {
+            return;
+        }
+        self.pending_events.push_back((vector, err_code));
+    }
+
+    /// Starts recording the non-deterministic inputs of the guest to `log`,
+    /// or replaying them from it, or stops if `None`. `RDTSC` and `RDTSCP`
+    /// cause VM exits from now on, to be recorded, and the branches retired
+    /// by the guest are counted. Like [`VCpu::restore_state`], it must be
+    /// called right before [`VCpu::run`].
+    ///
+    /// Returns [`HyperError::NotSupported`] if the processor can't count the
+    /// branches retired by the guest.
+    pub fn set_replay_log(&mut self, log: Option<ReplayLog>) -> HyperResult {
+        if log.is_some() && !self.branch_counting {
+            if !branch_counter::is_supported() {
+                return Err(HyperError::NotSupported);
+            }
+            // The counter only counts in the guest, and is hidden from it.
+            dispatch!(&mut self.arch, vcpu => vcpu.enable_branch_counting())?;
+            self.branch_counting = true;
+        }
+        if log.is_some() {
+            dispatch!(&mut self.arch, vcpu => vcpu.enable_rdtsc_exiting())?;
+        }
+        self.replay = log;
+        Ok(())
+    }
+
+    /// Takes the log given to [`VCpu::set_replay_log`], with the recorded
+    /// inputs.
+    pub fn take_replay_log(&mut self) -> Option<ReplayLog> {
+        self.replay.take()
+    }
+
+    /// Returns the non-deterministic input of the current VM exit: the
+    /// replayed one if it's from the same source as `expected`, otherwise the
+    /// one given by `live`, which is recorded if a log is being recorded.
+    pub fn replay_input<F>(&mut self, expected: ReplayInput, live: F) -> HyperResult<ReplayInput>
+    where
+        F: FnOnce(&mut Self) -> HyperResult<ReplayInput>,
+    {
+        let Some(mut log) = self.replay.take() else {
+            return live(self);
+        };
+        let rip = self.rip() as u64;
+        let rcx = self.regs().rcx;
+        let res = log.input(rip, rcx, expected, || live(self));
+        self.replay = Some(log);
+        res
+    }
+
+    /// Set the guest `CR2` to be loaded when the next page fault is injected.
+    pub(crate) fn set_pending_cr2(&mut self, cr2: usize) {
+        self.pending_cr2 = Some(cr2);
+    }
+
+    /// If enabled, a VM exit occurs once the guest can take interrupts.
+    /// (SDM Vol. 3C, Section 24.4.2, and APM Vol. 2, Section 15.21.4)
+    pub fn set_interrupt_window(&mut self, enable: bool) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_interrupt_window(enable))
+    }
+
+    /// If enabled, a VM exit occurs after the guest executes one instruction,
+    /// reported as a monitor trap flag exit.
+    pub fn set_monitor_trap(&mut self, enable: bool) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.set_monitor_trap(enable))
+    }
+
+    /// If enabled, guest exceptions of `vector` cause VM exits instead of
+    /// being delivered to the guest.
+    pub fn set_exception_exiting(&mut self, vector: u8, enable: bool) -> HyperResult {
+        if vector >= 32 {
+            return Err(HyperError::InvalidParam);
+        }
+        dispatch!(&mut self.arch, vcpu => vcpu.set_exception_exiting(vector, enable))
+    }
+
+    /// Overrides the guest `DR0`-`DR3` and `DR7` with the hardware breakpoints
+    /// of a debugger, or gives the guest its own values back if `None`.
+    pub fn set_debug_regs(&mut self, regs: Option<([usize; 4], usize)>) -> HyperResult {
+        use x86::debugregs::{dr0, dr1, dr2, dr3};
+        if regs.is_some() && self.saved_debug_regs.is_none() {
+            // The guest DR0-DR3 are left in the hardware registers on VM exits.
+            let guest_drs = unsafe { [dr0(), dr1(), dr2(), dr3()] };
+            let guest_dr7 = dispatch!(&self.arch, vcpu => vcpu.dr7())?;
+            self.saved_debug_regs = Some((guest_drs, guest_dr7));
+        }
+        let regs = match regs {
+            Some(regs) => regs,
+            None => match self.saved_debug_regs.take() {
+                Some(saved) => saved,
+                None => return Ok(()),
+            },
+        };
+        dispatch!(&mut self.arch, vcpu => vcpu.set_dr7(regs.1))?;
+        self.debug_regs = Some(regs);
+        self.load_debug_regs();
+        if self.saved_debug_regs.is_none() {
+            // given back to the guest, which owns them from now on
+            self.debug_regs = None;
+        }
+        Ok(())
+    }
+
+    /// The guest XCR0.
+    pub fn xcr0(&self) -> u64 {
+        self.xstate.xcr0()
+    }
+
+    /// Returns the virtual clock of the VM.
+    pub fn clock(&self) -> &VirtClock<H> {
+        &self.clock
+    }
+
+    /// Statistics of the VM exits of this vCPU.
+    pub fn exit_stats(&self) -> &Arc<ExitStats> {
+        &self.exit_stats
+    }
+
+    /// Returns the mutable reference of [`ApicTimer`].
+    pub fn apic_timer_mut(&mut self) -> &mut ApicTimer<H> {
+        &mut self.apic_timer
+    }
+
+    /// get vcpu_id
+    pub fn get_vcpu_id(&self) -> usize {
+        self.vcpu_id
+    }
+
+    /// get vm_id
+    pub fn get_vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    /// Saves the architectural state of the guest. It must be called in the
+    /// VM exit handler of this vCPU, whose VMCS is current.
+    pub fn save_state(&self) -> HyperResult<VcpuState> {
+        Ok(VcpuState {
+            regs: self.regs().clone(),
+            crs: [
+                self.cr(0) as u64,
+                self.guest_cr2(),
+                self.cr(3) as u64,
+                self.cr(4) as u64,
+            ],
+            guest_fields: dispatch!(&self.arch, vcpu => vcpu.save_guest_fields())?,
+            xcr0: self.xstate.xcr0(),
+            xsave_area: self.xstate.area().to_vec(),
+            apic_timer: self.apic_timer.save_state(),
+            pending_events: self.pending_events.iter().copied().collect(),
+            pending_cr2: self.pending_cr2.map(|cr2| cr2 as u64),
+        })
+    }
+
+    /// Loads the architectural state saved by [`VCpu::save_state`], possibly
+    /// from a vCPU of another VM. It must be called right before
+    /// [`VCpu::run`], and the virtual clock must have been restored to the
+    /// saved time.
+    pub fn restore_state(&mut self, state: &VcpuState) -> HyperResult {
+        self.xstate.set_area(state.xcr0, &state.xsave_area)?;
+        self.apic_timer.restore_state(&state.apic_timer)?;
+        dispatch!(&mut self.arch, vcpu => vcpu.restore_guest_fields(&state.guest_fields))?;
+        self.set_cr(0, state.crs[0])?;
+        self.set_cr(3, state.crs[2])?;
+        self.set_cr(4, state.crs[3])?;
+        dispatch!(&mut self.arch, vcpu => vcpu.set_cr2(state.crs[1]));
+
+        *self.regs_mut() = state.regs.clone();
+        self.pending_events = state.pending_events.iter().copied().collect();
+        self.pending_cr2 = state.pending_cr2.map(|cr2| cr2 as usize);
+        Ok(())
+    }
+
+    /// Make this vCPU current on the physical CPU again after other vCPUs
+    /// may have run: load its VMCS (there is none to load with SVM) and
+    /// extended FPU states. The guest XCR0 is loaded at the next VM entry.
+    pub fn load_vmcs(&mut self) -> HyperResult {
+        dispatch!(&mut self.arch, vcpu => vcpu.load())?;
+        self.xstate.restore();
+        Ok(())
+    }
+
+    /// Hooks to call as the task running the vCPU moves to another physical
+    /// CPU, while it is inside [`VCpu::run`].
+    ///
+    /// With VMX, the VMCS is cleared on the CPU left, then made current on
+    /// the CPU entered, with the host-state fields of that CPU. The next VM
+    /// entry launches it again instead of resuming it. None are needed with
+    /// SVM.
+    ///
+    /// The hooks refer to the vCPU, and must not be called after it is
+    /// dropped.
+    pub fn migration_hooks(&self) -> MigrationHooks {
+        dispatch!(&self.arch, vcpu => vcpu.migration_hooks())
+    }
+}
+
+// Implementation of private methods
+impl<H: HyperCraftHal> VCpu<H> {
+    /// Guest `CR2`, which is not switched by VMX entries and exits.
+    fn guest_cr2(&self) -> u64 {
+        dispatch!(&self.arch, vcpu => vcpu.cr2())
+    }
+
+    /// Refresh the TSC offset if the virtual clock has been stopped and
+    /// restarted since the last VM entry.
+    fn update_tsc_offset(&mut self) -> HyperResult {
+        let generation = self.clock.generation();
+        if generation != self.clock_generation {
+            self.clock_generation = generation;
+            dispatch!(&mut self.arch, vcpu => vcpu.set_tsc_offset(&self.clock))?;
+        }
+        Ok(())
+    }
+
+    /// Emulate RDTSC and RDTSCP if the TSC can not be virtualized in hardware.
+    fn handle_rdtsc(&mut self, rdtscp: bool) -> HyperResult {
+        let input = self.replay_input(ReplayInput::Tsc { value: 0 }, |vcpu| {
+            Ok(ReplayInput::Tsc {
+                value: vcpu.clock.guest_tsc(),
+            })
+        })?;
+        let ReplayInput::Tsc { value: tsc } = input else {
+            unreachable!()
+        };
+        let regs = self.regs_mut();
+        regs.rax = tsc & 0xffff_ffff;
+        regs.rdx = tsc >> 32;
+        if rdtscp {
+            regs.rcx = super::msr::Msr::IA32_TSC_AUX.read() & 0xffff_ffff;
+        }
+        self.advance_rip(if rdtscp { 3 } else { 2 })
+    }
+
+    /// Emulate accesses to the MSRs the extension hides from the guest, and
+    /// hide the branch counter while replaying: reads return 0 and writes are
+    /// ignored. Returns `None` for other MSRs.
+    fn handle_msr(&mut self, is_write: bool) -> Option<HyperResult> {
+        const VM_EXIT_INSTR_LEN_MSR: u8 = 2;
+        let regs = self.regs();
+        let msr = regs.rcx as u32;
+        let value = (regs.rax & 0xffff_ffff) | (regs.rdx << 32);
+        if self.branch_counting && branch_counter::is_counter_msr(msr) {
+            if !is_write {
+                let regs = self.regs_mut();
+                regs.rax = 0;
+                regs.rdx = 0;
+            }
+            return Some(self.advance_rip(VM_EXIT_INSTR_LEN_MSR));
+        }
+        if is_write {
+            if !dispatch!(&mut self.arch, vcpu => vcpu.write_hidden_msr(msr, value))? {
+                self.inject_event(GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+                return Some(Ok(()));
+            }
+        } else {
+            let value = dispatch!(&self.arch, vcpu => vcpu.read_hidden_msr(msr))?;
+            let regs = self.regs_mut();
+            regs.rax = value & 0xffff_ffff;
+            regs.rdx = value >> 32;
+        }
+        Some(self.advance_rip(VM_EXIT_INSTR_LEN_MSR))
+    }
+
+    /// Emulate guest accesses to control registers: MOV to `CR0`/`CR3`/`CR4`
+    /// (and from `CR3`), CLTS and LMSW. Invalid values cause `#GP(0)`.
+    fn handle_cr_access(&mut self, instr_len: u8) -> HyperResult {
+        let info = dispatch!(&self.arch, vcpu => vcpu.cr_access_info())?;
+        let cr0 = self.cr(0) as u64;
+        let (cr_idx, mut value) = match info.access_type {
+            VmxCrAccessType::MovToCr => {
+                let value = if info.gpr == 4 {
+                    self.stack_pointer() as u64
+                } else {
+                    self.regs().get_reg_of_index(info.gpr)
+                };
+                (info.cr_number as usize, value)
+            }
+            VmxCrAccessType::MovFromCr => {
+                if info.cr_number != 3 {
+                    return Err(HyperError::NotSupported);
+                }
+                let value = self.cr(3);
+                if info.gpr == 4 {
+                    self.set_stack_pointer(value);
+                } else {
+                    self.regs_mut().set_reg_of_index(info.gpr, value as u64);
+                }
+                return self.advance_rip(instr_len);
+            }
+            VmxCrAccessType::Clts => (0, cr0 & !Cr0Flags::TASK_SWITCHED.bits()),
+            VmxCrAccessType::Lmsw => {
+                // LMSW loads CR0[3:0], but can not clear PE.
+                let msw = info.lmsw_source_data as u64 & 0xf;
+                (0, (cr0 & !0xf) | msw | (cr0 & Cr0Flags::PROTECTED_MODE_ENABLE.bits()))
+            }
+        };
+        if cr_idx == 3 && self.cr(4) as u64 & Cr4Flags::PCID.bits() != 0 {
+            // Bit 63 only means no TLB flush if CR4.PCIDE = 1. TLBs of guests
+            // are flushed on every VM entry anyway.
+            value &= !(1 << 63);
+        }
+        trace!("VM {} vcpu {} CR{} <- {:#x}", self.vm_id, self.vcpu_id, cr_idx, value);
+
+        if !dispatch!(&mut self.arch, vcpu => vcpu.emulate_cr_load(cr_idx, value))? {
+            warn!("VM {} vcpu {} loads invalid CR{} {:#x}", self.vm_id, self.vcpu_id, cr_idx, value);
+            self.inject_event(GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+            return Ok(());
+        }
+        if cr_idx == 3 {
+            H::guest_cr3_loaded(self.vm_id, self.vcpu_id, value as usize);
+        }
+        self.advance_rip(instr_len)
+    }
+
+    /// Emulate XSETBV, injecting `#GP(0)` if the new XCR0 is invalid.
+    fn handle_xsetbv(&mut self) -> HyperResult {
+        const VM_EXIT_INSTR_LEN_XSETBV: u8 = 3;
+        let regs = self.regs();
+        let index = regs.rcx as u32;
+        let value = (regs.rax & 0xffff_ffff) | (regs.rdx << 32);
+        if index != 0 || self.xstate.set_xcr0(value).is_err() {
+            warn!("VM {} vcpu {} XSETBV({:#x}, {:#x}) rejected", self.vm_id, self.vcpu_id, index, value);
+            self.inject_event(GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+            return Ok(());
+        }
+        self.advance_rip(VM_EXIT_INSTR_LEN_XSETBV)
+    }
+
+    /// Loads the `DR0`-`DR3` set by a debugger, which are not part of the
+    /// VMCS or VMCB and may be changed by other vCPUs on this CPU.
+    fn load_debug_regs(&self) {
+        use x86::debugregs::{dr0_write, dr1_write, dr2_write, dr3_write};
+        if let Some((drs, _)) = self.debug_regs {
+            unsafe {
+                dr0_write(drs[0]);
+                dr1_write(drs[1]);
+                dr2_write(drs[2]);
+                dr3_write(drs[3]);
+            }
+        }
+    }
+
+    /// Puts the recorded interrupt in front of the pending events once the
+    /// guest reaches its position, while replaying.
+    fn check_replayed_interrupt(&mut self) -> HyperResult {
+        if self.replay.is_none() {
+            return Ok(());
+        }
+        let rip = self.rip() as u64;
+        let rcx = self.regs().rcx;
+        let log = self.replay.as_mut().unwrap();
+        let step = match log.due_interrupt(rip, rcx) {
+            DueInterrupt::None => false,
+            DueInterrupt::Inject(vector) => {
+                self.pending_events.push_front((vector, None));
+                false
+            }
+            DueInterrupt::Step => true,
+        };
+        if step != self.replay_stepping {
+            self.set_monitor_trap(step)?;
+            self.replay_stepping = step;
+        }
+        Ok(())
+    }
+
+    /// Try to inject a pending event before next VM entry.
+    fn check_pending_events(&mut self) -> HyperResult {
+        if let ArchVcpu::Svm(vcpu) = &mut self.arch {
+            if vcpu.reinject_interrupted_event() {
+                return Ok(());
+            }
+        }
+        self.check_replayed_interrupt()?;
+        if let Some(&(vector, err_code)) = self.pending_events.front() {
+            let allowed = dispatch!(&self.arch, vcpu => vcpu.allow_interrupt());
+            if vector < 32 || allowed {
+                // if it's an exception, or an interrupt that is not blocked, inject it directly.
+                let cr2 = if vector == PAGE_FAULT_VECTOR {
+                    self.pending_cr2.take()
+                } else {
+                    None
+                };
+                dispatch!(&mut self.arch, vcpu => vcpu.inject(vector, err_code, cr2))?;
+                if vector >= 32 && self.replay.is_some() {
+                    let rip = self.rip() as u64;
+                    let rcx = self.regs().rcx;
+                    self.replay.as_mut().unwrap().record_interrupt(rip, rcx, vector);
+                }
+                self.pending_events.pop_front();
+            } else {
+                // interrupts are blocked, enable interrupt-window exiting.
+                self.set_interrupt_window(true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the guest states the processor does not, right before a VM entry.
+    fn prepare_entry(&mut self) {
+        self.xstate.load_xcr0();
+        dispatch!(&mut self.arch, vcpu => vcpu.prepare_entry());
+        if self.replay.is_some() {
+            branch_counter::reset();
+        }
+    }
+
+    /// Handles the exits the vCPU emulates itself, and passes the others to
+    /// [`HyperCraftHal::vmexit_handler`].
+    fn handle_exit(&mut self, exit_info: &VmExitInfo) -> HyperResult {
+        match exit_info.exit_reason {
+            VmExitReason::INTERRUPT_WINDOW => self.set_interrupt_window(false),
+            // single-stepped to a replayed interrupt, checked before VM entry
+            VmExitReason::MONITOR_TRAP_FLAG if self.replay_stepping => Ok(()),
+            VmExitReason::RDTSC => self.handle_rdtsc(false),
+            VmExitReason::RDTSCP => self.handle_rdtsc(true),
+            VmExitReason::XSETBV => self.handle_xsetbv(),
+            VmExitReason::CR_ACCESS => {
+                self.handle_cr_access(exit_info.exit_instruction_length as u8)
+            }
+            VmExitReason::MSR_READ | VmExitReason::MSR_WRITE => {
+                let is_write = exit_info.exit_reason == VmExitReason::MSR_WRITE;
+                match self.handle_msr(is_write) {
+                    Some(result) => result,
+                    None => H::vmexit_handler(self),
+                }
+            }
+            VmExitReason::EXCEPTION_NMI => {
+                let info = self.interrupt_exit_info()?;
+                let intercepted =
+                    dispatch!(&self.arch, vcpu => vcpu.exception_exiting(info.vector));
+                if info.valid && info.int_type != VmxInterruptionType::NMI && !intercepted {
+                    // Intercepted only for single-stepping, give it back to the guest.
+                    self.inject_event(info.vector, info.err_code);
+                    Ok(())
+                } else {
+                    H::vmexit_handler(self)
+                }
+            }
+            VmExitReason::VIRTUALIZATION_INSTRUCTION => {
+                self.inject_event(INVALID_OPCODE_VECTOR, None);
+                Ok(())
+            }
+            _ => H::vmexit_handler(self),
+        }
+    }
+
+    /// Handles a VM exit, and returns whether to enter the guest again.
+    fn vmexit_handler(&mut self) -> bool {
+        let exit_ns = H::current_time_nanos();
+        let guest_ns = exit_ns.saturating_sub(self.entered_at_ns);
+        // Save the guest extended states eagerly, as the exit handler may
+        // yield to other vCPUs. The host itself never touches them.
+        self.xstate.save();
+        dispatch!(&mut self.arch, vcpu => vcpu.exited());
+
+        let (result, trace) = match self.exit_info() {
+            Ok(exit_info) => {
+                if exit_info.entry_failure {
+                    self.dump_exit_trace();
+                    panic!("VM entry failed: {:#x?}\n{:#x?}", exit_info, self);
+                }
+                if exit_info.exit_reason == VmExitReason::PREEMPTION_TIMER {
+                    info!("VM {} vcpu {} vmexit with {:#x?}!!!",self.vm_id, self.vcpu_id, exit_info.exit_reason);
+                }
+                trace!("VM exit: {:#x?}", exit_info);
+
+                let qualification = self.exit_qualification().unwrap();
+                let port = (exit_info.exit_reason == VmExitReason::IO_INSTRUCTION)
+                    .then(|| qualification.get_bits(16..32) as u16);
+                let msr = matches!(
+                    exit_info.exit_reason,
+                    VmExitReason::MSR_READ | VmExitReason::MSR_WRITE
+                )
+                .then(|| self.regs().rcx as u32);
+                if let Some(log) = self.replay.as_mut() {
+                    log.count_exit(exit_info.exit_reason, branch_counter::read());
+                }
+                let entry = ExitTraceEntry {
+                    time_ns: exit_ns,
+                    reason: exit_info.exit_reason,
+                    qualification,
+                    rip: exit_info.guest_rip,
+                    handler_ns: 0,
+                };
+                (self.handle_exit(&exit_info), Some((entry, port, msr)))
+            }
+            // Left to the hypervisor, which can not handle it either, but may
+            // shut the VM down.
+            Err(_) => (H::vmexit_handler(self), None),
+        };
+
+        if let Err(err) = result {
+            let reason = trace.as_ref().map(|(entry, ..)| entry.reason);
+            self.record_exit(trace, guest_ns);
+            self.dump_exit_trace();
+            panic!(
+                "Failed to handle VM-exit {:?}, error {:?}:\n{:#x?}",
+                reason, err, self
+            );
+        }
+
+        // Handle some vmexits concerning apic timer and events here.
+        // Theoretically the best practice is enabling users to inject
+        // anything they want (including apic timers) to vcpus and let
+        // them handle all vmexits, but it's not very pragmatic now.
+        if self.apic_timer.check_interrupt() {
+            self.inject_event(self.apic_timer.vector(), None);
+        }
+        // Let the host kick us out at the next APIC timer deadline, instead
+        // of waiting for an unrelated VM exit.
+        self.update_tsc_offset().unwrap();
+        let deadline = self.apic_timer.deadline_ns().map(|ns| self.clock.to_host_ns(ns));
+        if deadline != self.armed_timer_deadline {
+            H::set_vcpu_timer(self.vm_id, self.vcpu_id, deadline);
+            self.armed_timer_deadline = deadline;
+        }
+        self.load_debug_regs();
+        self.check_pending_events().unwrap();
+        self.record_exit(trace, guest_ns);
+        !self.stopping
+    }
+
+    /// Counts the VM exit traced by `trace`, which occurred after `guest_ns`
+    /// in the guest, with the port or MSR it accessed, and starts timing the
+    /// guest again. Exits unknown to the vCPU are not counted.
+    fn record_exit(&mut self, trace: Option<(ExitTraceEntry, Option<u16>, Option<u32>)>, guest_ns: u64) {
+        let now = H::current_time_nanos();
+        if let Some((mut entry, port, msr)) = trace {
+            entry.handler_ns = now.saturating_sub(entry.time_ns);
+            self.exit_stats.record(entry, guest_ns, port, msr);
+        }
+        self.entered_at_ns = now;
+    }
+
+    /// Logs the traced VM exits, if any, before the VM crashes.
+    fn dump_exit_trace(&self) {
+        let trace = self.exit_stats.trace();
+        if trace.is_empty() {
+            return;
+        }
+        error!("VM {} vcpu {} last {} VM exits:", self.vm_id, self.vcpu_id, trace.len());
+        for e in trace {
+            error!(
+                "  [{}.{:09}] {:?} rip={:#x} qual={:#x} handler={}ns",
+                e.time_ns / 1_000_000_000,
+                e.time_ns % 1_000_000_000,
+                e.reason,
+                e.rip,
+                e.qualification,
+                e.handler_ns
+            );
+        }
+    }
+}
+
+impl<H: HyperCraftHal> Drop for VCpu<H> {
+    fn drop(&mut self) {
+        if self.armed_timer_deadline.is_some() {
+            H::set_vcpu_timer(self.vm_id, self.vcpu_id, None);
+        }
+    }
+}
+
+impl<H: HyperCraftHal> GuestPagingBackend for VCpu<H> {
+    type Hal = H;
+
+    fn paging_context(&self) -> HyperResult<GuestPagingContext> {
+        dispatch!(&self.arch, vcpu => vcpu.paging_context())
+    }
+
+    fn nested_translate(&self, gpa: GuestPhysAddr, write: bool) -> GuestMemoryResult<HostPhysAddr> {
+        dispatch!(&self.arch, vcpu => vcpu.nested_translate(gpa, write))
+    }
+
+    fn linear_rip(&self) -> HyperResult<usize> {
+        dispatch!(&self.arch, vcpu => vcpu.linear_rip())
+    }
+
+    fn inject_page_fault_event(&mut self, fault: GuestPageFault) {
+        self.set_pending_cr2(fault.vaddr);
+        self.inject_event(PAGE_FAULT_VECTOR, Some(fault.error_code.bits()));
+    }
+
+    fn pae_pdpte(&self, ctx: &GuestPagingContext, index: usize) -> GuestMemoryResult<u64> {
+        match &self.arch {
+            // The PDPTEs are loaded into the VMCS when EPT is enabled.
+            ArchVcpu::Vmx(vcpu) => Ok(vcpu.pae_pdpte(index)?),
+            // Read from guest memory at CR3, as the processor does.
+            ArchVcpu::Svm(_) => {
+                let gpa = (ctx.cr3 & 0xffff_ffe0) + index * 8;
+                let hpa = self.nested_translate(gpa, false)?;
+                Ok(unsafe { (H::phys_to_virt(hpa) as *const u64).read_volatile() })
+            }
+        }
+    }
+}
+
+impl<H: HyperCraftHal> Debug for VCpu<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        dispatch!(&self.arch, vcpu => vcpu.fmt(f))
+    }
+}
+
+#[cfg(test)]
+mod tests;
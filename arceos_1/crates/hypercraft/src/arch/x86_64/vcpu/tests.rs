@@ -0,0 +1,299 @@
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+
+use super::{ArchVcpu, VCpu, VmxVcpu};
+use crate::arch::clock::VirtClock;
+use crate::arch::exit::VmExitReason;
+use crate::arch::io_bitmap::IoBitmap;
+use crate::arch::mock::{InMemoryHw, MockHal};
+use crate::arch::vmx::vmcs::controls::PrimaryControls;
+use crate::arch::vmx::vmcs::{VmcsControl32, VmcsGuestNW, VmcsHost16, VmcsReadOnly32};
+use crate::arch::vmx::VmxExitReason;
+
+const ENTRY: usize = 0x7c00;
+const RFLAGS_IF: usize = 0x202;
+const VECTOR: u8 = 0x30;
+/// Valid bit of the VM-entry interruption-information field.
+const VALID: u64 = 1 << 31;
+
+fn new_vcpu() -> VCpu<MockHal> {
+    let clock = Arc::new(VirtClock::new(None));
+    clock.vcpu_scheduled();
+    let io_bitmap = Arc::new(IoBitmap::intercept_all().unwrap());
+    VCpu::new(0, 0, 1, ENTRY, 0x1000, clock, io_bitmap).unwrap()
+}
+
+/// The VMX part of the vCPU, as the in-memory processor only implements VMX.
+fn vmx(vcpu: &mut VCpu<MockHal>) -> &mut VmxVcpu<MockHal> {
+    match &mut vcpu.arch {
+        ArchVcpu::Vmx(vmx) => vmx,
+        ArchVcpu::Svm(_) => unreachable!(),
+    }
+}
+
+/// Writes the information of a VM exit into the current VMCS.
+fn fake_exit(reason: VmxExitReason, instr_len: u32) {
+    InMemoryHw::write_field(VmcsReadOnly32::EXIT_REASON as u32, reason as u64);
+    InMemoryHw::write_field(VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN as u32, instr_len as u64);
+}
+
+/// Fakes a VM exit of the vCPU and handles it, then prepares the next VM
+/// entry if any, as in `run`.
+fn exit(vcpu: &mut VCpu<MockHal>, reason: VmxExitReason, instr_len: u32) -> bool {
+    fake_exit(reason, instr_len);
+    let resume = vcpu.vmexit_handler();
+    if resume {
+        vcpu.prepare_entry();
+    }
+    resume
+}
+
+/// The event injected at the next VM entry.
+fn injected_event() -> u64 {
+    InMemoryHw::read_field(VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD as u32)
+}
+
+/// Sets the SSE state of the processor as a guest would: `MXCSR`, and `XMM15`
+/// which the compiled test code leaves alone.
+fn set_sse_state(mxcsr: u32, xmm15: u128) {
+    unsafe {
+        asm!("ldmxcsr [{}]", in(reg) &mxcsr);
+        asm!("movdqu xmm15, [{}]", in(reg) &xmm15, out("xmm15") _);
+    }
+}
+
+fn sse_state() -> (u32, u128) {
+    let mut mxcsr = 0u32;
+    let mut xmm15 = 0u128;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr);
+        asm!("movdqu [{}], xmm15", in(reg) &mut xmm15);
+    }
+    (mxcsr, xmm15)
+}
+
+fn interrupt_window_exiting() -> bool {
+    let ctrl = InMemoryHw::read_field(VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS as u32);
+    ctrl & PrimaryControls::INTERRUPT_WINDOW_EXITING.bits() as u64 != 0
+}
+
+#[test]
+fn initial_guest_state() {
+    let mut vcpu = new_vcpu();
+    assert_eq!(InMemoryHw::current_vmcs(), Some(vmx(&mut vcpu).vmcs_paddr()));
+    assert_eq!(vcpu.rip(), ENTRY);
+    assert_eq!(vcpu.rflags(), 0x2);
+    // ET and NE as seen by the guest, VMXE hidden
+    assert_eq!(vcpu.cr(0), 0x30);
+    assert_eq!(vcpu.cr(4), 0);
+    assert_eq!(VmcsGuestNW::CR4.read().unwrap() & 0x2000, 0x2000);
+    assert_eq!(InMemoryHw::invept_count(), 1);
+}
+
+#[test]
+fn interrupt_injected_when_allowed() {
+    let mut vcpu = new_vcpu();
+    vcpu.set_rflags(RFLAGS_IF).unwrap();
+    vcpu.inject_event(VECTOR, None);
+    vcpu.check_pending_events().unwrap();
+    assert_eq!(injected_event(), VALID | VECTOR as u64);
+    assert!(!vcpu.has_pending_events());
+}
+
+#[test]
+fn interrupt_waits_for_window() {
+    let mut vcpu = new_vcpu();
+    vcpu.inject_event(VECTOR, None);
+    vcpu.check_pending_events().unwrap();
+    assert_eq!(injected_event(), 0);
+    assert!(vcpu.has_pending_events());
+    assert!(interrupt_window_exiting());
+
+    // the guest executes STI
+    vcpu.set_rflags(RFLAGS_IF).unwrap();
+    assert!(exit(&mut vcpu, VmxExitReason::INTERRUPT_WINDOW, 0));
+    assert!(!interrupt_window_exiting());
+    assert_eq!(injected_event(), VALID | VECTOR as u64);
+    assert!(MockHal::take_exits().is_empty());
+}
+
+#[test]
+fn exception_with_error_code() {
+    let mut vcpu = new_vcpu();
+    // exceptions are not blocked by RFLAGS.IF
+    vcpu.inject_event(13, Some(0x10));
+    vcpu.check_pending_events().unwrap();
+    // hardware exception, with an error code
+    assert_eq!(injected_event(), VALID | 3 << 8 | 1 << 11 | 13);
+    let err_code = InMemoryHw::read_field(VmcsControl32::VMENTRY_EXCEPTION_ERR_CODE as u32);
+    assert_eq!(err_code, 0x10);
+}
+
+#[test]
+fn page_fault_loads_cr2() {
+    let mut vcpu = new_vcpu();
+    vcpu.set_pending_cr2(0xdead_b000);
+    vcpu.inject_event(14, Some(0b10));
+    assert_eq!(InMemoryHw::cr2(), 0);
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+    assert_eq!(InMemoryHw::cr2(), 0xdead_b000);
+    assert_eq!(injected_event() & 0xff, 14);
+    MockHal::take_exits();
+}
+
+#[test]
+fn cr2_switched_between_vcpus() {
+    let mut vcpu = new_vcpu();
+    let mut other = new_vcpu();
+    vcpu.load_vmcs().unwrap();
+    InMemoryHw::set_cr2(0x1000); // set by the guest
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+    other.load_vmcs().unwrap();
+    InMemoryHw::set_cr2(0x2000);
+    assert!(exit(&mut other, VmxExitReason::CPUID, 2));
+    assert_eq!(InMemoryHw::cr2(), 0x2000);
+    assert_eq!((vcpu.guest_cr2(), other.guest_cr2()), (0x1000, 0x2000));
+    MockHal::take_exits();
+}
+
+#[test]
+fn sse_state_switched_between_vcpus() {
+    // Both guests only enabled x87 in XCR0, but use SSE.
+    let mut vcpu = new_vcpu();
+    let mut other = new_vcpu();
+    vcpu.load_vmcs().unwrap();
+    vcpu.xstate.load_xcr0(); // as before VM entries
+    assert_eq!(InMemoryHw::xcr0(), 1);
+    set_sse_state(0x1f82, 0x1234);
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+
+    other.load_vmcs().unwrap();
+    assert_eq!(sse_state(), (0x1f80, 0));
+    set_sse_state(0x1f81, 0x5678);
+    assert!(exit(&mut other, VmxExitReason::CPUID, 2));
+
+    vcpu.load_vmcs().unwrap();
+    assert_eq!(sse_state(), (0x1f82, 0x1234));
+    other.load_vmcs().unwrap();
+    assert_eq!(sse_state(), (0x1f81, 0x5678));
+    set_sse_state(0x1f80, 0);
+    MockHal::take_exits();
+}
+
+#[test]
+fn rdtsc_emulated() {
+    let mut vcpu = new_vcpu();
+    MockHal::advance_ns(1_000_000_000);
+    assert!(exit(&mut vcpu, VmxExitReason::RDTSC, 2));
+    let tsc = vcpu.clock().guest_tsc();
+    assert_eq!(tsc, 2_000_000_000);
+    assert_eq!(vcpu.regs().rax, tsc & 0xffff_ffff);
+    assert_eq!(vcpu.regs().rdx, tsc >> 32);
+    assert_eq!(vcpu.rip(), ENTRY + 2);
+    assert!(MockHal::take_exits().is_empty());
+}
+
+#[test]
+fn other_exits_forwarded() {
+    let mut vcpu = new_vcpu();
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+    assert!(exit(&mut vcpu, VmxExitReason::IO_INSTRUCTION, 1));
+    assert_eq!(
+        MockHal::take_exits(),
+        [VmExitReason::CPUID, VmExitReason::IO_INSTRUCTION]
+    );
+    vcpu.stop();
+    assert!(!exit(&mut vcpu, VmxExitReason::HLT, 1));
+}
+
+#[test]
+fn apic_timer_injected_at_exits() {
+    let mut vcpu = new_vcpu();
+    vcpu.set_rflags(RFLAGS_IF).unwrap();
+    let timer = vcpu.apic_timer_mut();
+    timer.set_divide(0b1011).unwrap();
+    timer.set_lvt_timer(VECTOR as u32).unwrap();
+    timer.set_initial_count(1000).unwrap();
+
+    // the host timer is armed at the deadline
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+    let deadline = vcpu.clock().to_host_ns(1000);
+    assert_eq!(MockHal::vcpu_timer(0, 0), Some(deadline));
+    assert_eq!(injected_event(), 0);
+
+    MockHal::advance_ns(1000);
+    assert!(exit(&mut vcpu, VmxExitReason::EXTERNAL_INTERRUPT, 0));
+    assert_eq!(injected_event(), VALID | VECTOR as u64);
+    assert_eq!(MockHal::vcpu_timer(0, 0), None);
+}
+
+#[test]
+fn halted_vcpu_waits_for_apic_timer() {
+    let mut vcpu = new_vcpu();
+    // The VM was paused for a while, its virtual time is behind the host.
+    vcpu.clock().pause();
+    MockHal::advance_ns(1_000_000_000);
+    vcpu.clock().resume();
+    let timer = vcpu.apic_timer_mut();
+    timer.set_divide(0b1011).unwrap();
+    timer.set_lvt_timer(VECTOR as u32).unwrap();
+    timer.set_initial_count(1000).unwrap();
+
+    // the guest halts, and blocks until the deadline
+    assert!(exit(&mut vcpu, VmxExitReason::HLT, 1));
+    assert!(!vcpu.apic_timer_mut().is_due());
+    MockHal::advance_ns(999);
+    assert!(!vcpu.apic_timer_mut().is_due());
+    MockHal::advance_ns(1);
+    assert!(vcpu.apic_timer_mut().is_due());
+    MockHal::take_exits();
+}
+
+#[test]
+fn state_moves_between_vcpus() {
+    let mut vcpu = new_vcpu();
+    vcpu.set_rip(0x1234).unwrap();
+    vcpu.set_cr(3, 0x5000).unwrap();
+    vcpu.regs_mut().rbx = 7;
+    vmx(&mut vcpu).set_cr2(0x1000);
+    vcpu.set_pending_cr2(0x2000);
+    vcpu.inject_event(14, Some(0b10));
+    let state = vcpu.save_state().unwrap();
+
+    let mut other = new_vcpu();
+    assert_eq!(other.rip(), ENTRY);
+    other.restore_state(&state).unwrap();
+    assert_eq!(other.rip(), 0x1234);
+    assert_eq!(other.cr(0), 0x30);
+    assert_eq!(other.cr(3), 0x5000);
+    assert_eq!(other.regs().rbx, 7);
+    assert_eq!(other.guest_cr2(), 0x1000);
+    assert!(other.has_pending_events());
+    assert!(exit(&mut other, VmxExitReason::CPUID, 2));
+    assert_eq!(InMemoryHw::cr2(), 0x2000);
+    assert_eq!(injected_event() & 0xff, 14);
+    MockHal::take_exits();
+}
+
+#[test]
+fn vmcs_launched_again_after_migration() {
+    let mut vcpu = new_vcpu();
+    let paddr = vmx(&mut vcpu).vmcs_paddr();
+    // entered once, as in `run`
+    vmx(&mut vcpu).launched().store(true, Ordering::Relaxed);
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+
+    let hooks = vcpu.migration_hooks();
+    (hooks.leave_cpu)(hooks.arg);
+    assert_eq!(InMemoryHw::current_vmcs(), None);
+    assert!(!vmx(&mut vcpu).launched().load(Ordering::Relaxed));
+    (hooks.enter_cpu)(hooks.arg);
+    assert_eq!(InMemoryHw::current_vmcs(), Some(paddr));
+    assert_eq!(InMemoryHw::read_field(VmcsHost16::TR_SELECTOR as u32), 0x18);
+
+    assert!(exit(&mut vcpu, VmxExitReason::CPUID, 2));
+    vcpu.stop();
+    assert!(!exit(&mut vcpu, VmxExitReason::HLT, 1));
+    MockHal::take_exits();
+}
@@ -0,0 +1,111 @@
+use alloc::vec::Vec;
+
+use super::lapic::ApicTimerState;
+use super::saved_guest_fields;
+use crate::arch::regs::GeneralRegisters;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::{HyperError, HyperResult};
+
+/// Architectural state of a vCPU, saved by [`VCpu::save_state`] and loaded
+/// by [`VCpu::restore_state`].
+///
+/// [`VCpu::save_state`]: super::VCpu::save_state
+/// [`VCpu::restore_state`]: super::VCpu::restore_state
+#[derive(Debug, Clone, Default)]
+pub struct VcpuState {
+    /// General-purpose registers except `RSP`.
+    pub regs: GeneralRegisters,
+    /// `CR0`, `CR2`, `CR3` and `CR4` as seen by the guest.
+    pub crs: [u64; 4],
+    /// Encodings and values of the saved guest-state fields of the VMCS, or
+    /// offsets and values of those of the VMCB.
+    pub guest_fields: Vec<(u32, u64)>,
+    /// Guest `XCR0`.
+    pub xcr0: u64,
+    /// The XSAVE (or FXSAVE) area of the extended states.
+    pub xsave_area: Vec<u8>,
+    /// The local APIC timer.
+    pub apic_timer: ApicTimerState,
+    /// Interrupts and exceptions not injected yet, and their error codes.
+    pub pending_events: Vec<(u8, Option<u32>)>,
+    /// Guest `CR2` to be loaded with a pending page fault.
+    pub pending_cr2: Option<u64>,
+}
+
+impl VcpuState {
+    /// Appends the state to `w`.
+    pub fn encode(&self, w: &mut StateWriter) {
+        for idx in (0..16).filter(|&i| i != 4) {
+            w.put_u64(self.regs.get_reg_of_index(idx));
+        }
+        for cr in self.crs {
+            w.put_u64(cr);
+        }
+        w.put_u32(self.guest_fields.len() as u32);
+        for &(field, value) in &self.guest_fields {
+            w.put_u32(field);
+            w.put_u64(value);
+        }
+        w.put_u64(self.xcr0);
+        w.put_bytes(&self.xsave_area);
+
+        let timer = &self.apic_timer;
+        w.put_u32(timer.lvt_timer);
+        w.put_u8(timer.divide_shift);
+        w.put_u32(timer.initial_count);
+        w.put_u64(timer.last_start_ns);
+        w.put_u64(timer.deadline_ns);
+        w.put_u64(timer.deadline_tsc);
+
+        w.put_u32(self.pending_events.len() as u32);
+        for &(vector, err_code) in &self.pending_events {
+            w.put_u8(vector);
+            w.put_bool(err_code.is_some());
+            w.put_u32(err_code.unwrap_or(0));
+        }
+        w.put_bool(self.pending_cr2.is_some());
+        w.put_u64(self.pending_cr2.unwrap_or(0));
+    }
+
+    /// Reads a state written by [`VcpuState::encode`].
+    pub fn decode(r: &mut StateReader) -> HyperResult<Self> {
+        let mut state = Self::default();
+        for idx in (0..16).filter(|&i| i != 4) {
+            state.regs.set_reg_of_index(idx, r.get_u64()?);
+        }
+        for cr in state.crs.iter_mut() {
+            *cr = r.get_u64()?;
+        }
+        for _ in 0..r.get_u32()? {
+            let field = r.get_u32()?;
+            if !saved_guest_fields().contains(&field) {
+                return Err(HyperError::DecodeError);
+            }
+            state.guest_fields.push((field, r.get_u64()?));
+        }
+        state.xcr0 = r.get_u64()?;
+        state.xsave_area = r.get_bytes()?.to_vec();
+
+        state.apic_timer = ApicTimerState {
+            lvt_timer: r.get_u32()?,
+            divide_shift: r.get_u8()?,
+            initial_count: r.get_u32()?,
+            last_start_ns: r.get_u64()?,
+            deadline_ns: r.get_u64()?,
+            deadline_tsc: r.get_u64()?,
+        };
+
+        for _ in 0..r.get_u32()? {
+            let vector = r.get_u8()?;
+            let has_err_code = r.get_bool()?;
+            let err_code = r.get_u32()?;
+            state
+                .pending_events
+                .push((vector, has_err_code.then_some(err_code)));
+        }
+        let has_pending_cr2 = r.get_bool()?;
+        let pending_cr2 = r.get_u64()?;
+        state.pending_cr2 = has_pending_cr2.then_some(pending_cr2);
+        Ok(state)
+    }
+}
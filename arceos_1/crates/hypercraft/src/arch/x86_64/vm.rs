@@ -0,0 +1,88 @@
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperResult, HyperError};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use super::VCpu;
+use crate::arch::clock::VirtClock;
+use crate::arch::io_bitmap::IoBitmap;
+
+/// the struct of VM
+#[repr(C)]
+pub struct VM<H: HyperCraftHal> {
+    id: usize,
+    vcpu_count: usize,
+    vcpu: Vec<VCpu<H>>,
+    clock: Arc<VirtClock<H>>,
+    io_bitmap: Arc<IoBitmap<H>>,
+}
+
+impl<H: HyperCraftHal> VM<H> {
+    /// create new VM
+    pub fn new(
+        id: usize,
+    ) -> HyperResult<Self> {
+        Ok(Self {
+            id: id,
+            vcpu_count: 0,
+            vcpu: Vec::new(),
+            clock: Arc::new(VirtClock::new(None)),
+            io_bitmap: Arc::new(IoBitmap::intercept_all()?),
+        })
+    }
+    /// Set the TSC frequency seen by the guest, the host TSC frequency is
+    /// used by default. Must be called before any vcpu is added.
+    pub fn set_guest_tsc_mhz(&mut self, mhz: u64) -> HyperResult {
+        if self.vcpu_count != 0 {
+            return Err(HyperError::BadState);
+        }
+        if mhz == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.clock = Arc::new(VirtClock::new(Some(mhz)));
+        Ok(())
+    }
+    /// Returns the virtual clock of the VM.
+    pub fn clock(&self) -> &VirtClock<H> {
+        &self.clock
+    }
+    /// Let the guest access the I/O `ports` directly, all ports are
+    /// intercepted by default. Must be called before any vcpu is added.
+    pub fn set_io_passthrough(&mut self, ports: RangeInclusive<u16>) -> HyperResult {
+        if ports.is_empty() {
+            return Err(HyperError::InvalidParam);
+        }
+        let io_bitmap = Arc::get_mut(&mut self.io_bitmap).ok_or(HyperError::BadState)?;
+        io_bitmap.set_intercept(ports, false);
+        Ok(())
+    }
+    /// add a new vcpu to VM
+    pub fn add_vcpu(&mut self, vmcs_revision_id: u32, entry: GuestPhysAddr, npt_root: HostPhysAddr) -> HyperResult<usize> {
+        self.vcpu.push(VCpu::new(
+            self.id,
+            self.vcpu_count,
+            vmcs_revision_id,
+            entry,
+            npt_root,
+            self.clock.clone(),
+            self.io_bitmap.clone(),
+        )?);
+        // update vcpu_count
+        self.vcpu_count += 1;
+        Ok(self.vcpu_count - 1)
+    }
+    /// Returns a reference to the vCPU with `vcpu_id` if it exists.
+    pub fn get_vcpu(&mut self, vcpu_id: usize) -> HyperResult<&mut VCpu<H>> {
+        info!("{} {}", vcpu_id, self.vcpu_count);
+        if vcpu_id < self.vcpu_count {
+            let vcpu = &mut self.vcpu[vcpu_id];
+            Ok(vcpu)
+        } else {
+            Err(HyperError::NotFound)
+        }
+        
+    }
+    /// get vm id
+    pub fn get_vm_id(&self) -> usize{
+        self.id
+    }
+}
\ No newline at end of file
@@ -3,12 +3,12 @@ mod detect;
 mod percpu;
 mod region;
 mod vcpu;
-mod vmcs;
-mod vm;
+pub(crate) mod vmcs;
 
 pub use detect::has_hardware_support;
 pub use percpu::VmxPerCpuState;
 pub use vcpu::VmxVcpu;
-pub use definitions::VmxExitReason;
-pub use vmcs::VmxExitInfo;
-pub use vm::VM;
+pub(crate) use vcpu::{CORE_REG_FIELDS, SAVED_GUEST_FIELDS};
+pub use definitions::{VmxExitReason, VmxInterruptionType};
+pub use vmcs::{flush_ept, VmxCrAccessInfo, VmxCrAccessType, VmxInterruptInfo, VmxIoExitInfo};
+pub(crate) use vmcs::{invept, InvEptType};
@@ -13,6 +13,9 @@ pub struct VmxPerCpuState<H: HyperCraftHal> {
 }
 
 impl<H: HyperCraftHal> VmxPerCpuState<H> {
+    /// Name of the hardware virtualization extension.
+    pub const EXTENSION: &'static str = "VMX";
+
     pub const fn new() -> Self {
         Self {
             vmcs_revision_id: 0,
@@ -84,6 +87,10 @@ impl<H: HyperCraftHal> VmxPerCpuState<H> {
         unsafe {
             // Enable VMX using the VMXE bit.
             Cr4::write(Cr4::read() | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS);
+            // Enable XSAVE and XCR0 to switch extended FPU states of guests.
+            if crate::arch::xstate::has_xsave() {
+                Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            }
             // Execute VMXON.
             vmx::vmxon(self.vmx_region.phys_addr() as _)?;
         }
@@ -1,161 +1,642 @@
-use alloc::collections::VecDeque;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{arch::asm, mem::size_of};
 
 use bit_field::BitField;
-use x86::bits64::vmx;
-use x86::dtables::{self, DescriptorTablePointer};
-use x86::segmentation::SegmentSelector;
-use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::rflags::RFlags;
 
 use super::region::{MsrBitmap, VmxRegion};
+use crate::arch::branch_counter;
+use crate::arch::io_bitmap::IoBitmap;
 use super::vmcs::{
     self, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64,
-    VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
+    VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW, VmcsReadOnly32,
+    VmxCrAccessInfo, VmxInterruptInfo, VmxIoExitInfo,
 };
-use super::VmxPerCpuState;
+use crate::arch::ept::ept_translate;
+use crate::arch::exit::{VmExitInfo, VmExitReason};
+use crate::arch::hw::{Hw, HwAccess};
+use crate::arch::guest_memory::{GuestMemoryResult, GuestPagingContext};
 use super::definitions::VmxExitReason;
 use crate::arch::{msr::Msr, memory::NestedPageFaultInfo, regs::GeneralRegisters};
-use crate::arch::lapic::ApicTimer;
-use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperResult};
+use crate::arch::migration::MigrationHooks;
+use crate::arch::clock::VirtClock;
+use crate::arch::coredump::CoreRegFields;
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult};
 
 const PREEMPTION_TIMER_VALUE: u32 = 80000000; 
+/// Max TSC ticks between two PAUSEs to be considered in the same spin loop.
+const PLE_GAP: u32 = 128;
+/// TSC ticks a guest may spin in a PAUSE loop before a VM exit occurs.
+const PLE_WINDOW: u32 = 4096;
 
-/// A virtual CPU within a guest.
+/// `CR0` bits owned by the hypervisor. Guest writes changing them cause VM
+/// exits, and guest reads get the values in the read shadow.
+const CR0_HOST_OWNED: Cr0Flags = Cr0Flags::PROTECTED_MODE_ENABLE
+    .union(Cr0Flags::PAGING)
+    .union(Cr0Flags::NUMERIC_ERROR)
+    .union(Cr0Flags::NOT_WRITE_THROUGH)
+    .union(Cr0Flags::CACHE_DISABLE);
+/// `CR4` bits owned by the hypervisor.
+const CR4_HOST_OWNED: Cr4Flags = Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS
+    .union(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+    .union(Cr4Flags::PAGE_SIZE_EXTENSION)
+    .union(Cr4Flags::L5_PAGING);
+
+/// Guest-state fields saved in [`VcpuState::guest_fields`]. Control registers
+/// are saved separately, and the VMCS link pointer and the preemption timer
+/// are set up by the hypervisor.
+pub(crate) const SAVED_GUEST_FIELDS: &[u32] = &[
+    VmcsGuest16::ES_SELECTOR as u32,
+    VmcsGuest16::CS_SELECTOR as u32,
+    VmcsGuest16::SS_SELECTOR as u32,
+    VmcsGuest16::DS_SELECTOR as u32,
+    VmcsGuest16::FS_SELECTOR as u32,
+    VmcsGuest16::GS_SELECTOR as u32,
+    VmcsGuest16::LDTR_SELECTOR as u32,
+    VmcsGuest16::TR_SELECTOR as u32,
+    VmcsGuest64::IA32_DEBUGCTL as u32,
+    VmcsGuest64::IA32_PAT as u32,
+    VmcsGuest64::IA32_EFER as u32,
+    VmcsGuest64::PDPTE0 as u32,
+    VmcsGuest64::PDPTE1 as u32,
+    VmcsGuest64::PDPTE2 as u32,
+    VmcsGuest64::PDPTE3 as u32,
+    VmcsGuest32::ES_LIMIT as u32,
+    VmcsGuest32::CS_LIMIT as u32,
+    VmcsGuest32::SS_LIMIT as u32,
+    VmcsGuest32::DS_LIMIT as u32,
+    VmcsGuest32::FS_LIMIT as u32,
+    VmcsGuest32::GS_LIMIT as u32,
+    VmcsGuest32::LDTR_LIMIT as u32,
+    VmcsGuest32::TR_LIMIT as u32,
+    VmcsGuest32::GDTR_LIMIT as u32,
+    VmcsGuest32::IDTR_LIMIT as u32,
+    VmcsGuest32::ES_ACCESS_RIGHTS as u32,
+    VmcsGuest32::CS_ACCESS_RIGHTS as u32,
+    VmcsGuest32::SS_ACCESS_RIGHTS as u32,
+    VmcsGuest32::DS_ACCESS_RIGHTS as u32,
+    VmcsGuest32::FS_ACCESS_RIGHTS as u32,
+    VmcsGuest32::GS_ACCESS_RIGHTS as u32,
+    VmcsGuest32::LDTR_ACCESS_RIGHTS as u32,
+    VmcsGuest32::TR_ACCESS_RIGHTS as u32,
+    VmcsGuest32::INTERRUPTIBILITY_STATE as u32,
+    VmcsGuest32::ACTIVITY_STATE as u32,
+    VmcsGuest32::IA32_SYSENTER_CS as u32,
+    VmcsGuestNW::ES_BASE as u32,
+    VmcsGuestNW::CS_BASE as u32,
+    VmcsGuestNW::SS_BASE as u32,
+    VmcsGuestNW::DS_BASE as u32,
+    VmcsGuestNW::FS_BASE as u32,
+    VmcsGuestNW::GS_BASE as u32,
+    VmcsGuestNW::LDTR_BASE as u32,
+    VmcsGuestNW::TR_BASE as u32,
+    VmcsGuestNW::GDTR_BASE as u32,
+    VmcsGuestNW::IDTR_BASE as u32,
+    VmcsGuestNW::DR7 as u32,
+    VmcsGuestNW::RSP as u32,
+    VmcsGuestNW::RIP as u32,
+    VmcsGuestNW::RFLAGS as u32,
+    VmcsGuestNW::PENDING_DBG_EXCEPTIONS as u32,
+    VmcsGuestNW::IA32_SYSENTER_ESP as u32,
+    VmcsGuestNW::IA32_SYSENTER_EIP as u32,
+];
+
+/// Guest-state fields holding the registers of core files.
+pub(crate) const CORE_REG_FIELDS: CoreRegFields = CoreRegFields {
+    rip: VmcsGuestNW::RIP as u32,
+    rflags: VmcsGuestNW::RFLAGS as u32,
+    rsp: VmcsGuestNW::RSP as u32,
+    cs: VmcsGuest16::CS_SELECTOR as u32,
+    ss: VmcsGuest16::SS_SELECTOR as u32,
+    ds: VmcsGuest16::DS_SELECTOR as u32,
+    es: VmcsGuest16::ES_SELECTOR as u32,
+    fs: VmcsGuest16::FS_SELECTOR as u32,
+    gs: VmcsGuest16::GS_SELECTOR as u32,
+    fs_base: VmcsGuestNW::FS_BASE as u32,
+    gs_base: VmcsGuestNW::GS_BASE as u32,
+};
+
+/// Physical address of a VMCS and whether it has been launched on the CPU
+/// it is active on, reachable from the [`MigrationHooks`] of the vCPU.
+struct LoadedVmcs {
+    paddr: u64,
+    launched: AtomicBool,
+}
+
+/// The VMX part of a [`VCpu`](crate::arch::VCpu): the guest state in the
+/// VMCS, and the guest registers it does not hold.
 #[repr(C)]
 pub struct VmxVcpu<H: HyperCraftHal> {
     guest_regs: GeneralRegisters,
     host_stack_top: u64,
     vmcs: VmxRegion<H>,
+    loaded_vmcs: Box<LoadedVmcs>,
     msr_bitmap: MsrBitmap<H>,
-    apic_timer: ApicTimer<H>,
-    pending_events: VecDeque<(u8, Option<u32>)>,
-    vcpu_id: usize,
-    vm_id: usize,
+    /// I/O bitmaps A and B, shared by all vCPUs of the VM.
+    io_bitmap: Arc<IoBitmap<H>>,
+    rdtsc_exiting: bool,
+    tsc_scaling: bool,
+    /// Guest `CR2`, which is not switched by VM entries and exits. Saved at
+    /// VM exits, and loaded right before VM entries.
+    guest_cr2: u64,
 }
 
 impl<H: HyperCraftHal> VmxVcpu<H> {
     pub(crate) fn new(
-        vm_id: usize,
-        vcpu_id: usize,
         vmcs_revision_id: u32,
         entry: GuestPhysAddr,
         ept_root: HostPhysAddr,
+        clock: &VirtClock<H>,
+        io_bitmap: Arc<IoBitmap<H>>,
     ) -> HyperResult<Self> {
+        let vmcs = VmxRegion::new(vmcs_revision_id, false)?;
+        let loaded_vmcs = Box::new(LoadedVmcs {
+            paddr: vmcs.phys_addr() as u64,
+            launched: AtomicBool::new(false),
+        });
         let mut vcpu = Self {
-            vcpu_id: vcpu_id,
-            vm_id: vm_id,
             guest_regs: GeneralRegisters::default(),
             host_stack_top: 0,
-            vmcs: VmxRegion::new(vmcs_revision_id, false)?,
+            vmcs,
+            loaded_vmcs,
             msr_bitmap: MsrBitmap::passthrough_all()?,
-            apic_timer: ApicTimer::new(),
-            pending_events: VecDeque::with_capacity(8),
+            io_bitmap,
+            rdtsc_exiting: false,
+            tsc_scaling: false,
+            guest_cr2: 0,
         };
         vcpu.setup_msr_bitmap()?;
-        vcpu.setup_vmcs(entry, ept_root, 0)?;
+        vcpu.setup_vmcs(entry, ept_root, clock, 0)?;
         info!("[HV] created VmxVcpu(vmcs: {:#x})", vcpu.vmcs.phys_addr());
         Ok(vcpu)
     }
 
-    /// Run the guest, never return.
-    pub fn run(&mut self) -> ! {
-        VmcsHostNW::RSP
-            .write(&self.host_stack_top as *const _ as usize)
-            .unwrap();
-        unsafe { self.vmx_launch() }
+    /// Prepares the first VM entry of [`VCpu::run`](crate::arch::VCpu::run).
+    pub(crate) fn start(&mut self) -> HyperResult {
+        VmcsHostNW::RSP.write(&self.host_stack_top as *const _ as usize)?;
+        Ok(())
+    }
+
+    /// Loads the guest `CR2` right before a VM entry.
+    pub(crate) fn prepare_entry(&mut self) {
+        Hw::write_cr2(self.guest_cr2);
+    }
+
+    /// Enters the guest, launching the VMCS if it's not launched on this CPU
+    /// yet, and returns at the next VM exit.
+    pub(crate) unsafe fn enter(&mut self) {
+        let launched = self.loaded_vmcs.launched.swap(true, Ordering::Relaxed);
+        self.vmx_enter(launched);
+    }
+
+    /// Saves the guest `CR2` right after a VM exit.
+    pub(crate) fn exited(&mut self) {
+        self.guest_cr2 = Hw::read_cr2();
     }
 
     /// Basic information about VM exits.
-    pub fn exit_info(&self) -> HyperResult<vmcs::VmxExitInfo> {
-        vmcs::exit_info()
+    pub(crate) fn exit_info(&self) -> HyperResult<VmExitInfo> {
+        // SDM Vol. 3C, Section 24.9.1
+        let full_reason = VmcsReadOnly32::EXIT_REASON.read()?;
+        let hw_reason = full_reason.get_bits(0..16);
+        let exit_reason = VmxExitReason::try_from(hw_reason).map_err(|_| {
+            warn!("Unknown VM-exit reason {:#x}", hw_reason);
+            HyperError::NotSupported
+        })?;
+        Ok(VmExitInfo {
+            entry_failure: full_reason.get_bit(31),
+            exit_reason: Self::neutral_exit_reason(exit_reason),
+            hw_reason: hw_reason as u64,
+            exit_instruction_length: VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?,
+            guest_rip: VmcsGuestNW::RIP.read()?,
+        })
+    }
+
+    fn neutral_exit_reason(reason: VmxExitReason) -> VmExitReason {
+        use VmExitReason as Exit;
+        use VmxExitReason as Vmx;
+        match reason {
+            Vmx::EXCEPTION_NMI => Exit::EXCEPTION_NMI,
+            Vmx::EXTERNAL_INTERRUPT => Exit::EXTERNAL_INTERRUPT,
+            Vmx::TRIPLE_FAULT => Exit::TRIPLE_FAULT,
+            Vmx::INIT => Exit::INIT,
+            Vmx::SIPI => Exit::SIPI,
+            Vmx::INTERRUPT_WINDOW => Exit::INTERRUPT_WINDOW,
+            Vmx::CPUID => Exit::CPUID,
+            Vmx::HLT => Exit::HLT,
+            Vmx::RDTSC => Exit::RDTSC,
+            Vmx::RDTSCP => Exit::RDTSCP,
+            Vmx::VMCALL => Exit::VMCALL,
+            Vmx::VMCLEAR
+            | Vmx::VMLAUNCH
+            | Vmx::VMPTRLD
+            | Vmx::VMPTRST
+            | Vmx::VMREAD
+            | Vmx::VMRESUME
+            | Vmx::VMWRITE
+            | Vmx::VMOFF
+            | Vmx::VMON
+            | Vmx::INVEPT
+            | Vmx::INVVPID
+            | Vmx::VMFUNC => Exit::VIRTUALIZATION_INSTRUCTION,
+            Vmx::CR_ACCESS => Exit::CR_ACCESS,
+            Vmx::IO_INSTRUCTION => Exit::IO_INSTRUCTION,
+            Vmx::MSR_READ => Exit::MSR_READ,
+            Vmx::MSR_WRITE => Exit::MSR_WRITE,
+            Vmx::MONITOR_TRAP_FLAG => Exit::MONITOR_TRAP_FLAG,
+            Vmx::PAUSE_INSTRUCTION => Exit::PAUSE_INSTRUCTION,
+            Vmx::EPT_VIOLATION => Exit::NESTED_PAGE_FAULT,
+            Vmx::PREEMPTION_TIMER => Exit::PREEMPTION_TIMER,
+            Vmx::XSETBV => Exit::XSETBV,
+            _ => Exit::OTHER,
+        }
     }
 
     /// Information for VM exits due to external interrupts.
-    pub fn interrupt_exit_info(&self) -> HyperResult<vmcs::VmxInterruptInfo> {
+    pub(crate) fn interrupt_exit_info(&self) -> HyperResult<VmxInterruptInfo> {
         vmcs::interrupt_exit_info()
     }
 
     /// Information for VM exits due to I/O instructions.
-    pub fn io_exit_info(&self) -> HyperResult<vmcs::VmxIoExitInfo> {
+    pub(crate) fn io_exit_info(&self) -> HyperResult<VmxIoExitInfo> {
         vmcs::io_exit_info()
     }
 
     /// Information for VM exits due to nested page table faults (EPT violation).
-    pub fn nested_page_fault_info(&self) -> HyperResult<NestedPageFaultInfo> {
+    pub(crate) fn nested_page_fault_info(&self) -> HyperResult<NestedPageFaultInfo> {
         vmcs::ept_violation_info()
     }
 
-    /// Guest general-purpose registers.
-    pub fn regs(&self) -> &GeneralRegisters {
+    /// Exit qualification of the last VM exit, whose meaning depends on the
+    /// exit reason. (SDM Vol. 3C, Section 27.2.1)
+    pub(crate) fn exit_qualification(&self) -> HyperResult<usize> {
+        Ok(vmcs::VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?)
+    }
+
+    /// Information for VM exits due to control-register accesses.
+    pub(crate) fn cr_access_info(&self) -> HyperResult<VmxCrAccessInfo> {
+        vmcs::cr_access_info()
+    }
+
+    pub(crate) fn regs(&self) -> &GeneralRegisters {
         &self.guest_regs
     }
 
-    /// Mutable reference of guest general-purpose registers.
-    pub fn regs_mut(&mut self) -> &mut GeneralRegisters {
+    pub(crate) fn regs_mut(&mut self) -> &mut GeneralRegisters {
         &mut self.guest_regs
     }
 
-    /// Guest stack pointer. (`RSP`)
-    pub fn stack_pointer(&self) -> usize {
+    /// Guest control register `CR0`, `CR3` or `CR4` as seen by the guest,
+    /// with host-owned bits taken from the read shadow.
+    pub(crate) fn cr(&self, cr_idx: usize) -> usize {
+        (|| -> HyperResult<usize> {
+            Ok(match cr_idx {
+                0 => {
+                    let mask = VmcsControlNW::CR0_GUEST_HOST_MASK.read()?;
+                    let shadow = VmcsControlNW::CR0_READ_SHADOW.read()?;
+                    (VmcsGuestNW::CR0.read()? & !mask) | (shadow & mask)
+                }
+                3 => VmcsGuestNW::CR3.read()?,
+                4 => {
+                    let mask = VmcsControlNW::CR4_GUEST_HOST_MASK.read()?;
+                    let shadow = VmcsControlNW::CR4_READ_SHADOW.read()?;
+                    (VmcsGuestNW::CR4.read()? & !mask) | (shadow & mask)
+                }
+                _ => unreachable!(),
+            })
+        })()
+        .expect("Failed to read guest control register")
+    }
+
+    /// Set the guest control register `CR0`, `CR3` or `CR4` to the value the
+    /// guest will see, without checking it. The value really used contains
+    /// the bits VMX requires, and `EFER.LMA` follows `CR0.PG`.
+    pub(crate) fn set_cr(&mut self, cr_idx: usize, val: u64) -> HyperResult {
+        match cr_idx {
+            0 => {
+                // Unrestricted guests can run with PE and PG cleared.
+                let fixed0 = Msr::IA32_VMX_CR0_FIXED0.read()
+                    & !(Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING).bits();
+                let fixed1 = Msr::IA32_VMX_CR0_FIXED1.read();
+                // Caching is never disabled by guests.
+                let real = ((val | fixed0) & fixed1)
+                    & !(Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE).bits();
+                VmcsGuestNW::CR0.write(real as _)?;
+                VmcsControlNW::CR0_READ_SHADOW.write(val as _)?;
+                self.update_long_mode(val & Cr0Flags::PAGING.bits() != 0)?;
+            }
+            3 => VmcsGuestNW::CR3.write(val as _)?,
+            4 => {
+                let fixed0 = Msr::IA32_VMX_CR4_FIXED0.read();
+                let fixed1 = Msr::IA32_VMX_CR4_FIXED1.read();
+                VmcsGuestNW::CR4.write(((val | fixed0) & fixed1) as _)?;
+                VmcsControlNW::CR4_READ_SHADOW.write(val as _)?;
+            }
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    /// Checks and loads `value` into `CR0`, `CR3` or `CR4` for the guest,
+    /// with the PDPTEs of PAE paging. Returns `false` if the guest must get
+    /// `#GP(0)` instead.
+    pub(crate) fn emulate_cr_load(&mut self, cr_idx: usize, value: u64) -> HyperResult<bool> {
+        let valid = match cr_idx {
+            0 => self.cr0_is_valid(value)?,
+            3 => true,
+            4 => self.cr4_is_valid(value)?,
+            _ => return Err(HyperError::NotSupported),
+        };
+        if !valid {
+            return Ok(false);
+        }
+
+        // With EPT, the PDPTEs of PAE paging are loaded into the VMCS by the
+        // hypervisor instead of the processor. (SDM Vol. 3C, Section 28.3.3.1)
+        let (mut new_cr0, mut new_cr3, mut new_cr4) =
+            (self.cr(0) as u64, self.cr(3) as u64, self.cr(4) as u64);
+        match cr_idx {
+            0 => new_cr0 = value,
+            3 => new_cr3 = value,
+            _ => new_cr4 = value,
+        }
+        let lme = VmcsGuest64::IA32_EFER.read()? & EferFlags::LONG_MODE_ENABLE.bits() != 0;
+        if new_cr0 & Cr0Flags::PAGING.bits() != 0
+            && new_cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() != 0
+            && !lme
+        {
+            match self.read_pdptes(new_cr3)? {
+                Some(pdptes) => {
+                    VmcsGuest64::PDPTE0.write(pdptes[0])?;
+                    VmcsGuest64::PDPTE1.write(pdptes[1])?;
+                    VmcsGuest64::PDPTE2.write(pdptes[2])?;
+                    VmcsGuest64::PDPTE3.write(pdptes[3])?;
+                }
+                None => return Ok(false),
+            }
+        }
+
+        self.set_cr(cr_idx, value)?;
+        Ok(true)
+    }
+
+    /// If enabled, guest loads of `CR3` cause VM exits.
+    pub(crate) fn set_cr3_load_exiting(&mut self, enable: bool) -> HyperResult {
+        Self::set_primary_control(vmcs::controls::PrimaryControls::CR3_LOAD_EXITING, enable)
+    }
+
+    pub(crate) fn stack_pointer(&self) -> usize {
         VmcsGuestNW::RSP.read().unwrap()
     }
 
-    /// Set guest stack pointer. (`RSP`)
-    pub fn set_stack_pointer(&mut self, rsp: usize) {
+    pub(crate) fn set_stack_pointer(&mut self, rsp: usize) {
         VmcsGuestNW::RSP.write(rsp).unwrap()
     }
 
-    /// Advance guest `RIP` by `instr_len` bytes.
-    pub fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
+    pub(crate) fn rip(&self) -> usize {
+        VmcsGuestNW::RIP.read().unwrap()
+    }
+
+    pub(crate) fn set_rip(&mut self, rip: usize) -> HyperResult {
+        Ok(VmcsGuestNW::RIP.write(rip)?)
+    }
+
+    pub(crate) fn rflags(&self) -> usize {
+        VmcsGuestNW::RFLAGS.read().unwrap()
+    }
+
+    pub(crate) fn set_rflags(&mut self, rflags: usize) -> HyperResult {
+        Ok(VmcsGuestNW::RFLAGS.write(rflags)?)
+    }
+
+    pub(crate) fn segment_selectors(&self) -> HyperResult<[u16; 6]> {
+        Ok([
+            VmcsGuest16::CS_SELECTOR.read()?,
+            VmcsGuest16::SS_SELECTOR.read()?,
+            VmcsGuest16::DS_SELECTOR.read()?,
+            VmcsGuest16::ES_SELECTOR.read()?,
+            VmcsGuest16::FS_SELECTOR.read()?,
+            VmcsGuest16::GS_SELECTOR.read()?,
+        ])
+    }
+
+    pub(crate) fn advance_rip(&mut self, instr_len: u8) -> HyperResult {
         Ok(VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)?)
     }
 
-    /// Add a virtual interrupt or exception to the pending events list,
-    /// and try to inject it before later VM entries.
-    pub fn inject_event(&mut self, vector: u8, err_code: Option<u32>) {
-        self.pending_events.push_back((vector, err_code));
+    pub(crate) fn cr2(&self) -> u64 {
+        self.guest_cr2
+    }
+
+    pub(crate) fn set_cr2(&mut self, cr2: u64) {
+        self.guest_cr2 = cr2;
+    }
+
+    pub(crate) fn dr7(&self) -> HyperResult<usize> {
+        Ok(VmcsGuestNW::DR7.read()?)
+    }
+
+    pub(crate) fn set_dr7(&mut self, dr7: usize) -> HyperResult {
+        Ok(VmcsGuestNW::DR7.write(dr7)?)
     }
 
-    /// If enable, a VM exit occurs at the beginning of any instruction if
+    /// If enabled, a VM exit occurs at the beginning of any instruction if
     /// `RFLAGS.IF` = 1 and there are no other blocking of interrupts.
     /// (see SDM, Vol. 3C, Section 24.4.2)
-    pub fn set_interrupt_window(&mut self, enable: bool) -> HyperResult {
-        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
-        let bits = vmcs::controls::PrimaryControls::INTERRUPT_WINDOW_EXITING.bits();
-        if enable {
-            ctrl |= bits
-        } else {
-            ctrl &= !bits
+    pub(crate) fn set_interrupt_window(&mut self, enable: bool) -> HyperResult {
+        Self::set_primary_control(vmcs::controls::PrimaryControls::INTERRUPT_WINDOW_EXITING, enable)
+    }
+
+    /// If enabled, a VM exit occurs after the guest executes one instruction,
+    /// with the monitor trap flag. (SDM Vol. 3C, Section 25.5.2)
+    pub(crate) fn set_monitor_trap(&mut self, enable: bool) -> HyperResult {
+        Self::set_primary_control(vmcs::controls::PrimaryControls::MONITOR_TRAP_FLAG, enable)
+    }
+
+    pub(crate) fn set_exception_exiting(&mut self, vector: u8, enable: bool) -> HyperResult {
+        let mut bitmap = VmcsControl32::EXCEPTION_BITMAP.read()?;
+        bitmap.set_bit(vector as usize, enable);
+        VmcsControl32::EXCEPTION_BITMAP.write(bitmap)?;
+        Ok(())
+    }
+
+    /// Whether guest exceptions of `vector` cause VM exits.
+    pub(crate) fn exception_exiting(&self, vector: u8) -> bool {
+        let bitmap = VmcsControl32::EXCEPTION_BITMAP.read().unwrap();
+        vector < 32 && bitmap.get_bit(vector as usize)
+    }
+
+    /// Makes `RDTSC` and `RDTSCP` cause VM exits, if they don't already.
+    pub(crate) fn enable_rdtsc_exiting(&mut self) -> HyperResult {
+        if !self.rdtsc_exiting {
+            Self::set_primary_control(vmcs::controls::PrimaryControls::RDTSC_EXITING, true)?;
+            self.rdtsc_exiting = true;
         }
-        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
         Ok(())
     }
 
-    /// Returns the mutable reference of [`ApicTimer`].
-    pub fn apic_timer_mut(&mut self) -> &mut ApicTimer<H> {
-        &mut self.apic_timer
+    /// Enables the branch counter in the guest only, by loading
+    /// `IA32_PERF_GLOBAL_CTRL` at VM entries and exits, and hides it from the
+    /// guest.
+    pub(crate) fn enable_branch_counting(&mut self) -> HyperResult {
+        vmcs::set_control(
+            VmcsControl32::VMEXIT_CONTROLS,
+            Msr::IA32_VMX_TRUE_EXIT_CTLS,
+            VmcsControl32::VMEXIT_CONTROLS.read()?,
+            vmcs::controls::ExitControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits(),
+            0,
+        )
+        .map_err(|_| HyperError::NotSupported)?;
+        vmcs::set_control(
+            VmcsControl32::VMENTRY_CONTROLS,
+            Msr::IA32_VMX_TRUE_ENTRY_CTLS,
+            VmcsControl32::VMENTRY_CONTROLS.read()?,
+            vmcs::controls::EntryControls::LOAD_IA32_PERF_GLOBAL_CTRL.bits(),
+            0,
+        )
+        .map_err(|_| HyperError::NotSupported)?;
+        VmcsGuest64::IA32_PERF_GLOBAL_CTRL.write(branch_counter::GUEST_PERF_GLOBAL_CTRL)?;
+        VmcsHost64::IA32_PERF_GLOBAL_CTRL.write(0)?;
+        for msr in branch_counter::counter_msrs() {
+            self.msr_bitmap.set_read_intercept(msr, true);
+            self.msr_bitmap.set_write_intercept(msr, true);
+        }
+        Ok(())
     }
 
-    /// get vcpu_id
-    pub fn get_vcpu_id(&self) -> usize {
-        self.vcpu_id
+    /// No MSR is hidden from guests with VMX.
+    pub(crate) fn read_hidden_msr(&self, _msr: u32) -> Option<u64> {
+        None
     }
 
-    /// get vm_id
-    pub fn get_vm_id(&self) -> usize {
-        self.vm_id
+    /// No MSR is hidden from guests with VMX.
+    pub(crate) fn write_hidden_msr(&mut self, _msr: u32, _value: u64) -> Option<bool> {
+        None
     }
 
-    /// get vmcs
-    pub fn load_vmcs(&self) -> HyperResult {
-        let paddr = self.vmcs.phys_addr() as u64;
-        unsafe {
-            vmx::vmptrld(paddr)?;
+    /// Refresh the TSC offset after the virtual clock has been restarted.
+    pub(crate) fn set_tsc_offset(&mut self, clock: &VirtClock<H>) -> HyperResult {
+        if !self.rdtsc_exiting {
+            VmcsControl64::TSC_OFFSET.write(clock.tsc_offset(self.tsc_scaling))?;
         }
+        Ok(())
+    }
+
+    /// Whether the guest interrupts are blocked. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
+    pub(crate) fn allow_interrupt(&self) -> bool {
+        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
+        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
+        rflags as u64 & RFlags::INTERRUPT_FLAG.bits() != 0 && block_state == 0
+    }
+
+    /// Injects the event at the next VM entry, and loads `cr2` for a page
+    /// fault.
+    pub(crate) fn inject(&mut self, vector: u8, err_code: Option<u32>, cr2: Option<usize>) -> HyperResult {
+        if let Some(cr2) = cr2 {
+            self.guest_cr2 = cr2 as u64;
+        }
+        vmcs::inject_event(vector, err_code)
+    }
+
+    /// Reads the guest-state fields of [`SAVED_GUEST_FIELDS`].
+    pub(crate) fn save_guest_fields(&self) -> HyperResult<Vec<(u32, u64)>> {
+        let mut guest_fields = Vec::with_capacity(SAVED_GUEST_FIELDS.len());
+        for &field in SAVED_GUEST_FIELDS {
+            guest_fields.push((field, Hw::vmread(field)?));
+        }
+        Ok(guest_fields)
+    }
+
+    /// Writes the guest-state fields saved by [`VmxVcpu::save_guest_fields`].
+    pub(crate) fn restore_guest_fields(&mut self, guest_fields: &[(u32, u64)]) -> HyperResult {
+        for &(field, value) in guest_fields {
+            if !SAVED_GUEST_FIELDS.contains(&field) {
+                return Err(HyperError::InvalidParam);
+            }
+            Hw::vmwrite(field, value)?;
+        }
+        // The "IA-32e mode guest" entry control must agree with EFER.LMA.
+        let lma = VmcsGuest64::IA32_EFER.read()? & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+        let ia32e_mode = vmcs::controls::EntryControls::IA32E_MODE_GUEST.bits();
+        let entry_ctrl = VmcsControl32::VMENTRY_CONTROLS.read()?;
+        VmcsControl32::VMENTRY_CONTROLS.write(if lma {
+            entry_ctrl | ia32e_mode
+        } else {
+            entry_ctrl & !ia32e_mode
+        })?;
+        Ok(())
+    }
+
+    /// Makes the VMCS current on the physical CPU again.
+    pub(crate) fn load(&mut self) -> HyperResult {
+        Hw::vmptrld(self.vmcs.phys_addr() as u64)?;
         VmcsGuest32::VMX_PREEMPTION_TIMER_VALUE.write(PREEMPTION_TIMER_VALUE)?;
         Ok(())
     }
+
+    /// The VMCS is cleared on the CPU left, then made current on the CPU
+    /// entered, with the host-state fields of that CPU.
+    pub(crate) fn migration_hooks(&self) -> MigrationHooks {
+        MigrationHooks {
+            leave_cpu: Self::leave_cpu,
+            enter_cpu: Self::enter_cpu,
+            arg: &*self.loaded_vmcs as *const LoadedVmcs as usize,
+        }
+    }
+
+    pub(crate) fn paging_context(&self) -> HyperResult<GuestPagingContext> {
+        // CPL is the DPL of SS. (SDM Vol. 3C, Section 24.4.1)
+        let ss_dpl = VmcsGuest32::SS_ACCESS_RIGHTS.read()?.get_bits(5..7);
+        Ok(GuestPagingContext {
+            cr0: VmcsGuestNW::CR0.read()?,
+            cr3: VmcsGuestNW::CR3.read()?,
+            cr4: VmcsGuestNW::CR4.read()?,
+            efer: VmcsGuest64::IA32_EFER.read()?,
+            user: ss_dpl == 3,
+            rflags: VmcsGuestNW::RFLAGS.read()?,
+            debugger: false,
+        })
+    }
+
+    pub(crate) fn nested_translate(
+        &self,
+        gpa: GuestPhysAddr,
+        write: bool,
+    ) -> GuestMemoryResult<HostPhysAddr> {
+        ept_translate::<H>(VmcsControl64::EPTP.read()?, gpa, write)
+    }
+
+    pub(crate) fn linear_rip(&self) -> HyperResult<usize> {
+        Ok(VmcsGuestNW::CS_BASE.read()? + VmcsGuestNW::RIP.read()?)
+    }
+
+    /// PDPTE `index` of PAE paging, loaded into the VMCS.
+    pub(crate) fn pae_pdpte(&self, index: usize) -> HyperResult<u64> {
+        Ok(match index {
+            0 => VmcsGuest64::PDPTE0.read()?,
+            1 => VmcsGuest64::PDPTE1.read()?,
+            2 => VmcsGuest64::PDPTE2.read()?,
+            _ => VmcsGuest64::PDPTE3.read()?,
+        })
+    }
+
+    /// Physical address of the VMCS.
+    #[cfg(test)]
+    pub(crate) fn vmcs_paddr(&self) -> u64 {
+        self.vmcs.phys_addr() as u64
+    }
+
+    /// Whether the next VM entry resumes the VMCS instead of launching it.
+    #[cfg(test)]
+    pub(crate) fn launched(&self) -> &AtomicBool {
+        &self.loaded_vmcs.launched
+    }
 }
 
 // Implementation of private methods
@@ -165,6 +646,10 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         let msr = x86::msr::IA32_APIC_BASE;
         self.msr_bitmap.set_read_intercept(msr, true);
         self.msr_bitmap.set_write_intercept(msr, true);
+        // Intercept IA32_TSC_DEADLINE MSR accesses
+        let msr = x86::msr::IA32_TSC_DEADLINE;
+        self.msr_bitmap.set_read_intercept(msr, true);
+        self.msr_bitmap.set_write_intercept(msr, true);
         // Intercept all x2APIC MSR accesses
         for msr in 0x800..=0x83f {
             self.msr_bitmap.set_read_intercept(msr, true);
@@ -173,46 +658,45 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         Ok(())
     }
 
-    fn setup_vmcs(&mut self, entry: GuestPhysAddr, ept_root: HostPhysAddr, active: u32) -> HyperResult {
+    fn setup_vmcs(
+        &mut self,
+        entry: GuestPhysAddr,
+        ept_root: HostPhysAddr,
+        clock: &VirtClock<H>,
+        active: u32,
+    ) -> HyperResult {
         let paddr = self.vmcs.phys_addr() as u64;
-        unsafe {
-            vmx::vmclear(paddr)?;
-            vmx::vmptrld(paddr)?;
-        }
-        self.setup_vmcs_host()?;
+        Hw::vmclear(paddr)?;
+        Hw::vmptrld(paddr)?;
+        Self::setup_vmcs_host()?;
         self.setup_vmcs_guest(entry, active)?;
-        self.setup_vmcs_control(ept_root)?;
+        self.setup_vmcs_control(ept_root, clock)?;
         Ok(())
     }
 
-    fn setup_vmcs_host(&mut self) -> HyperResult {
+    /// Writes the host-state fields of the current CPU into the current VMCS.
+    fn setup_vmcs_host() -> HyperResult {
         VmcsHost64::IA32_PAT.write(Msr::IA32_PAT.read())?;
         VmcsHost64::IA32_EFER.write(Msr::IA32_EFER.read())?;
 
-        VmcsHostNW::CR0.write(Cr0::read_raw() as _)?;
-        VmcsHostNW::CR3.write(Cr3::read_raw().0.start_address().as_u64() as _)?;
-        VmcsHostNW::CR4.write(Cr4::read_raw() as _)?;
+        let host = Hw::host_state();
+        VmcsHostNW::CR0.write(host.cr0 as _)?;
+        VmcsHostNW::CR3.write(host.cr3 as _)?;
+        VmcsHostNW::CR4.write(host.cr4 as _)?;
 
-        VmcsHost16::ES_SELECTOR.write(x86::segmentation::es().bits())?;
-        VmcsHost16::CS_SELECTOR.write(x86::segmentation::cs().bits())?;
-        VmcsHost16::SS_SELECTOR.write(x86::segmentation::ss().bits())?;
-        VmcsHost16::DS_SELECTOR.write(x86::segmentation::ds().bits())?;
-        VmcsHost16::FS_SELECTOR.write(x86::segmentation::fs().bits())?;
-        VmcsHost16::GS_SELECTOR.write(x86::segmentation::gs().bits())?;
+        VmcsHost16::ES_SELECTOR.write(host.es)?;
+        VmcsHost16::CS_SELECTOR.write(host.cs)?;
+        VmcsHost16::SS_SELECTOR.write(host.ss)?;
+        VmcsHost16::DS_SELECTOR.write(host.ds)?;
+        VmcsHost16::FS_SELECTOR.write(host.fs)?;
+        VmcsHost16::GS_SELECTOR.write(host.gs)?;
         VmcsHostNW::FS_BASE.write(Msr::IA32_FS_BASE.read() as _)?;
         VmcsHostNW::GS_BASE.write(Msr::IA32_GS_BASE.read() as _)?;
 
-        let tr = unsafe { x86::task::tr() };
-        let mut gdtp = DescriptorTablePointer::<u64>::default();
-        let mut idtp = DescriptorTablePointer::<u64>::default();
-        unsafe {
-            dtables::sgdt(&mut gdtp);
-            dtables::sidt(&mut idtp);
-        }
-        VmcsHost16::TR_SELECTOR.write(tr.bits())?;
-        VmcsHostNW::TR_BASE.write(get_tr_base(tr, &gdtp) as _)?;
-        VmcsHostNW::GDTR_BASE.write(gdtp.base as _)?;
-        VmcsHostNW::IDTR_BASE.write(idtp.base as _)?;
+        VmcsHost16::TR_SELECTOR.write(host.tr)?;
+        VmcsHostNW::TR_BASE.write(host.tr_base as _)?;
+        VmcsHostNW::GDTR_BASE.write(host.gdtr_base as _)?;
+        VmcsHostNW::IDTR_BASE.write(host.idtr_base as _)?;
         VmcsHostNW::RIP.write(Self::vmx_exit as usize)?;
 
         VmcsHostNW::IA32_SYSENTER_ESP.write(0)?;
@@ -222,20 +706,8 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
     }
 
     fn setup_vmcs_guest(&mut self, entry: GuestPhysAddr, active: u32) -> HyperResult {
-        let cr0_guest = Cr0Flags::EXTENSION_TYPE | Cr0Flags::NUMERIC_ERROR;
-        let cr0_host_owned =
-            Cr0Flags::NUMERIC_ERROR | Cr0Flags::NOT_WRITE_THROUGH | Cr0Flags::CACHE_DISABLE;
-        let cr0_read_shadow = Cr0Flags::NUMERIC_ERROR;
-        VmcsGuestNW::CR0.write(cr0_guest.bits() as _)?;
-        VmcsControlNW::CR0_GUEST_HOST_MASK.write(cr0_host_owned.bits() as _)?;
-        VmcsControlNW::CR0_READ_SHADOW.write(cr0_read_shadow.bits() as _)?;
-
-        let cr4_guest = Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS;
-        let cr4_host_owned = Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS;
-        let cr4_read_shadow = 0;
-        VmcsGuestNW::CR4.write(cr4_guest.bits() as _)?;
-        VmcsControlNW::CR4_GUEST_HOST_MASK.write(cr4_host_owned.bits() as _)?;
-        VmcsControlNW::CR4_READ_SHADOW.write(cr4_read_shadow)?;
+        VmcsControlNW::CR0_GUEST_HOST_MASK.write(CR0_HOST_OWNED.bits() as _)?;
+        VmcsControlNW::CR4_GUEST_HOST_MASK.write(CR4_HOST_OWNED.bits() as _)?;
 
         macro_rules! set_guest_segment {
             ($seg: ident, $access_rights: expr) => {{
@@ -263,7 +735,6 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         VmcsGuestNW::IDTR_BASE.write(0)?;
         VmcsGuest32::IDTR_LIMIT.write(0xffff)?;
 
-        VmcsGuestNW::CR3.write(0)?;
         VmcsGuestNW::DR7.write(0x400)?;
         VmcsGuestNW::RSP.write(0)?;
         VmcsGuestNW::RIP.write(entry)?;
@@ -281,10 +752,14 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         VmcsGuest64::IA32_DEBUGCTL.write(0)?;
         VmcsGuest64::IA32_PAT.write(Msr::IA32_PAT.read())?;
         VmcsGuest64::IA32_EFER.write(0)?;
+
+        self.set_cr(0, (Cr0Flags::EXTENSION_TYPE | Cr0Flags::NUMERIC_ERROR).bits())?;
+        self.set_cr(3, 0)?;
+        self.set_cr(4, 0)?;
         Ok(())
     }
 
-    fn setup_vmcs_control(&mut self, ept_root: HostPhysAddr) -> HyperResult {
+    fn setup_vmcs_control(&mut self, ept_root: HostPhysAddr, clock: &VirtClock<H>) -> HyperResult {
         // Intercept NMI and external interrupts.
         use super::vmcs::controls::*;
         use PinbasedControls as PinCtrl;
@@ -296,33 +771,60 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             0,
         )?;
 
-        // Intercept all I/O instructions, use MSR bitmaps, activate secondary controls,
-        // disable CR3 load/store interception.
+        // Decide how the guest TSC is virtualized: offsetting (and scaling if the
+        // guest TSC frequency differs) in hardware, or emulating RDTSC(P) on exits.
         use PrimaryControls as CpuCtrl;
+        use SecondaryControls as CpuCtrl2;
+        let ctrl_allowed1 = (Msr::IA32_VMX_TRUE_PROCBASED_CTLS.read() >> 32) as u32;
+        let ctrl2_allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+        let has_offsetting = ctrl_allowed1 & CpuCtrl::USE_TSC_OFFSETTING.bits() != 0;
+        let has_scaling = ctrl2_allowed1 & CpuCtrl2::USE_TSC_SCALING.bits() != 0;
+        self.tsc_scaling = has_offsetting && clock.need_scaling() && has_scaling;
+        self.rdtsc_exiting = !has_offsetting || (clock.need_scaling() && !has_scaling);
+        let tsc_ctrl = if self.rdtsc_exiting {
+            CpuCtrl::RDTSC_EXITING
+        } else {
+            CpuCtrl::USE_TSC_OFFSETTING
+        };
+
+        // Intercept all I/O instructions and HLT, use MSR bitmaps, activate secondary controls,
+        // disable CR3 load/store interception.
         vmcs::set_control(
             VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
             Msr::IA32_VMX_PROCBASED_CTLS.read() as u32,
-            (CpuCtrl::UNCOND_IO_EXITING | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS)
+            (CpuCtrl::USE_IO_BITMAPS
+                | CpuCtrl::HLT_EXITING
+                | CpuCtrl::USE_MSR_BITMAPS
+                | CpuCtrl::SECONDARY_CONTROLS
+                | tsc_ctrl)
                 .bits(),
             (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
         )?;
 
-        // Enable EPT, RDTSCP, INVPCID, and unrestricted guest.
-        use SecondaryControls as CpuCtrl2;
+        // Enable EPT, RDTSCP, INVPCID, unrestricted guest, and PAUSE-loop exiting if supported.
+        let mut ctrl2 = CpuCtrl2::ENABLE_EPT
+            | CpuCtrl2::ENABLE_RDTSCP
+            | CpuCtrl2::ENABLE_INVPCID
+            | CpuCtrl2::UNRESTRICTED_GUEST;
+        if self.tsc_scaling {
+            ctrl2 |= CpuCtrl2::USE_TSC_SCALING;
+            VmcsControl64::TSC_MULTIPLIER.write(clock.tsc_multiplier())?;
+        }
+        if ctrl2_allowed1 & CpuCtrl2::PAUSE_LOOP_EXITING.bits() != 0 {
+            ctrl2 |= CpuCtrl2::PAUSE_LOOP_EXITING;
+            VmcsControl32::PLE_GAP.write(PLE_GAP)?;
+            VmcsControl32::PLE_WINDOW.write(PLE_WINDOW)?;
+        }
         vmcs::set_control(
             VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_PROCBASED_CTLS2,
             0,
-            (CpuCtrl2::ENABLE_EPT
-                | CpuCtrl2::ENABLE_RDTSCP
-                | CpuCtrl2::ENABLE_INVPCID
-                | CpuCtrl2::UNRESTRICTED_GUEST)
-                .bits(),
+            ctrl2.bits(),
             0,
         )?;
 
-        // Switch to 64-bit host, acknowledge interrupt info, switch IA32_PAT/IA32_EFER on VM exit.
+        // Switch to 64-bit host, acknowledge interrupt info, switch IA32_PAT/IA32_EFER and save DR7 on VM exit.
         use ExitControls as ExitCtrl;
         vmcs::set_control(
             VmcsControl32::VMEXIT_CONTROLS,
@@ -334,65 +836,111 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
                 | ExitCtrl::LOAD_IA32_PAT
                 | ExitCtrl::SAVE_IA32_EFER
                 | ExitCtrl::LOAD_IA32_EFER
-                | ExitCtrl::SAVE_VMX_PREEMPTION_TIMER)
+                | ExitCtrl::SAVE_VMX_PREEMPTION_TIMER
+                | ExitCtrl::SAVE_DEBUG_CONTROLS)
                 .bits(),
             0,
         )?;
 
-        // Load guest IA32_PAT/IA32_EFER on VM entry.
+        // Load guest IA32_PAT/IA32_EFER and DR7 on VM entry.
         use EntryControls as EntryCtrl;
         vmcs::set_control(
             VmcsControl32::VMENTRY_CONTROLS,
             Msr::IA32_VMX_TRUE_ENTRY_CTLS,
             Msr::IA32_VMX_ENTRY_CTLS.read() as u32,
-            (EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER).bits(),
+            (EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER | EntryCtrl::LOAD_DEBUG_CONTROLS)
+                .bits(),
             0,
         )?;
 
         vmcs::set_ept_pointer(ept_root)?;
+        if !self.rdtsc_exiting {
+            VmcsControl64::TSC_OFFSET.write(clock.tsc_offset(self.tsc_scaling))?;
+        }
 
         // No MSR switches if hypervisor doesn't use and there is only one vCPU.
         VmcsControl32::VMEXIT_MSR_STORE_COUNT.write(0)?;
         VmcsControl32::VMEXIT_MSR_LOAD_COUNT.write(0)?;
         VmcsControl32::VMENTRY_MSR_LOAD_COUNT.write(0)?;
 
-        // Pass-through exceptions, don't use I/O bitmap, set MSR bitmaps.
+        // Pass-through exceptions, set I/O bitmap and MSR bitmaps.
         VmcsControl32::EXCEPTION_BITMAP.write(0)?;
-        VmcsControl64::IO_BITMAP_A_ADDR.write(0)?;
-        VmcsControl64::IO_BITMAP_B_ADDR.write(0)?;
+        VmcsControl64::IO_BITMAP_A_ADDR.write(self.io_bitmap.phys_addr() as _)?;
+        VmcsControl64::IO_BITMAP_B_ADDR.write(self.io_bitmap.phys_addr_b() as _)?;
         VmcsControl64::MSR_BITMAPS_ADDR.write(self.msr_bitmap.phys_addr() as _)?;
         Ok(())
     }
 
+    fn leave_cpu(arg: usize) {
+        let vmcs = unsafe { &*(arg as *const LoadedVmcs) };
+        Hw::vmclear(vmcs.paddr).unwrap();
+        vmcs.launched.store(false, Ordering::Relaxed);
+    }
+
+    fn enter_cpu(arg: usize) {
+        let vmcs = unsafe { &*(arg as *const LoadedVmcs) };
+        Hw::vmptrld(vmcs.paddr).unwrap();
+        Self::setup_vmcs_host().unwrap();
+    }
+
+    /// Sets or clears `bits` of the primary processor-based VM-execution
+    /// controls.
+    fn set_primary_control(bits: vmcs::controls::PrimaryControls, enable: bool) -> HyperResult {
+        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        if enable {
+            ctrl |= bits.bits()
+        } else {
+            ctrl &= !bits.bits()
+        }
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
+    /// Enters the guest with VMLAUNCH, or VMRESUME if `launched`, and
+    /// returns at the next VM exit through [`VmxVcpu::vmx_exit`].
     #[naked]
-    unsafe extern "C" fn vmx_launch(&mut self) -> ! {
+    unsafe extern "C" fn vmx_enter(&mut self, launched: bool) {
         asm!(
+            "pushfq",                               // save host callee-saved registers
+            "push   rbp",
+            "push   rbx",
+            "push   r12",
+            "push   r13",
+            "push   r14",
+            "push   r15",
             "mov    [rdi + {host_stack_top}], rsp", // save current RSP to Vcpu::host_stack_top
             "mov    rsp, rdi",                      // set RSP to guest regs area
+            "test   sil, sil",                      // before the flags are clobbered by restoring
+            "jnz    2f",
             restore_regs_from_stack!(),
             "vmlaunch",
             "jmp    {failed}",
+            "2:",
+            restore_regs_from_stack!(),
+            "vmresume",
+            "jmp    {failed}",
             host_stack_top = const size_of::<GeneralRegisters>(),
             failed = sym Self::vmx_entry_failed,
             options(noreturn),
         )
     }
 
+    /// The host `RIP` of VM exits: saves the guest registers, and returns to
+    /// the caller of [`VmxVcpu::vmx_enter`].
     #[naked]
     unsafe extern "C" fn vmx_exit(&mut self) -> ! {
         asm!(
             save_regs_to_stack!(),
-            "mov    r15, rsp",                      // save temporary RSP to r15
-            "mov    rdi, rsp",                      // set the first arg to &Vcpu
             "mov    rsp, [rsp + {host_stack_top}]", // set RSP to Vcpu::host_stack_top
-            "call   {vmexit_handler}",              // call vmexit_handler
-            "mov    rsp, r15",                      // load temporary RSP from r15
-            restore_regs_from_stack!(),
-            "vmresume",
-            "jmp    {failed}",
+            "pop    r15",                           // restore host callee-saved registers
+            "pop    r14",
+            "pop    r13",
+            "pop    r12",
+            "pop    rbx",
+            "pop    rbp",
+            "popfq",
+            "ret",
             host_stack_top = const size_of::<GeneralRegisters>(),
-            vmexit_handler = sym Self::vmexit_handler,
-            failed = sym Self::vmx_entry_failed,
             options(noreturn),
         );
     }
@@ -401,97 +949,85 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         panic!("{}", vmcs::instruction_error().as_str())
     }
 
-    /// Whether the guest interrupts are blocked. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
-    fn allow_interrupt(&self) -> bool {
-        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
-        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
-        rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0
-            && block_state == 0
-    }
-
-    /// Try to inject a pending event before next VM entry.
-    fn check_pending_events(&mut self) -> HyperResult {
-        if let Some(event) = self.pending_events.front() {
-            if event.0 < 32 || self.allow_interrupt() {
-                // if it's an exception, or an interrupt that is not blocked, inject it directly.
-                vmcs::inject_event(event.0, event.1)?;
-                self.pending_events.pop_front();
-            } else {
-                // interrupts are blocked, enable interrupt-window exiting.
-                self.set_interrupt_window(true)?;
-            }
+    /// Set `EFER.LMA` and the "IA-32e mode guest" VM-entry control when the
+    /// guest enables paging with `EFER.LME` set, and clear them when it
+    /// disables paging. (SDM Vol. 3A, Section 10.8.5)
+    fn update_long_mode(&mut self, paging: bool) -> HyperResult {
+        let efer = VmcsGuest64::IA32_EFER.read()?;
+        let lme = efer & EferFlags::LONG_MODE_ENABLE.bits() != 0;
+        let lma = efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+        if lma == (lme && paging) {
+            return Ok(());
+        }
+        let ia32e_mode = vmcs::controls::EntryControls::IA32E_MODE_GUEST.bits();
+        let entry_ctrl = VmcsControl32::VMENTRY_CONTROLS.read()?;
+        if lma {
+            VmcsGuest64::IA32_EFER.write(efer & !EferFlags::LONG_MODE_ACTIVE.bits())?;
+            VmcsControl32::VMENTRY_CONTROLS.write(entry_ctrl & !ia32e_mode)?;
+        } else {
+            VmcsGuest64::IA32_EFER.write(efer | EferFlags::LONG_MODE_ACTIVE.bits())?;
+            VmcsControl32::VMENTRY_CONTROLS.write(entry_ctrl | ia32e_mode)?;
         }
         Ok(())
     }
 
-    fn vmexit_handler(&mut self) {
-        let exit_info = self.exit_info().unwrap();
-
-        if exit_info.entry_failure {
-            panic!("VM entry failed: {:#x?}", exit_info);
-        }
-        
-        if exit_info.exit_reason == VmxExitReason::PREEMPTION_TIMER {
-            info!("VM {} vcpu {} vmexit with {:#x?}!!!",self.vm_id, self.vcpu_id, exit_info.exit_reason);
-        }
-        
-        
-        trace!("VM exit: {:#x?}", exit_info);
-
-        // Handle some vmexits concerning apic timer and events here.
-        // Theoretically the best practice is enabling users to inject
-        // anything they want (including apic timers) to vcpus and let
-        // them handle all vmexits, but it's not very pragmatic now.
-        let result: HyperResult = match exit_info.exit_reason {
-            VmxExitReason::INTERRUPT_WINDOW => self.set_interrupt_window(false),
-            _ => H::vmexit_handler(self),
-        };
-
-        // info!("VM {} vcpu {} vmexit come with {:#x?}!!!",self.vm_id, self.vcpu_id, exit_info.exit_reason);
-        // if exit_info.exit_reason == VmxExitReason::PREEMPTION_TIMER {
-        //     info!("VM {} vcpu {} vmexit come back with {:#x?}!!!",self.vm_id, self.vcpu_id, exit_info.exit_reason);
-        // }
-
-        if result.is_err() {
-            panic!(
-                "Failed to handle VM-exit {:?}, error {:?}:\n{:#x?}",
-                exit_info.exit_reason, result.err().unwrap(), self
-            );
+    /// Read the 4 PDPTEs for PAE paging from guest physical memory at `cr3`,
+    /// or `None` if any of them has reserved bits set. (SDM Vol. 3A, Section 4.4.1)
+    fn read_pdptes(&self, cr3: u64) -> HyperResult<Option<[u64; 4]>> {
+        const PDPTE_PRESENT: u64 = 1 << 0;
+        const PDPTE_RESERVED: u64 = 0b1_1110_0110;
+        let gpa = (cr3 & 0xffff_ffe0) as GuestPhysAddr;
+        let hpa = self.nested_translate(gpa, false).map_err(|_| HyperError::BadState)?;
+        let ptr = H::phys_to_virt(hpa) as *const [u64; 4];
+        let pdptes = unsafe { ptr.read_volatile() };
+        if pdptes
+            .iter()
+            .any(|&e| e & PDPTE_PRESENT != 0 && e & PDPTE_RESERVED != 0)
+        {
+            return Ok(None);
         }
+        Ok(Some(pdptes))
+    }
 
-        // Check if there is an APIC timer interrupt
-        if self.apic_timer.check_interrupt() {
-            self.inject_event(self.apic_timer.vector(), None);
-        }
-        self.check_pending_events().unwrap();
+    /// Check a new `CR0` value loaded by the guest. (SDM Vol. 3A, Section 2.5 and MOV CR)
+    fn cr0_is_valid(&self, cr0: u64) -> HyperResult<bool> {
+        let old_cr0 = self.cr(0) as u64;
+        let cr4 = self.cr(4) as u64;
+        let efer = VmcsGuest64::IA32_EFER.read()?;
+        let pg = cr0 & Cr0Flags::PAGING.bits() != 0;
+        let old_pg = old_cr0 & Cr0Flags::PAGING.bits() != 0;
+        let cs_long = VmcsGuest32::CS_ACCESS_RIGHTS.read()?.get_bit(13);
+        Ok(!(cr0 >> 32 != 0
+            || (pg && cr0 & Cr0Flags::PROTECTED_MODE_ENABLE.bits() == 0)
+            || (cr0 & Cr0Flags::NOT_WRITE_THROUGH.bits() != 0
+                && cr0 & Cr0Flags::CACHE_DISABLE.bits() == 0)
+            || (pg
+                && !old_pg
+                && efer & EferFlags::LONG_MODE_ENABLE.bits() != 0
+                && cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() == 0)
+            || (!pg && old_pg && cs_long)))
     }
 
-    
+    /// Check a new `CR4` value loaded by the guest.
+    fn cr4_is_valid(&self, cr4: u64) -> HyperResult<bool> {
+        let old_cr4 = self.cr(4) as u64;
+        let lma = VmcsGuest64::IA32_EFER.read()? & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+        // VMX is hidden from guests.
+        let reserved =
+            !Msr::IA32_VMX_CR4_FIXED1.read() | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
+        Ok(!(cr4 & reserved != 0
+            || (lma && cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() == 0)
+            || (lma && (cr4 ^ old_cr4) & Cr4Flags::L5_PAGING.bits() != 0)))
+    }
 }
 
 impl<H: HyperCraftHal> Drop for VmxVcpu<H> {
     fn drop(&mut self) {
-        unsafe { vmx::vmclear(self.vmcs.phys_addr() as u64).unwrap() };
+        Hw::vmclear(self.vmcs.phys_addr() as u64).unwrap();
         info!("[HV] dropped VmxVcpu(vmcs: {:#x})", self.vmcs.phys_addr());
     }
 }
 
-fn get_tr_base(tr: SegmentSelector, gdt: &DescriptorTablePointer<u64>) -> u64 {
-    let index = tr.index() as usize;
-    let table_len = (gdt.limit as usize + 1) / core::mem::size_of::<u64>();
-    let table = unsafe { core::slice::from_raw_parts(gdt.base, table_len) };
-    let entry = table[index];
-    if entry & (1 << 47) != 0 {
-        // present
-        let base_low = entry.get_bits(16..40) | entry.get_bits(56..64) << 24;
-        let base_high = table[index + 1] & 0xffff_ffff;
-        base_low | base_high << 32
-    } else {
-        // no present
-        0
-    }
-}
-
 impl<H: HyperCraftHal> Debug for VmxVcpu<H> {
     fn fmt(&self, f: &mut Formatter) -> Result {
         (|| -> HyperResult<Result> {
@@ -1,13 +1,15 @@
 use bit_field::BitField;
 use bitflags::bitflags;
 use core::arch::asm;
-use x86::bits64::{rflags::{self, RFlags}, vmx};
+use x86::bits64::rflags::{self, RFlags};
 use x86::vmx::{Result, VmFail};
 use page_table_entry::MappingFlags;
 
-use super::definitions::{VmxExitReason, VmxInstructionError, VmxInterruptionType};
+use super::definitions::{VmxInstructionError, VmxInterruptionType};
 use crate::{HostPhysAddr, HyperError, HyperResult};
 use crate::arch::memory::NestedPageFaultInfo;
+use crate::arch::ept::ept_ad_supported;
+use crate::arch::hw::{Hw, HwAccess};
 use crate::arch::msr::Msr;
 use crate::memory::PAGE_SIZE_4K;
 
@@ -16,13 +18,13 @@ macro_rules! vmcs_read {
         impl $field_enum {
             pub fn read(self) -> x86::vmx::Result<u64> {
                 #[cfg(target_pointer_width = "64")]
-                unsafe {
-                    vmx::vmread(self as u32)
+                {
+                    Hw::vmread(self as u32)
                 }
                 #[cfg(target_pointer_width = "32")]
-                unsafe {
+                {
                     let field = self as u32;
-                    Ok(vmx::vmread(field)? + (vmx::vmread(field + 1)? << 32))
+                    Ok(Hw::vmread(field)? + (Hw::vmread(field + 1)? << 32))
                 }
             }
         }
@@ -30,7 +32,7 @@ macro_rules! vmcs_read {
     ($field_enum: ident, $ux: ty) => {
         impl $field_enum {
             pub fn read(self) -> x86::vmx::Result<$ux> {
-                unsafe { vmx::vmread(self as u32).map(|v| v as $ux) }
+                Hw::vmread(self as u32).map(|v| v as $ux)
             }
         }
     };
@@ -41,14 +43,14 @@ macro_rules! vmcs_write {
         impl $field_enum {
             pub fn write(self, value: u64) -> x86::vmx::Result<()> {
                 #[cfg(target_pointer_width = "64")]
-                unsafe {
-                    vmx::vmwrite(self as u32, value)
+                {
+                    Hw::vmwrite(self as u32, value)
                 }
                 #[cfg(target_pointer_width = "32")]
-                unsafe {
+                {
                     let field = self as u32;
-                    vmx::vmwrite(field, value & 0xffff_ffff)?;
-                    vmx::vmwrite(field + 1, value >> 32)?;
+                    Hw::vmwrite(field, value & 0xffff_ffff)?;
+                    Hw::vmwrite(field + 1, value >> 32)?;
                     Ok(())
                 }
             }
@@ -57,7 +59,7 @@ macro_rules! vmcs_write {
     ($field_enum: ident, $ux: ty) => {
         impl $field_enum {
             pub fn write(self, value: $ux) -> x86::vmx::Result<()> {
-                unsafe { vmx::vmwrite(self as u32, value as u64) }
+                Hw::vmwrite(self as u32, value as u64)
             }
         }
     };
@@ -478,20 +480,6 @@ pub enum VmcsReadOnlyNW {
 }
 vmcs_read!(VmcsReadOnlyNW, usize);
 
-/// VM-Exit Informations. (SDM Vol. 3C, Section 24.9.1)
-#[derive(Debug)]
-pub struct VmxExitInfo {
-    /// VM-entry failure. (0 = true VM exit; 1 = VM-entry failure)
-    pub entry_failure: bool,
-    /// Basic exit reason.
-pub exit_reason: VmxExitReason,
-    /// For VM exits resulting from instruction execution, this field receives
-    /// the length in bytes of the instruction whose execution led to the VM exit.
-    pub exit_instruction_length: u32,
-    /// Guest `RIP` where the VM exit occurs.
-    pub guest_rip: usize,
-}
-
 /// VM-Entry/VM-Exit Interruption-Information Field. (SDM Vol. 3C, Section 24.8.3, 24.9.2)
 #[derive(Debug)]
 pub struct VmxInterruptInfo {
@@ -541,6 +529,34 @@ pub struct VmxIoExitInfo {
     pub port: u16,
 }
 
+/// Access type of control-register accesses. (SDM Vol. 3C, Section 27.2.1, Table 27-3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxCrAccessType {
+    /// MOV to CR.
+    MovToCr = 0,
+    /// MOV from CR.
+    MovFromCr = 1,
+    /// CLTS.
+    Clts = 2,
+    /// LMSW.
+    Lmsw = 3,
+}
+
+/// Exit Qualification for Control-Register Accesses. (SDM Vol. 3C, Section 27.2.1, Table 27-3)
+#[derive(Debug)]
+pub struct VmxCrAccessInfo {
+    /// Number of control register. (0 for CLTS and LMSW)
+    pub cr_number: u8,
+    /// Access type.
+    pub access_type: VmxCrAccessType,
+    /// LMSW operand type (0 = register; 1 = memory).
+    pub lmsw_memory_operand: bool,
+    /// General-purpose register index for MOV CR.
+    pub gpr: u8,
+    /// LMSW source data.
+    pub lmsw_source_data: u16,
+}
+
 pub mod controls {
     pub use x86::vmx::vmcs::control::{EntryControls, ExitControls};
     pub use x86::vmx::vmcs::control::{PinbasedControls, PrimaryControls, SecondaryControls};
@@ -607,32 +623,32 @@ impl EPTPointer {
     pub fn from_table_phys(pml4_paddr: HostPhysAddr) -> Self {
         let aligned_addr = pml4_paddr & !(PAGE_SIZE_4K - 1);
         let flags = unsafe { Self::from_bits_unchecked(aligned_addr as u64) };
-        flags | Self::MEM_TYPE_WB | Self::WALK_LENGTH_4 | Self::ENABLE_ACCESSED_DIRTY
+        let flags = flags | Self::MEM_TYPE_WB | Self::WALK_LENGTH_4;
+        if ept_ad_supported() {
+            flags | Self::ENABLE_ACCESSED_DIRTY
+        } else {
+            flags
+        }
     }
 }
 
 pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> HyperResult {
     let eptp = EPTPointer::from_table_phys(pml4_paddr).bits();
     VmcsControl64::EPTP.write(eptp)?;
-    unsafe { invept(InvEptType::SingleContext, eptp)? };
+    Hw::invept_single_context(eptp)?;
     Ok(())
 }
 
-pub fn instruction_error() -> VmxInstructionError {
-    VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
+/// Invalidates the guest-physical mappings derived from the EPT rooted at
+/// `pml4_paddr` on the current CPU, after some of its entries are removed.
+pub fn flush_ept(pml4_paddr: HostPhysAddr) -> HyperResult {
+    let eptp = EPTPointer::from_table_phys(pml4_paddr).bits();
+    Hw::invept_single_context(eptp)?;
+    Ok(())
 }
 
-pub fn exit_info() -> HyperResult<VmxExitInfo> {
-    let full_reason = VmcsReadOnly32::EXIT_REASON.read()?;
-    Ok(VmxExitInfo {
-        exit_reason: full_reason
-            .get_bits(0..16)
-            .try_into()
-            .expect("Unknown VM-exit reason"),
-        entry_failure: full_reason.get_bit(31),
-        exit_instruction_length: VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?,
-        guest_rip: VmcsGuestNW::RIP.read()?,
-    })
+pub fn instruction_error() -> VmxInstructionError {
+    VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
 }
 
 pub fn interrupt_exit_info() -> HyperResult<VmxInterruptInfo> {
@@ -681,6 +697,23 @@ pub fn io_exit_info() -> HyperResult<VmxIoExitInfo> {
     })
 }
 
+pub fn cr_access_info() -> HyperResult<VmxCrAccessInfo> {
+    // SDM Vol. 3C, Section 27.2.1, Table 27-3
+    let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
+    Ok(VmxCrAccessInfo {
+        cr_number: qualification.get_bits(0..4) as u8,
+        access_type: match qualification.get_bits(4..6) {
+            0 => VmxCrAccessType::MovToCr,
+            1 => VmxCrAccessType::MovFromCr,
+            2 => VmxCrAccessType::Clts,
+            _ => VmxCrAccessType::Lmsw,
+        },
+        lmsw_memory_operand: qualification.get_bit(6),
+        gpr: qualification.get_bits(8..12) as u8,
+        lmsw_source_data: qualification.get_bits(16..32) as u16,
+    })
+}
+
 pub fn ept_violation_info() -> HyperResult<NestedPageFaultInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-7
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
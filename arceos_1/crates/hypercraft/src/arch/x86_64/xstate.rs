@@ -0,0 +1,241 @@
+use core::arch::asm;
+use raw_cpuid::{cpuid, CpuIdResult};
+
+use super::hw::{Hw, HwAccess};
+use super::memory::PhysFrame;
+use crate::{HyperCraftHal, HyperError, HyperResult};
+
+/// x87 FPU state.
+const XCR0_X87: u64 = 1 << 0;
+/// SSE state.
+const XCR0_SSE: u64 = 1 << 1;
+/// AVX state (upper halves of YMM registers).
+const XCR0_AVX: u64 = 1 << 2;
+/// AVX-512 state (opmask, upper halves of ZMM0-15, ZMM16-31).
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// State components that can be enabled by guests. Others (e.g., MPX, PKRU,
+/// AMX) need extra virtualization support and are hidden from guests.
+const XCR0_GUEST_ALLOWED: u64 = XCR0_X87 | XCR0_SSE | XCR0_AVX | XCR0_AVX512;
+
+/// Size of the legacy region of the XSAVE area, which is also the FXSAVE area.
+const LEGACY_AREA_SIZE: usize = 512;
+/// Size of the XSAVE header.
+const XSAVE_HEADER_SIZE: usize = 64;
+/// Offset of the MXCSR register in the legacy region.
+const MXCSR_OFFSET: usize = 24;
+/// Default value of the MXCSR register, all exceptions masked.
+const MXCSR_DEFAULT: u32 = 0x1f80;
+/// Default value of the x87 FPU control word, all exceptions masked.
+const FCW_DEFAULT: u16 = 0x37f;
+
+/// Whether the processor supports XSAVE/XRSTOR and XCR0.
+pub fn has_xsave() -> bool {
+    cpuid!(1).ecx & (1 << 26) != 0
+}
+
+/// The state components that guests can enable in XCR0, zero if XSAVE is not
+/// supported.
+pub fn supported_xcr0() -> u64 {
+    if !has_xsave() {
+        return 0;
+    }
+    let res = cpuid!(0xd, 0);
+    (res.eax as u64 | (res.edx as u64) << 32) & XCR0_GUEST_ALLOWED
+}
+
+/// Size of the XSAVE area for all state components in `xcr0`.
+fn xsave_area_size(xcr0: u64) -> usize {
+    let mut size = LEGACY_AREA_SIZE + XSAVE_HEADER_SIZE;
+    for i in 2..63 {
+        if xcr0 & (1 << i) != 0 {
+            let res = cpuid!(0xd, i);
+            size = size.max((res.ebx + res.eax) as usize);
+        }
+    }
+    size
+}
+
+/// Returns CPUID leaf 0xD (processor extended state enumeration) for guests,
+/// consistent with the state components saved by [`XState`], and the guest
+/// `xcr0`.
+pub fn xstate_cpuid(subleaf: u32, xcr0: u64) -> CpuIdResult {
+    let supported = supported_xcr0();
+    let empty = CpuIdResult {
+        eax: 0,
+        ebx: 0,
+        ecx: 0,
+        edx: 0,
+    };
+    if supported == 0 {
+        return empty;
+    }
+    let mut res = cpuid!(0xd, subleaf);
+    match subleaf {
+        0 => {
+            res.eax = supported as u32;
+            res.edx = (supported >> 32) as u32;
+            // EBX is for the current XCR0, which is the host one on VM exits.
+            res.ebx = xsave_area_size(xcr0) as u32;
+            res.ecx = xsave_area_size(supported) as u32;
+        }
+        1 => {
+            // XSAVES/XRSTORS are not enabled in the secondary controls, and
+            // no supervisor state component is supported.
+            res.eax &= !(1 << 3);
+            res.ecx = 0;
+            res.edx = 0;
+        }
+        i if i < 63 && supported & (1 << i) != 0 => {}
+        _ => res = empty,
+    }
+    res
+}
+
+/// Extended processor states (x87, SSE, AVX, ...) of a vCPU, saved with
+/// XSAVE, or FXSAVE if XSAVE is not supported.
+///
+/// XSAVE and XRSTOR only handle the components enabled in the current XCR0,
+/// while guests can use SSE even with only x87 enabled in their XCR0. The
+/// states are therefore saved and loaded with all supported components
+/// enabled, and the guest XCR0 is only loaded right before VM entries, with
+/// [`XState::load_xcr0`].
+pub struct XState<H: HyperCraftHal> {
+    area: PhysFrame<H>,
+    area_size: usize,
+    xcr0: u64,
+    supported_xcr0: u64,
+}
+
+impl<H: HyperCraftHal> XState<H> {
+    /// Creates an extended state in the initial state, with only x87 enabled
+    /// in XCR0.
+    pub fn new() -> HyperResult<Self> {
+        let supported_xcr0 = supported_xcr0();
+        let area_size = if supported_xcr0 != 0 {
+            xsave_area_size(supported_xcr0)
+        } else {
+            LEGACY_AREA_SIZE
+        };
+        if area_size > H::PAGE_SIZE {
+            return Err(HyperError::NotSupported);
+        }
+        // An all-zero XSAVE header means all components are in the initial
+        // state, except MXCSR which is always loaded from the legacy region.
+        let area = PhysFrame::alloc_zero()?;
+        unsafe {
+            let ptr = area.as_mut_ptr();
+            (ptr as *mut u16).write(FCW_DEFAULT);
+            (ptr.add(MXCSR_OFFSET) as *mut u32).write(MXCSR_DEFAULT);
+        }
+        Ok(Self {
+            area,
+            area_size,
+            xcr0: XCR0_X87,
+            supported_xcr0,
+        })
+    }
+
+    /// The guest XCR0.
+    pub const fn xcr0(&self) -> u64 {
+        self.xcr0
+    }
+
+    /// Sets the guest XCR0 on XSETBV, loaded at the next VM entry.
+    ///
+    /// Returns [`HyperError::InvalidParam`] if `xcr0` is invalid and the guest
+    /// should receive a `#GP`. (SDM Vol. 1, Section 13.3)
+    pub fn set_xcr0(&mut self, xcr0: u64) -> HyperResult {
+        if self.supported_xcr0 == 0 || !self.xcr0_is_valid(xcr0) {
+            return Err(HyperError::InvalidParam);
+        }
+        self.xcr0 = xcr0;
+        Ok(())
+    }
+
+    fn xcr0_is_valid(&self, xcr0: u64) -> bool {
+        let avx512 = xcr0 & XCR0_AVX512;
+        xcr0 & !self.supported_xcr0 == 0
+            && xcr0 & XCR0_X87 != 0
+            && (xcr0 & XCR0_AVX == 0 || xcr0 & XCR0_SSE != 0)
+            && (avx512 == 0 || (avx512 == XCR0_AVX512 && xcr0 & XCR0_AVX != 0))
+    }
+
+    /// The saved XSAVE (or FXSAVE) area, in the standard format.
+    pub fn area(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_mut_ptr(), self.area_size) }
+    }
+
+    /// Replaces the guest XCR0 and the saved area with ones returned by
+    /// [`XState::xcr0`] and [`XState::area`], possibly on another vCPU.
+    ///
+    /// Returns [`HyperError::InvalidParam`] if they are not valid on this
+    /// processor, as restoring them would fault.
+    pub fn set_area(&mut self, xcr0: u64, area: &[u8]) -> HyperResult {
+        if area.len() != self.area_size {
+            return Err(HyperError::InvalidParam);
+        }
+        let mxcsr = u32::from_le_bytes(area[MXCSR_OFFSET..MXCSR_OFFSET + 4].try_into().unwrap());
+        if mxcsr & 0xffff_0000 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.supported_xcr0 == 0 {
+            if xcr0 != XCR0_X87 {
+                return Err(HyperError::InvalidParam);
+            }
+        } else {
+            // The XSAVE header: XSTATE_BV, XCOMP_BV and reserved bytes.
+            // (SDM Vol. 1, Section 13.4.2)
+            let header = &area[LEGACY_AREA_SIZE..LEGACY_AREA_SIZE + XSAVE_HEADER_SIZE];
+            let xstate_bv = u64::from_le_bytes(header[..8].try_into().unwrap());
+            if !self.xcr0_is_valid(xcr0)
+                || xstate_bv & !xcr0 != 0
+                || header[8..].iter().any(|&b| b != 0)
+            {
+                return Err(HyperError::InvalidParam);
+            }
+        }
+        unsafe { core::ptr::copy_nonoverlapping(area.as_ptr(), self.area.as_mut_ptr(), area.len()) };
+        self.xcr0 = xcr0;
+        Ok(())
+    }
+
+    /// Saves the extended states from the hardware, which must be the ones
+    /// of this vCPU. Only the components that fit in the area are saved.
+    /// XCR0 is left with all supported components enabled.
+    pub fn save(&mut self) {
+        let ptr = self.area.as_mut_ptr();
+        let mask = self.supported_xcr0;
+        unsafe {
+            if mask != 0 {
+                Hw::xsetbv(mask);
+                Hw::xsave(ptr, mask);
+            } else {
+                asm!("fxsave64 [{}]", in(reg) ptr);
+            }
+        }
+    }
+
+    /// Loads the extended states of this vCPU into the hardware. XCR0 is left
+    /// with all supported components enabled.
+    pub fn restore(&self) {
+        let ptr = self.area.as_mut_ptr();
+        let mask = self.supported_xcr0;
+        unsafe {
+            if mask != 0 {
+                Hw::xsetbv(mask);
+                Hw::xrstor(ptr, mask);
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) ptr);
+            }
+        }
+    }
+
+    /// Loads the guest XCR0 into the hardware. Must be called right before
+    /// VM entries, after the host is done with the extended states.
+    pub fn load_xcr0(&self) {
+        if self.supported_xcr0 != 0 {
+            unsafe { Hw::xsetbv(self.xcr0) };
+        }
+    }
+}
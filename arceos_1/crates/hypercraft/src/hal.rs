@@ -42,4 +42,16 @@ pub trait HyperCraftHal: Sized {
     /// Current time in nanoseconds.
     #[cfg(target_arch = "x86_64")]
     fn current_time_nanos() -> u64;
+    /// Converts nanoseconds to TSC ticks.
+    #[cfg(target_arch = "x86_64")]
+    fn nanos_to_ticks(nanos: u64) -> u64;
+    /// Arms a host timer that kicks the vCPU out of the guest (or wakes it up
+    /// if it's halted) at `deadline_ns`, replacing the previous one of the vCPU.
+    /// `None` disarms the timer.
+    #[cfg(target_arch = "x86_64")]
+    fn set_vcpu_timer(_vm_id: usize, _vcpu_id: usize, _deadline_ns: Option<u64>) {}
+    /// Called after the guest loads `CR3` with `cr3`, if CR3-load exiting is
+    /// enabled on the vCPU by `set_cr3_load_exiting`.
+    #[cfg(target_arch = "x86_64")]
+    fn guest_cr3_loaded(_vm_id: usize, _vcpu_id: usize, _cr3: usize) {}
 }
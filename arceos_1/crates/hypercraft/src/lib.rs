@@ -20,6 +20,8 @@
 extern crate log;
 #[macro_use]
 extern crate alloc;
+#[cfg(any(test, feature = "mock"))]
+extern crate std;
 
 #[cfg(target_arch = "aarch64")]
 #[path = "arch/aarch64/mod.rs"]
@@ -33,6 +35,7 @@ mod arch;
 
 mod hal;
 mod memory;
+pub mod snapshot;
 mod traits;
 mod vcpus;
 
@@ -60,8 +63,21 @@ pub use vcpus::VmCpus;
 #[cfg(target_arch = "aarch64")]
 pub use arch::lower_aarch64_synchronous;
 
+#[cfg(all(target_arch = "x86_64", feature = "mock"))]
+pub use arch::mock;
+
 #[cfg(target_arch = "x86_64")]
-pub use arch::{VmxExitReason, VmxExitInfo};
+pub use arch::{
+    core_file_headers, ept_ad_supported, ept_max_page_size, flush_ept, xstate_cpuid,
+    ApicTimerState, ExitStats, ExitStatsSummary, ExitTraceEntry, MigrationHooks,
+    NestedPageFaultInfo, ReplayInput, ReplayLog, VcpuState, VirtClock, VmExitReason,
+    VmxExitReason, CORE_FILE_ALIGN,
+};
+
+#[cfg(target_arch = "x86_64")]
+pub use arch::{
+    GuestMemoryAccessor, GuestMemoryError, GuestMemoryResult, GuestPageFault, PageFaultErrorCode,
+};
 
 /// The error type for hypervisor operation failures.
 #[derive(Debug, PartialEq)]
@@ -44,6 +44,10 @@ pub trait GuestPageTableTrait {
     /// Unmap the guest physical frame `hpa`
     fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult<()>;
 
+    /// Unmap the guest physical region starts from `gpa`, which may be mapped
+    /// by huge pages.
+    fn unmap_region(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()>;
+
     /// Translate the host physical address which the guest physical frame of
     /// `gpa` maps to.
     fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr>;
@@ -0,0 +1,131 @@
+//! Binary encoding of saved VM states.
+//!
+//! All integers are little-endian, and byte strings are prefixed with their
+//! length as a `u64`. There is no framing or type information, a state must
+//! be read back in the same order and with the same types as it is written.
+
+use alloc::vec::Vec;
+
+use crate::{HyperError, HyperResult};
+
+/// Appends saved states to a byte buffer.
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Creates an empty writer.
+    pub const fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    /// Writes a `u8`.
+    pub fn put_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    /// Writes a `u16`.
+    pub fn put_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a `u32`.
+    pub fn put_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a `u64`.
+    pub fn put_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a `bool` as one byte.
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+
+    /// Writes a length-prefixed byte string.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Bytes written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Consumes the writer and returns the bytes written.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads saved states written by [`StateWriter`].
+///
+/// All methods return [`HyperError::DecodeError`] if the input is truncated
+/// or malformed.
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Creates a reader of `buf`.
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Number of bytes not read yet.
+    pub const fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn take(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(HyperError::DecodeError);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> HyperResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Reads a `u8`.
+    pub fn get_u8(&mut self) -> HyperResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a `u16`.
+    pub fn get_u16(&mut self) -> HyperResult<u16> {
+        Ok(u16::from_le_bytes(self.take_array()?))
+    }
+
+    /// Reads a `u32`.
+    pub fn get_u32(&mut self) -> HyperResult<u32> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+
+    /// Reads a `u64`.
+    pub fn get_u64(&mut self) -> HyperResult<u64> {
+        Ok(u64::from_le_bytes(self.take_array()?))
+    }
+
+    /// Reads a `bool`.
+    pub fn get_bool(&mut self) -> HyperResult<bool> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(HyperError::DecodeError),
+        }
+    }
+
+    /// Reads a length-prefixed byte string.
+    pub fn get_bytes(&mut self) -> HyperResult<&'a [u8]> {
+        let len = self.get_u64()?;
+        self.take(usize::try_from(len).map_err(|_| HyperError::DecodeError)?)
+    }
+}
@@ -123,6 +123,36 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf> PageTable64<M, PTE, IF> {
         Ok((entry.paddr() + off, entry.flags(), size))
     }
 
+    /// Changes the mapping flags of the mapping starts with `vaddr` to `flags`,
+    /// keeping its target frame and its accessed and dirty flags.
+    ///
+    /// Returns the page size of the mapping, or
+    /// [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the mapping
+    /// is not present.
+    pub fn protect(&mut self, vaddr: VirtAddr, flags: MappingFlags) -> PagingResult<PageSize> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        entry.set_flags(flags, size.is_huge());
+        Ok(size)
+    }
+
+    /// Clears the dirty flag of the mapping starts with `vaddr`.
+    ///
+    /// Returns whether the mapping was dirty and its page size, or
+    /// [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the mapping
+    /// is not present.
+    pub fn query_and_clear_dirty(&mut self, vaddr: VirtAddr) -> PagingResult<(bool, PageSize)> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        let dirty = entry.is_dirty();
+        entry.clear_dirty();
+        Ok((dirty, size))
+    }
+
     /// Map a contiguous virtual memory region to a contiguous physical memory
     /// region with the given mapping `flags`.
     ///
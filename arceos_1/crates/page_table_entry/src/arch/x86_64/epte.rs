@@ -130,6 +130,17 @@ impl GenericPTE for EPTEntry {
     fn clear(&mut self) {
         self.0 = 0
     }
+    fn is_dirty(&self) -> bool {
+        EPTFlags::from_bits_truncate(self.0).contains(EPTFlags::DIRTY)
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !EPTFlags::DIRTY.bits();
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let ad = self.0 & (EPTFlags::ACCESSED | EPTFlags::DIRTY).bits();
+        *self = Self::new_page(self.paddr(), flags, is_huge);
+        self.0 |= ad;
+    }
 }
 
 impl fmt::Debug for EPTEntry {
@@ -1,6 +1,8 @@
 //! x86 page table entries on 64-bit paging.
 mod pte;
 mod epte;
+mod npte;
 
 pub use pte::{PTF, X64PTE};
 pub use epte::EPTEntry;
+pub use npte::NPTEntry;
@@ -0,0 +1,75 @@
+use core::fmt;
+use memory_addr::PhysAddr;
+
+use super::PTF;
+use crate::{GenericPTE, MappingFlags};
+
+/// An AMD nested page table entry. (APM Vol. 2, Section 15.25.5)
+///
+/// Nested page tables use the same format as the host long mode page tables,
+/// but all nested walks are treated as user accesses, so the `U` bit is always
+/// set on present entries and hidden from [`GenericPTE::flags`].
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct NPTEntry(u64);
+
+impl NPTEntry {
+    const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+}
+
+impl GenericPTE for NPTEntry {
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut flags = PTF::from(flags);
+        if !flags.is_empty() {
+            flags |= PTF::USER_ACCESSIBLE;
+        }
+        if is_huge {
+            flags |= PTF::HUGE_PAGE;
+        }
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        let flags = PTF::PRESENT | PTF::WRITABLE | PTF::USER_ACCESSIBLE;
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        let flags = PTF::from_bits_truncate(self.0) - PTF::USER_ACCESSIBLE;
+        flags.into()
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::PRESENT)
+    }
+    fn is_huge(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::HUGE_PAGE)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+    fn is_dirty(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::DIRTY)
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTF::DIRTY.bits();
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let ad = self.0 & (PTF::ACCESSED | PTF::DIRTY).bits();
+        *self = Self::new_page(self.paddr(), flags, is_huge);
+        self.0 |= ad;
+    }
+}
+
+impl fmt::Debug for NPTEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NPTEntry")
+            .field("raw", &self.0)
+            .field("hpaddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
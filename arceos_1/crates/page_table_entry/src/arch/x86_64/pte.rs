@@ -89,6 +89,17 @@ impl GenericPTE for X64PTE {
     fn clear(&mut self) {
         self.0 = 0
     }
+    fn is_dirty(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::DIRTY)
+    }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTF::DIRTY.bits();
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let ad = self.0 & (PTF::ACCESSED | PTF::DIRTY).bits();
+        *self = Self::new_page(self.paddr(), flags, is_huge);
+        self.0 |= ad;
+    }
 }
 
 impl fmt::Debug for X64PTE {
@@ -61,4 +61,19 @@ pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
     fn is_huge(&self) -> bool;
     /// Set this entry to zero.
     fn clear(&mut self);
+
+    /// Returns whether the page or block mapped by this entry has been written
+    /// since its dirty flag was last cleared. Entries without a dirty flag
+    /// managed by the hardware are always considered dirty.
+    fn is_dirty(&self) -> bool {
+        true
+    }
+    /// Clears the dirty flag of this entry, if any.
+    fn clear_dirty(&mut self) {}
+    /// Changes the mapping flags of this page or block entry to `flags`,
+    /// keeping its target frame and the accessed and dirty flags. The default
+    /// suits entries created with these flags always set.
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        *self = Self::new_page(self.paddr(), flags, is_huge);
+    }
 }
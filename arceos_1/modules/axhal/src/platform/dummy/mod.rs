@@ -18,10 +18,22 @@ pub mod misc {
     pub fn terminate() -> ! {
         unimplemented!()
     }
+
+    /// Shutdown the whole system with an exit status, including all CPUs.
+    pub fn terminate_with_status(_status: u8) -> ! {
+        unimplemented!()
+    }
 }
 
 #[cfg(feature = "smp")]
 pub mod mp {
+    /// The IRQ number of the inter-processor interrupts sent by [`send_ipi`].
+    pub const IPI_IRQ_NUM: usize = 0;
+
+    /// Sends an inter-processor interrupt to the given CPU, handled there by
+    /// the handler registered for [`IPI_IRQ_NUM`].
+    pub fn send_ipi(cpu_id: usize) {}
+
     /// Starts the given secondary CPU with its boot stack.
     pub fn start_secondary_cpu(cpu_id: usize, stack_top: crate::mem::PhysAddr) {}
 }
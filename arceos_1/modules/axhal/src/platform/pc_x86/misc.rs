@@ -1,5 +1,12 @@
 use x86_64::instructions::port::PortWriteOnly;
 
+/// Port of the QEMU `isa-debug-exit` device (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
+#[cfg(feature = "qemu-debug-exit")]
+const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+/// Highest status QEMU can report, as it exits with `(status << 1) | 1`.
+#[cfg(feature = "qemu-debug-exit")]
+const QEMU_DEBUG_EXIT_MAX_STATUS: u8 = 0x7f;
+
 /// Shutdown the whole system (in QEMU), including all CPUs.
 ///
 /// See <https://wiki.osdev.org/Shutdown> for more information.
@@ -12,3 +19,21 @@ pub fn terminate() -> ! {
         crate::arch::halt();
     }
 }
+
+/// Shutdown the whole system (in QEMU) with an exit status.
+///
+/// With the `qemu-debug-exit` feature, QEMU exits with `(status << 1) | 1`,
+/// `status` saturated to 127, if it has the `isa-debug-exit` device, and with
+/// 0 if `status` is 0 or there is no such device. Otherwise the status is only
+/// logged, as the port may belong to another device on real machines.
+pub fn terminate_with_status(status: u8) -> ! {
+    if status != 0 {
+        info!("Shutting down with status {}...", status);
+        #[cfg(feature = "qemu-debug-exit")]
+        unsafe {
+            let status = status.min(QEMU_DEBUG_EXIT_MAX_STATUS);
+            PortWriteOnly::new(QEMU_DEBUG_EXIT_PORT).write(status as u32)
+        };
+    }
+    terminate()
+}
@@ -28,6 +28,16 @@ unsafe fn setup_startup_page(stack_top: PhysAddr) {
     start_page[U64_PER_PAGE - 1] = ap_entry32 as usize as _; // entry
 }
 
+/// The IRQ number of the inter-processor interrupts sent by [`send_ipi`].
+pub const IPI_IRQ_NUM: usize = super::apic::vectors::APIC_IPI_VECTOR as usize;
+
+/// Sends an inter-processor interrupt to the given CPU, handled there by the
+/// handler registered for [`IPI_IRQ_NUM`].
+pub fn send_ipi(cpu_id: usize) {
+    let apic_id = super::apic::raw_apic_id(cpu_id as u8);
+    unsafe { super::apic::local_apic().send_ipi(IPI_IRQ_NUM as u8, apic_id) };
+}
+
 /// Starts the given secondary CPU with its boot stack.
 pub fn start_secondary_cpu(apic_id: usize, stack_top: PhysAddr) {
     unsafe { setup_startup_page(stack_top) };
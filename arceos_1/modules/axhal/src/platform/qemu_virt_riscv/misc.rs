@@ -7,3 +7,16 @@ pub fn terminate() -> ! {
         crate::arch::halt();
     }
 }
+
+/// Shutdown the whole system, reporting a system failure if `status` is not 0.
+pub fn terminate_with_status(status: u8) -> ! {
+    if status == 0 {
+        terminate()
+    }
+    info!("Shutting down with status {}...", status);
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+    warn!("It should shutdown!");
+    loop {
+        crate::arch::halt();
+    }
+}
@@ -18,6 +18,11 @@ pub mod time {
 
 pub mod misc {
     pub use crate::platform::aarch64_common::psci::system_off as terminate;
+
+    /// Shutdown the whole system, the exit status is not reported.
+    pub fn terminate_with_status(_status: u8) -> ! {
+        terminate()
+    }
 }
 
 extern "C" {
@@ -40,6 +40,21 @@ pub use smoltcp::wire::{IpAddress as IpAddr, IpEndpoint as SocketAddr, Ipv4Addre
 
 use axdriver::{prelude::*, AxDeviceContainer};
 
+/// Connects the network stack to a virtual switch besides the NIC, e.g., of
+/// a hypervisor. `transmit` is given all frames sent by the stack, and returns
+/// `false` if a frame should go to the NIC as well.
+///
+/// It can be called only once, after the network subsystem is initialized.
+pub fn set_uplink(transmit: fn(&[u8]) -> bool) {
+    net_impl::set_uplink(transmit);
+}
+
+/// Passes a frame from the virtual switch to the network stack, which handles
+/// it the next time it's polled.
+pub fn uplink_receive(frame: &[u8]) {
+    net_impl::uplink_receive(frame);
+}
+
 /// Initializes the network subsystem by NIC devices.
 pub fn init_network(mut net_devs: AxDeviceContainer<AxNetDevice>) {
     info!("Initialize network subsystem...");
@@ -3,7 +3,7 @@ mod listen_table;
 mod tcp;
 mod udp;
 
-use alloc::{collections::VecDeque, vec};
+use alloc::{collections::VecDeque, vec, vec::Vec};
 use core::cell::RefCell;
 use core::ops::DerefMut;
 
@@ -47,6 +47,11 @@ static LISTEN_TABLE: LazyInit<ListenTable> = LazyInit::new();
 static SOCKET_SET: LazyInit<SocketSetWrapper> = LazyInit::new();
 static ETH0: LazyInit<InterfaceWrapper> = LazyInit::new();
 
+// transmits frames to the virtual switch, returns `false` if they go to the
+// NIC as well
+static UPLINK_TX: LazyInit<fn(&[u8]) -> bool> = LazyInit::new();
+static UPLINK_RX_QUEUE: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+
 struct SocketSetWrapper<'a>(Mutex<SocketSet<'a>>);
 
 struct DeviceWrapper {
@@ -210,8 +215,11 @@ impl Device for DeviceWrapper {
     type TxToken<'a> = AxNetTxToken<'a> where Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        if let Some(frame) = UPLINK_RX_QUEUE.lock().pop_front() {
+            return Some((AxNetRxToken::Uplink(frame), AxNetTxToken(&self.inner)));
+        }
         let rx_buf = self.receive()?;
-        Some((AxNetRxToken(&self.inner, rx_buf), AxNetTxToken(&self.inner)))
+        Some((AxNetRxToken::Nic(&self.inner, rx_buf), AxNetTxToken(&self.inner)))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
@@ -227,7 +235,11 @@ impl Device for DeviceWrapper {
     }
 }
 
-struct AxNetRxToken<'a>(&'a RefCell<AxNetDevice>, NetBufferBox<'static>);
+enum AxNetRxToken<'a> {
+    Nic(&'a RefCell<AxNetDevice>, NetBufferBox<'static>),
+    // a frame from the virtual switch
+    Uplink(Vec<u8>),
+}
 struct AxNetTxToken<'a>(&'a RefCell<AxNetDevice>);
 
 impl<'a> RxToken for AxNetRxToken<'a> {
@@ -235,15 +247,22 @@ impl<'a> RxToken for AxNetRxToken<'a> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut rx_buf = self.1;
-        trace!(
-            "RECV {} bytes: {:02X?}",
-            rx_buf.packet().len(),
-            rx_buf.packet()
-        );
-        let result = f(rx_buf.packet_mut());
-        self.0.borrow_mut().recycle_rx_buffer(rx_buf).unwrap();
-        result
+        match self {
+            Self::Nic(dev, mut rx_buf) => {
+                trace!(
+                    "RECV {} bytes: {:02X?}",
+                    rx_buf.packet().len(),
+                    rx_buf.packet()
+                );
+                let result = f(rx_buf.packet_mut());
+                dev.borrow_mut().recycle_rx_buffer(rx_buf).unwrap();
+                result
+            }
+            Self::Uplink(mut frame) => {
+                trace!("RECV {} bytes from uplink: {:02X?}", frame.len(), frame);
+                f(&mut frame)
+            }
+        }
     }
 }
 
@@ -257,6 +276,11 @@ impl<'a> TxToken for AxNetTxToken<'a> {
         dev.prepare_tx_buffer(&mut tx_buf, len).unwrap();
         let result = f(tx_buf.packet_mut());
         trace!("SEND {} bytes: {:02X?}", len, tx_buf.packet());
+        if let Some(uplink_tx) = UPLINK_TX.try_get() {
+            if uplink_tx(tx_buf.packet()) {
+                return result;
+            }
+        }
         dev.transmit(&tx_buf).unwrap();
         result
     }
@@ -282,6 +306,18 @@ fn snoop_tcp_packet(buf: &[u8]) -> Result<(), smoltcp::wire::Error> {
     Ok(())
 }
 
+pub(crate) fn set_uplink(transmit: fn(&[u8]) -> bool) {
+    UPLINK_TX.init_by(transmit);
+}
+
+pub(crate) fn uplink_receive(frame: &[u8]) {
+    let mut queue = UPLINK_RX_QUEUE.lock();
+    if queue.len() < RX_BUF_QUEUE_SIZE {
+        snoop_tcp_packet(frame).ok();
+        queue.push_back(frame.to_vec());
+    }
+}
+
 pub(crate) fn init(mut net_dev: AxNetDevice) {
     let pool = NetBufferPool::new(NET_BUF_POOL_SIZE, NET_BUF_LEN).unwrap();
     NET_BUF_POOL.init_by(pool);
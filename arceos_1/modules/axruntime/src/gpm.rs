@@ -3,6 +3,8 @@ use axhal::mem::{PhysAddr, VirtAddr};
 use hypercraft::{GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult, NestedPageTable};
 
 use page_table_entry::MappingFlags;
+#[cfg(target_arch = "x86_64")]
+use page_table::PageSize;
 
 pub type GuestPagingIfImpl = axhal::paging::PagingIfImpl;
 
@@ -69,7 +71,7 @@ impl GuestPageTableTrait for GuestPageTable {
         size: usize,
         flags: MappingFlags,
     ) -> HyperResult<()> {
-        #[cfg(any(target_arch = "riscv64", target_arch = "x86_64"))]
+        #[cfg(target_arch = "riscv64")]
         {
             self.0
                 .map_region(VirtAddr::from(gpa), PhysAddr::from(hpa), size, flags, true)
@@ -89,6 +91,48 @@ impl GuestPageTableTrait for GuestPageTable {
                 })?;
             Ok(())
         }
+        #[cfg(target_arch = "x86_64")]
+        {
+            // Use the largest pages allowed by the alignment and the EPT capabilities.
+            let max_page_size = hypercraft::ept_max_page_size() as usize;
+            let (mut gpa, mut hpa, mut size) = (gpa, hpa, size);
+            while size > 0 {
+                let page_size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K]
+                    .into_iter()
+                    .find(|&ps| {
+                        let ps = ps as usize;
+                        ps <= max_page_size && (gpa | hpa) % ps == 0 && size >= ps
+                    })
+                    .ok_or(HyperError::InvalidParam)?;
+                self.0
+                    .map(VirtAddr::from(gpa), PhysAddr::from(hpa), page_size, flags)
+                    .map_err(|err| {
+                        error!("paging error: {:?}", err);
+                        HyperError::Internal
+                    })?;
+                gpa += page_size as usize;
+                hpa += page_size as usize;
+                size -= page_size as usize;
+            }
+            Ok(())
+        }
+        #[cfg(not(any(target_arch = "riscv64", target_arch = "x86_64", target_arch = "aarch64")))]
+        {
+            todo!()
+        }
+    }
+
+    fn unmap_region(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()> {
+        #[cfg(any(target_arch = "riscv64", target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            self.0
+                .unmap_region(VirtAddr::from(gpa), size)
+                .map_err(|paging_err| {
+                    error!("paging error: {:?}", paging_err);
+                    HyperError::Internal
+                })?;
+            Ok(())
+        }
         #[cfg(not(any(target_arch = "riscv64", target_arch = "x86_64", target_arch = "aarch64")))]
         {
             todo!()
@@ -145,4 +189,36 @@ impl GuestPageTable {
     pub fn root_paddr(&self) -> PhysAddr {
         self.0.root_paddr()
     }
+
+    /// Changes the permissions of the mapping containing `gpa`, and returns
+    /// the size of that mapping, or [`HyperError::NotFound`] if `gpa` is not
+    /// mapped.
+    pub fn protect(&mut self, gpa: GuestPhysAddr, flags: MappingFlags) -> HyperResult<usize> {
+        let page_size = self
+            .0
+            .protect(VirtAddr::from(gpa), flags)
+            .map_err(not_mapped_or_internal)?;
+        Ok(page_size as usize)
+    }
+
+    /// Clears the dirty flag of the mapping containing `gpa`, and returns
+    /// whether it was dirty and the size of that mapping, or
+    /// [`HyperError::NotFound`] if `gpa` is not mapped.
+    pub fn test_and_clear_dirty(&mut self, gpa: GuestPhysAddr) -> HyperResult<(bool, usize)> {
+        let (dirty, page_size) = self
+            .0
+            .query_and_clear_dirty(VirtAddr::from(gpa))
+            .map_err(not_mapped_or_internal)?;
+        Ok((dirty, page_size as usize))
+    }
+}
+
+fn not_mapped_or_internal(paging_err: page_table::PagingError) -> HyperError {
+    match paging_err {
+        page_table::PagingError::NotMapped => HyperError::NotFound,
+        _ => {
+            error!("paging error: {:?}", paging_err);
+            HyperError::Internal
+        }
+    }
 }
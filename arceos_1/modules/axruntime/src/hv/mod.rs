@@ -4,6 +4,15 @@ use hypercraft::{HostPhysAddr, HostVirtAddr, HyperCraftHal, HyperResult, VCpu};
 
 #[cfg(target_arch = "x86_64")]
 mod vmx;
+#[cfg(target_arch = "x86_64")]
+pub use vmx::{
+    acpi, console, crash, demand_paging, dirty_log, ept_flush, exit_stats, gdbstub, host_cpus,
+    io_passthrough, notify_vcpu, set_hpet_enabled, shmem, snapshot, vm_control, vswitch,
+};
+#[cfg(all(target_arch = "x86_64", feature = "smp", feature = "irq"))]
+pub(crate) use vmx::handle_ipi;
+#[cfg(all(target_arch = "x86_64", feature = "irq"))]
+pub(crate) use vmx::{check_vtimer_events, program_timer};
 
 /// An empty struct to implementate of `HyperCraftHal`
 pub struct HyperCraftHalImpl;
@@ -39,4 +48,14 @@ impl HyperCraftHal for HyperCraftHalImpl {
     fn current_time_nanos() -> u64 { 
         axhal::time::current_time_nanos()
     }
+
+    #[cfg(target_arch = "x86_64")]
+    fn nanos_to_ticks(nanos: u64) -> u64 {
+        axhal::time::nanos_to_ticks(nanos)
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "irq"))]
+    fn set_vcpu_timer(vm_id: usize, vcpu_id: usize, deadline_ns: Option<u64>) {
+        vmx::set_vcpu_timer(vm_id, vcpu_id, deadline_ns)
+    }
 }
@@ -0,0 +1,436 @@
+//! ACPI tables describing a VM as the hypervisor emulates it, to be copied
+//! into its memory where the guest looks for the RSDP. (ACPI 6.4, Chapter 5)
+//!
+//! The tables are the RSDP, the XSDT, the FADT with its FACS and DSDT, and,
+//! depending on the configuration, the MADT listing the vCPUs, the local
+//! APIC and the IOAPIC with the ISA interrupt overrides, and the HPET table.
+//! The DSDT only holds a power button and the `_S5` sleep state handled by
+//! the emulated PM1a registers.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use hypercraft::GuestPhysAddr;
+
+use super::device_emu::{ACPI_PM1A_PORT, HPET_BLOCK_ID, HPET_GPA, RESET_CONTROL_PORT};
+
+/// Guest physical address of the tables, starting with the RSDP, in the BIOS
+/// area searched by the guest. (ACPI 6.4, Section 5.2.5.1)
+pub const ACPI_TABLES_GPA: GuestPhysAddr = 0xe_0000;
+/// Max size of the tables, up to the end of the BIOS area.
+pub const ACPI_TABLES_MAX_SIZE: usize = 0x2_0000;
+
+const LOCAL_APIC_GPA: u32 = 0xfee0_0000;
+const IO_APIC_GPA: u32 = 0xfec0_0000;
+/// The ISA IRQ of the SCI, as with QEMU.
+const SCI_IRQ: u16 = 9;
+/// Value written to the reset register: `SYS_RST` and `RST_CPU`.
+const RESET_VALUE: u8 = 0x6;
+
+const OEM_ID: &[u8; 6] = b"ARCEOS";
+const OEM_TABLE_ID: &[u8; 8] = b"HVCRAFT ";
+const CREATOR_ID: &[u8; 4] = b"AXHV";
+const HEADER_LEN: usize = 36;
+
+/// Devices of a VM to describe.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiConfig {
+    /// Number of vCPUs, listed with local APIC IDs from 0.
+    pub cpu_count: usize,
+    /// Whether the local APIC is mapped. The MADT is only provided then.
+    pub local_apic: bool,
+    /// Whether the IOAPIC is mapped.
+    pub io_apic: bool,
+    /// Whether the HPET is emulated.
+    pub hpet: bool,
+}
+
+/// Builds the ACPI tables of a VM, to be copied at [`ACPI_TABLES_GPA`].
+pub fn build_tables(config: &AcpiConfig) -> Vec<u8> {
+    let mut tables = Tables::default();
+    // the RSDP is filled in last, once the XSDT address is known
+    tables.buf.resize(RSDP_LEN, 0);
+
+    let facs = tables.add_facs();
+    let dsdt = tables.add_table(b"DSDT", 2, &dsdt_aml());
+    let mut entries = Vec::new();
+    entries.push(tables.add_table(b"FACP", 6, &fadt_body(facs, dsdt)));
+    if config.local_apic {
+        entries.push(tables.add_table(b"APIC", 5, &madt_body(config)));
+    }
+    if config.hpet {
+        entries.push(tables.add_table(b"HPET", 1, &hpet_body()));
+    }
+    let xsdt_body: Vec<u8> = entries.iter().flat_map(|gpa| gpa.to_le_bytes()).collect();
+    let xsdt = tables.add_table(b"XSDT", 1, &xsdt_body);
+
+    tables.buf[..RSDP_LEN].copy_from_slice(&rsdp(xsdt));
+    assert!(tables.buf.len() <= ACPI_TABLES_MAX_SIZE);
+    tables.buf
+}
+
+const RSDP_LEN: usize = 36;
+
+/// The RSDP of ACPI 2.0 and later, with no RSDT. (ACPI 6.4, Section 5.2.5.3)
+fn rsdp(xsdt: u64) -> [u8; RSDP_LEN] {
+    let mut rsdp = [0; RSDP_LEN];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(OEM_ID);
+    rsdp[15] = 2; // revision
+    rsdp[20..24].copy_from_slice(&(RSDP_LEN as u32).to_le_bytes());
+    rsdp[24..32].copy_from_slice(&xsdt.to_le_bytes());
+    // the first checksum covers the ACPI 1.0 part only
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+/// The FADT fields after the header. (ACPI 6.4, Section 5.2.9)
+fn fadt_body(facs: u64, dsdt: u64) -> Vec<u8> {
+    const WBINVD: u32 = 1 << 0;
+    const PROC_C1: u32 = 1 << 2;
+    /// The power button is a control method device in the DSDT.
+    const PWR_BUTTON: u32 = 1 << 4;
+    /// No sleep button.
+    const SLP_BUTTON: u32 = 1 << 5;
+    const RESET_REG_SUP: u32 = 1 << 10;
+    /// `IAPC_BOOT_ARCH`: there are legacy devices and an 8042.
+    const LEGACY_DEVICES: u16 = 1 << 0;
+    const I8042: u16 = 1 << 1;
+
+    let pm1a_evt = ACPI_PM1A_PORT as u32;
+    let pm1a_cnt = ACPI_PM1A_PORT as u32 + 4;
+    let mut b = Vec::with_capacity(276 - HEADER_LEN);
+    b.extend_from_slice(&(facs as u32).to_le_bytes()); // FIRMWARE_CTRL
+    b.extend_from_slice(&(dsdt as u32).to_le_bytes());
+    b.push(0); // reserved
+    b.push(0); // preferred PM profile: unspecified
+    b.extend_from_slice(&SCI_IRQ.to_le_bytes());
+    b.extend_from_slice(&0u32.to_le_bytes()); // no SMI command port
+    b.extend_from_slice(&[0; 4]); // ACPI_ENABLE, ACPI_DISABLE, S4BIOS_REQ, PSTATE_CNT
+    b.extend_from_slice(&pm1a_evt.to_le_bytes());
+    b.extend_from_slice(&0u32.to_le_bytes()); // PM1b_EVT_BLK
+    b.extend_from_slice(&pm1a_cnt.to_le_bytes());
+    b.extend_from_slice(&[0; 20]); // PM1b_CNT_BLK, PM2_CNT_BLK, PM_TMR_BLK, GPE0_BLK, GPE1_BLK
+    b.push(4); // PM1_EVT_LEN
+    b.push(2); // PM1_CNT_LEN
+    b.extend_from_slice(&[0; 6]); // PM2_CNT_LEN to CST_CNT
+    b.extend_from_slice(&101u16.to_le_bytes()); // P_LVL2_LAT: no C2
+    b.extend_from_slice(&1001u16.to_le_bytes()); // P_LVL3_LAT: no C3
+    b.extend_from_slice(&[0; 9]); // FLUSH_SIZE to CENTURY
+    b.extend_from_slice(&(LEGACY_DEVICES | I8042).to_le_bytes());
+    b.push(0); // reserved
+    let flags = WBINVD | PROC_C1 | PWR_BUTTON | SLP_BUTTON | RESET_REG_SUP;
+    b.extend_from_slice(&flags.to_le_bytes());
+    b.extend_from_slice(&io_gas(RESET_CONTROL_PORT, 8, 1)); // RESET_REG
+    b.push(RESET_VALUE);
+    b.extend_from_slice(&0u16.to_le_bytes()); // ARM_BOOT_ARCH
+    b.push(4); // FADT minor version
+    b.extend_from_slice(&0u64.to_le_bytes()); // X_FIRMWARE_CTRL, FIRMWARE_CTRL is used
+    b.extend_from_slice(&dsdt.to_le_bytes());
+    b.extend_from_slice(&io_gas(ACPI_PM1A_PORT, 32, 2)); // X_PM1a_EVT_BLK
+    b.extend_from_slice(&[0; 12]); // X_PM1b_EVT_BLK
+    b.extend_from_slice(&io_gas(ACPI_PM1A_PORT + 4, 16, 2)); // X_PM1a_CNT_BLK
+    b.extend_from_slice(&[0; 12 * 7]); // X_PM1b_CNT_BLK to SLEEP_STATUS_REG
+    b.extend_from_slice(&0u64.to_le_bytes()); // hypervisor vendor identity
+    b
+}
+
+/// The MADT fields and interrupt controller structures. (ACPI 6.4, Section
+/// 5.2.12)
+fn madt_body(config: &AcpiConfig) -> Vec<u8> {
+    /// The 8259 PICs are present too.
+    const PCAT_COMPAT: u32 = 1 << 0;
+    const ENABLED: u32 = 1 << 0;
+    /// `MPS INTI` flags of interrupt source overrides. (ACPI 6.4, Table 5.26)
+    const ACTIVE_HIGH: u16 = 0b01;
+    const LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+    let mut b = Vec::new();
+    b.extend_from_slice(&LOCAL_APIC_GPA.to_le_bytes());
+    b.extend_from_slice(&PCAT_COMPAT.to_le_bytes());
+    for cpu_id in 0..config.cpu_count.min(u8::MAX as usize) {
+        // processor local APIC: processor UID and APIC ID
+        b.extend_from_slice(&[0, 8, cpu_id as u8, cpu_id as u8]);
+        b.extend_from_slice(&ENABLED.to_le_bytes());
+    }
+    if config.io_apic {
+        // I/O APIC: ID, reserved, address, and first GSI
+        b.extend_from_slice(&[1, 12, 0, 0]);
+        b.extend_from_slice(&IO_APIC_GPA.to_le_bytes());
+        b.extend_from_slice(&0u32.to_le_bytes());
+        // ISA IRQs are identity-mapped to GSIs, except the timer on pin 2
+        b.extend_from_slice(&interrupt_source_override(0, 2, 0));
+        let sci_flags = ACTIVE_HIGH | LEVEL_TRIGGERED;
+        b.extend_from_slice(&interrupt_source_override(SCI_IRQ as u8, SCI_IRQ as u32, sci_flags));
+    }
+    b
+}
+
+/// An interrupt source override of the ISA `irq` to `gsi`, with the polarity
+/// and trigger mode `flags`, or those of the ISA bus if 0. (ACPI 6.4, Section
+/// 5.2.12.5)
+fn interrupt_source_override(irq: u8, gsi: u32, flags: u16) -> [u8; 10] {
+    let mut iso = [0; 10];
+    iso[0] = 2; // type
+    iso[1] = 10; // length
+    iso[2] = 0; // ISA bus
+    iso[3] = irq;
+    iso[4..8].copy_from_slice(&gsi.to_le_bytes());
+    iso[8..10].copy_from_slice(&flags.to_le_bytes());
+    iso
+}
+
+/// The HPET table fields. (IA-PC HPET Specification 1.0a, Section 3.2.4)
+fn hpet_body() -> Vec<u8> {
+    let mut b = Vec::new();
+    b.extend_from_slice(&HPET_BLOCK_ID.to_le_bytes());
+    b.extend_from_slice(&[0, 64, 0, 0]); // system memory, 64 bits
+    b.extend_from_slice(&(HPET_GPA as u64).to_le_bytes());
+    b.push(0); // HPET number
+    b.extend_from_slice(&0x80u16.to_le_bytes()); // minimum clock tick in periodic mode
+    b.push(0); // no page protection
+    b
+}
+
+/// The definition block of the DSDT, in AML:
+///
+/// ```text
+/// Name (_S5, Package (4) { 0, 0, 0, 0 })
+/// Scope (\_SB) {
+///     Device (PWRB) {
+///         Name (_HID, EisaId ("PNP0C0C"))
+///     }
+/// }
+/// ```
+///
+/// (ACPI 6.4, Section 20.2)
+fn dsdt_aml() -> Vec<u8> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: &[u8] = &[0x12];
+    const SCOPE_OP: &[u8] = &[0x10];
+    const DEVICE_OP: &[u8] = &[0x5b, 0x82];
+    const ZERO_OP: u8 = 0x00;
+    const DWORD_PREFIX: u8 = 0x0c;
+
+    // SLP_TYP of PM1a and PM1b, and two reserved values
+    let s5 = aml_pkg(PACKAGE_OP, &[4, ZERO_OP, ZERO_OP, ZERO_OP, ZERO_OP]);
+    let mut aml = Vec::new();
+    aml.push(NAME_OP);
+    aml.extend_from_slice(b"_S5_");
+    aml.extend_from_slice(&s5);
+
+    let mut pwrb = Vec::new();
+    pwrb.extend_from_slice(b"PWRB");
+    pwrb.push(NAME_OP);
+    pwrb.extend_from_slice(b"_HID");
+    pwrb.push(DWORD_PREFIX);
+    pwrb.extend_from_slice(&eisa_id(b"PNP0C0C"));
+    let mut sb = Vec::new();
+    sb.extend_from_slice(b"\\_SB_");
+    sb.extend_from_slice(&aml_pkg(DEVICE_OP, &pwrb));
+    aml.extend_from_slice(&aml_pkg(SCOPE_OP, &sb));
+    aml
+}
+
+/// Encodes the AML object starting with `op`, its package length, and
+/// `contents`. (ACPI 6.4, Section 20.2.4)
+fn aml_pkg(op: &[u8], contents: &[u8]) -> Vec<u8> {
+    let mut aml = op.to_vec();
+    if contents.len() + 1 < 1 << 6 {
+        aml.push(contents.len() as u8 + 1);
+    } else {
+        let len = contents.len() + 2;
+        assert!(len < 1 << 12);
+        aml.push(0x40 | (len & 0xf) as u8);
+        aml.push((len >> 4) as u8);
+    }
+    aml.extend_from_slice(contents);
+    aml
+}
+
+/// Compresses an EISA ID such as `PNP0C0C`, as the `EisaId` macro of ASL.
+fn eisa_id(id: &[u8; 7]) -> [u8; 4] {
+    let letter = |i: usize| (id[i] - b'@') as u16 & 0x1f;
+    let digit = |i: usize| (id[i] as char).to_digit(16).unwrap() as u8;
+    let vendor = letter(0) << 10 | letter(1) << 5 | letter(2);
+    let [hi, lo] = vendor.to_be_bytes();
+    [hi, lo, digit(3) << 4 | digit(4), digit(5) << 4 | digit(6)]
+}
+
+/// A Generic Address Structure of I/O ports, with the access size encoded as
+/// 1 for bytes and 2 for words. (ACPI 6.4, Section 5.2.3.2)
+fn io_gas(port: u16, bit_width: u8, access_size: u8) -> [u8; 12] {
+    let mut gas = [0; 12];
+    gas[0] = 1; // system I/O
+    gas[1] = bit_width;
+    gas[3] = access_size;
+    gas[4..12].copy_from_slice(&(port as u64).to_le_bytes());
+    gas
+}
+
+/// The sum of all bytes with the checksum must be 0.
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+#[derive(Default)]
+struct Tables {
+    buf: Vec<u8>,
+}
+
+impl Tables {
+    fn align(&mut self, align: usize) {
+        let len = (self.buf.len() + align - 1) / align * align;
+        self.buf.resize(len, 0);
+    }
+
+    fn gpa(&self) -> u64 {
+        (ACPI_TABLES_GPA + self.buf.len()) as u64
+    }
+
+    /// Appends a table with a header for `signature`, and returns its guest
+    /// physical address. (ACPI 6.4, Section 5.2.6)
+    fn add_table(&mut self, signature: &[u8; 4], revision: u8, body: &[u8]) -> u64 {
+        self.align(16);
+        let gpa = self.gpa();
+        let start = self.buf.len();
+        let len = (HEADER_LEN + body.len()) as u32;
+        self.buf.extend_from_slice(signature);
+        self.buf.extend_from_slice(&len.to_le_bytes());
+        self.buf.push(revision);
+        self.buf.push(0); // checksum
+        self.buf.extend_from_slice(OEM_ID);
+        self.buf.extend_from_slice(OEM_TABLE_ID);
+        self.buf.extend_from_slice(&1u32.to_le_bytes()); // OEM revision
+        self.buf.extend_from_slice(CREATOR_ID);
+        self.buf.extend_from_slice(&1u32.to_le_bytes()); // creator revision
+        self.buf.extend_from_slice(body);
+        self.buf[start + 9] = checksum(&self.buf[start..]);
+        gpa
+    }
+
+    /// Appends the FACS, with no firmware waking vector nor global lock.
+    /// (ACPI 6.4, Section 5.2.10)
+    fn add_facs(&mut self) -> u64 {
+        const FACS_LEN: usize = 64;
+        self.align(64);
+        let gpa = self.gpa();
+        let mut facs = [0; FACS_LEN];
+        facs[0..4].copy_from_slice(b"FACS");
+        facs[4..8].copy_from_slice(&(FACS_LEN as u32).to_le_bytes());
+        facs[32] = 2; // version
+        self.buf.extend_from_slice(&facs);
+        gpa
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AcpiConfig = AcpiConfig {
+        cpu_count: 2,
+        local_apic: true,
+        io_apic: true,
+        hpet: true,
+    };
+
+    fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+    }
+
+    /// The table at `gpa`, checking its length and checksum.
+    fn table<'a>(tables: &'a [u8], gpa: u64, signature: &[u8; 4]) -> &'a [u8] {
+        let start = gpa as usize - ACPI_TABLES_GPA;
+        let len = le_u32(tables, start + 4) as usize;
+        assert!(len >= HEADER_LEN && start + len <= tables.len());
+        let table = &tables[start..start + len];
+        assert_eq!(&table[..4], signature);
+        assert_eq!(sum(table), 0, "bad checksum of {:?}", signature);
+        table
+    }
+
+    /// The tables listed by the XSDT.
+    fn xsdt_entries(tables: &[u8]) -> Vec<&[u8]> {
+        let xsdt = table(tables, le_u64(tables, 24), b"XSDT");
+        assert_eq!((xsdt.len() - HEADER_LEN) % 8, 0);
+        (HEADER_LEN..xsdt.len())
+            .step_by(8)
+            .map(|offset| {
+                let gpa = le_u64(xsdt, offset);
+                let start = gpa as usize - ACPI_TABLES_GPA;
+                table(tables, gpa, tables[start..start + 4].try_into().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rsdp_checksums() {
+        let tables = build_tables(&CONFIG);
+        let rsdp = &tables[..RSDP_LEN];
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(sum(&rsdp[..20]), 0);
+        assert_eq!(sum(rsdp), 0);
+        assert_eq!(le_u32(rsdp, 20) as usize, RSDP_LEN);
+        assert!(tables.len() <= ACPI_TABLES_MAX_SIZE);
+    }
+
+    #[test]
+    fn xsdt_lists_configured_tables() {
+        let tables = build_tables(&CONFIG);
+        let signatures: Vec<_> = xsdt_entries(&tables).iter().map(|t| &t[..4]).collect();
+        assert_eq!(signatures, [b"FACP", b"APIC", b"HPET"]);
+
+        let minimal = build_tables(&AcpiConfig {
+            local_apic: false,
+            hpet: false,
+            ..CONFIG
+        });
+        let signatures: Vec<_> = xsdt_entries(&minimal).iter().map(|t| &t[..4]).collect();
+        assert_eq!(signatures, [b"FACP"]);
+    }
+
+    #[test]
+    fn fadt_points_to_dsdt() {
+        let tables = build_tables(&CONFIG);
+        let fadt = xsdt_entries(&tables)[0];
+        // the length of revision 6 FADTs
+        assert_eq!(fadt.len(), 276);
+        let dsdt = table(&tables, le_u64(fadt, 140), b"DSDT");
+        assert_eq!(le_u32(fadt, 40) as u64, le_u64(fadt, 140));
+        assert_eq!(&dsdt[HEADER_LEN..], &dsdt_aml()[..]);
+        let facs_gpa = le_u32(fadt, 36) as usize;
+        assert_eq!(&tables[facs_gpa - ACPI_TABLES_GPA..][..4], b"FACS");
+    }
+
+    #[test]
+    fn madt_lists_vcpus() {
+        let tables = build_tables(&CONFIG);
+        let madt = xsdt_entries(&tables)[1];
+        // local APIC address and flags, 2 local APICs, the IOAPIC and 2
+        // interrupt source overrides
+        assert_eq!(madt.len(), HEADER_LEN + 8 + 2 * 8 + 12 + 2 * 10);
+        assert_eq!(le_u32(madt, HEADER_LEN), LOCAL_APIC_GPA);
+        for cpu_id in 0..2 {
+            let entry = &madt[HEADER_LEN + 8 + cpu_id * 8..][..8];
+            assert_eq!(entry[..4], [0, 8, cpu_id as u8, cpu_id as u8]);
+        }
+
+        let single = build_tables(&AcpiConfig {
+            cpu_count: 1,
+            io_apic: false,
+            ..CONFIG
+        });
+        let madt = xsdt_entries(&single)[1];
+        assert_eq!(madt.len(), HEADER_LEN + 8 + 8);
+    }
+}
@@ -0,0 +1,295 @@
+//! Multiplexing the host console between the emulated UARTs of the VMs and
+//! the management console.
+//!
+//! One VM has the console focus: it receives the host console input, and in
+//! the focused output mode it's the only one whose output is shown, the others
+//! buffering theirs until they get the focus. In the shared output mode, the
+//! output of all VMs is shown, each line tagged with the VM id if several VMs
+//! are running.
+//!
+//! Hotkeys start with `Ctrl-A`:
+//! - `Ctrl-A c` enters the management console, until [`leave_manager`].
+//! - `Ctrl-A n` moves the focus to the next running VM.
+//! - `Ctrl-A 0`-`9` moves the focus to the given VM.
+//! - `Ctrl-A s` toggles between the focused and the shared output modes.
+//! - `Ctrl-A Ctrl-A` sends a `Ctrl-A` to the focused VM.
+//!
+//! While the management console is active, the output of all VMs is buffered.
+
+extern crate alloc;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::fmt::{self, Write};
+
+use axhal::console as uart;
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::vm_control::{self, VmStatus};
+
+/// The prefix of hotkeys.
+pub const ESCAPE_KEY: u8 = 0x01;
+const ENTER_MANAGER_KEY: u8 = b'c';
+const NEXT_VM_KEY: u8 = b'n';
+const TOGGLE_OUTPUT_MODE_KEY: u8 = b's';
+
+/// Max number of input bytes buffered for each destination.
+const INPUT_BUFFER_SIZE: usize = 256;
+/// Max number of output bytes buffered for each VM, the oldest are dropped.
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+/// Which VMs have their output shown on the host console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Only the focused VM.
+    Focused,
+    /// All VMs, with lines tagged by the VM id.
+    Shared,
+}
+
+struct ConsoleInput {
+    /// Whether the last byte was [`ESCAPE_KEY`].
+    escaped: bool,
+    vm_queues: Vec<VecDeque<u8>>,
+    manager_queue: VecDeque<u8>,
+}
+
+struct ConsoleOutput {
+    focus: usize,
+    mode: OutputMode,
+    manager_active: bool,
+    /// Output of VMs not shown yet.
+    buffers: Vec<VecDeque<u8>>,
+    /// VM that printed the last byte in the shared mode.
+    last_writer: Option<usize>,
+    at_line_start: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref CONSOLE_INPUT: SpinNoIrq<ConsoleInput> = SpinNoIrq::new(ConsoleInput {
+        escaped: false,
+        vm_queues: (0..MAX_VMS).map(|_| VecDeque::new()).collect(),
+        manager_queue: VecDeque::new(),
+    });
+    static ref CONSOLE_OUTPUT: SpinNoIrq<ConsoleOutput> = SpinNoIrq::new(ConsoleOutput {
+        focus: 0,
+        mode: OutputMode::Shared,
+        manager_active: false,
+        buffers: (0..MAX_VMS).map(|_| VecDeque::new()).collect(),
+        last_writer: None,
+        at_line_start: true,
+    });
+}
+
+fn push_bounded(queue: &mut VecDeque<u8>, c: u8, capacity: usize, drop_oldest: bool) {
+    if queue.len() == capacity {
+        if !drop_oldest {
+            return;
+        }
+        queue.pop_front();
+    }
+    queue.push_back(c);
+}
+
+/// Writes to the host console.
+struct HostConsole;
+
+impl fmt::Write for HostConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            uart::putchar(c);
+        }
+        Ok(())
+    }
+}
+
+fn running_vms() -> usize {
+    (0..MAX_VMS)
+        .filter(|&id| vm_control::status(id) != VmStatus::Stopped)
+        .count()
+}
+
+impl ConsoleOutput {
+    fn shows(&self, vm_id: usize) -> bool {
+        !self.manager_active && (self.mode == OutputMode::Shared || vm_id == self.focus)
+    }
+
+    fn print(&mut self, vm_id: usize, c: u8) {
+        if self.mode == OutputMode::Shared && running_vms() > 1 {
+            if self.last_writer != Some(vm_id) || self.at_line_start {
+                if !self.at_line_start {
+                    uart::putchar(b'\n');
+                }
+                let _ = write!(HostConsole, "[vm{}] ", vm_id);
+            }
+            self.last_writer = Some(vm_id);
+        }
+        uart::putchar(c);
+        self.at_line_start = c == b'\n';
+    }
+
+    /// Prints the buffered output of the VMs now shown.
+    fn flush(&mut self) {
+        for vm_id in 0..MAX_VMS {
+            if self.shows(vm_id) {
+                while let Some(c) = self.buffers[vm_id].pop_front() {
+                    self.print(vm_id, c);
+                }
+            }
+        }
+    }
+
+    fn notice(&mut self, args: fmt::Arguments) {
+        if !self.at_line_start {
+            uart::putchar(b'\n');
+        }
+        let _ = writeln!(HostConsole, "[hv] {}", args);
+        self.at_line_start = true;
+        self.last_writer = None;
+    }
+
+    fn set_focus(&mut self, vm_id: usize) {
+        self.focus = vm_id;
+        if !self.manager_active {
+            self.notice(format_args!("console focus on VM {}", vm_id));
+            self.flush();
+        }
+    }
+}
+
+impl ConsoleInput {
+    /// Handles a hotkey after [`ESCAPE_KEY`], returns the byte to send to the
+    /// focused VM, if any.
+    fn hotkey(&mut self, c: u8, output: &mut ConsoleOutput) -> Option<u8> {
+        match c {
+            ESCAPE_KEY => return Some(c),
+            ENTER_MANAGER_KEY => output.manager_active = true,
+            NEXT_VM_KEY => {
+                let next = (1..=MAX_VMS)
+                    .map(|i| (output.focus + i) % MAX_VMS)
+                    .find(|&id| vm_control::status(id) != VmStatus::Stopped);
+                if let Some(id) = next {
+                    output.set_focus(id);
+                }
+            }
+            TOGGLE_OUTPUT_MODE_KEY => {
+                output.mode = match output.mode {
+                    OutputMode::Focused => OutputMode::Shared,
+                    OutputMode::Shared => OutputMode::Focused,
+                };
+                output.flush();
+            }
+            b'0'..=b'9' if ((c - b'0') as usize) < MAX_VMS => {
+                output.set_focus((c - b'0') as usize);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn route(&mut self, c: u8) {
+        let mut output = CONSOLE_OUTPUT.lock();
+        if output.manager_active {
+            push_bounded(&mut self.manager_queue, c, INPUT_BUFFER_SIZE, false);
+            return;
+        }
+        let c = if self.escaped {
+            self.escaped = false;
+            match self.hotkey(c, &mut output) {
+                Some(c) => c,
+                None => return,
+            }
+        } else if c == ESCAPE_KEY {
+            self.escaped = true;
+            return;
+        } else {
+            c
+        };
+        push_bounded(&mut self.vm_queues[output.focus], c, INPUT_BUFFER_SIZE, false);
+    }
+
+    /// Moves the pending bytes of the host console to the queues.
+    fn poll_host(&mut self) {
+        while let Some(c) = uart::getchar() {
+            self.route(c);
+        }
+    }
+}
+
+/// Moves the console focus to the VM.
+pub fn set_focus(vm_id: usize) -> bool {
+    if vm_id >= MAX_VMS {
+        return false;
+    }
+    CONSOLE_OUTPUT.lock().set_focus(vm_id);
+    true
+}
+
+/// Returns the VM having the console focus.
+pub fn focus() -> usize {
+    CONSOLE_OUTPUT.lock().focus
+}
+
+/// Sets which VMs have their output shown.
+pub fn set_output_mode(mode: OutputMode) {
+    let mut output = CONSOLE_OUTPUT.lock();
+    output.mode = mode;
+    output.flush();
+}
+
+/// Returns which VMs have their output shown.
+pub fn output_mode() -> OutputMode {
+    CONSOLE_OUTPUT.lock().mode
+}
+
+/// Whether the input goes to the management console.
+pub fn manager_active() -> bool {
+    CONSOLE_INPUT.lock().poll_host();
+    CONSOLE_OUTPUT.lock().manager_active
+}
+
+/// Sends the input to the management console, as if the hotkey was typed.
+pub fn enter_manager() {
+    CONSOLE_OUTPUT.lock().manager_active = true;
+}
+
+/// Sends the input to the focused VM again, and shows the output buffered in
+/// the meantime.
+pub fn leave_manager() {
+    let mut input = CONSOLE_INPUT.lock();
+    let mut output = CONSOLE_OUTPUT.lock();
+    input.manager_queue.clear();
+    output.manager_active = false;
+    output.at_line_start = true;
+    output.last_writer = None;
+    output.flush();
+}
+
+/// Reads a byte typed in the management console.
+pub fn manager_getchar() -> Option<u8> {
+    let mut input = CONSOLE_INPUT.lock();
+    input.poll_host();
+    input.manager_queue.pop_front()
+}
+
+/// Reads a byte sent to the VM. Called by its emulated UART.
+pub(super) fn guest_getchar(vm_id: usize) -> Option<u8> {
+    let mut input = CONSOLE_INPUT.lock();
+    input.poll_host();
+    input.vm_queues[vm_id].pop_front()
+}
+
+/// Shows or buffers a byte written by the VM. Called by its emulated UART.
+pub(super) fn guest_putchar(vm_id: usize, c: u8) {
+    let mut output = CONSOLE_OUTPUT.lock();
+    if output.shows(vm_id) {
+        output.print(vm_id, c);
+    } else {
+        push_bounded(&mut output.buffers[vm_id], c, OUTPUT_BUFFER_SIZE, true);
+    }
+}
+
+/// Drops the input and output buffered for the VM, when it's reset.
+pub(super) fn reset(vm_id: usize) {
+    CONSOLE_INPUT.lock().vm_queues[vm_id].clear();
+    CONSOLE_OUTPUT.lock().buffers[vm_id].clear();
+}
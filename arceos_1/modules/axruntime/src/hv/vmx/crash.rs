@@ -0,0 +1,42 @@
+//! States of the vCPUs which crashed their VMs, kept until the task running
+//! the VM writes them to a core file.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use hypercraft::VcpuState;
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::VCpu;
+
+lazy_static::lazy_static! {
+    static ref CRASHED_VCPUS: Vec<SpinNoIrq<Vec<(usize, VcpuState)>>> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS {
+            temp.push(SpinNoIrq::new(Vec::new()));
+        }
+        temp
+    };
+}
+
+/// Saves the state of `vcpu`, which crashed its VM. Called at the VM exit
+/// where it crashed.
+pub(super) fn record(vcpu: &VCpu) {
+    let vm_id = vcpu.get_vm_id();
+    if vm_id >= MAX_VMS {
+        return;
+    }
+    match vcpu.save_state() {
+        Ok(state) => CRASHED_VCPUS[vm_id].lock().push((vcpu.get_vcpu_id(), state)),
+        Err(err) => warn!("VM {} vcpu {} state not saved: {:?}", vm_id, vcpu.get_vcpu_id(), err),
+    }
+}
+
+/// Takes the states of the vCPUs which crashed the VM, with their IDs. Empty
+/// if the VM did not crash since the last call.
+pub fn take(vm_id: usize) -> Vec<(usize, VcpuState)> {
+    CRASHED_VCPUS
+        .get(vm_id)
+        .map_or_else(Vec::new, |slot| core::mem::take(&mut *slot.lock()))
+}
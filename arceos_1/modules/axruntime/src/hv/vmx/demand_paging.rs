@@ -0,0 +1,198 @@
+//! Guest RAM whose host pages are allocated on first touch.
+//!
+//! RAM regions added here are left unmapped in the EPT. The first guest access
+//! to a page causes an EPT violation, then a zeroed host page is allocated and
+//! mapped, as long as the VM stays under its memory cap. Pages given back by
+//! the guest through the balloon device are unmapped and freed.
+
+extern crate alloc;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axalloc::global_allocator;
+use axhal::mem::{virt_to_phys, PAGE_SIZE_4K};
+use hypercraft::{
+    GuestPageTableTrait, GuestPhysAddr, HostPhysAddr, HyperError, HyperResult, NestedPageFaultInfo,
+};
+use page_table_entry::MappingFlags;
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::{dirty_log, ept_flush};
+use crate::GuestPageTable;
+
+struct DemandPagedMemory {
+    npt: Arc<SpinNoIrq<GuestPageTable>>,
+    regions: Vec<(GuestPhysAddr, usize, MappingFlags)>,
+    /// Populated guest pages and their host virtual addresses.
+    pages: BTreeMap<GuestPhysAddr, usize>,
+    max_pages: usize,
+}
+
+impl DemandPagedMemory {
+    fn region_flags(&self, gpa: GuestPhysAddr) -> Option<MappingFlags> {
+        self.regions
+            .iter()
+            .find(|&&(start, size, _)| start <= gpa && gpa < start + size)
+            .map(|&(_, _, flags)| flags)
+    }
+
+    fn populate(&mut self, vm_id: usize, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
+        let gpa = gpa & !(PAGE_SIZE_4K - 1);
+        if let Some(&vaddr) = self.pages.get(&gpa) {
+            return Ok(virt_to_phys(vaddr.into()).into());
+        }
+        let flags = self.region_flags(gpa).ok_or(HyperError::InvalidParam)?;
+        if self.pages.len() >= self.max_pages {
+            error!(
+                "VM {} exceeds its memory cap of {} pages at GPA {:#x}",
+                vm_id, self.max_pages, gpa
+            );
+            return Err(HyperError::NoMemory);
+        }
+        let vaddr = global_allocator()
+            .alloc_pages(1, PAGE_SIZE_4K)
+            .map_err(|_| HyperError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE_4K) };
+        let hpa: HostPhysAddr = virt_to_phys(vaddr.into()).into();
+        if let Err(err) = self.npt.lock().map(gpa, hpa, flags) {
+            global_allocator().dealloc_pages(vaddr, 1);
+            return Err(err);
+        }
+        self.pages.insert(gpa, vaddr);
+        Ok(hpa)
+    }
+
+    /// Unmaps the guest page containing `gpa`, and returns its host virtual
+    /// address and the EPT root if it was populated. The page is still in
+    /// use by the CPUs which ran the VM, until they flush the EPT.
+    fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult<Option<(usize, HostPhysAddr)>> {
+        let gpa = gpa & !(PAGE_SIZE_4K - 1);
+        let Some(vaddr) = self.pages.remove(&gpa) else {
+            return Ok(None);
+        };
+        let mut npt = self.npt.lock();
+        npt.unmap(gpa)?;
+        Ok(Some((vaddr, npt.root_paddr().into())))
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref VM_MEMORY: Vec<SpinNoIrq<Option<DemandPagedMemory>>> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS {
+            temp.push(SpinNoIrq::new(None));
+        }
+        temp
+    };
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_BALLOON_TARGET: AtomicUsize = AtomicUsize::new(0);
+static BALLOON_TARGETS: [AtomicUsize; MAX_VMS] = [NO_BALLOON_TARGET; MAX_VMS];
+
+/// Enables demand paging for the VM whose EPT is `npt`, allowing at most
+/// `max_pages` guest pages to be populated.
+pub fn init(vm_id: usize, npt: Arc<SpinNoIrq<GuestPageTable>>, max_pages: usize) {
+    *VM_MEMORY[vm_id].lock() = Some(DemandPagedMemory {
+        npt,
+        regions: Vec::new(),
+        pages: BTreeMap::new(),
+        max_pages,
+    });
+}
+
+/// Adds a guest RAM region populated on demand with `flags`.
+pub fn add_region(vm_id: usize, gpa: GuestPhysAddr, size: usize, flags: MappingFlags) -> HyperResult {
+    let mut mem = VM_MEMORY[vm_id].lock();
+    let mem = mem.as_mut().ok_or(HyperError::BadState)?;
+    mem.regions.push((gpa, size, flags));
+    Ok(())
+}
+
+/// Populates the guest page containing `gpa` if it's not yet, and returns
+/// the host physical address of `gpa`.
+pub fn populate(vm_id: usize, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
+    let mut mem = VM_MEMORY[vm_id].lock();
+    let mem = mem.as_mut().ok_or(HyperError::BadState)?;
+    let hpa = mem.populate(vm_id, gpa)?;
+    // The page is zeroed if newly populated, or written by the caller.
+    dirty_log::mark_dirty(vm_id, gpa);
+    Ok(hpa + (gpa & (PAGE_SIZE_4K - 1)))
+}
+
+/// Returns the host physical address of `gpa` if its page is populated,
+/// without populating it.
+pub fn lookup(vm_id: usize, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+    let mem = VM_MEMORY[vm_id].lock();
+    let vaddr = *mem.as_ref()?.pages.get(&(gpa & !(PAGE_SIZE_4K - 1)))?;
+    let hpa: HostPhysAddr = virt_to_phys(vaddr.into()).into();
+    Some(hpa + (gpa & (PAGE_SIZE_4K - 1)))
+}
+
+/// Unmaps and frees the guest page containing `gpa`, which will be populated
+/// again on the next access.
+pub fn reclaim(vm_id: usize, gpa: GuestPhysAddr) -> HyperResult {
+    let unmapped = match VM_MEMORY[vm_id].lock().as_mut() {
+        Some(mem) => mem.unmap(gpa)?,
+        None => return Ok(()),
+    };
+    if let Some((vaddr, root)) = unmapped {
+        // Not under the lock, which other CPUs may spin on with IRQs disabled.
+        ept_flush::flush_all(vm_id, root)?;
+        global_allocator().dealloc_pages(vaddr, 1);
+    }
+    dirty_log::mark_dirty(vm_id, gpa);
+    Ok(())
+}
+
+/// Number of guest pages currently backed by host memory.
+pub fn populated_pages(vm_id: usize) -> usize {
+    VM_MEMORY[vm_id]
+        .lock()
+        .as_ref()
+        .map_or(0, |mem| mem.pages.len())
+}
+
+/// Frees all populated pages and disables demand paging of the VM, the EPT
+/// must not be used anymore.
+pub fn release(vm_id: usize) {
+    if let Some(mem) = VM_MEMORY[vm_id].lock().take() {
+        for &vaddr in mem.pages.values() {
+            global_allocator().dealloc_pages(vaddr, 1);
+        }
+    }
+    BALLOON_TARGETS[vm_id].store(0, Ordering::Relaxed);
+}
+
+/// Asks the guest to give `pages` pages back through the balloon device.
+pub fn set_balloon_target(vm_id: usize, pages: usize) {
+    BALLOON_TARGETS[vm_id].store(pages, Ordering::Relaxed);
+}
+
+/// Number of pages the guest is asked to keep in the balloon.
+pub fn balloon_target(vm_id: usize) -> usize {
+    BALLOON_TARGETS[vm_id].load(Ordering::Relaxed)
+}
+
+/// Populates the faulting page if it's in a demand-paged region, the guest
+/// then retries the access.
+pub(super) fn handle_ept_violation(vm_id: usize, fault: &NestedPageFaultInfo) -> HyperResult {
+    trace!(
+        "VM {} EPT violation @ {:#x}: {:?}",
+        vm_id,
+        fault.fault_guest_paddr,
+        fault.access_flags
+    );
+    populate(vm_id, fault.fault_guest_paddr)
+        .map(|_| ())
+        .map_err(|err| {
+            error!(
+                "VM {} invalid guest memory access @ {:#x}: {:?}",
+                vm_id,
+                fault.fault_guest_paddr,
+                fault.access_flags
+            );
+            err
+        })
+}
@@ -0,0 +1,113 @@
+//! Emulated ACPI PM1a event and control registers, at the ports QEMU uses so
+//! that writing `0x2000` to port `0x604` powers the VM off. (ACPI 6.4,
+//! Section 4.8.3.1 and 4.8.3.2)
+//!
+//! - `base + 0`: PM1a status, write 1 to clear.
+//! - `base + 2`: PM1a enable.
+//! - `base + 4`: PM1a control, `SLP_EN` with `SLP_TYP` 0 enters S5 (soft off).
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::super::vm_control;
+use super::PortIoDevice;
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{HyperError, HyperResult};
+
+const REG_PM1_STS: u16 = 0;
+const REG_PM1_EN: u16 = 2;
+const REG_PM1_CNT: u16 = 4;
+
+/// `PM1_CNT.SCI_EN`: always in ACPI mode, there is no SMI command port.
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+/// `SLP_TYP` of the S5 sleep state, as in the `_S5` object of QEMU's DSDT.
+const SLP_TYP_S5: u16 = 0;
+
+pub struct AcpiPm {
+    port_base: u16,
+    vm_id: usize,
+    status: AtomicU16,
+    enable: AtomicU16,
+    control: AtomicU16,
+}
+
+impl PortIoDevice for AcpiPm {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port_base..self.port_base + 6
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 2 {
+            return Err(HyperError::InvalidParam);
+        }
+        let reg = match port - self.port_base {
+            REG_PM1_STS => &self.status,
+            REG_PM1_EN => &self.enable,
+            REG_PM1_CNT => &self.control,
+            _ => return Err(HyperError::InvalidParam),
+        };
+        Ok(reg.load(Ordering::Relaxed) as u32)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 2 {
+            return Err(HyperError::InvalidParam);
+        }
+        let value = value as u16;
+        match port - self.port_base {
+            REG_PM1_STS => {
+                self.status.fetch_and(!value, Ordering::Relaxed);
+            }
+            REG_PM1_EN => self.enable.store(value, Ordering::Relaxed),
+            REG_PM1_CNT => {
+                // SLP_EN is write-only, and reads as 0.
+                let control = (value & !PM1_CNT_SLP_EN) | PM1_CNT_SCI_EN;
+                self.control.store(control, Ordering::Relaxed);
+                if value & PM1_CNT_SLP_EN != 0 {
+                    let slp_typ = (value & PM1_CNT_SLP_TYP_MASK) >> PM1_CNT_SLP_TYP_SHIFT;
+                    if slp_typ == SLP_TYP_S5 {
+                        info!("VM {} entered ACPI S5", self.vm_id);
+                        vm_control::guest_shutdown(self.vm_id, 0);
+                    } else {
+                        warn!("VM {} sleep type {} not supported", self.vm_id, slp_typ);
+                    }
+                }
+            }
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u16(self.status.load(Ordering::Relaxed));
+        w.put_u16(self.enable.load(Ordering::Relaxed));
+        w.put_u16(self.control.load(Ordering::Relaxed));
+    }
+
+    fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        self.status.store(r.get_u16()?, Ordering::Relaxed);
+        self.enable.store(r.get_u16()?, Ordering::Relaxed);
+        self.control.store(r.get_u16()?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.status.store(0, Ordering::Relaxed);
+        self.enable.store(0, Ordering::Relaxed);
+        self.control.store(PM1_CNT_SCI_EN, Ordering::Relaxed);
+    }
+}
+
+impl AcpiPm {
+    pub const fn new(port_base: u16, vm_id: usize) -> Self {
+        Self {
+            port_base,
+            vm_id,
+            status: AtomicU16::new(0),
+            enable: AtomicU16::new(0),
+            control: AtomicU16::new(PM1_CNT_SCI_EN),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Emulated memory balloon, through which the guest gives unused pages back
+//! to the host.
+//!
+//! All registers are 32-bit:
+//! - `base + 0`, read: number of pages the host asks the guest to give back.
+//! - `base + 0`, write: the guest gives back the page of this frame number.
+//! - `base + 4`, read: number of pages currently given back.
+//! - `base + 4`, write: the guest takes back the page of this frame number.
+
+extern crate alloc;
+use alloc::collections::BTreeSet;
+use spinlock::SpinNoIrq;
+
+use super::super::demand_paging;
+use super::PortIoDevice;
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{HyperError, HyperResult};
+
+const REG_TARGET_INFLATE: u16 = 0;
+const REG_SIZE_DEFLATE: u16 = 4;
+
+pub struct Balloon {
+    port_base: u16,
+    vm_id: usize,
+    pages: SpinNoIrq<BTreeSet<usize>>,
+}
+
+impl PortIoDevice for Balloon {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port_base..self.port_base + 8
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        match port - self.port_base {
+            REG_TARGET_INFLATE => Ok(demand_paging::balloon_target(self.vm_id) as u32),
+            REG_SIZE_DEFLATE => Ok(self.pages.lock().len() as u32),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        let pfn = value as usize;
+        match port - self.port_base {
+            REG_TARGET_INFLATE => {
+                demand_paging::reclaim(self.vm_id, pfn << 12)?;
+                self.pages.lock().insert(pfn);
+                Ok(())
+            }
+            REG_SIZE_DEFLATE => {
+                // The page is populated again when the guest touches it.
+                self.pages.lock().remove(&pfn);
+                Ok(())
+            }
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        let pages = self.pages.lock();
+        w.put_u64(pages.len() as u64);
+        for &pfn in pages.iter() {
+            w.put_u64(pfn as u64);
+        }
+    }
+
+    fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        let mut pages = self.pages.lock();
+        pages.clear();
+        for _ in 0..r.get_u64()? {
+            pages.insert(r.get_u64()? as usize);
+        }
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.pages.lock().clear();
+    }
+}
+
+impl Balloon {
+    pub fn new(port_base: u16, vm_id: usize) -> Self {
+        Self {
+            port_base,
+            vm_id,
+            pages: SpinNoIrq::new(BTreeSet::new()),
+        }
+    }
+}
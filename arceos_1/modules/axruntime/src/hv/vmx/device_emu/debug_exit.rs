@@ -0,0 +1,33 @@
+//! Emulated QEMU `isa-debug-exit` device: a write powers the VM off, with the
+//! written value as its exit code.
+
+use super::super::vm_control;
+use super::PortIoDevice;
+use hypercraft::{HyperError, HyperResult};
+
+pub struct DebugExit {
+    port_base: u16,
+    vm_id: usize,
+}
+
+impl PortIoDevice for DebugExit {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port_base..self.port_base + 4
+    }
+
+    fn read(&self, _port: u16, _access_size: u8) -> HyperResult<u32> {
+        Err(HyperError::NotSupported) // write-only
+    }
+
+    fn write(&self, _port: u16, _access_size: u8, value: u32) -> HyperResult {
+        info!("VM {} exited with code {:#x}", self.vm_id, value);
+        vm_control::guest_shutdown(self.vm_id, value);
+        Ok(())
+    }
+}
+
+impl DebugExit {
+    pub const fn new(port_base: u16, vm_id: usize) -> Self {
+        Self { port_base, vm_id }
+    }
+}
@@ -0,0 +1,60 @@
+//! Emulated doorbell of the shared memory regions, through which a VM sends
+//! interrupts to the peers attached to the same region.
+//!
+//! All registers are 32-bit:
+//! - `base + 0`, read: the ID of this VM, to tell peers apart.
+//! - `base + 4`, write: bits 0..16 are the peer VM ID, or `0xffff` for all
+//!   peers, bits 16..24 are the number of the region in this VM, and bits
+//!   24..32 are the vCPU of the peers to interrupt.
+
+use super::super::shmem;
+use super::PortIoDevice;
+use hypercraft::{HyperError, HyperResult};
+
+const REG_VM_ID: u16 = 0;
+const REG_DOORBELL: u16 = 4;
+
+pub struct Doorbell {
+    port_base: u16,
+    vm_id: usize,
+}
+
+impl PortIoDevice for Doorbell {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port_base..self.port_base + 8
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        match port - self.port_base {
+            REG_VM_ID => Ok(self.vm_id as u32),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        match port - self.port_base {
+            REG_DOORBELL => {
+                let peer = (value & 0xffff) as usize;
+                let slot = ((value >> 16) & 0xff) as usize;
+                let vcpu_id = (value >> 24) as usize;
+                if shmem::ring_doorbell(self.vm_id, slot, peer, vcpu_id).is_err() {
+                    warn!("VM {} rings the doorbell of unknown region {}", self.vm_id, slot);
+                }
+                Ok(())
+            }
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+}
+
+impl Doorbell {
+    pub const fn new(port_base: u16, vm_id: usize) -> Self {
+        Self { port_base, vm_id }
+    }
+}
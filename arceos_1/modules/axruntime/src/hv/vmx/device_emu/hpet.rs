@@ -0,0 +1,203 @@
+//! Emulated HPET, whose main counter runs on the virtual clock of the VM, so
+//! that it stays coherent with the guest TSC and the virtual local APIC timers.
+//!
+//! The comparators can be programmed and read back, but no interrupt route is
+//! offered in their capabilities and they never fire: guests only use the
+//! HPET as a clock source, and the local APIC timer for clock events.
+//! (IA-PC HPET Specification 1.0a, Section 2.3)
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinlock::SpinNoIrq;
+
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Guest physical address of the register block.
+pub const HPET_GPA: GuestPhysAddr = 0xfed0_0000;
+const HPET_SIZE: usize = 0x400;
+
+const NUM_TIMERS: usize = 3;
+/// Period of the main counter in femtoseconds, the longest the specification
+/// allows, i.e. 10 MHz.
+const COUNTER_CLK_PERIOD_FS: u64 = 100_000_000;
+const COUNTER_CLK_PERIOD_NS: u64 = COUNTER_CLK_PERIOD_FS / 1_000_000;
+
+/// Low 32 bits of the capabilities register: revision 1, `NUM_TIMERS`
+/// timers, a 64-bit main counter, no legacy replacement route, and Intel as
+/// the vendor. The ACPI HPET table reports it as the Event Timer Block ID.
+pub const HPET_BLOCK_ID: u32 = 0x8086_0000 | (1 << 13) | ((NUM_TIMERS as u32 - 1) << 8) | 1;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_INT_STATUS: usize = 0x020;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+const REG_TIMER_BASE: usize = 0x100;
+const TIMER_STRIDE: usize = 0x20;
+const TIMER_CONFIG: usize = 0x00;
+const TIMER_COMPARATOR: usize = 0x08;
+const TIMER_FSB_ROUTE: usize = 0x10;
+
+/// `ENABLE_CNF` of the configuration register.
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// `Tn_SIZE_CAP` of the timer configuration registers: 64-bit comparators.
+const TIMER_SIZE_CAP: u64 = 1 << 5;
+/// Writable bits of the timer configuration registers: `Tn_INT_TYPE_CNF`,
+/// `Tn_INT_ENB_CNF`, `Tn_TYPE_CNF`, `Tn_VAL_SET_CNF`, `Tn_32MODE_CNF` and
+/// `Tn_INT_ROUTE_CNF`.
+const TIMER_CONFIG_MASK: u64 = 0x3f4e;
+
+#[derive(Clone, Copy, Default)]
+struct HpetTimer {
+    config: u64,
+    comparator: u64,
+}
+
+#[derive(Default)]
+struct HpetState {
+    config: u64,
+    /// The main counter at virtual time `base_ns`.
+    counter: u64,
+    base_ns: u64,
+    timers: [HpetTimer; NUM_TIMERS],
+}
+
+impl HpetState {
+    fn counter(&self, now_ns: u64) -> u64 {
+        if self.config & CONFIG_ENABLE == 0 {
+            return self.counter;
+        }
+        let elapsed = now_ns.saturating_sub(self.base_ns) / COUNTER_CLK_PERIOD_NS;
+        self.counter.wrapping_add(elapsed)
+    }
+
+    fn set_counter(&mut self, counter: u64, now_ns: u64) {
+        self.counter = counter;
+        self.base_ns = now_ns;
+    }
+
+    fn read(&self, reg: usize, now_ns: u64) -> u64 {
+        match reg {
+            REG_CAPABILITIES => (COUNTER_CLK_PERIOD_FS << 32) | HPET_BLOCK_ID as u64,
+            REG_CONFIG => self.config,
+            REG_INT_STATUS => 0,
+            REG_MAIN_COUNTER => self.counter(now_ns),
+            _ => match timer_reg(reg) {
+                Some((n, TIMER_CONFIG)) => self.timers[n].config | TIMER_SIZE_CAP,
+                Some((n, TIMER_COMPARATOR)) => self.timers[n].comparator,
+                _ => 0,
+            },
+        }
+    }
+
+    fn write(&mut self, reg: usize, value: u64, now_ns: u64) {
+        match reg {
+            REG_CONFIG => {
+                // Freeze or restart the main counter at its current value.
+                let counter = self.counter(now_ns);
+                self.config = value & CONFIG_ENABLE;
+                self.set_counter(counter, now_ns);
+            }
+            REG_MAIN_COUNTER => self.set_counter(value, now_ns),
+            _ => match timer_reg(reg) {
+                Some((n, TIMER_CONFIG)) => self.timers[n].config = value & TIMER_CONFIG_MASK,
+                Some((n, TIMER_COMPARATOR)) => self.timers[n].comparator = value,
+                // The interrupt status, the FSB routes and the read-only
+                // registers ignore writes.
+                _ => {}
+            },
+        }
+    }
+}
+
+/// The timer and the offset of its register `reg` belongs to.
+fn timer_reg(reg: usize) -> Option<(usize, usize)> {
+    let n = reg.checked_sub(REG_TIMER_BASE)? / TIMER_STRIDE;
+    let offset = (reg - REG_TIMER_BASE) % TIMER_STRIDE;
+    (n < NUM_TIMERS && offset <= TIMER_FSB_ROUTE).then_some((n, offset))
+}
+
+pub struct Hpet {
+    /// Whether the VM is configured with an HPET.
+    enabled: AtomicBool,
+    state: SpinNoIrq<HpetState>,
+}
+
+impl Hpet {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            state: SpinNoIrq::new(HpetState::default()),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    /// Whether `gpa` is in the registers of the HPET, if the VM has one.
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        self.enabled.load(Ordering::Acquire) && (HPET_GPA..HPET_GPA + HPET_SIZE).contains(&gpa)
+    }
+
+    /// Reads `access_size` bytes at `gpa`, `now_ns` being the virtual time
+    /// of the VM. The 64-bit registers can also be read in 32-bit halves.
+    pub fn read(&self, gpa: GuestPhysAddr, access_size: u8, now_ns: u64) -> HyperResult<u64> {
+        let offset = gpa - HPET_GPA;
+        let value = self.state.lock().read(offset & !7, now_ns);
+        match (access_size, offset & 7) {
+            (8, 0) => Ok(value),
+            (4, 0) => Ok(value & 0xffff_ffff),
+            (4, 4) => Ok(value >> 32),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Writes `access_size` bytes at `gpa`, `now_ns` being the virtual time
+    /// of the VM.
+    pub fn write(&self, gpa: GuestPhysAddr, access_size: u8, value: u64, now_ns: u64) -> HyperResult {
+        let offset = gpa - HPET_GPA;
+        let reg = offset & !7;
+        let mut state = self.state.lock();
+        let value = match (access_size, offset & 7) {
+            (8, 0) => value,
+            (4, 0) => (state.read(reg, now_ns) & !0xffff_ffff) | (value & 0xffff_ffff),
+            (4, 4) => (state.read(reg, now_ns) & 0xffff_ffff) | (value << 32),
+            _ => return Err(HyperError::InvalidParam),
+        };
+        state.write(reg, value, now_ns);
+        Ok(())
+    }
+
+    /// Saves the registers. The main counter is kept relative to the virtual
+    /// time, which a restored VM continues from.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let state = self.state.lock();
+        w.put_u64(state.config);
+        w.put_u64(state.counter);
+        w.put_u64(state.base_ns);
+        for timer in &state.timers {
+            w.put_u64(timer.config);
+            w.put_u64(timer.comparator);
+        }
+    }
+
+    /// Restores the registers saved by [`Hpet::save_state`].
+    pub fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        let mut state = HpetState {
+            config: r.get_u64()? & CONFIG_ENABLE,
+            counter: r.get_u64()?,
+            base_ns: r.get_u64()?,
+            ..Default::default()
+        };
+        for timer in &mut state.timers {
+            timer.config = r.get_u64()? & TIMER_CONFIG_MASK;
+            timer.comparator = r.get_u64()?;
+        }
+        *self.state.lock() = state;
+        Ok(())
+    }
+
+    pub fn reset(&self) {
+        *self.state.lock() = HpetState::default();
+    }
+}
@@ -0,0 +1,48 @@
+//! Emulated Intel 8042 keyboard controller, only to reset the VM by pulsing
+//! the CPU reset line with command `0xfe`. There is no keyboard behind it.
+//! (ref: https://wiki.osdev.org/%228042%22_PS/2_Controller)
+//!
+//! Port `0x61` (system control port B) in the middle is read as 0.
+
+use super::super::vm_control;
+use super::PortIoDevice;
+use hypercraft::{HyperError, HyperResult};
+
+const DATA_PORT: u16 = 0x60;
+const COMMAND_PORT: u16 = 0x64;
+const CMD_PULSE_RESET: u8 = 0xfe;
+
+pub struct I8042 {
+    vm_id: usize,
+}
+
+impl PortIoDevice for I8042 {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        DATA_PORT..COMMAND_PORT + 1
+    }
+
+    fn read(&self, _port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        // No data, and the input buffer is always empty.
+        Ok(0)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        if port == COMMAND_PORT && value as u8 == CMD_PULSE_RESET {
+            info!("VM {} reset through the keyboard controller", self.vm_id);
+            vm_control::guest_reset(self.vm_id);
+        }
+        Ok(()) // ignore other commands and data
+    }
+}
+
+impl I8042 {
+    pub const fn new(vm_id: usize) -> Self {
+        Self { vm_id }
+    }
+}
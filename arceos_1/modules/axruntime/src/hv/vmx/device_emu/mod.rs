@@ -1,20 +1,42 @@
 
+mod acpi_pm;
+mod balloon;
+mod debug_exit;
+mod doorbell;
+mod hpet;
+mod i8042;
 mod i8259_pic;
 mod lapic;
+mod reset_control;
 mod uart16550;
+mod virtio_net;
 
 extern crate alloc;
 use alloc::{sync::Arc, vec, vec::Vec};
-use hypercraft::HyperResult;
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{HyperError, HyperResult};
 
+pub use self::hpet::{Hpet, HPET_BLOCK_ID, HPET_GPA};
 pub use self::lapic::VirtLocalApic;
 use self::uart16550::Uart16550;
+pub use self::virtio_net::VirtioNet;
 use core::any::Any;
 
 pub trait PortIoDevice: Any + Send + Sync {
     fn port_range(&self) -> core::ops::Range<u16>;
     fn read(&self, port: u16, access_size: u8) -> HyperResult<u32>;
     fn write(&self, port: u16, access_size: u8, value: u32) -> HyperResult;
+
+    /// Saves the device state for snapshots, nothing for stateless devices.
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    /// Restores the device state saved by [`PortIoDevice::save_state`].
+    fn restore_state(&self, _r: &mut StateReader) -> HyperResult {
+        Ok(())
+    }
+
+    /// Puts the device back to its power-on state when the VM is reset.
+    fn reset(&self) {}
 }
 
 impl dyn PortIoDevice {
@@ -43,6 +65,7 @@ impl dyn PortIoDevice {
 
 pub struct VirtDeviceList {
     port_io_devices: Vec<Arc<dyn PortIoDevice>>,
+    hpet: Hpet,
 }
 
 impl VirtDeviceList {
@@ -52,6 +75,62 @@ impl VirtDeviceList {
             .find(|dev| dev.port_range().contains(&port))
     }
 
+    /// The HPET, the only device emulated by MMIO.
+    pub fn hpet(&self) -> &Hpet {
+        &self.hpet
+    }
+
+    /// Port ranges of all devices.
+    pub fn port_ranges(&self) -> impl Iterator<Item = core::ops::Range<u16>> + '_ {
+        self.port_io_devices.iter().map(|dev| dev.port_range())
+    }
+
+    /// Saves the states of all devices, in the order they are registered.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.put_u32(self.port_io_devices.len() as u32);
+        for dev in &self.port_io_devices {
+            let mut dev_w = StateWriter::new();
+            dev.save_state(&mut dev_w);
+            w.put_u16(dev.port_range().start);
+            w.put_bytes(dev_w.as_bytes());
+        }
+        self.hpet.save_state(w);
+    }
+
+    /// Restores the device states saved by [`VirtDeviceList::save_state`].
+    pub fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        if r.get_u32()? as usize != self.port_io_devices.len() {
+            return Err(HyperError::DecodeError);
+        }
+        for dev in &self.port_io_devices {
+            if r.get_u16()? != dev.port_range().start {
+                return Err(HyperError::DecodeError);
+            }
+            let mut dev_r = StateReader::new(r.get_bytes()?);
+            dev.restore_state(&mut dev_r)?;
+            if dev_r.remaining() != 0 {
+                return Err(HyperError::DecodeError);
+            }
+        }
+        self.hpet.restore_state(r)
+    }
+
+    /// Resets all devices to their power-on states.
+    pub fn reset(&self) {
+        for dev in &self.port_io_devices {
+            dev.reset();
+        }
+        self.hpet.reset();
+    }
+
+    pub fn find_virtio_net(&self) -> Option<Arc<VirtioNet>> {
+        let dev = self.port_io_devices.iter().find(|dev| {
+            let dev: &dyn Any = &***dev;
+            dev.is::<VirtioNet>()
+        })?;
+        dev.clone().downcast_arc::<VirtioNet>()
+    }
+
     pub fn find_uart(&self, port: u16) -> Option<Arc<Uart16550>> {
         if let Some(dev) = self.find_port_io_device(port) {
             let p = dev.clone().downcast_arc::<Uart16550>().unwrap();
@@ -65,6 +144,11 @@ impl VirtDeviceList {
 
 pub const MAX_VMS: usize = 2;
 
+/// Base port of the ACPI PM1a event and control registers.
+pub(super) const ACPI_PM1A_PORT: u16 = 0x600;
+/// Port of the reset control register.
+pub(super) const RESET_CONTROL_PORT: u16 = 0xcf9;
+
 lazy_static::lazy_static! {
     static ref VIRT_DEVICES : Vec<VirtDeviceList> = {
         let mut temp = Vec::new();
@@ -74,7 +158,15 @@ lazy_static::lazy_static! {
                     Arc::new(uart16550::Uart16550::new(0x3f8, i)), // COM1
                     Arc::new(i8259_pic::I8259Pic::new(0x20)), // PIC1
                     Arc::new(i8259_pic::I8259Pic::new(0xA0)), // PIC2
+                    Arc::new(balloon::Balloon::new(0x700, i)), // memory balloon
+                    Arc::new(doorbell::Doorbell::new(0x710, i)), // shared memory doorbell
+                    Arc::new(i8042::I8042::new(i)), // keyboard controller
+                    Arc::new(acpi_pm::AcpiPm::new(ACPI_PM1A_PORT, i)), // ACPI PM1a
+                    Arc::new(reset_control::ResetControl::new(RESET_CONTROL_PORT, i)), // reset control
+                    Arc::new(debug_exit::DebugExit::new(0xf4, i)), // QEMU debug exit
+                    Arc::new(virtio_net::VirtioNet::new(0xc000, i)), // virtio-net
                 ],
+                hpet: Hpet::new(),
             });
         };
         temp
@@ -84,3 +176,8 @@ lazy_static::lazy_static! {
 pub fn all_virt_devices(id:usize) -> &'static VirtDeviceList {
     &VIRT_DEVICES[id]
 }
+
+/// Sets whether the VM has an HPET, from its configuration.
+pub fn set_hpet_enabled(vm_id: usize, enabled: bool) {
+    VIRT_DEVICES[vm_id].hpet.set_enabled(enabled);
+}
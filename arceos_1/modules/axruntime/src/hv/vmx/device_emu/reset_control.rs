@@ -0,0 +1,67 @@
+//! Emulated reset control register at port `0xcf9`, as in Intel chipsets.
+//! Setting `RST_CPU` resets the VM.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::super::vm_control;
+use super::PortIoDevice;
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{HyperError, HyperResult};
+
+/// `RST_CPU`: resets the CPU, a hard or full reset is not different to us.
+const RST_CPU: u8 = 1 << 2;
+
+pub struct ResetControl {
+    port: u16,
+    vm_id: usize,
+    value: AtomicU8,
+}
+
+impl PortIoDevice for ResetControl {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port..self.port + 1
+    }
+
+    fn read(&self, _port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(self.value.load(Ordering::Relaxed) as u32)
+    }
+
+    fn write(&self, _port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        let value = value as u8;
+        self.value.store(value & !RST_CPU, Ordering::Relaxed);
+        if value & RST_CPU != 0 {
+            info!("VM {} reset through port {:#x}", self.vm_id, self.port);
+            vm_control::guest_reset(self.vm_id);
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u8(self.value.load(Ordering::Relaxed));
+    }
+
+    fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        self.value.store(r.get_u8()?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.value.store(0, Ordering::Relaxed);
+    }
+}
+
+impl ResetControl {
+    pub const fn new(port: u16, vm_id: usize) -> Self {
+        Self {
+            port,
+            vm_id,
+            value: AtomicU8::new(0),
+        }
+    }
+}
@@ -1,8 +1,12 @@
 //! Emulated UART 16550. (ref: https://wiki.osdev.org/Serial_Ports)
 
+extern crate alloc;
+use alloc::vec::Vec;
+
+use super::super::console;
 use super::PortIoDevice;
 
-use axhal::console as uart;
+use hypercraft::snapshot::{StateReader, StateWriter};
 use hypercraft::{HyperError, HyperResult};
 use spin::Mutex;
 
@@ -102,13 +106,13 @@ impl PortIoDevice for Uart16550 {
             }
             LINE_STATUS_REG => {
                 
-                // check if the physical serial port has an available byte, and push it to FIFO.
+                // check if the host console has a byte for this VM, and push it to FIFO.
                 let mut fifo = self.fifo.lock();
                 let int_en = self.int_en.lock();
                 // info!("port {} read lrs, num: {}", port, fifo.num);
                 if *int_en == 0 {
                     if !fifo.is_full() {
-                        if let Some(c) = uart::getchar() {
+                        if let Some(c) = console::guest_getchar(self.id) {
                             fifo.push(c);
                         }
                     }
@@ -163,7 +167,7 @@ impl PortIoDevice for Uart16550 {
                     }
                     
                 } else {
-                    uart::putchar(value as u8);
+                    console::guest_putchar(self.id, value as u8);
                 }
                 
             }
@@ -179,7 +183,32 @@ impl PortIoDevice for Uart16550 {
         }
         Ok(())
     }
-    
+
+    fn save_state(&self, w: &mut StateWriter) {
+        let fifo = self.fifo.lock();
+        let bytes: Vec<u8> = (0..fifo.num).map(|i| fifo.buf[(fifo.head + i) % UART_FIFO_CAPACITY]).collect();
+        w.put_bytes(&bytes);
+        w.put_u8(*self.int_en.lock());
+    }
+
+    fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        let bytes = r.get_bytes()?;
+        if bytes.len() > UART_FIFO_CAPACITY {
+            return Err(HyperError::DecodeError);
+        }
+        let mut fifo = self.fifo.lock();
+        *fifo = Fifo::new();
+        for &c in bytes {
+            fifo.push(c);
+        }
+        *self.int_en.lock() = r.get_u8()?;
+        Ok(())
+    }
+
+    fn reset(&self) {
+        *self.fifo.lock() = Fifo::new();
+        *self.int_en.lock() = 0;
+    }
 }
 
 impl Uart16550 {
@@ -0,0 +1,539 @@
+//! Emulated virtio-net device, connected to the virtual switch. (ref: Virtual
+//! I/O Device (VIRTIO) Version 1.1, Section 4.1.4.8 and 5.1)
+//!
+//! The registers have the layout of a legacy virtio PCI device in its I/O
+//! BAR, at a fixed port base, and interrupts are sent with the vector given
+//! to [`vswitch::attach`](super::super::vswitch::attach). Queue 0 receives
+//! and queue 1 transmits, with the legacy 10-byte packet header.
+//!
+//! There is no PCI function nor ACPI description of the device, so guests
+//! cannot discover it: their driver must be told the port base (0xc000) and
+//! the vector, which are both in the VM configuration. Standard virtio-pci
+//! drivers do not find it.
+//!
+//! Malformed queues from the driver, e.g., a descriptor loop or a buffer
+//! out of guest memory, set `DEVICE_NEEDS_RESET`: the chain being processed
+//! is dropped, the device stops until the driver resets it, and the driver
+//! is notified with a configuration change interrupt.
+
+extern crate alloc;
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{fence, Ordering};
+
+use axhal::mem::{phys_to_virt, PAGE_SIZE_4K};
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult};
+use spinlock::SpinNoIrq;
+
+use super::super::{demand_paging, dirty_log, notify_vcpu, vswitch};
+use super::PortIoDevice;
+use crate::GuestPageTable;
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+const REG_CONFIG: u16 = 0x14;
+// MAC address and link status
+const CONFIG_SIZE: u16 = 8;
+
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u32 = 1 << 16;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+const ISR_QUEUE: u8 = 1;
+const ISR_CONFIG: u8 = 2;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const QUEUE_SIZE: u16 = 256;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const NET_HDR_LEN: usize = 10;
+// ethernet frames with a VLAN tag
+const MAX_FRAME_LEN: usize = 1518;
+// frames kept while the guest has no receive buffers
+const MAX_RX_BACKLOG: usize = 64;
+
+/// Guest physical memory accessed by the device through the EPT of the VM.
+struct GuestMemory {
+    vm_id: usize,
+    npt: Arc<SpinNoIrq<GuestPageTable>>,
+}
+
+impl GuestMemory {
+    fn host_ptr(&self, gpa: GuestPhysAddr) -> HyperResult<*mut u8> {
+        // RAM populated on demand is not in the EPT before the guest touches it
+        let hpa = demand_paging::populate(self.vm_id, gpa)
+            .or_else(|_| self.npt.lock().translate(gpa))?;
+        Ok(usize::from(phys_to_virt(hpa.into())) as *mut u8)
+    }
+
+    fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult {
+        gpa.checked_add(buf.len()).ok_or(HyperError::InvalidParam)?;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let len = (PAGE_SIZE_4K - (gpa + offset) % PAGE_SIZE_4K).min(buf.len() - offset);
+            let src = self.host_ptr(gpa + offset)?;
+            unsafe { core::ptr::copy_nonoverlapping(src, buf[offset..].as_mut_ptr(), len) };
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult {
+        gpa.checked_add(buf.len()).ok_or(HyperError::InvalidParam)?;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let len = (PAGE_SIZE_4K - (gpa + offset) % PAGE_SIZE_4K).min(buf.len() - offset);
+            let dst = self.host_ptr(gpa + offset)?;
+            unsafe { core::ptr::copy_nonoverlapping(buf[offset..].as_ptr(), dst, len) };
+            dirty_log::mark_dirty(self.vm_id, gpa + offset);
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn read_u16(&self, gpa: GuestPhysAddr) -> HyperResult<u16> {
+        let mut buf = [0; 2];
+        self.read(gpa, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn write_u16(&self, gpa: GuestPhysAddr, value: u16) -> HyperResult {
+        self.write(gpa, &value.to_le_bytes())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue in the legacy layout, where the used ring starts at the
+/// next page after the available ring.
+#[derive(Clone, Copy, Default)]
+struct Virtqueue {
+    pfn: u32,
+    last_avail_idx: u16,
+}
+
+impl Virtqueue {
+    fn desc_addr(&self) -> GuestPhysAddr {
+        (self.pfn as usize) * PAGE_SIZE_4K
+    }
+
+    fn avail_addr(&self) -> GuestPhysAddr {
+        self.desc_addr() + 16 * QUEUE_SIZE as usize
+    }
+
+    fn used_addr(&self) -> GuestPhysAddr {
+        let avail_end = self.avail_addr() + 6 + 2 * QUEUE_SIZE as usize;
+        (avail_end + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.pfn != 0
+    }
+
+    /// Takes the head of the next available descriptor chain.
+    fn pop_avail(&mut self, mem: &GuestMemory) -> HyperResult<Option<u16>> {
+        let avail_idx = mem.read_u16(self.avail_addr() + 2)?;
+        if avail_idx == self.last_avail_idx {
+            return Ok(None);
+        }
+        // read the ring entry after the index
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail_idx % QUEUE_SIZE) as usize;
+        let head = mem.read_u16(self.avail_addr() + 4 + 2 * slot)?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        Ok(Some(head % QUEUE_SIZE))
+    }
+
+    fn descriptor(&self, mem: &GuestMemory, index: u16) -> HyperResult<Descriptor> {
+        let mut buf = [0; 16];
+        mem.read(self.desc_addr() + 16 * index as usize, &mut buf)?;
+        Ok(Descriptor {
+            addr: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            flags: u16::from_le_bytes(buf[12..14].try_into().unwrap()),
+            next: u16::from_le_bytes(buf[14..16].try_into().unwrap()),
+        })
+    }
+
+    /// Returns the descriptors of the chain starting at `head`.
+    fn chain(&self, mem: &GuestMemory, head: u16) -> HyperResult<Vec<Descriptor>> {
+        let mut descs = Vec::new();
+        let mut index = head;
+        loop {
+            let desc = self.descriptor(mem, index)?;
+            descs.push(desc);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(descs);
+            }
+            if descs.len() >= QUEUE_SIZE as usize {
+                // a loop in the chain
+                return Err(HyperError::InvalidParam);
+            }
+            index = desc.next % QUEUE_SIZE;
+        }
+    }
+
+    /// Gives the chain starting at `head` back to the driver, with `len`
+    /// bytes written to it.
+    fn push_used(&self, mem: &GuestMemory, head: u16, len: u32) -> HyperResult {
+        let used_idx = mem.read_u16(self.used_addr() + 2)?;
+        let slot = (used_idx % QUEUE_SIZE) as usize;
+        let mut elem = [0; 8];
+        elem[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&len.to_le_bytes());
+        mem.write(self.used_addr() + 4 + 8 * slot, &elem)?;
+        // publish the ring entry before the index
+        fence(Ordering::Release);
+        mem.write_u16(self.used_addr() + 2, used_idx.wrapping_add(1))
+    }
+
+    fn needs_interrupt(&self, mem: &GuestMemory) -> HyperResult<bool> {
+        fence(Ordering::SeqCst);
+        Ok(mem.read_u16(self.avail_addr())? & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
+
+#[derive(Default)]
+struct NetState {
+    mac: [u8; 6],
+    vector: u8,
+    // not connected to the switch if `None`
+    mem: Option<GuestMemory>,
+    guest_features: u32,
+    queue_select: u16,
+    status: u8,
+    isr: u8,
+    queues: [Virtqueue; 2],
+    rx_backlog: VecDeque<Vec<u8>>,
+}
+
+impl NetState {
+    fn reset_device(&mut self) {
+        self.guest_features = 0;
+        self.queue_select = 0;
+        self.status = 0;
+        self.isr = 0;
+        self.queues = Default::default();
+        self.rx_backlog.clear();
+    }
+
+    fn is_running(&self) -> bool {
+        self.mem.is_some()
+            && self.status & STATUS_DRIVER_OK != 0
+            && self.status & STATUS_DEVICE_NEEDS_RESET == 0
+    }
+
+    /// Stops the device after the driver gave it a malformed queue, until it
+    /// resets the device. Returns the vector of the configuration change
+    /// interrupt telling the driver.
+    fn set_needs_reset(&mut self) -> u8 {
+        self.status |= STATUS_DEVICE_NEEDS_RESET;
+        self.isr |= ISR_CONFIG;
+        self.rx_backlog.clear();
+        self.vector
+    }
+
+    /// Handles a notification of the queue `queue`, returns the frames to
+    /// transmit, and the vector to send if any.
+    fn notify_queue(&mut self, queue: usize) -> HyperResult<(Vec<Vec<u8>>, Option<u8>)> {
+        match queue {
+            RX_QUEUE => match self.flush_rx_backlog()? {
+                true => Ok((Vec::new(), self.raise_interrupt(RX_QUEUE)?)),
+                false => Ok((Vec::new(), None)),
+            },
+            TX_QUEUE => {
+                let frames = self.take_tx_frames()?;
+                match frames.is_empty() {
+                    true => Ok((frames, None)),
+                    false => Ok((frames, self.raise_interrupt(TX_QUEUE)?)),
+                }
+            }
+            _ => Ok((Vec::new(), None)),
+        }
+    }
+
+    fn config_byte(&self, offset: u16) -> u8 {
+        match offset {
+            0..=5 => self.mac[offset as usize],
+            6 => VIRTIO_NET_S_LINK_UP as u8,
+            _ => 0,
+        }
+    }
+
+    /// Copies `frame` to the next receive buffer, returns `false` if the guest
+    /// has given none.
+    fn fill_rx_buffer(&mut self, frame: &[u8]) -> HyperResult<bool> {
+        let mem = self.mem.as_ref().ok_or(HyperError::BadState)?;
+        let queue = &mut self.queues[RX_QUEUE];
+        if !queue.is_ready() {
+            return Ok(false);
+        }
+        let Some(head) = queue.pop_avail(mem)? else {
+            return Ok(false);
+        };
+        // a zeroed header: no checksum offload nor segmentation
+        let header = [0; NET_HDR_LEN];
+        let mut data = header.iter().chain(frame.iter()).copied();
+        let mut written = 0;
+        for desc in queue.chain(mem, head)? {
+            if desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+                continue;
+            }
+            let chunk: Vec<u8> = data.by_ref().take(desc.len as usize).collect();
+            mem.write(desc.addr as usize, &chunk)?;
+            written += chunk.len();
+            if written == NET_HDR_LEN + frame.len() {
+                break;
+            }
+        }
+        if written < NET_HDR_LEN + frame.len() {
+            warn!("VM {} receive buffer too small, frame truncated", mem.vm_id);
+        }
+        queue.push_used(mem, head, written as u32)?;
+        Ok(true)
+    }
+
+    /// Moves as many frames of the backlog as possible to receive buffers,
+    /// returns whether any was moved.
+    fn flush_rx_backlog(&mut self) -> HyperResult<bool> {
+        let mut moved = false;
+        while let Some(frame) = self.rx_backlog.pop_front() {
+            if !self.fill_rx_buffer(&frame)? {
+                self.rx_backlog.push_front(frame);
+                break;
+            }
+            moved = true;
+        }
+        Ok(moved)
+    }
+
+    /// Takes all frames the guest has queued for transmission.
+    fn take_tx_frames(&mut self) -> HyperResult<Vec<Vec<u8>>> {
+        let mem = self.mem.as_ref().ok_or(HyperError::BadState)?;
+        let queue = &mut self.queues[TX_QUEUE];
+        let mut frames = Vec::new();
+        if !queue.is_ready() {
+            return Ok(frames);
+        }
+        while let Some(head) = queue.pop_avail(mem)? {
+            let mut packet = Vec::new();
+            for desc in queue.chain(mem, head)? {
+                if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
+                    continue;
+                }
+                let len = (desc.len as usize).min(NET_HDR_LEN + MAX_FRAME_LEN - packet.len());
+                let start = packet.len();
+                packet.resize(start + len, 0);
+                mem.read(desc.addr as usize, &mut packet[start..])?;
+            }
+            queue.push_used(mem, head, 0)?;
+            if packet.len() > NET_HDR_LEN {
+                frames.push(packet.split_off(NET_HDR_LEN));
+            }
+        }
+        Ok(frames)
+    }
+
+    /// Raises the queue interrupt, returns the vector to send if the driver
+    /// has not suppressed it.
+    fn raise_interrupt(&mut self, queue: usize) -> HyperResult<Option<u8>> {
+        let mem = self.mem.as_ref().ok_or(HyperError::BadState)?;
+        self.isr |= ISR_QUEUE;
+        Ok(self.queues[queue].needs_interrupt(mem)?.then_some(self.vector))
+    }
+}
+
+pub struct VirtioNet {
+    port_base: u16,
+    vm_id: usize,
+    state: SpinNoIrq<NetState>,
+}
+
+impl PortIoDevice for VirtioNet {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port_base..self.port_base + REG_CONFIG + CONFIG_SIZE
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> HyperResult<u32> {
+        let mut state = self.state.lock();
+        let offset = port - self.port_base;
+        let value = match offset {
+            REG_DEVICE_FEATURES => VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS,
+            REG_GUEST_FEATURES => state.guest_features,
+            REG_QUEUE_PFN => state
+                .queues
+                .get(state.queue_select as usize)
+                .map_or(0, |q| q.pfn),
+            REG_QUEUE_SIZE => match state.queue_select as usize {
+                RX_QUEUE | TX_QUEUE => QUEUE_SIZE as u32,
+                _ => 0,
+            },
+            REG_QUEUE_SELECT => state.queue_select as u32,
+            REG_DEVICE_STATUS => state.status as u32,
+            // reading the ISR status acknowledges the interrupt
+            REG_ISR_STATUS => core::mem::take(&mut state.isr) as u32,
+            _ if offset >= REG_CONFIG => (0..access_size as u16).fold(0, |value, i| {
+                value | (state.config_byte(offset - REG_CONFIG + i) as u32) << (8 * i)
+            }),
+            // e.g., misaligned accesses
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&self, port: u16, _access_size: u8, value: u32) -> HyperResult {
+        let mut state = self.state.lock();
+        let mut vector = None;
+        let mut tx_frames = Vec::new();
+        match port - self.port_base {
+            REG_GUEST_FEATURES => state.guest_features = value & (VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS),
+            REG_QUEUE_PFN => {
+                let select = state.queue_select as usize;
+                if let Some(queue) = state.queues.get_mut(select) {
+                    *queue = Virtqueue {
+                        pfn: value,
+                        last_avail_idx: 0,
+                    };
+                }
+            }
+            REG_QUEUE_SELECT => state.queue_select = value as u16,
+            REG_QUEUE_NOTIFY if state.is_running() => match state.notify_queue(value as usize) {
+                Ok((frames, notify_vector)) => {
+                    tx_frames = frames;
+                    vector = notify_vector;
+                }
+                Err(err) => {
+                    warn!("VM {} virtio-net queue {} broken: {:?}", self.vm_id, value, err);
+                    vector = Some(state.set_needs_reset());
+                }
+            },
+            REG_QUEUE_NOTIFY => {}
+            REG_DEVICE_STATUS => {
+                if value == 0 {
+                    state.reset_device();
+                } else {
+                    // only a reset clears DEVICE_NEEDS_RESET
+                    state.status = value as u8 | (state.status & STATUS_DEVICE_NEEDS_RESET);
+                }
+            }
+            // read-only registers, the MAC address given by the host, and
+            // e.g. misaligned accesses
+            _ => {}
+        }
+        drop(state);
+
+        // the switch may deliver frames to this device
+        for frame in tx_frames.iter() {
+            vswitch::forward(vswitch::SwitchPort::Vm(self.vm_id), frame);
+        }
+        if let Some(vector) = vector {
+            notify_vcpu(self.vm_id, 0, vector);
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        let state = self.state.lock();
+        w.put_u32(state.guest_features);
+        w.put_u16(state.queue_select);
+        w.put_u8(state.status);
+        w.put_u8(state.isr);
+        for queue in state.queues.iter() {
+            w.put_u32(queue.pfn);
+            w.put_u16(queue.last_avail_idx);
+        }
+    }
+
+    fn restore_state(&self, r: &mut StateReader) -> HyperResult {
+        let mut state = self.state.lock();
+        state.reset_device();
+        state.guest_features = r.get_u32()?;
+        state.queue_select = r.get_u16()?;
+        state.status = r.get_u8()?;
+        state.isr = r.get_u8()?;
+        for queue in state.queues.iter_mut() {
+            queue.pfn = r.get_u32()?;
+            queue.last_avail_idx = r.get_u16()?;
+        }
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.state.lock().reset_device();
+    }
+}
+
+impl VirtioNet {
+    pub fn new(port_base: u16, vm_id: usize) -> Self {
+        Self {
+            port_base,
+            vm_id,
+            state: SpinNoIrq::new(NetState::default()),
+        }
+    }
+
+    /// Connects the device to the switch, with the MAC address `mac`, and the
+    /// guest memory mapped by `npt`.
+    pub fn attach(&self, mac: [u8; 6], vector: u8, npt: Arc<SpinNoIrq<GuestPageTable>>) {
+        let mut state = self.state.lock();
+        state.mac = mac;
+        state.vector = vector;
+        state.mem = Some(GuestMemory {
+            vm_id: self.vm_id,
+            npt,
+        });
+    }
+
+    /// Disconnects the device, the guest memory must not be accessed anymore.
+    pub fn detach(&self) {
+        let mut state = self.state.lock();
+        state.mem = None;
+        state.reset_device();
+    }
+
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.state.lock().mac
+    }
+
+    /// Delivers a frame from the switch to the guest, it's dropped if the
+    /// driver is not ready, or if too many frames wait for receive buffers.
+    pub fn receive(&self, frame: &[u8]) {
+        let mut state = self.state.lock();
+        if !state.is_running() {
+            return;
+        }
+        if state.rx_backlog.len() >= MAX_RX_BACKLOG {
+            return;
+        }
+        state.rx_backlog.push_back(frame.to_vec());
+        let res = state.flush_rx_backlog().and_then(|moved| match moved {
+            true => state.raise_interrupt(RX_QUEUE),
+            false => Ok(None),
+        });
+        let vector = res.unwrap_or_else(|err| {
+            warn!("VM {} virtio-net receive queue broken: {:?}", self.vm_id, err);
+            Some(state.set_needs_reset())
+        });
+        drop(state);
+        if let Some(vector) = vector {
+            notify_vcpu(self.vm_id, 0, vector);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Tracking of guest pages written since the last time they were collected.
+//!
+//! If the processor supports EPT accessed and dirty flags, the dirty flags of
+//! the EPT entries are collected and cleared. Otherwise, tracked regions are
+//! write-protected in the EPT, the first write to a page is recorded by the
+//! EPT violation handler, and the page is made writable again until the next
+//! collection.
+//!
+//! Dirty state is tracked per EPT mapping, so a write to a huge page marks
+//! all its 4K pages dirty.
+
+extern crate alloc;
+use alloc::{collections::BTreeSet, sync::Arc, vec, vec::Vec};
+
+use axhal::mem::PAGE_SIZE_4K;
+use hypercraft::{GuestPhysAddr, HostPhysAddr, HyperError, HyperResult};
+use page_table_entry::MappingFlags;
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::ept_flush;
+use crate::GuestPageTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirtyLogMode {
+    /// Use the dirty flags of EPT entries.
+    AccessedDirty,
+    /// Write-protect clean pages and record the first write to them.
+    WriteProtect,
+}
+
+#[derive(Clone, Copy)]
+struct TrackedRegion {
+    start: GuestPhysAddr,
+    size: usize,
+    flags: MappingFlags,
+    /// Not collected yet, so all pages are dirty.
+    all_dirty: bool,
+}
+
+impl TrackedRegion {
+    fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        self.start <= gpa && gpa < self.start + self.size
+    }
+}
+
+struct DirtyLog {
+    npt: Arc<SpinNoIrq<GuestPageTable>>,
+    mode: DirtyLogMode,
+    regions: Vec<TrackedRegion>,
+    /// Dirty 4K pages not yet collected, which are writable in the EPT in the
+    /// write-protect mode.
+    dirty: BTreeSet<GuestPhysAddr>,
+}
+
+impl DirtyLog {
+    fn region_of(&self, gpa: GuestPhysAddr) -> Option<TrackedRegion> {
+        self.regions.iter().find(|r| r.contains(gpa)).copied()
+    }
+
+    /// Marks dirty the 4K pages of the mapping of `page_size` containing
+    /// `gpa`, within `region`.
+    fn mark_mapping(&mut self, gpa: GuestPhysAddr, page_size: usize, region: &TrackedRegion) {
+        let first = (gpa & !(page_size - 1)).max(region.start);
+        let last = ((gpa & !(page_size - 1)) + page_size).min(region.start + region.size);
+        for page in (first..last).step_by(PAGE_SIZE_4K) {
+            self.dirty.insert(page);
+        }
+    }
+
+    /// Sets the permissions of the mapped pages in `[start, start + size)`.
+    fn protect_range(&self, start: GuestPhysAddr, size: usize, flags: MappingFlags) -> HyperResult {
+        let mut npt = self.npt.lock();
+        let mut gpa = start;
+        while gpa < start + size {
+            match npt.protect(gpa, flags) {
+                Ok(page_size) => gpa = (gpa & !(page_size - 1)) + page_size,
+                // not populated yet
+                Err(HyperError::NotFound) => gpa += PAGE_SIZE_4K,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// The EPT root, flushed with [`ept_flush::flush_all`] once the log is
+    /// unlocked, as other CPUs may wait for it in the EPT violation handler.
+    fn ept_root(&self) -> HostPhysAddr {
+        self.npt.lock().root_paddr().into()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DIRTY_LOGS: Vec<SpinNoIrq<Option<DirtyLog>>> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS {
+            temp.push(SpinNoIrq::new(None));
+        }
+        temp
+    };
+}
+
+/// Starts dirty page tracking for the VM whose EPT is `npt`, with no region
+/// tracked yet.
+pub fn enable(vm_id: usize, npt: Arc<SpinNoIrq<GuestPageTable>>) {
+    let mode = if hypercraft::ept_ad_supported() {
+        DirtyLogMode::AccessedDirty
+    } else {
+        DirtyLogMode::WriteProtect
+    };
+    debug!("VM {} dirty page tracking mode: {:?}", vm_id, mode);
+    *DIRTY_LOGS[vm_id].lock() = Some(DirtyLog {
+        npt,
+        mode,
+        regions: Vec::new(),
+        dirty: BTreeSet::new(),
+    });
+}
+
+/// Tracks writes to the region `[gpa, gpa + size)` mapped with `flags`. All
+/// its pages are considered dirty until the first collection.
+pub fn add_region(vm_id: usize, gpa: GuestPhysAddr, size: usize, flags: MappingFlags) -> HyperResult {
+    let mut log = DIRTY_LOGS[vm_id].lock();
+    let log = log.as_mut().ok_or(HyperError::BadState)?;
+    // Pages are left writable, as they are all dirty.
+    log.regions.push(TrackedRegion {
+        start: gpa,
+        size,
+        flags,
+        all_dirty: true,
+    });
+    Ok(())
+}
+
+/// Marks the page containing `gpa` dirty, when its content is changed by the
+/// hypervisor, e.g., the page is populated or reclaimed.
+pub fn mark_dirty(vm_id: usize, gpa: GuestPhysAddr) {
+    if let Some(log) = DIRTY_LOGS[vm_id].lock().as_mut() {
+        if log.region_of(gpa).is_some() {
+            log.dirty.insert(gpa & !(PAGE_SIZE_4K - 1));
+        }
+    }
+}
+
+/// Returns the pages of the tracked region starting at `gpa` that were
+/// written since the last call, one bit per 4K page, and clears them.
+pub fn take_dirty_bitmap(vm_id: usize, gpa: GuestPhysAddr) -> HyperResult<Vec<u64>> {
+    let mut guard = DIRTY_LOGS[vm_id].lock();
+    let log = guard.as_mut().ok_or(HyperError::BadState)?;
+    let idx = log
+        .regions
+        .iter()
+        .position(|r| r.start == gpa)
+        .ok_or(HyperError::NotFound)?;
+    let region = log.regions[idx];
+    let (start, size, flags) = (region.start, region.size, region.flags);
+
+    if log.mode == DirtyLogMode::AccessedDirty {
+        let mut npt = log.npt.lock();
+        let mut dirty_mappings = Vec::new();
+        let mut gpa = start;
+        while gpa < start + size {
+            match npt.test_and_clear_dirty(gpa) {
+                Ok((dirty, page_size)) => {
+                    if dirty {
+                        dirty_mappings.push((gpa, page_size));
+                    }
+                    gpa = (gpa & !(page_size - 1)) + page_size;
+                }
+                Err(HyperError::NotFound) => gpa += PAGE_SIZE_4K,
+                Err(err) => return Err(err),
+            }
+        }
+        drop(npt);
+        for (gpa, page_size) in dirty_mappings {
+            log.mark_mapping(gpa, page_size, &region);
+        }
+    }
+
+    let pages: Vec<GuestPhysAddr> = log.dirty.range(start..start + size).copied().collect();
+    let num_pages = size / PAGE_SIZE_4K;
+    let mut bitmap = vec![0u64; (num_pages + 63) / 64];
+    if region.all_dirty {
+        for i in 0..num_pages {
+            bitmap[i / 64] |= 1 << (i % 64);
+        }
+        log.regions[idx].all_dirty = false;
+    }
+    for &page in &pages {
+        let i = (page - start) / PAGE_SIZE_4K;
+        bitmap[i / 64] |= 1 << (i % 64);
+        log.dirty.remove(&page);
+    }
+
+    if log.mode == DirtyLogMode::WriteProtect && flags.contains(MappingFlags::WRITE) {
+        log.protect_range(start, size, flags - MappingFlags::WRITE)?;
+    }
+    let root = log.ept_root();
+    drop(guard);
+    // Cached translations may have stale dirty flags or permissions. Writes
+    // through them before the flush are in the pages copied afterwards.
+    ept_flush::flush_all(vm_id, root)?;
+    Ok(bitmap)
+}
+
+/// Stops dirty page tracking, and gives back write permissions to the
+/// write-protected pages.
+pub fn disable(vm_id: usize) -> HyperResult {
+    let log = DIRTY_LOGS[vm_id].lock().take();
+    if let Some(log) = log {
+        if log.mode == DirtyLogMode::WriteProtect {
+            for r in &log.regions {
+                log.protect_range(r.start, r.size, r.flags)?;
+            }
+            ept_flush::flush_all(vm_id, log.ept_root())?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles a write to a write-protected page, returns `false` if the EPT
+/// violation was not caused by dirty page tracking.
+pub(super) fn handle_write_fault(vm_id: usize, gpa: GuestPhysAddr) -> HyperResult<bool> {
+    let mut log = DIRTY_LOGS[vm_id].lock();
+    let Some(log) = log.as_mut() else {
+        return Ok(false);
+    };
+    let Some(region) = log.region_of(gpa) else {
+        return Ok(false);
+    };
+    if log.mode != DirtyLogMode::WriteProtect || !region.flags.contains(MappingFlags::WRITE) {
+        return Ok(false);
+    }
+    // The EPT violation has invalidated the cached translations of `gpa`, no
+    // need to flush.
+    let res = log.npt.lock().protect(gpa, region.flags);
+    match res {
+        Ok(page_size) => {
+            log.mark_mapping(gpa, page_size, &region);
+            Ok(true)
+        }
+        Err(HyperError::NotFound) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,85 @@
+//! Flushing the EPT translations of a VM on all the CPUs which ran it.
+//!
+//! INVEPT only invalidates the translations cached by the CPU executing it.
+//! Before a guest page is freed or write-protected, the other CPUs which ran
+//! vCPUs of the VM are asked with an IPI to flush their own, which also kicks
+//! them out of the guest, and the caller waits until they did.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use hypercraft::{HostPhysAddr, HyperResult};
+
+use super::device_emu::MAX_VMS;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CPUS: AtomicU64 = AtomicU64::new(0);
+/// Bitmap of the CPUs which may cache translations of the EPT of each VM.
+static VM_CPUS: [AtomicU64; MAX_VMS] = [NO_CPUS; MAX_VMS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+/// The EPT root of each VM, set before its first flush.
+static EPT_ROOTS: [AtomicUsize; MAX_VMS] = [ZERO; MAX_VMS];
+/// Number of flushes requested to each CPU.
+#[cfg(all(feature = "smp", feature = "irq"))]
+static REQUESTED: [AtomicUsize; axconfig::SMP] = [ZERO; axconfig::SMP];
+/// Number of flushes requested to each CPU, that it has done.
+#[cfg(all(feature = "smp", feature = "irq"))]
+static DONE: [AtomicUsize; axconfig::SMP] = [ZERO; axconfig::SMP];
+
+/// Notes that a vCPU of the VM runs on the current CPU, which may then cache
+/// translations of its EPT until flushed.
+pub(super) fn note_current_cpu(vm_id: usize) {
+    VM_CPUS[vm_id].fetch_or(1 << axhal::cpu::this_cpu_id(), Ordering::AcqRel);
+}
+
+/// Invalidates the translations of the EPT of the VM, rooted at `root`, on
+/// all the CPUs which ran the VM, after some of its entries are removed or
+/// write-protected. Returns once they all did.
+///
+/// Locks taken by exit handlers must not be held: other CPUs flush from their
+/// IPI handler, which cannot run while they spin on such a lock.
+pub fn flush_all(vm_id: usize, root: HostPhysAddr) -> HyperResult {
+    hypercraft::flush_ept(root)?;
+    EPT_ROOTS[vm_id].store(root, Ordering::Release);
+    #[cfg(all(feature = "smp", feature = "irq"))]
+    {
+        let this_cpu = axhal::cpu::this_cpu_id();
+        let others = VM_CPUS[vm_id].load(Ordering::Acquire) & !(1 << this_cpu);
+        let mut tickets = [0; axconfig::SMP];
+        for cpu_id in (0..axconfig::SMP).filter(|&i| others & (1 << i) != 0) {
+            tickets[cpu_id] = REQUESTED[cpu_id].fetch_add(1, Ordering::AcqRel) + 1;
+            axhal::mp::send_ipi(cpu_id);
+        }
+        for cpu_id in (0..axconfig::SMP).filter(|&i| others & (1 << i) != 0) {
+            while DONE[cpu_id].load(Ordering::Acquire) < tickets[cpu_id] {
+                // That CPU may be waiting for this one as well, with IRQs
+                // disabled.
+                handle_requests();
+                core::hint::spin_loop();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Flushes the EPTs of the VMs which ran on this CPU, if other CPUs asked to.
+/// Called by the IPI handler.
+#[cfg(all(feature = "smp", feature = "irq"))]
+pub(super) fn handle_requests() {
+    let this_cpu = axhal::cpu::this_cpu_id();
+    // Requests made before this point are all satisfied by the flushes below.
+    let requested = REQUESTED[this_cpu].load(Ordering::Acquire);
+    if DONE[this_cpu].load(Ordering::Acquire) >= requested {
+        return;
+    }
+    for vm_id in 0..MAX_VMS {
+        let root = EPT_ROOTS[vm_id].load(Ordering::Acquire);
+        if root != 0 && VM_CPUS[vm_id].load(Ordering::Acquire) & (1 << this_cpu) != 0 {
+            if let Err(err) = hypercraft::flush_ept(root) {
+                warn!("VM {} EPT flush failed on CPU {}: {:?}", vm_id, this_cpu, err);
+            }
+        }
+    }
+    DONE[this_cpu].fetch_max(requested, Ordering::AcqRel);
+}
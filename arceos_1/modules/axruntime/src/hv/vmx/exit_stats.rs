@@ -0,0 +1,118 @@
+//! VM-exit statistics of the registered vCPUs, readable from any task.
+//!
+//! Each vCPU counts its VM exits by reason, I/O port and MSR, and the time it
+//! spent in the guest and in exit handlers. It can also keep a trace of its
+//! most recent VM exits, which is logged if the VM crashes.
+
+extern crate alloc;
+use alloc::{format, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+
+use hypercraft::{ExitStats, ExitStatsSummary};
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::vcpu_wait::MAX_VCPUS_PER_VM;
+use super::VCpu;
+
+lazy_static::lazy_static! {
+    static ref VCPU_STATS: Vec<SpinNoIrq<Option<Arc<ExitStats>>>> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS * MAX_VCPUS_PER_VM {
+            temp.push(SpinNoIrq::new(None));
+        }
+        temp
+    };
+}
+
+fn slot(vm_id: usize, vcpu_id: usize) -> &'static SpinNoIrq<Option<Arc<ExitStats>>> {
+    assert!(vm_id < MAX_VMS && vcpu_id < MAX_VCPUS_PER_VM);
+    &VCPU_STATS[vm_id * MAX_VCPUS_PER_VM + vcpu_id]
+}
+
+fn vcpu_stats(vm_id: usize) -> impl Iterator<Item = (usize, Arc<ExitStats>)> {
+    (0..MAX_VCPUS_PER_VM)
+        .filter_map(move |vcpu_id| Some((vcpu_id, slot(vm_id, vcpu_id).lock().clone()?)))
+}
+
+/// Makes the VM-exit statistics of the vCPU available here, and keeps a trace
+/// of its most recent `trace_capacity` VM exits if it's not 0.
+pub fn register(vcpu: &VCpu, trace_capacity: usize) {
+    let stats = vcpu.exit_stats();
+    stats.set_trace_capacity(trace_capacity);
+    *slot(vcpu.get_vm_id(), vcpu.get_vcpu_id()).lock() = Some(stats.clone());
+}
+
+/// Forgets the statistics of all vCPUs of the VM.
+pub fn unregister(vm_id: usize) {
+    for vcpu_id in 0..MAX_VCPUS_PER_VM {
+        slot(vm_id, vcpu_id).lock().take();
+    }
+}
+
+/// Returns the statistics of the VM, summed up over its registered vCPUs.
+pub fn summary(vm_id: usize) -> ExitStatsSummary {
+    let mut summary = ExitStatsSummary::default();
+    for (_, stats) in vcpu_stats(vm_id) {
+        summary.merge(&stats.summary());
+    }
+    summary
+}
+
+/// Clears the statistics and the traces of the VM.
+pub fn reset(vm_id: usize) {
+    for (_, stats) in vcpu_stats(vm_id) {
+        stats.reset();
+    }
+}
+
+/// Writes the statistics of each registered vCPU of the VM, and their sum.
+pub fn dump(vm_id: usize, out: &mut dyn Write) -> fmt::Result {
+    for (vcpu_id, stats) in vcpu_stats(vm_id) {
+        writeln!(out, "VM {} vcpu {}:", vm_id, vcpu_id)?;
+        write_summary(&stats.summary(), out)?;
+    }
+    writeln!(out, "VM {} total:", vm_id)?;
+    write_summary(&summary(vm_id), out)
+}
+
+/// Writes the traced VM exits of each registered vCPU of the VM.
+pub fn dump_trace(vm_id: usize, out: &mut dyn Write) -> fmt::Result {
+    for (vcpu_id, stats) in vcpu_stats(vm_id) {
+        let trace = stats.trace();
+        writeln!(out, "VM {} vcpu {}: last {} VM exits", vm_id, vcpu_id, trace.len())?;
+        for e in trace {
+            writeln!(
+                out,
+                "  [{}.{:09}] {:?} rip={:#x} qual={:#x} handler={}ns",
+                e.time_ns / 1_000_000_000,
+                e.time_ns % 1_000_000_000,
+                e.reason,
+                e.rip,
+                e.qualification,
+                e.handler_ns
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_summary(s: &ExitStatsSummary, out: &mut dyn Write) -> fmt::Result {
+    writeln!(
+        out,
+        "  {} exits, {} us in guest, {} us in handlers",
+        s.exits,
+        s.guest_ns / 1000,
+        s.handler_ns / 1000
+    )?;
+    for (reason, n) in &s.reasons {
+        writeln!(out, "  {:<24} {}", format!("{:?}", reason), n)?;
+    }
+    for (port, n) in &s.io_ports {
+        writeln!(out, "  port {:#06x}              {}", port, n)?;
+    }
+    for (msr, n) in &s.msrs {
+        writeln!(out, "  msr {:#010x}             {}", msr, n)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,423 @@
+//! A GDB remote serial protocol stub for debugging guests.
+//!
+//! Each vCPU of the debugged VM is a thread to GDB. Supported are reading and
+//! writing the general-purpose registers and guest virtual memory, software
+//! breakpoints, hardware breakpoints and watchpoints in the guest debug
+//! registers, single-stepping with the monitor trap flag, and interrupting the
+//! running VM with Ctrl-C. While attached, the guest cannot use its own debug
+//! registers.
+
+mod serial;
+mod target;
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use core::time::Duration;
+
+use hypercraft::{HyperError, HyperResult};
+
+use target::{HwBreakpoint, HwBreakpointKind, Registers, Request, Response, StopReason};
+
+pub use serial::SerialConnection;
+pub(super) use target::{check_stop, handle_exception, handle_monitor_trap};
+
+/// Max length of packets, in bytes.
+const MAX_PACKET_SIZE: usize = 0x1000;
+/// Interval of polling the connection while the VM is running.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// A byte stream to GDB.
+pub trait GdbConnection {
+    /// Reads a byte if there is one, without blocking.
+    fn try_read(&mut self) -> HyperResult<Option<u8>>;
+    /// Writes all bytes in `buf`.
+    fn write_all(&mut self, buf: &[u8]) -> HyperResult;
+}
+
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+}
+
+struct GdbStub<'a> {
+    vm_id: usize,
+    vcpu_count: usize,
+    conn: &'a mut dyn GdbConnection,
+    no_ack: bool,
+    /// vCPU whose registers and memory are accessed. (`Hg`)
+    cur_vcpu: usize,
+    last_stop: StopReason,
+}
+
+/// Stops the `vcpu_count` running vCPUs of the VM, and serves GDB on `conn`
+/// until it detaches or the connection fails. The VM then runs on.
+pub fn serve(vm_id: usize, vcpu_count: usize, conn: &mut dyn GdbConnection) -> HyperResult {
+    target::attach(vm_id, vcpu_count)?;
+    info!("GDB stub attached to VM {}", vm_id);
+    let mut stub = GdbStub {
+        vm_id,
+        vcpu_count,
+        conn,
+        no_ack: false,
+        cur_vcpu: 0,
+        last_stop: StopReason::Interrupted,
+    };
+    let res = stub.run();
+    if res.is_err() {
+        // The breakpoints are removed by stopped vCPUs.
+        target::interrupt(vm_id);
+        while target::stopped(vm_id).is_none() {
+            axtask::sleep(POLL_INTERVAL);
+        }
+    }
+    target::detach(vm_id);
+    info!("GDB stub detached from VM {}", vm_id);
+    res
+}
+
+impl GdbStub<'_> {
+    fn run(&mut self) -> HyperResult {
+        let mut attaching = true;
+        loop {
+            let (vcpu_id, reason) = self.wait_stop()?;
+            if vcpu_id < self.vcpu_count {
+                self.cur_vcpu = vcpu_id;
+            }
+            self.last_stop = reason;
+            // GDB asks with `?` after connecting.
+            if !attaching {
+                let reply = self.stop_reply();
+                self.send_packet(&reply)?;
+            }
+            attaching = false;
+
+            loop {
+                let packet = self.recv_packet()?;
+                match self.handle_packet(&packet) {
+                    Action::Reply(reply) => {
+                        self.send_packet(&reply)?;
+                        if packet == b"QStartNoAckMode" {
+                            self.no_ack = true;
+                        }
+                    }
+                    Action::Resume { step } => {
+                        target::resume(self.vm_id, step.then_some(self.cur_vcpu));
+                        break;
+                    }
+                    Action::Detach => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Waits until all vCPUs stop, and stops them on Ctrl-C.
+    fn wait_stop(&mut self) -> HyperResult<(usize, StopReason)> {
+        loop {
+            if let Some(event) = target::stopped(self.vm_id) {
+                return Ok(event);
+            }
+            match self.conn.try_read()? {
+                Some(0x03) => target::interrupt(self.vm_id),
+                Some(_) => {}
+                None => axtask::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> HyperResult<u8> {
+        loop {
+            match self.conn.try_read()? {
+                Some(b) => return Ok(b),
+                None => axtask::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Receives a packet `$data#checksum`, and acknowledges it.
+    fn recv_packet(&mut self) -> HyperResult<Vec<u8>> {
+        loop {
+            while self.read_byte()? != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b if data.len() < MAX_PACKET_SIZE => data.push(b),
+                    _ => {}
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+            if self.no_ack {
+                return Ok(data);
+            }
+            if parse_hex(&checksum) == Some(expected as usize) {
+                self.conn.write_all(b"+")?;
+                return Ok(data);
+            }
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    /// Sends a packet, and resends it until it's acknowledged.
+    fn send_packet(&mut self, data: &str) -> HyperResult {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let mut packet = String::with_capacity(data.len() + 4);
+        let _ = write!(packet, "${}#{:02x}", data, checksum);
+        loop {
+            self.conn.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        let signal = match self.last_stop {
+            StopReason::Interrupted => SIGINT,
+            _ => SIGTRAP,
+        };
+        let mut reply = String::new();
+        let _ = write!(reply, "T{:02x}thread:{:x};", signal, self.cur_vcpu + 1);
+        match self.last_stop {
+            StopReason::SwBreakpoint => reply.push_str("swbreak:;"),
+            StopReason::HwBreakpoint => reply.push_str("hwbreak:;"),
+            StopReason::Watchpoint(addr) => {
+                let _ = write!(reply, "watch:{:x};", addr);
+            }
+            _ => {}
+        }
+        reply
+    }
+
+    fn request(&self, req: Request) -> Response {
+        target::request(self.vm_id, self.cur_vcpu, req)
+    }
+
+    fn read_regs(&self) -> Option<Registers> {
+        match self.request(Request::ReadRegs) {
+            Response::Regs(regs) => Some(regs),
+            _ => None,
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> Action {
+        let ok = || Action::Reply(String::from("OK"));
+        let error = |code: u8| {
+            let mut reply = String::new();
+            let _ = write!(reply, "E{:02x}", code);
+            Action::Reply(reply)
+        };
+        let Some((&cmd, args)) = packet.split_first() else {
+            return Action::Reply(String::new());
+        };
+        match cmd {
+            b'?' => Action::Reply(self.stop_reply()),
+            b'g' => match self.read_regs() {
+                Some(regs) => {
+                    let mut reply = String::new();
+                    for r in regs.gprs.iter().chain([regs.rip].iter()) {
+                        push_hex(&mut reply, &r.to_le_bytes());
+                    }
+                    push_hex(&mut reply, &regs.eflags.to_le_bytes());
+                    for s in regs.segments {
+                        push_hex(&mut reply, &s.to_le_bytes());
+                    }
+                    Action::Reply(reply)
+                }
+                None => error(1),
+            },
+            b'G' => {
+                let (Some(data), Some(mut regs)) = (decode_hex(args), self.read_regs()) else {
+                    return error(1);
+                };
+                if data.len() < 16 * 8 + 8 + 4 {
+                    return error(1);
+                }
+                let u64_at = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+                for (i, r) in regs.gprs.iter_mut().enumerate() {
+                    *r = u64_at(i);
+                }
+                regs.rip = u64_at(16);
+                regs.eflags = u32::from_le_bytes(data[17 * 8..17 * 8 + 4].try_into().unwrap());
+                match self.request(Request::WriteRegs(regs)) {
+                    Response::Done => ok(),
+                    _ => error(1),
+                }
+            }
+            b'm' => {
+                let Some((addr, len)) = parse_addr_len(args) else {
+                    return error(1);
+                };
+                match self.request(Request::ReadMem(addr, len.min(MAX_PACKET_SIZE / 2))) {
+                    Response::Mem(data) => {
+                        let mut reply = String::new();
+                        push_hex(&mut reply, &data);
+                        Action::Reply(reply)
+                    }
+                    _ => error(0x0e),
+                }
+            }
+            b'M' => {
+                let mut parts = args.splitn(2, |&b| b == b':');
+                let (Some((addr, len)), Some(data)) = (
+                    parts.next().and_then(parse_addr_len),
+                    parts.next().and_then(decode_hex),
+                ) else {
+                    return error(1);
+                };
+                if data.len() != len {
+                    return error(1);
+                }
+                match self.request(Request::WriteMem(addr, data)) {
+                    Response::Done => ok(),
+                    _ => error(0x0e),
+                }
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let (Some(addr), Some(mut regs)) = (parse_hex(args), self.read_regs()) else {
+                        return error(1);
+                    };
+                    regs.rip = addr as u64;
+                    if !matches!(self.request(Request::WriteRegs(regs)), Response::Done) {
+                        return error(1);
+                    }
+                }
+                Action::Resume { step: cmd == b's' }
+            }
+            b'D' => {
+                let _ = self.send_packet("OK");
+                Action::Detach
+            }
+            b'k' => Action::Detach,
+            b'H' => {
+                // `Hg<thread>` selects the vCPU, 0 or -1 for any.
+                if args.first() == Some(&b'g') {
+                    if let Some(tid) = parse_hex(&args[1..]) {
+                        if tid > 0 && tid <= self.vcpu_count {
+                            self.cur_vcpu = tid - 1;
+                        }
+                    }
+                }
+                ok()
+            }
+            b'T' => match parse_hex(args) {
+                Some(tid) if tid > 0 && tid <= self.vcpu_count => ok(),
+                _ => error(1),
+            },
+            b'Z' | b'z' => self.handle_breakpoint(cmd == b'Z', args),
+            b'q' | b'Q' => Action::Reply(self.handle_query(packet)),
+            _ => Action::Reply(String::new()),
+        }
+    }
+
+    /// `Z`/`z` `type,addr,kind`: inserts or removes breakpoints.
+    fn handle_breakpoint(&mut self, insert: bool, args: &[u8]) -> Action {
+        let mut fields = args.split(|&b| b == b',').map(parse_hex);
+        let (Some(Some(ty)), Some(Some(addr)), Some(Some(kind))) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Action::Reply(String::from("E01"));
+        };
+        let hw_kind = match ty {
+            0 => None,
+            1 => Some(HwBreakpointKind::Execute),
+            2 => Some(HwBreakpointKind::Write),
+            4 => Some(HwBreakpointKind::Access),
+            // x86 has no read-only watchpoints.
+            _ => return Action::Reply(String::new()),
+        };
+        let res = match hw_kind {
+            None if insert => target::insert_sw_breakpoint(self.vm_id, self.cur_vcpu, addr),
+            None => target::remove_sw_breakpoint(self.vm_id, self.cur_vcpu, addr),
+            Some(kind_) => {
+                // Instruction breakpoints must have a length of 1.
+                let len = if kind_ == HwBreakpointKind::Execute { 1 } else { kind };
+                let bp = HwBreakpoint {
+                    addr,
+                    len,
+                    kind: kind_,
+                };
+                if insert {
+                    target::insert_hw_breakpoint(self.vm_id, bp)
+                } else {
+                    target::remove_hw_breakpoint(self.vm_id, bp)
+                }
+            }
+        };
+        match res {
+            Ok(()) => Action::Reply(String::from("OK")),
+            Err(HyperError::PageFault) => Action::Reply(String::from("E0e")),
+            Err(_) => Action::Reply(String::from("E01")),
+        }
+    }
+
+    fn handle_query(&mut self, packet: &[u8]) -> String {
+        let mut reply = String::new();
+        if packet.starts_with(b"qSupported") {
+            let _ = write!(
+                reply,
+                "PacketSize={:x};swbreak+;hwbreak+;QStartNoAckMode+",
+                MAX_PACKET_SIZE
+            );
+        } else if packet == b"QStartNoAckMode" {
+            reply.push_str("OK");
+        } else if packet == b"qAttached" {
+            reply.push('1');
+        } else if packet == b"qC" {
+            let _ = write!(reply, "QC{:x}", self.cur_vcpu + 1);
+        } else if packet == b"qfThreadInfo" {
+            reply.push('m');
+            for tid in 1..=self.vcpu_count {
+                if tid > 1 {
+                    reply.push(',');
+                }
+                let _ = write!(reply, "{:x}", tid);
+            }
+        } else if packet == b"qsThreadInfo" {
+            reply.push('l');
+        }
+        reply
+    }
+}
+
+fn push_hex(s: &mut String, bytes: &[u8]) {
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| parse_hex(pair).map(|b| b as u8))
+        .collect()
+}
+
+fn parse_hex(hex: &[u8]) -> Option<usize> {
+    if hex.is_empty() {
+        return None;
+    }
+    let s = core::str::from_utf8(hex).ok()?;
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Parses `addr,len`.
+fn parse_addr_len(args: &[u8]) -> Option<(usize, usize)> {
+    let mut fields = args.splitn(2, |&b| b == b',');
+    Some((parse_hex(fields.next()?)?, parse_hex(fields.next()?)?))
+}
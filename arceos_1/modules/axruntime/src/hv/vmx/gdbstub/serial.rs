@@ -0,0 +1,64 @@
+//! A polled 16550 UART as the connection to GDB.
+
+use x86_64::instructions::port::Port;
+
+use super::GdbConnection;
+use hypercraft::HyperResult;
+
+/// Line status register bits.
+const LSR_DATA_READY: u8 = 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const UART_CLOCK: usize = 115200;
+
+/// A host serial port, used without interrupts. It must not be used by the
+/// console or passed through to guests.
+pub struct SerialConnection {
+    base: u16,
+}
+
+impl SerialConnection {
+    /// The second serial port of PCs.
+    pub const COM2: u16 = 0x2f8;
+
+    /// Initializes the UART at I/O port `base` with 8N1 at `baud_rate`.
+    pub fn new(base: u16, baud_rate: usize) -> Self {
+        let divisor = UART_CLOCK / baud_rate;
+        unsafe {
+            // Disable interrupts, set the divisor with DLAB, 8N1, enable and
+            // clear the FIFOs, DTR and RTS.
+            Port::<u8>::new(base + 1).write(0);
+            Port::<u8>::new(base + 3).write(0x80);
+            Port::<u8>::new(base).write(divisor as u8);
+            Port::<u8>::new(base + 1).write((divisor >> 8) as u8);
+            Port::<u8>::new(base + 3).write(0x03);
+            Port::<u8>::new(base + 2).write(0xc7);
+            Port::<u8>::new(base + 4).write(0x03);
+        }
+        Self { base }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.base + 5).read() }
+    }
+}
+
+impl GdbConnection for SerialConnection {
+    fn try_read(&mut self) -> HyperResult<Option<u8>> {
+        if self.line_status() & LSR_DATA_READY != 0 {
+            Ok(Some(unsafe { Port::<u8>::new(self.base).read() }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> HyperResult {
+        for &b in buf {
+            while self.line_status() & LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            unsafe { Port::<u8>::new(self.base).write(b) };
+        }
+        Ok(())
+    }
+}
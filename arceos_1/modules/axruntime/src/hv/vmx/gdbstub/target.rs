@@ -0,0 +1,469 @@
+//! Stopping the vCPUs of a debugged VM, and accessing their states while
+//! they are stopped.
+//!
+//! A stopped vCPU stays in its own task, at the end of the VM exit handling,
+//! and serves the requests of the stub with its VMCS loaded.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use axtask::WaitQueue;
+use hypercraft::{GuestMemoryAccessor, HyperError, HyperResult, VmExitReason};
+use spinlock::SpinNoIrq;
+
+use super::super::device_emu::MAX_VMS;
+use super::super::vcpu_wait::{self, MAX_VCPUS_PER_VM};
+use super::super::VCpu;
+
+const DEBUG_VECTOR: u8 = 1;
+const BREAKPOINT_VECTOR: u8 = 3;
+const INT3: u8 = 0xcc;
+const RFLAGS_RF: usize = 1 << 16;
+
+/// Why the VM stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum StopReason {
+    /// Stopped by the debugger.
+    Interrupted,
+    /// A software breakpoint was hit.
+    SwBreakpoint,
+    /// A hardware instruction breakpoint was hit.
+    HwBreakpoint,
+    /// A hardware data breakpoint at the address was hit.
+    Watchpoint(usize),
+    /// A single step finished.
+    Step,
+    /// Other debug exceptions, e.g., raised by the guest itself.
+    Trap,
+}
+
+/// Kinds of hardware breakpoints, in the encoding of the `R/W` fields of `DR7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum HwBreakpointKind {
+    Execute = 0b00,
+    Write = 0b01,
+    Access = 0b11,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct HwBreakpoint {
+    pub addr: usize,
+    pub len: usize,
+    pub kind: HwBreakpointKind,
+}
+
+/// Registers in the order of the GDB `g` packet of x86-64.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Registers {
+    /// `RAX`, `RBX`, `RCX`, `RDX`, `RSI`, `RDI`, `RBP`, `RSP`, `R8`-`R15`.
+    pub gprs: [u64; 16],
+    pub rip: u64,
+    pub eflags: u32,
+    /// `CS`, `SS`, `DS`, `ES`, `FS` and `GS`, which are read-only.
+    pub segments: [u32; 6],
+}
+
+/// Indices in [`hypercraft::GeneralRegisters`] of [`Registers::gprs`], with
+/// `RSP` (4) taken from the VMCS.
+const GPR_INDICES: [u8; 16] = [0, 3, 1, 2, 6, 7, 5, 4, 8, 9, 10, 11, 12, 13, 14, 15];
+
+pub(super) enum Request {
+    ReadRegs,
+    WriteRegs(Registers),
+    ReadMem(usize, usize),
+    WriteMem(usize, Vec<u8>),
+}
+
+pub(super) enum Response {
+    Regs(Registers),
+    Mem(Vec<u8>),
+    Done,
+    Failed,
+}
+
+#[derive(Default)]
+struct VcpuSlot {
+    parked: bool,
+    request: Option<Request>,
+    response: Option<Response>,
+    /// Stopped at a hardware instruction breakpoint, which must be ignored
+    /// once when resuming.
+    at_hw_breakpoint: bool,
+}
+
+struct DebugTarget {
+    attached: bool,
+    /// The vCPUs stop at their next VM exits.
+    stopping: bool,
+    /// The first reason of the current stop, and the vCPU that caused it.
+    stop_event: Option<(usize, StopReason)>,
+    /// The vCPU to single-step on the next resume.
+    step_vcpu: Option<usize>,
+    vcpus: Vec<VcpuSlot>,
+    /// Addresses of the software breakpoints and the bytes they replaced.
+    sw_breakpoints: Vec<(usize, u8)>,
+    hw_breakpoints: [Option<HwBreakpoint>; 4],
+}
+
+impl DebugTarget {
+    const fn new() -> Self {
+        Self {
+            attached: false,
+            stopping: false,
+            stop_event: None,
+            step_vcpu: None,
+            vcpus: Vec::new(),
+            sw_breakpoints: Vec::new(),
+            hw_breakpoints: [None; 4],
+        }
+    }
+
+    fn all_parked(&self) -> bool {
+        self.vcpus.iter().all(|v| v.parked)
+    }
+
+    /// `DR0`-`DR3` and `DR7` for the hardware breakpoints.
+    fn debug_regs(&self) -> ([usize; 4], usize) {
+        let mut drs = [0; 4];
+        let mut dr7 = 0;
+        for (i, bp) in self.hw_breakpoints.iter().enumerate() {
+            if let Some(bp) = bp {
+                drs[i] = bp.addr;
+                let len_bits = match bp.len {
+                    1 => 0b00,
+                    2 => 0b01,
+                    8 => 0b10,
+                    _ => 0b11,
+                };
+                // L0-L3, R/W0-R/W3 and LEN0-LEN3. (SDM Vol. 3B, Section 17.2.4)
+                dr7 |= 1 << (i * 2);
+                dr7 |= (bp.kind as usize) << (16 + i * 4);
+                dr7 |= len_bits << (18 + i * 4);
+            }
+        }
+        (drs, dr7)
+    }
+}
+
+struct TargetSlot {
+    target: SpinNoIrq<DebugTarget>,
+    /// The stub waits here for the vCPUs.
+    stub_wq: WaitQueue,
+    /// Stopped vCPUs wait here for requests or resuming.
+    vcpu_wq: WaitQueue,
+}
+
+lazy_static::lazy_static! {
+    static ref TARGETS: Vec<TargetSlot> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS {
+            temp.push(TargetSlot {
+                target: SpinNoIrq::new(DebugTarget::new()),
+                stub_wq: WaitQueue::new(),
+                vcpu_wq: WaitQueue::new(),
+            });
+        }
+        temp
+    };
+}
+
+/// Starts debugging the `vcpu_count` vCPUs of the VM, and stops them.
+pub(super) fn attach(vm_id: usize, vcpu_count: usize) -> HyperResult {
+    if vcpu_count == 0 || vcpu_count > MAX_VCPUS_PER_VM {
+        return Err(HyperError::InvalidParam);
+    }
+    let mut target = TARGETS[vm_id].target.lock();
+    if target.attached {
+        return Err(HyperError::BadState);
+    }
+    *target = DebugTarget::new();
+    target.attached = true;
+    target.vcpus = (0..vcpu_count).map(|_| VcpuSlot::default()).collect();
+    drop(target);
+    request_stop(vm_id, usize::MAX, StopReason::Interrupted);
+    Ok(())
+}
+
+/// Removes all breakpoints and lets the VM run without the debugger.
+pub(super) fn detach(vm_id: usize) {
+    let bps = core::mem::take(&mut TARGETS[vm_id].target.lock().sw_breakpoints);
+    for (addr, orig) in bps {
+        request(vm_id, 0, Request::WriteMem(addr, alloc::vec![orig]));
+    }
+    let mut target = TARGETS[vm_id].target.lock();
+    target.attached = false;
+    target.hw_breakpoints = [None; 4];
+    drop(target);
+    resume(vm_id, None);
+}
+
+/// Stops all vCPUs because of `reason` seen by `vcpu_id`.
+fn request_stop(vm_id: usize, vcpu_id: usize, reason: StopReason) {
+    let slot = &TARGETS[vm_id];
+    let mut target = slot.target.lock();
+    if target.stop_event.is_none() {
+        target.stop_event = Some((vcpu_id, reason));
+    }
+    target.stopping = true;
+    let vcpu_count = target.vcpus.len();
+    drop(target);
+    for id in (0..vcpu_count).filter(|&id| id != vcpu_id) {
+        vcpu_wait::kick_vcpu(vm_id, id);
+    }
+}
+
+/// Stops the running VM as asked by the debugger.
+pub(super) fn interrupt(vm_id: usize) {
+    request_stop(vm_id, usize::MAX, StopReason::Interrupted);
+}
+
+/// Returns the stop event if all vCPUs have stopped.
+pub(super) fn stopped(vm_id: usize) -> Option<(usize, StopReason)> {
+    let target = TARGETS[vm_id].target.lock();
+    if target.stopping && target.all_parked() {
+        target.stop_event
+    } else {
+        None
+    }
+}
+
+/// Lets the stopped vCPUs run again, single-stepping `step_vcpu` if given.
+pub(super) fn resume(vm_id: usize, step_vcpu: Option<usize>) {
+    let slot = &TARGETS[vm_id];
+    let mut target = slot.target.lock();
+    target.step_vcpu = step_vcpu;
+    target.stop_event = None;
+    target.stopping = false;
+    drop(target);
+    slot.vcpu_wq.notify_all(false);
+}
+
+/// Runs `req` on the stopped vCPU `vcpu_id`, and waits for its response.
+pub(super) fn request(vm_id: usize, vcpu_id: usize, req: Request) -> Response {
+    let slot = &TARGETS[vm_id];
+    match slot.target.lock().vcpus.get_mut(vcpu_id) {
+        Some(vcpu) if vcpu.parked => vcpu.request = Some(req),
+        _ => return Response::Failed,
+    }
+    slot.vcpu_wq.notify_all(false);
+    slot.stub_wq
+        .wait_until(|| slot.target.lock().vcpus[vcpu_id].response.is_some());
+    slot.target.lock().vcpus[vcpu_id].response.take().unwrap()
+}
+
+/// Inserts a software breakpoint at `addr` through the stopped `vcpu_id`.
+pub(super) fn insert_sw_breakpoint(vm_id: usize, vcpu_id: usize, addr: usize) -> HyperResult {
+    if TARGETS[vm_id].target.lock().sw_breakpoints.iter().any(|bp| bp.0 == addr) {
+        return Ok(());
+    }
+    let orig = match request(vm_id, vcpu_id, Request::ReadMem(addr, 1)) {
+        Response::Mem(data) => data[0],
+        _ => return Err(HyperError::PageFault),
+    };
+    match request(vm_id, vcpu_id, Request::WriteMem(addr, alloc::vec![INT3])) {
+        Response::Done => {}
+        _ => return Err(HyperError::PageFault),
+    }
+    TARGETS[vm_id].target.lock().sw_breakpoints.push((addr, orig));
+    Ok(())
+}
+
+/// Removes the software breakpoint at `addr` through the stopped `vcpu_id`.
+pub(super) fn remove_sw_breakpoint(vm_id: usize, vcpu_id: usize, addr: usize) -> HyperResult {
+    let mut target = TARGETS[vm_id].target.lock();
+    let idx = target
+        .sw_breakpoints
+        .iter()
+        .position(|bp| bp.0 == addr)
+        .ok_or(HyperError::NotFound)?;
+    let (_, orig) = target.sw_breakpoints.remove(idx);
+    drop(target);
+    match request(vm_id, vcpu_id, Request::WriteMem(addr, alloc::vec![orig])) {
+        Response::Done => Ok(()),
+        _ => Err(HyperError::PageFault),
+    }
+}
+
+/// Sets a hardware breakpoint in a free debug register, which takes effect
+/// when the VM resumes.
+pub(super) fn insert_hw_breakpoint(vm_id: usize, bp: HwBreakpoint) -> HyperResult {
+    if !matches!(bp.len, 1 | 2 | 4 | 8) || bp.addr % bp.len != 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    let mut target = TARGETS[vm_id].target.lock();
+    let free = target
+        .hw_breakpoints
+        .iter_mut()
+        .find(|b| b.is_none())
+        .ok_or(HyperError::NoMemory)?;
+    *free = Some(bp);
+    Ok(())
+}
+
+/// Clears the hardware breakpoint `bp`.
+pub(super) fn remove_hw_breakpoint(vm_id: usize, bp: HwBreakpoint) -> HyperResult {
+    let mut target = TARGETS[vm_id].target.lock();
+    let slot = target
+        .hw_breakpoints
+        .iter_mut()
+        .find(|b| matches!(b, Some(b) if b.addr == bp.addr && b.kind == bp.kind))
+        .ok_or(HyperError::NotFound)?;
+    *slot = None;
+    Ok(())
+}
+
+fn read_regs(vcpu: &VCpu) -> HyperResult<Registers> {
+    let mut regs = Registers::default();
+    for (i, &idx) in GPR_INDICES.iter().enumerate() {
+        regs.gprs[i] = if idx == 4 {
+            vcpu.stack_pointer() as u64
+        } else {
+            vcpu.regs().get_reg_of_index(idx)
+        };
+    }
+    regs.rip = vcpu.rip() as u64;
+    regs.eflags = vcpu.rflags() as u32;
+    for (seg, sel) in regs.segments.iter_mut().zip(vcpu.segment_selectors()?) {
+        *seg = sel as u32;
+    }
+    Ok(regs)
+}
+
+fn write_regs(vcpu: &mut VCpu, regs: &Registers) -> HyperResult {
+    for (i, &idx) in GPR_INDICES.iter().enumerate() {
+        if idx == 4 {
+            vcpu.set_stack_pointer(regs.gprs[i] as usize);
+        } else {
+            vcpu.regs_mut().set_reg_of_index(idx, regs.gprs[i]);
+        }
+    }
+    vcpu.set_rip(regs.rip as usize)?;
+    // The reserved bit 1 is always set.
+    vcpu.set_rflags(regs.eflags as usize | 0b10)
+}
+
+fn serve_request(vcpu: &mut VCpu, req: Request) -> Response {
+    let res = match req {
+        Request::ReadRegs => read_regs(vcpu).map(Response::Regs),
+        Request::WriteRegs(regs) => write_regs(vcpu, &regs).map(|_| Response::Done),
+        Request::ReadMem(addr, len) => {
+            let mut buf = alloc::vec![0; len];
+            match vcpu.debug_read(addr, &mut buf) {
+                Ok(()) => Ok(Response::Mem(buf)),
+                Err(_) => Ok(Response::Failed),
+            }
+        }
+        Request::WriteMem(addr, data) => match vcpu.debug_write(addr, &data) {
+            Ok(()) => Ok(Response::Done),
+            Err(_) => Ok(Response::Failed),
+        },
+    };
+    res.unwrap_or(Response::Failed)
+}
+
+/// Handles exceptions intercepted for the debugger.
+pub(in super::super) fn handle_exception(vcpu: &mut VCpu) -> HyperResult {
+    let int_info = vcpu.interrupt_exit_info()?;
+    let (vm_id, vcpu_id) = (vcpu.get_vm_id(), vcpu.get_vcpu_id());
+    let target = TARGETS[vm_id].target.lock();
+    match int_info.vector {
+        BREAKPOINT_VECTOR => {
+            let rip = vcpu.rip();
+            if target.attached && target.sw_breakpoints.iter().any(|bp| bp.0 == rip) {
+                drop(target);
+                request_stop(vm_id, vcpu_id, StopReason::SwBreakpoint);
+            } else {
+                // The guest's own INT3.
+                vcpu.inject_event(BREAKPOINT_VECTOR, None);
+            }
+            Ok(())
+        }
+        DEBUG_VECTOR => {
+            // B0-B3 tell which breakpoints are hit. (SDM Vol. 3C, Table 27-1)
+            let hits = vcpu.exit_qualification()? & 0xf;
+            let hit = (0..4)
+                .filter(|i| hits & (1 << i) != 0)
+                .find_map(|i| target.hw_breakpoints[i]);
+            let reason = match hit {
+                Some(bp) if bp.kind == HwBreakpointKind::Execute => StopReason::HwBreakpoint,
+                Some(bp) => StopReason::Watchpoint(bp.addr),
+                // The debug registers belong to the debugger while it's
+                // attached, so report anything else as a trap.
+                None => StopReason::Trap,
+            };
+            drop(target);
+            if reason == StopReason::HwBreakpoint {
+                TARGETS[vm_id].target.lock().vcpus[vcpu_id].at_hw_breakpoint = true;
+            }
+            request_stop(vm_id, vcpu_id, reason);
+            Ok(())
+        }
+        _ => Err(HyperError::NotSupported),
+    }
+}
+
+/// Handles the VM exit after a single step.
+pub(in super::super) fn handle_monitor_trap(vcpu: &mut VCpu) -> HyperResult {
+    vcpu.set_monitor_trap(false)?;
+    request_stop(vcpu.get_vm_id(), vcpu.get_vcpu_id(), StopReason::Step);
+    Ok(())
+}
+
+/// Stops `vcpu` if its VM is stopping, and serves the requests of the stub
+/// until the VM resumes. Called at the end of VM exit handling.
+pub(in super::super) fn check_stop(
+    vcpu: &mut VCpu,
+    exit_reason: Option<VmExitReason>,
+) -> HyperResult {
+    let slot = &TARGETS[vcpu.get_vm_id()];
+    let vcpu_id = vcpu.get_vcpu_id();
+    {
+        let mut target = slot.target.lock();
+        if !target.stopping || vcpu_id >= target.vcpus.len() {
+            return Ok(());
+        }
+        target.vcpus[vcpu_id].parked = true;
+    }
+    trace!("vcpu {} stopped for the debugger after {:?}", vcpu_id, exit_reason);
+    vcpu.clock().pause();
+    slot.stub_wq.notify_all(false);
+
+    loop {
+        slot.vcpu_wq.wait_until(|| {
+            let target = slot.target.lock();
+            !target.stopping || target.vcpus[vcpu_id].request.is_some()
+        });
+        let req = {
+            let mut target = slot.target.lock();
+            if !target.stopping {
+                target.vcpus[vcpu_id].parked = false;
+                break;
+            }
+            target.vcpus[vcpu_id].request.take().unwrap()
+        };
+        // Other vCPUs may have been run on this CPU while we were waiting.
+        vcpu.load_vmcs()?;
+        let resp = serve_request(vcpu, req);
+        slot.target.lock().vcpus[vcpu_id].response = Some(resp);
+        slot.stub_wq.notify_all(false);
+    }
+
+    vcpu.load_vmcs()?;
+    let mut target = slot.target.lock();
+    let attached = target.attached;
+    vcpu.set_exception_exiting(BREAKPOINT_VECTOR, attached)?;
+    vcpu.set_exception_exiting(DEBUG_VECTOR, attached)?;
+    vcpu.set_debug_regs(attached.then(|| target.debug_regs()))?;
+    // Only the vCPU being stepped, as another one may have stopped first.
+    vcpu.set_monitor_trap(attached && target.step_vcpu == Some(vcpu_id))?;
+    if core::mem::take(&mut target.vcpus[vcpu_id].at_hw_breakpoint) {
+        // Don't hit the same instruction breakpoint again.
+        vcpu.set_rflags(vcpu.rflags() | RFLAGS_RF)?;
+    }
+    if !attached {
+        target.vcpus[vcpu_id] = VcpuSlot::default();
+    }
+    drop(target);
+    vcpu.clock().resume();
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Hardware virtualization on the physical CPUs.
+//!
+//! Each CPU enables it as it boots, so vCPUs can run on any of them. The
+//! VMCS of a vCPU stays loaded on the CPU it runs on: the task running it is
+//! pinned with `axtask::set_current_affinity` before creating the vCPU, and
+//! only moves to another CPU when asked to by [`migrate`].
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use hypercraft::{HyperError, HyperResult, PerCpu};
+use spinlock::SpinNoIrq;
+
+use super::VCpu;
+use crate::hv::HyperCraftHalImpl;
+
+/// States of the CPUs with hardware virtualization enabled, kept until the
+/// host shuts down.
+static HOST_CPUS: SpinNoIrq<Vec<PerCpu<HyperCraftHalImpl>>> = SpinNoIrq::new(Vec::new());
+
+/// Bitmap of the CPUs with hardware virtualization enabled.
+static ENABLED_CPUS: AtomicU64 = AtomicU64::new(0);
+
+static VMCS_REVISION_ID: AtomicU32 = AtomicU32::new(0);
+
+/// The vCPUs which can be moved, by VM and vCPU IDs, and the CPUs they are
+/// asked to move to.
+static MOVABLE_VCPUS: SpinNoIrq<BTreeMap<(usize, usize), Option<usize>>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+/// Enables hardware virtualization on the current CPU, called once by each
+/// CPU at boot.
+pub(crate) fn enable_current(cpu_id: usize) {
+    let mut pcpu = PerCpu::new(cpu_id);
+    if let Err(err) = pcpu.hardware_enable() {
+        panic!("failed to enable hardware virtualization on CPU {}: {:?}", cpu_id, err);
+    }
+    VMCS_REVISION_ID.store(pcpu.get_vmcs_revision_id(), Ordering::Relaxed);
+    HOST_CPUS.lock().push(pcpu);
+    ENABLED_CPUS.fetch_or(1 << cpu_id, Ordering::Release);
+}
+
+/// Whether vCPUs can run on the CPU `cpu_id`.
+pub fn is_enabled(cpu_id: usize) -> bool {
+    cpu_id < u64::BITS as usize && ENABLED_CPUS.load(Ordering::Acquire) & (1 << cpu_id) != 0
+}
+
+/// The VMCS revision identifier of the processors, to create vCPUs with.
+pub fn vmcs_revision_id() -> u32 {
+    VMCS_REVISION_ID.load(Ordering::Relaxed)
+}
+
+/// Lets [`migrate`] move `vcpu` to other CPUs, along with the current task
+/// which runs it. The task must call [`detach_vcpu`] before dropping it.
+pub fn attach_vcpu(vcpu: &VCpu) {
+    let hooks = vcpu.migration_hooks();
+    axtask::set_current_migration_hooks(Some(axtask::MigrationHooks {
+        leave_cpu: hooks.leave_cpu,
+        enter_cpu: hooks.enter_cpu,
+        arg: hooks.arg,
+    }));
+    let key = (vcpu.get_vm_id(), vcpu.get_vcpu_id());
+    MOVABLE_VCPUS.lock().insert(key, None);
+    super::ept_flush::note_current_cpu(vcpu.get_vm_id());
+    super::vcpu_wait::note_current_cpu(key.0, key.1);
+}
+
+/// Undoes [`attach_vcpu`] once `vcpu` returned from `run`.
+pub fn detach_vcpu(vcpu: &VCpu) {
+    let key = (vcpu.get_vm_id(), vcpu.get_vcpu_id());
+    MOVABLE_VCPUS.lock().remove(&key);
+    super::vcpu_wait::clear_current_cpu(key.0, key.1);
+    axtask::set_current_migration_hooks(None);
+}
+
+/// Moves the vCPU `vcpu_id` of the VM `vm_id` to the CPU `cpu_id`, at its
+/// next VM exit. It is woken up first if halted.
+pub fn migrate(vm_id: usize, vcpu_id: usize, cpu_id: usize) -> HyperResult {
+    if !is_enabled(cpu_id) {
+        return Err(HyperError::InvalidParam);
+    }
+    match MOVABLE_VCPUS.lock().get_mut(&(vm_id, vcpu_id)) {
+        Some(request) => *request = Some(cpu_id),
+        None => return Err(HyperError::NotFound),
+    }
+    super::vcpu_wait::kick_vcpu(vm_id, vcpu_id);
+    Ok(())
+}
+
+/// Moves `vcpu` to the CPU asked by [`migrate`], if any. Called at the end of
+/// VM exit handling.
+pub(super) fn check_request(vcpu: &mut VCpu) -> HyperResult {
+    let (vm_id, vcpu_id) = (vcpu.get_vm_id(), vcpu.get_vcpu_id());
+    let request = MOVABLE_VCPUS
+        .lock()
+        .get_mut(&(vm_id, vcpu_id))
+        .and_then(Option::take);
+    let Some(cpu_id) = request else {
+        return Ok(());
+    };
+    let from = axhal::cpu::this_cpu_id();
+    if from == cpu_id {
+        return Ok(());
+    }
+    // The VMCS is cleared on this CPU and loaded on the other one by the
+    // migration hooks, as the task moves.
+    if !axtask::set_current_affinity(Some(cpu_id)) {
+        return Err(HyperError::InvalidParam);
+    }
+    // Other vCPUs may have been run on that CPU meanwhile.
+    vcpu.load_vmcs()?;
+    super::ept_flush::note_current_cpu(vm_id);
+    super::vcpu_wait::note_current_cpu(vm_id, vcpu_id);
+    #[cfg(feature = "irq")]
+    super::vtimer::move_to_current_cpu(vm_id, vcpu_id);
+    info!(
+        "VM {} vCPU {} moved from CPU {} to CPU {}",
+        vm_id, vcpu_id, from, cpu_id
+    );
+    Ok(())
+}
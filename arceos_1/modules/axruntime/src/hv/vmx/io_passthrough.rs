@@ -0,0 +1,78 @@
+//! Port I/O passthrough: I/O ports given to a VM for its own drivers, which
+//! it then accesses without VM exits.
+//!
+//! A port is owned by at most one VM, and ports of emulated devices are never
+//! passed through.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::ops::{Range, RangeInclusive};
+
+use hypercraft::{HyperError, HyperResult, VM};
+use spinlock::SpinNoIrq;
+
+use super::device_emu::{all_virt_devices, MAX_VMS};
+use crate::hv::HyperCraftHalImpl;
+
+/// Port ranges passed through, and the VMs owning them.
+static OWNERS: SpinNoIrq<Vec<(usize, RangeInclusive<u16>)>> = SpinNoIrq::new(Vec::new());
+
+fn overlaps(a: &RangeInclusive<u16>, b: &RangeInclusive<u16>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
+
+fn overlaps_emulated(emulated: &Range<u16>, ports: &RangeInclusive<u16>) -> bool {
+    emulated.start <= *ports.end() && *ports.start() < emulated.end
+}
+
+/// Lets the guest of `vm` access `ports` directly. Fails if any of them is
+/// emulated, or owned by another VM. Ports stay owned by the VM when it's
+/// created again after a reset, until [`release`] is called.
+pub fn assign(vm: &mut VM<HyperCraftHalImpl>, ports: RangeInclusive<u16>) -> HyperResult {
+    let vm_id = vm.get_vm_id();
+    if vm_id >= MAX_VMS || ports.is_empty() {
+        return Err(HyperError::InvalidParam);
+    }
+    if let Some(emulated) = all_virt_devices(vm_id)
+        .port_ranges()
+        .find(|range| overlaps_emulated(range, &ports))
+    {
+        warn!(
+            "VM {} ports {:#x?} overlap emulated device ports {:#x?}",
+            vm_id, ports, emulated
+        );
+        return Err(HyperError::InvalidParam);
+    }
+
+    let mut owners = OWNERS.lock();
+    if let Some((owner, owned)) = owners
+        .iter()
+        .find(|(owner, owned)| *owner != vm_id && overlaps(owned, &ports))
+    {
+        warn!(
+            "VM {} ports {:#x?} already owned by VM {} ({:#x?})",
+            vm_id, ports, owner, owned
+        );
+        return Err(HyperError::BadState);
+    }
+    vm.set_io_passthrough(ports.clone())?;
+    if !owners.iter().any(|(owner, owned)| *owner == vm_id && *owned == ports) {
+        info!("VM {} owns ports {:#x?}", vm_id, ports);
+        owners.push((vm_id, ports));
+    }
+    Ok(())
+}
+
+/// Gives back all ports owned by the VM, once it's destroyed.
+pub fn release(vm_id: usize) {
+    OWNERS.lock().retain(|(owner, _)| *owner != vm_id);
+}
+
+/// Returns the VM owning `port`, if it's passed through.
+pub fn owner(port: u16) -> Option<usize> {
+    OWNERS
+        .lock()
+        .iter()
+        .find(|(_, owned)| owned.contains(&port))
+        .map(|(owner, _)| *owner)
+}
@@ -0,0 +1,243 @@
+//! Emulation of guest instructions accessing emulated MMIO registers.
+//!
+//! MMIO regions are left unmapped in the EPT, so guest accesses cause EPT
+//! violations, which tell the address but not the instruction. It's fetched
+//! from guest memory and decoded here, in the forms compilers emit for
+//! `readl`/`writel`-style accessors: `MOV` between memory and a register or
+//! an immediate, and `MOVZX` from memory. 16-bit code is not supported.
+
+use hypercraft::{GuestMemoryAccessor, GuestMemoryError, GuestPhysAddr, HyperError, HyperResult};
+
+use super::VCpu;
+
+/// Maximum length of an x86 instruction.
+const MAX_INSTR_LEN: usize = 15;
+const PAGE_SIZE: usize = 0x1000;
+
+/// A general-purpose register operand.
+#[derive(Debug, Clone, Copy)]
+struct Reg {
+    index: u8,
+    /// `AH`, `CH`, `DH` or `BH`: bits 15:8 of register `index`.
+    high_byte: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MmioOp {
+    /// Loads the value into the register, zero-extended if `zero_extend`
+    /// (`MOVZX`), or as `MOV` does otherwise.
+    Load { reg: Reg, zero_extend: bool },
+    /// Stores the register.
+    StoreReg(Reg),
+    /// Stores the immediate.
+    StoreImm(u64),
+}
+
+/// A decoded instruction accessing memory.
+#[derive(Debug, Clone, Copy)]
+struct MmioInstr {
+    len: u8,
+    /// The size of the memory access in bytes.
+    size: u8,
+    op: MmioOp,
+}
+
+/// Decodes the instruction in `bytes`. Returns `Ok(None)` if it's longer than
+/// `bytes`, and `Err(NotSupported)` if it's not one of the supported forms.
+fn decode(bytes: &[u8]) -> HyperResult<Option<MmioInstr>> {
+    let mut pos = 0;
+    let mut operand_16 = false;
+    let mut rex = 0u8;
+    macro_rules! next {
+        () => {{
+            let Some(&b) = bytes.get(pos) else {
+                return Ok(None);
+            };
+            pos += 1;
+            b
+        }};
+    }
+
+    // Legacy prefixes, then REX, which must come right before the opcode.
+    let mut opcode = next!();
+    loop {
+        match opcode {
+            0x66 => operand_16 = true,
+            // segment overrides do not change the guest physical address
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => {}
+            _ => break,
+        }
+        opcode = next!();
+    }
+    if opcode & 0xf0 == 0x40 {
+        // In 32-bit code, these are INC and DEC of registers, which never
+        // access memory.
+        rex = opcode;
+        opcode = next!();
+    }
+    let rex_w = rex & 0x8 != 0;
+    let rex_r = (rex & 0x4) << 1;
+    let full_size = if rex_w {
+        8
+    } else if operand_16 {
+        2
+    } else {
+        4
+    };
+    let (size, zero_extend) = match opcode {
+        0x88 | 0x8a | 0xc6 => (1, false),
+        0x89 | 0x8b | 0xc7 => (full_size, false),
+        0x0f => match next!() {
+            0xb6 => (1, true),
+            0xb7 => (2, true),
+            _ => return Err(HyperError::NotSupported),
+        },
+        _ => return Err(HyperError::NotSupported),
+    };
+
+    // ModRM, SIB and displacement, with 32-bit or 64-bit addressing.
+    let modrm = next!();
+    let mode = modrm >> 6;
+    let reg_field = (modrm >> 3) & 0x7;
+    let rm = modrm & 0x7;
+    if mode == 3 {
+        // a register operand, not memory
+        return Err(HyperError::NotSupported);
+    }
+    let mut disp_len = match mode {
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    if rm == 4 {
+        let sib = next!();
+        if mode == 0 && sib & 0x7 == 5 {
+            disp_len = 4;
+        }
+    } else if mode == 0 && rm == 5 {
+        // RIP-relative in 64-bit code, absolute otherwise
+        disp_len = 4;
+    }
+    pos += disp_len;
+
+    // Without REX, byte registers 4-7 are the high bytes of registers 0-3.
+    // The destination of MOVZX is not a byte register.
+    let high_byte = matches!(opcode, 0x88 | 0x8a) && rex == 0 && reg_field >= 4;
+    let reg = Reg {
+        index: if high_byte { reg_field - 4 } else { reg_field | rex_r },
+        high_byte,
+    };
+    let op = match opcode {
+        0x88 | 0x89 => MmioOp::StoreReg(reg),
+        0xc6 | 0xc7 => {
+            if reg_field != 0 {
+                return Err(HyperError::NotSupported);
+            }
+            let imm_len = size.min(4) as usize;
+            let Some(imm) = bytes.get(pos..pos + imm_len) else {
+                return Ok(None);
+            };
+            pos += imm_len;
+            let mut buf = [0; 8];
+            buf[..imm_len].copy_from_slice(imm);
+            let mut imm = u64::from_le_bytes(buf);
+            if size == 8 {
+                // the 32-bit immediate is sign-extended
+                imm = imm as u32 as i32 as i64 as u64;
+            }
+            MmioOp::StoreImm(imm)
+        }
+        _ => MmioOp::Load { reg, zero_extend },
+    };
+    if pos > bytes.len() {
+        return Ok(None);
+    }
+    Ok(Some(MmioInstr {
+        len: pos as u8,
+        size,
+        op,
+    }))
+}
+
+fn read_reg(vcpu: &VCpu, index: u8) -> u64 {
+    if index == 4 {
+        vcpu.stack_pointer() as u64
+    } else {
+        vcpu.regs().get_reg_of_index(index)
+    }
+}
+
+fn write_reg(vcpu: &mut VCpu, index: u8, value: u64) {
+    if index == 4 {
+        vcpu.set_stack_pointer(value as usize);
+    } else {
+        vcpu.regs_mut().set_reg_of_index(index, value);
+    }
+}
+
+/// Fetches and decodes the instruction at the guest `RIP`. Returns `Ok(None)`
+/// if fetching it causes a guest page fault, which is injected.
+fn fetch(vcpu: &mut VCpu) -> HyperResult<Option<MmioInstr>> {
+    let mut buf = [0; MAX_INSTR_LEN];
+    // Most instructions end in the page of RIP, the next one may not be mapped.
+    let in_page = (PAGE_SIZE - vcpu.rip() % PAGE_SIZE).min(MAX_INSTR_LEN);
+    for len in [in_page, MAX_INSTR_LEN] {
+        match vcpu.fetch_guest_instruction(&mut buf[..len]) {
+            Ok(()) => {}
+            Err(GuestMemoryError::PageFault(fault)) => {
+                vcpu.inject_page_fault(fault);
+                return Ok(None);
+            }
+            Err(GuestMemoryError::NotBacked(_) | GuestMemoryError::ReadOnly(_)) => {
+                return Err(HyperError::NotSupported)
+            }
+            Err(GuestMemoryError::Hyper(err)) => return Err(err),
+        }
+        if let Some(instr) = decode(&buf[..len])? {
+            return Ok(Some(instr));
+        }
+    }
+    Err(HyperError::NotSupported)
+}
+
+/// Emulates the guest instruction accessing MMIO at `gpa`, with `read` and
+/// `write` accessing the device registers by address and size.
+pub(super) fn emulate_access<R, W>(
+    vcpu: &mut VCpu,
+    gpa: GuestPhysAddr,
+    read: R,
+    write: W,
+) -> HyperResult
+where
+    R: FnOnce(&mut VCpu, u8) -> HyperResult<u64>,
+    W: FnOnce(&mut VCpu, u8, u64) -> HyperResult,
+{
+    let Some(instr) = fetch(vcpu)? else {
+        return Ok(());
+    };
+    trace!("VM exit: MMIO @ {:#x}: {:#x} {:x?}", vcpu.rip(), gpa, instr);
+    let mask = u64::MAX >> (64 - 8 * instr.size as u32);
+    match instr.op {
+        MmioOp::Load { reg, zero_extend } => {
+            let value = read(vcpu, instr.size)? & mask;
+            let old = read_reg(vcpu, reg.index);
+            // SDM Vol. 1, Section 3.4.1.1: 32-bit results are zero-extended,
+            // 8-bit and 16-bit ones leave the upper bits unchanged.
+            let new = if reg.high_byte {
+                (old & !0xff00) | (value << 8)
+            } else if zero_extend || instr.size >= 4 {
+                value
+            } else {
+                (old & !mask) | value
+            };
+            write_reg(vcpu, reg.index, new);
+        }
+        MmioOp::StoreReg(reg) => {
+            let value = read_reg(vcpu, reg.index);
+            let value = if reg.high_byte { value >> 8 } else { value };
+            write(vcpu, instr.size, value & mask)?;
+        }
+        MmioOp::StoreImm(imm) => write(vcpu, instr.size, imm & mask)?,
+    }
+    vcpu.advance_rip(instr.len)
+}
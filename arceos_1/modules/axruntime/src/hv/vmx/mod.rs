@@ -1,7 +1,32 @@
+pub mod acpi;
+pub mod console;
+pub mod crash;
+pub mod demand_paging;
 mod device_emu;
+pub mod dirty_log;
+pub mod ept_flush;
+pub mod exit_stats;
+pub mod gdbstub;
+pub mod host_cpus;
+pub mod io_passthrough;
+mod mmio;
+pub mod shmem;
+pub mod snapshot;
+mod vcpu_wait;
+pub mod vm_control;
+pub mod vswitch;
+#[cfg(feature = "irq")]
+mod vtimer;
 
-use hypercraft::{VmxExitReason, VCpu as HVCpu, HyperResult, HyperError, VmxExitInfo};
+use hypercraft::{VmExitReason, VCpu as HVCpu, HyperResult, HyperError, VmExitInfo, ReplayInput};
+use alloc::string::String;
 use device_emu::VirtLocalApic;
+use page_table_entry::MappingFlags;
+pub use device_emu::set_hpet_enabled;
+pub use vcpu_wait::notify_vcpu;
+#[cfg(feature = "irq")]
+pub use vtimer::{check_events as check_vtimer_events, program_timer, set_vcpu_timer};
+extern crate alloc;
 #[cfg(feature = "axtask")]
 extern crate axtask;
 use axtask as thread;
@@ -11,8 +36,19 @@ type VCpu = HVCpu<super::HyperCraftHalImpl>;
 const VM_EXIT_INSTR_LEN_CPUID: u8 = 2;
 const VM_EXIT_INSTR_LEN_RDMSR: u8 = 2;
 const VM_EXIT_INSTR_LEN_WRMSR: u8 = 2;
+const VM_EXIT_INSTR_LEN_HLT: u8 = 1;
 const VM_EXIT_INSTR_LEN_VMCALL: u8 = 3;
 
+/// Handles the IPIs sent by other CPUs on behalf of their VMs. Those sent by
+/// [`notify_vcpu`] need nothing more than the VM exit they cause.
+#[cfg(all(feature = "smp", feature = "irq"))]
+pub(crate) fn handle_ipi() {
+    ept_flush::handle_requests();
+}
+
+/// Exit code of VMs shut down by a triple fault or a VM exit not handled.
+const CRASH_EXIT_CODE: u32 = 0xff;
+
 fn handle_external_interrupt(vcpu: &mut VCpu) -> HyperResult {
     #[cfg(feature = "irq")]
     {
@@ -21,9 +57,11 @@ fn handle_external_interrupt(vcpu: &mut VCpu) -> HyperResult {
         //     info!("VM-exit: external interrupt: {:#x?}", int_info);
         // }
         trace!("VM-exit: external interrupt: {:#x?}", int_info);
-        
-        assert!(int_info.valid);
-        axhal::irq::dispatch_irq(int_info.vector as usize);
+
+        // With SVM, the host has already taken the interrupt itself.
+        if int_info.valid {
+            axhal::irq::dispatch_irq(int_info.vector as usize);
+        }
         Ok(())
     }
     #[cfg(not(feature = "irq"))] {
@@ -35,20 +73,53 @@ fn handle_cpuid(vcpu: &mut VCpu) -> HyperResult {
     use raw_cpuid::{cpuid, CpuIdResult};
 
     const LEAF_FEATURE_INFO: u32 = 0x1;
+    const LEAF_STRUCTURED_EXTENDED_FEATURE_FLAGS: u32 = 0x7;
+    const LEAF_EXTENDED_STATE: u32 = 0xd;
     const LEAF_HYPERVISOR_INFO: u32 = 0x4000_0000;
     const LEAF_HYPERVISOR_FEATURE: u32 = 0x4000_0001;
+    const LEAF_EXTENDED_FEATURE_INFO: u32 = 0x8000_0001;
     const VENDOR_STR: &[u8; 12] = b"RVMRVMRVMRVM";
     let vendor_regs = unsafe { &*(VENDOR_STR.as_ptr() as *const [u32; 3]) };
 
+    let guest_cr4 = vcpu.cr(4);
+    let guest_xcr0 = vcpu.xcr0();
     let regs = vcpu.regs_mut();
     let function = regs.rax as u32;
     let res = match function {
         LEAF_FEATURE_INFO => {
             const FEATURE_VMX: u32 = 1 << 5;
+            const FEATURE_TSC_DEADLINE: u32 = 1 << 24;
+            const FEATURE_OSXSAVE: u32 = 1 << 27;
             const FEATURE_HYPERVISOR: u32 = 1 << 31;
+            const CR4_OSXSAVE: usize = 1 << 18;
+            let mut res = cpuid!(regs.rax, regs.rcx);
+            res.ecx &= !(FEATURE_VMX | FEATURE_OSXSAVE);
+            res.ecx |= FEATURE_TSC_DEADLINE | FEATURE_HYPERVISOR;
+            // OSXSAVE reflects the guest CR4, not the host one.
+            if guest_cr4 & CR4_OSXSAVE != 0 {
+                res.ecx |= FEATURE_OSXSAVE;
+            }
+            res
+        }
+        LEAF_STRUCTURED_EXTENDED_FEATURE_FLAGS => {
+            // Hide features whose states are not switched between guests.
+            const FEATURE_MPX: u32 = 1 << 14;
+            const FEATURE_PKU: u32 = 1 << 3;
+            const FEATURE_OSPKE: u32 = 1 << 4;
+            const FEATURE_AMX: u32 = (1 << 22) | (1 << 24) | (1 << 25);
+            let mut res = cpuid!(regs.rax, regs.rcx);
+            if regs.rcx == 0 {
+                res.ebx &= !FEATURE_MPX;
+                res.ecx &= !(FEATURE_PKU | FEATURE_OSPKE);
+                res.edx &= !FEATURE_AMX;
+            }
+            res
+        }
+        LEAF_EXTENDED_STATE => hypercraft::xstate_cpuid(regs.rcx as u32, guest_xcr0),
+        LEAF_EXTENDED_FEATURE_INFO => {
+            const FEATURE_SVM: u32 = 1 << 2;
             let mut res = cpuid!(regs.rax, regs.rcx);
-            res.ecx &= !FEATURE_VMX;
-            res.ecx |= FEATURE_HYPERVISOR;
+            res.ecx &= !FEATURE_SVM;
             res
         }
         LEAF_HYPERVISOR_INFO => CpuIdResult {
@@ -70,15 +141,26 @@ fn handle_cpuid(vcpu: &mut VCpu) -> HyperResult {
         "VM exit: CPUID({:#x}, {:#x}): {:?}",
         regs.rax, regs.rcx, res
     );
-    regs.rax = res.eax as _;
-    regs.rbx = res.ebx as _;
-    regs.rcx = res.ecx as _;
-    regs.rdx = res.edx as _;
+    // The host CPU may differ when replaying, so the results are logged too.
+    let (leaf, subleaf) = (regs.rax as u32, regs.rcx as u32);
+    let live = [res.eax, res.ebx, res.ecx, res.edx];
+    let input = vcpu.replay_input(
+        ReplayInput::Cpuid { leaf, subleaf, regs: [0; 4] },
+        |_| Ok(ReplayInput::Cpuid { leaf, subleaf, regs: live }),
+    )?;
+    let ReplayInput::Cpuid { regs: res, .. } = input else {
+        unreachable!()
+    };
+    let regs = vcpu.regs_mut();
+    regs.rax = res[0] as _;
+    regs.rbx = res[1] as _;
+    regs.rcx = res[2] as _;
+    regs.rdx = res[3] as _;
     vcpu.advance_rip(VM_EXIT_INSTR_LEN_CPUID)?;
     Ok(())
 }
 
-fn handle_io_instruction(vcpu: &mut VCpu, exit_info: &VmxExitInfo) -> HyperResult {
+fn handle_io_instruction(vcpu: &mut VCpu, exit_info: &VmExitInfo) -> HyperResult {
     let io_info = vcpu.io_exit_info()?;
     trace!(
         "VM exit: I/O instruction @ {:#x}: {:#x?}",
@@ -96,7 +178,14 @@ fn handle_io_instruction(vcpu: &mut VCpu, exit_info: &VmxExitInfo) -> HyperResul
 
     if let Some(dev) = device_emu::all_virt_devices(vcpu.get_vm_id()).find_port_io_device(io_info.port) {
         if io_info.is_in {
-            let value = dev.read(io_info.port, io_info.access_size)?;
+            let port = io_info.port;
+            let input = vcpu.replay_input(ReplayInput::PortRead { port, value: 0 }, |_| {
+                let value = dev.read(port, io_info.access_size)?;
+                Ok(ReplayInput::PortRead { port, value })
+            })?;
+            let ReplayInput::PortRead { value, .. } = input else {
+                unreachable!()
+            };
             let rax = &mut vcpu.regs_mut().rax;
             // SDM Vol. 1, Section 3.4.1.1:
             // * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
@@ -134,17 +223,22 @@ fn handle_msr_read(vcpu: &mut VCpu) -> HyperResult {
     let msr = vcpu.regs().rcx as u32;
 
     use x86::msr::*;
-    let res = if msr == IA32_APIC_BASE {
-        let mut apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
-        apic_base |= 1 << 11 | 1 << 10; // enable xAPIC and x2APIC
-        Ok(apic_base)
-    } else if VirtLocalApic::msr_range().contains(&msr) {
-        VirtLocalApic::rdmsr(vcpu, msr)
-    } else {
-        Err(HyperError::NotSupported)
-    };
+    let res = vcpu.replay_input(ReplayInput::MsrRead { msr, value: 0 }, |vcpu| {
+        let value = if msr == IA32_APIC_BASE {
+            let mut apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
+            apic_base |= 1 << 11 | 1 << 10; // enable xAPIC and x2APIC
+            apic_base
+        } else if msr == IA32_TSC_DEADLINE {
+            vcpu.apic_timer_mut().tsc_deadline()
+        } else if VirtLocalApic::msr_range().contains(&msr) {
+            VirtLocalApic::rdmsr(vcpu, msr)?
+        } else {
+            return Err(HyperError::NotSupported);
+        };
+        Ok(ReplayInput::MsrRead { msr, value })
+    });
 
-    if let Ok(value) = res {
+    if let Ok(ReplayInput::MsrRead { value, .. }) = res {
         trace!("VM exit: RDMSR({:#x}) -> {:#x}", msr, value);
         vcpu.regs_mut().rax = value & 0xffff_ffff;
         vcpu.regs_mut().rdx = value >> 32;
@@ -165,6 +259,8 @@ fn handle_msr_write(vcpu: &mut VCpu) -> HyperResult {
     let res = if msr == IA32_APIC_BASE {
         
         Ok(()) // ignore
+    } else if msr == IA32_TSC_DEADLINE {
+        vcpu.apic_timer_mut().set_tsc_deadline(value)
     } else if VirtLocalApic::msr_range().contains(&msr) {
         VirtLocalApic::wrmsr(vcpu, msr, value)
     } else {
@@ -181,20 +277,117 @@ fn handle_msr_write(vcpu: &mut VCpu) -> HyperResult {
     Ok(())
 }
 
+fn handle_hlt(vcpu: &mut VCpu) -> HyperResult {
+    trace!("VM exit: HLT @ {:#x}", vcpu.exit_info()?.guest_rip);
+    vcpu.advance_rip(VM_EXIT_INSTR_LEN_HLT)?;
+    vcpu_wait::wait_for_interrupt(vcpu);
+    // Other vCPUs may have been run on this CPU while we were blocked.
+    vcpu.load_vmcs()
+}
+
+fn handle_pause(vcpu: &mut VCpu, exit_info: &VmExitInfo) -> HyperResult {
+    // The guest is spinning on a lock, give the CPU to others, with the
+    // virtual time of the VM stopped as for preemptions.
+    vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
+    vcpu.clock().vcpu_descheduled();
+    thread::yield_now();
+    vcpu.clock().vcpu_scheduled();
+    vcpu.load_vmcs()
+}
+
+fn handle_ept_violation(vcpu: &mut VCpu) -> HyperResult {
+    let fault = vcpu.nested_page_fault_info()?;
+    let gpa = fault.fault_guest_paddr;
+    let hpet = device_emu::all_virt_devices(vcpu.get_vm_id()).hpet();
+    if hpet.contains(gpa) {
+        return mmio::emulate_access(
+            vcpu,
+            gpa,
+            |vcpu, size| {
+                let input = vcpu.replay_input(ReplayInput::MmioRead { gpa: gpa as u64, value: 0 }, |vcpu| {
+                    let value = hpet.read(gpa, size, vcpu.clock().now_ns())?;
+                    Ok(ReplayInput::MmioRead { gpa: gpa as u64, value })
+                })?;
+                let ReplayInput::MmioRead { value, .. } = input else {
+                    unreachable!()
+                };
+                Ok(value)
+            },
+            |vcpu, size, value| hpet.write(gpa, size, value, vcpu.clock().now_ns()),
+        );
+    }
+    if fault.access_flags.contains(MappingFlags::WRITE)
+        && dirty_log::handle_write_fault(vcpu.get_vm_id(), fault.fault_guest_paddr)?
+    {
+        return Ok(());
+    }
+    demand_paging::handle_ept_violation(vcpu.get_vm_id(), &fault)
+}
+
+fn handle_triple_fault(vcpu: &mut VCpu, exit_info: &VmExitInfo) -> HyperResult {
+    // Real hardware resets, but a guest crashing at boot would then reboot forever.
+    warn!(
+        "VM {} vcpu {} triple fault @ {:#x}, shutting down",
+        vcpu.get_vm_id(),
+        vcpu.get_vcpu_id(),
+        exit_info.guest_rip
+    );
+    crash::record(vcpu);
+    vm_control::guest_shutdown(vcpu.get_vm_id(), CRASH_EXIT_CODE);
+    Ok(())
+}
+
+fn handle_crash(vcpu: &mut VCpu, exit_reason: Option<VmExitReason>, err: HyperError) -> HyperResult {
+    let vm_id = vcpu.get_vm_id();
+    error!(
+        "VM {} vcpu {} failed to handle VM exit {:?}, error {:?}, shutting down:\n{:#x?}",
+        vm_id,
+        vcpu.get_vcpu_id(),
+        exit_reason,
+        err,
+        vcpu
+    );
+    let mut trace = String::new();
+    if exit_stats::dump_trace(vm_id, &mut trace).is_ok() && !trace.is_empty() {
+        error!("{}", trace);
+    }
+    crash::record(vcpu);
+    vm_control::guest_shutdown(vm_id, CRASH_EXIT_CODE);
+    Ok(())
+}
+
 pub fn vmexit_handler(vcpu: &mut VCpu) -> HyperResult {
-    let exit_info = vcpu.exit_info()?;
-    
-    match exit_info.exit_reason {
-        VmxExitReason::EXTERNAL_INTERRUPT => handle_external_interrupt(vcpu),
-        VmxExitReason::CPUID => handle_cpuid(vcpu),
-        VmxExitReason::IO_INSTRUCTION => handle_io_instruction(vcpu, &exit_info),
-        VmxExitReason::MSR_READ => handle_msr_read(vcpu),
-        VmxExitReason::MSR_WRITE => handle_msr_write(vcpu),
-        VmxExitReason::PREEMPTION_TIMER => {
+    // Exits unknown to the vCPU can not be handled, but still shut the VM down.
+    let exit_info = vcpu.exit_info();
+    let exit_reason = exit_info.as_ref().ok().map(|info| info.exit_reason);
+    let res = exit_info.and_then(|exit_info| match exit_info.exit_reason {
+        VmExitReason::EXTERNAL_INTERRUPT => handle_external_interrupt(vcpu),
+        VmExitReason::CPUID => handle_cpuid(vcpu),
+        VmExitReason::IO_INSTRUCTION => handle_io_instruction(vcpu, &exit_info),
+        VmExitReason::MSR_READ => handle_msr_read(vcpu),
+        VmExitReason::MSR_WRITE => handle_msr_write(vcpu),
+        VmExitReason::HLT => handle_hlt(vcpu),
+        VmExitReason::PAUSE_INSTRUCTION => handle_pause(vcpu, &exit_info),
+        VmExitReason::NESTED_PAGE_FAULT => handle_ept_violation(vcpu),
+        VmExitReason::TRIPLE_FAULT => handle_triple_fault(vcpu, &exit_info),
+        VmExitReason::EXCEPTION_NMI => gdbstub::handle_exception(vcpu),
+        VmExitReason::MONITOR_TRAP_FLAG => gdbstub::handle_monitor_trap(vcpu),
+        VmExitReason::PREEMPTION_TIMER => {
+            // Stop the virtual time of the VM while it's not running.
+            vcpu.clock().vcpu_descheduled();
             thread::yield_now();
+            vcpu.clock().vcpu_scheduled();
             info!("VM {} vcpu {} vmexit come back with {:#x?}_1!!!",vcpu.get_vm_id(), vcpu.get_vcpu_id(),exit_info.exit_reason);
             vcpu.load_vmcs()
         }
-        _ => panic!("vmexit reason not supported {:?}:\n{:?}", exit_info.exit_reason, vcpu)
-    }
+        _ => Err(HyperError::NotSupported),
+    });
+    // Shut the VM down instead of panicking, so that it can be dumped.
+    let res = res.or_else(|err| handle_crash(vcpu, exit_reason, err));
+    let res = res.and_then(|_| snapshot::check_request(vcpu));
+    let res = res.and_then(|_| gdbstub::check_stop(vcpu, exit_reason));
+    let res = res.and_then(|_| vm_control::check_request(vcpu));
+    let res = res.and_then(|_| host_cpus::check_request(vcpu));
+    vcpu_wait::inject_pending_irqs(vcpu);
+    res
 }
\ No newline at end of file
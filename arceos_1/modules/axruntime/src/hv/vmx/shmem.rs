@@ -0,0 +1,129 @@
+//! Shared memory between VMs, like QEMU's `ivshmem`: the same host pages are
+//! mapped into several VMs, and a doorbell lets a VM interrupt its peers.
+//!
+//! A region is created when the first VM attaches to it by name, and freed
+//! once the last one detaches. Each VM numbers the regions it's attached to
+//! from 0 in the order it attached them, the doorbell uses these numbers.
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+
+use axalloc::global_allocator;
+use axhal::mem::{virt_to_phys, PAGE_SIZE_4K};
+use hypercraft::{HostPhysAddr, HyperError, HyperResult};
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::vcpu_wait::notify_vcpu;
+use super::vm_control;
+
+/// Rings the doorbell of all peers attached to the region.
+pub const BROADCAST: usize = 0xffff;
+
+struct Peer {
+    vm_id: usize,
+    // the number of the region in this VM
+    slot: usize,
+    // injected into the VM when a peer rings its doorbell
+    vector: u8,
+}
+
+struct SharedRegion {
+    name: String,
+    base: usize,
+    num_pages: usize,
+    peers: Vec<Peer>,
+}
+
+static REGIONS: SpinNoIrq<Vec<SharedRegion>> = SpinNoIrq::new(Vec::new());
+
+/// Attaches the VM to the shared region `name` of `size` bytes, creating it
+/// zeroed if no VM is attached yet, and returns its host physical address.
+/// `vector` is injected into the VM when a peer rings its doorbell.
+///
+/// A VM created again after a reset attaches to the same region again, with
+/// the same number, until [`detach`] is called.
+pub fn attach(vm_id: usize, name: &str, size: usize, vector: u8) -> HyperResult<HostPhysAddr> {
+    if vm_id >= MAX_VMS || name.is_empty() || size == 0 || size % PAGE_SIZE_4K != 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    let mut regions = REGIONS.lock();
+    let slot = regions
+        .iter()
+        .filter(|r| r.peers.iter().any(|p| p.vm_id == vm_id))
+        .count();
+    let region = match regions.iter_mut().find(|r| r.name == name) {
+        Some(region) => {
+            if region.num_pages * PAGE_SIZE_4K != size {
+                warn!(
+                    "VM {} shared memory {:?} size {:#x} mismatch, already {:#x}",
+                    vm_id,
+                    name,
+                    size,
+                    region.num_pages * PAGE_SIZE_4K
+                );
+                return Err(HyperError::InvalidParam);
+            }
+            region
+        }
+        None => {
+            let num_pages = size / PAGE_SIZE_4K;
+            let base = global_allocator()
+                .alloc_pages(num_pages, PAGE_SIZE_4K)
+                .map_err(|_| HyperError::NoMemory)?;
+            unsafe { core::ptr::write_bytes(base as *mut u8, 0, size) };
+            info!("shared memory {:?} created, size {:#x}", name, size);
+            regions.push(SharedRegion {
+                name: String::from(name),
+                base,
+                num_pages,
+                peers: Vec::new(),
+            });
+            regions.last_mut().unwrap()
+        }
+    };
+    match region.peers.iter_mut().find(|p| p.vm_id == vm_id) {
+        Some(peer) => peer.vector = vector,
+        None => {
+            info!("VM {} attached to shared memory {:?} as {}", vm_id, name, slot);
+            region.peers.push(Peer { vm_id, slot, vector });
+        }
+    }
+    Ok(virt_to_phys(region.base.into()).into())
+}
+
+/// Detaches the VM from all shared regions, once it's destroyed. Regions
+/// without VMs attached are freed.
+pub fn detach(vm_id: usize) {
+    REGIONS.lock().retain_mut(|region| {
+        region.peers.retain(|p| p.vm_id != vm_id);
+        if !region.peers.is_empty() {
+            return true;
+        }
+        info!("shared memory {:?} freed", region.name);
+        global_allocator().dealloc_pages(region.base, region.num_pages);
+        false
+    });
+}
+
+/// Sends the vector of `peer`, or of all other VMs if it's [`BROADCAST`], to
+/// their vCPU `vcpu_id`, on the region numbered `slot` in VM `vm_id`. Peers
+/// not attached, not running or without this vCPU are ignored.
+pub fn ring_doorbell(vm_id: usize, slot: usize, peer: usize, vcpu_id: usize) -> HyperResult {
+    let regions = REGIONS.lock();
+    let region = regions
+        .iter()
+        .find(|r| r.peers.iter().any(|p| p.vm_id == vm_id && p.slot == slot))
+        .ok_or(HyperError::NotFound)?;
+    for p in region.peers.iter() {
+        if p.vm_id != vm_id && (peer == BROADCAST || peer == p.vm_id) {
+            if vcpu_id >= vm_control::vcpu_count(p.vm_id) {
+                warn!("VM {} rings missing vcpu {} of VM {}", vm_id, vcpu_id, p.vm_id);
+                continue;
+            }
+            trace!("VM {} rings VM {} vcpu {} on {:?}", vm_id, p.vm_id, vcpu_id, region.name);
+            notify_vcpu(p.vm_id, vcpu_id, p.vector);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! Capturing and restoring the states of VMs for snapshots.
+//!
+//! After a snapshot is requested with [`request`], each vCPU of the VM saves
+//! its state on its next VM exit, then stays paused with the virtual clock
+//! stopped until [`resume`] is called, so the guest memory can be copied
+//! consistently in the meantime.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use axtask::WaitQueue;
+use hypercraft::snapshot::{StateReader, StateWriter};
+use hypercraft::{HyperError, HyperResult, VcpuState};
+use spinlock::SpinNoIrq;
+
+use super::device_emu::{all_virt_devices, MAX_VMS};
+use super::vcpu_wait::{self, MAX_VCPUS_PER_VM};
+use super::VCpu;
+
+/// States of a VM saved by its vCPUs, other than the guest memory.
+pub struct VmState {
+    /// Virtual time of the VM in nanoseconds.
+    pub clock_ns: u64,
+    /// States of all vCPUs, indexed by the vCPU ID.
+    pub vcpus: Vec<VcpuState>,
+    /// States of the emulated devices.
+    pub devices: Vec<u8>,
+}
+
+struct SnapshotSlot {
+    requested: AtomicBool,
+    resumed: AtomicBool,
+    clock_ns: AtomicU64,
+    vcpus: SpinNoIrq<Vec<Option<VcpuState>>>,
+    captured_wq: WaitQueue,
+    resume_wq: WaitQueue,
+}
+
+impl SnapshotSlot {
+    fn all_captured(&self) -> bool {
+        self.vcpus.lock().iter().all(|s| s.is_some())
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SNAPSHOT_SLOTS: Vec<SnapshotSlot> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS {
+            temp.push(SnapshotSlot {
+                requested: AtomicBool::new(false),
+                resumed: AtomicBool::new(false),
+                clock_ns: AtomicU64::new(0),
+                vcpus: SpinNoIrq::new(Vec::new()),
+                captured_wq: WaitQueue::new(),
+                resume_wq: WaitQueue::new(),
+            });
+        }
+        temp
+    };
+}
+
+/// Asks the `vcpu_count` vCPUs of the VM to save their states and pause.
+pub fn request(vm_id: usize, vcpu_count: usize) -> HyperResult {
+    let slot = &SNAPSHOT_SLOTS[vm_id];
+    if vcpu_count == 0 || vcpu_count > MAX_VCPUS_PER_VM {
+        return Err(HyperError::InvalidParam);
+    }
+    if slot.requested.load(Ordering::Acquire) {
+        return Err(HyperError::BadState);
+    }
+    *slot.vcpus.lock() = (0..vcpu_count).map(|_| None).collect();
+    slot.resumed.store(false, Ordering::Release);
+    slot.requested.store(true, Ordering::Release);
+    for vcpu_id in 0..vcpu_count {
+        vcpu_wait::kick_vcpu(vm_id, vcpu_id);
+    }
+    Ok(())
+}
+
+/// Waits until all vCPUs of the VM have saved their states after [`request`],
+/// and saves the emulated devices. The VM stays paused.
+pub fn wait_captured(vm_id: usize) -> HyperResult<VmState> {
+    let slot = &SNAPSHOT_SLOTS[vm_id];
+    if !slot.requested.load(Ordering::Acquire) {
+        return Err(HyperError::BadState);
+    }
+    slot.captured_wq.wait_until(|| slot.all_captured());
+    let vcpus = slot
+        .vcpus
+        .lock()
+        .iter_mut()
+        .map(|s| s.take().unwrap())
+        .collect();
+    let mut w = StateWriter::new();
+    all_virt_devices(vm_id).save_state(&mut w);
+    Ok(VmState {
+        clock_ns: slot.clock_ns.load(Ordering::Acquire),
+        vcpus,
+        devices: w.into_bytes(),
+    })
+}
+
+/// Lets the vCPUs paused for a snapshot run again.
+pub fn resume(vm_id: usize) {
+    let slot = &SNAPSHOT_SLOTS[vm_id];
+    slot.vcpus.lock().clear();
+    slot.requested.store(false, Ordering::Release);
+    slot.resumed.store(true, Ordering::Release);
+    slot.resume_wq.notify_all(false);
+}
+
+/// Restores the emulated devices of a VM from [`VmState::devices`].
+pub fn restore_devices(vm_id: usize, devices: &[u8]) -> HyperResult {
+    let mut r = StateReader::new(devices);
+    all_virt_devices(vm_id).restore_state(&mut r)?;
+    if r.remaining() != 0 {
+        return Err(HyperError::DecodeError);
+    }
+    Ok(())
+}
+
+/// Saves the state of `vcpu` and pauses it if a snapshot of its VM is
+/// requested. Called at the end of VM exit handling.
+pub(super) fn check_request(vcpu: &mut VCpu) -> HyperResult {
+    let slot = &SNAPSHOT_SLOTS[vcpu.get_vm_id()];
+    if !slot.requested.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let vcpu_id = vcpu.get_vcpu_id();
+    {
+        let mut vcpus = slot.vcpus.lock();
+        match vcpus.get_mut(vcpu_id) {
+            Some(saved @ None) => *saved = Some(vcpu.save_state()?),
+            // not part of the snapshot, or already saved
+            _ => return Ok(()),
+        }
+    }
+    vcpu.clock().pause();
+    slot.clock_ns.store(vcpu.clock().now_ns(), Ordering::Release);
+    slot.captured_wq.notify_all(false);
+
+    slot.resume_wq
+        .wait_until(|| slot.resumed.load(Ordering::Acquire));
+    vcpu.clock().resume();
+    // Other vCPUs may have been run on this CPU while we were paused.
+    vcpu.load_vmcs()
+}
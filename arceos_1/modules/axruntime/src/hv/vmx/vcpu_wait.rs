@@ -0,0 +1,115 @@
+//! Blocking idle vCPUs and waking them up on virtual interrupts.
+
+extern crate alloc;
+use alloc::{collections::VecDeque, vec::Vec};
+use axtask::WaitQueue;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spinlock::SpinNoIrq;
+
+use super::device_emu::MAX_VMS;
+use super::VCpu;
+
+/// Max number of vCPUs in each VM.
+pub const MAX_VCPUS_PER_VM: usize = 4;
+
+struct VCpuWaiter {
+    wq: WaitQueue,
+    pending_irqs: SpinNoIrq<VecDeque<u8>>,
+    kicked: AtomicBool,
+    /// The CPU the vCPU runs on, or `usize::MAX` when it's not attached.
+    cpu: AtomicUsize,
+}
+
+impl VCpuWaiter {
+    fn should_wake(&self) -> bool {
+        self.kicked.load(Ordering::Acquire) || !self.pending_irqs.lock().is_empty()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref VCPU_WAITERS: Vec<VCpuWaiter> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS * MAX_VCPUS_PER_VM {
+            temp.push(VCpuWaiter {
+                wq: WaitQueue::new(),
+                pending_irqs: SpinNoIrq::new(VecDeque::new()),
+                kicked: AtomicBool::new(false),
+                cpu: AtomicUsize::new(usize::MAX),
+            });
+        }
+        temp
+    };
+}
+
+fn waiter(vm_id: usize, vcpu_id: usize) -> &'static VCpuWaiter {
+    assert!(vm_id < MAX_VMS && vcpu_id < MAX_VCPUS_PER_VM);
+    &VCPU_WAITERS[vm_id * MAX_VCPUS_PER_VM + vcpu_id]
+}
+
+/// Sends the interrupt `vector` to the given vCPU, and wakes it up if it is
+/// halted. The interrupt is injected before the next VM entry of the vCPU.
+///
+/// It can be called from any task or from the IRQ context.
+pub fn notify_vcpu(vm_id: usize, vcpu_id: usize, vector: u8) {
+    let waiter = waiter(vm_id, vcpu_id);
+    waiter.pending_irqs.lock().push_back(vector);
+    waiter.wq.notify_one(false);
+    // A vCPU in the guest on another CPU must exit for the interrupt to be
+    // injected. If it's handling a VM exit instead, the IPI stays pending
+    // and makes it exit again right after its next VM entry.
+    #[cfg(all(feature = "smp", feature = "irq"))]
+    {
+        let cpu_id = waiter.cpu.load(Ordering::Acquire);
+        if cpu_id < axconfig::SMP && cpu_id != axhal::cpu::this_cpu_id() {
+            axhal::mp::send_ipi(cpu_id);
+        }
+    }
+}
+
+/// Notes that the given vCPU runs on the current CPU, where [`notify_vcpu`]
+/// sends IPIs to kick it out of the guest.
+pub(super) fn note_current_cpu(vm_id: usize, vcpu_id: usize) {
+    let cpu_id = axhal::cpu::this_cpu_id();
+    waiter(vm_id, vcpu_id).cpu.store(cpu_id, Ordering::Release);
+}
+
+/// Notes that the given vCPU no longer runs.
+pub(super) fn clear_current_cpu(vm_id: usize, vcpu_id: usize) {
+    waiter(vm_id, vcpu_id).cpu.store(usize::MAX, Ordering::Release);
+}
+
+/// Wakes up the given vCPU if it is halted, without sending an interrupt.
+///
+/// If the vCPU is running in the guest on this CPU, the host interrupt that
+/// calls this function has already kicked it out of the guest.
+pub fn kick_vcpu(vm_id: usize, vcpu_id: usize) {
+    let waiter = waiter(vm_id, vcpu_id);
+    waiter.kicked.store(true, Ordering::Release);
+    waiter.wq.notify_one(false);
+}
+
+/// Moves the interrupts sent by [`notify_vcpu`] to the pending events of `vcpu`.
+pub fn inject_pending_irqs(vcpu: &mut VCpu) {
+    let waiter = waiter(vcpu.get_vm_id(), vcpu.get_vcpu_id());
+    while let Some(vector) = waiter.pending_irqs.lock().pop_front() {
+        vcpu.inject_event(vector, None);
+    }
+}
+
+/// Blocks the task of a halted `vcpu` until its APIC timer fires or an
+/// interrupt is sent to it.
+pub fn wait_for_interrupt(vcpu: &mut VCpu) {
+    if vcpu.has_pending_events() {
+        return;
+    }
+    let waiter = waiter(vcpu.get_vm_id(), vcpu.get_vcpu_id());
+    // Clear stale kicks before checking the deadline, so that a timer firing
+    // in between is not lost.
+    waiter.kicked.store(false, Ordering::Release);
+    if vcpu.apic_timer_mut().is_due() {
+        return;
+    }
+    // The virtual timer of the vCPU has been armed on the host timer list,
+    // which kicks us at the deadline.
+    waiter.wq.wait_until(|| waiter.should_wake());
+}
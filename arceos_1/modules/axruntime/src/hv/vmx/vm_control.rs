@@ -0,0 +1,220 @@
+//! Pausing, resuming, resetting and destroying running VMs from other tasks,
+//! and powering them off or resetting them from the guests themselves.
+//!
+//! Requests take effect on the next VM exit of each vCPU: a paused vCPU blocks
+//! with the virtual clock stopped, and a vCPU of a VM being reset, shut down or
+//! destroyed returns from `run`. It's then up to the task that ran the VM to
+//! free it, and to create it again if [`finish`] tells the VM should be reset.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use axtask::WaitQueue;
+use hypercraft::{HyperError, HyperResult};
+use spinlock::SpinNoIrq;
+
+use super::console;
+use super::device_emu::{all_virt_devices, MAX_VMS};
+use super::vcpu_wait::{self, MAX_VCPUS_PER_VM};
+use super::VCpu;
+
+/// Run state of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmStatus {
+    /// Not created, or destroyed.
+    Stopped,
+    /// Running, or halted waiting for interrupts.
+    Running,
+    /// Paused by [`pause`].
+    Paused,
+    /// Being reset, its vCPUs are returning from `run`.
+    Resetting,
+    /// Being destroyed, its vCPUs are returning from `run`.
+    Destroying,
+    /// Powered off by the guest, its vCPUs are returning from `run`.
+    Shutdown,
+}
+
+struct VmControl {
+    status: VmStatus,
+    vcpu_count: usize,
+    exit_code: u32,
+}
+
+struct ControlSlot {
+    state: SpinNoIrq<VmControl>,
+    resume_wq: WaitQueue,
+}
+
+lazy_static::lazy_static! {
+    static ref VM_CONTROLS: Vec<ControlSlot> = {
+        let mut temp = Vec::new();
+        for _ in 0..MAX_VMS {
+            temp.push(ControlSlot {
+                state: SpinNoIrq::new(VmControl {
+                    status: VmStatus::Stopped,
+                    vcpu_count: 0,
+                    exit_code: 0,
+                }),
+                resume_wq: WaitQueue::new(),
+            });
+        }
+        temp
+    };
+}
+
+fn slot(vm_id: usize) -> HyperResult<&'static ControlSlot> {
+    VM_CONTROLS.get(vm_id).ok_or(HyperError::InvalidParam)
+}
+
+fn kick_all(vm_id: usize, vcpu_count: usize) {
+    for vcpu_id in 0..vcpu_count {
+        vcpu_wait::kick_vcpu(vm_id, vcpu_id);
+    }
+}
+
+/// Marks the VM with `vcpu_count` vCPUs as running, and resets its emulated
+/// devices and console buffers. Called before its vCPUs run.
+pub fn register(vm_id: usize, vcpu_count: usize) -> HyperResult {
+    if vcpu_count == 0 || vcpu_count > MAX_VCPUS_PER_VM {
+        return Err(HyperError::InvalidParam);
+    }
+    let slot = slot(vm_id)?;
+    if !matches!(slot.state.lock().status, VmStatus::Stopped | VmStatus::Resetting) {
+        return Err(HyperError::BadState);
+    }
+    all_virt_devices(vm_id).reset();
+    // The console locks are taken before ours when it checks the VM status.
+    console::reset(vm_id);
+    let mut state = slot.state.lock();
+    state.status = VmStatus::Running;
+    state.vcpu_count = vcpu_count;
+    state.exit_code = 0;
+    Ok(())
+}
+
+/// Returns the run state of the VM.
+pub fn status(vm_id: usize) -> VmStatus {
+    slot(vm_id).map_or(VmStatus::Stopped, |slot| slot.state.lock().status)
+}
+
+/// Number of vCPUs of the VM, 0 if it's not running.
+pub fn vcpu_count(vm_id: usize) -> usize {
+    slot(vm_id).map_or(0, |slot| slot.state.lock().vcpu_count)
+}
+
+/// Pauses all vCPUs of the running VM.
+pub fn pause(vm_id: usize) -> HyperResult {
+    let vcpu_count = {
+        let mut state = slot(vm_id)?.state.lock();
+        if state.status != VmStatus::Running {
+            return Err(HyperError::BadState);
+        }
+        state.status = VmStatus::Paused;
+        state.vcpu_count
+    };
+    kick_all(vm_id, vcpu_count);
+    Ok(())
+}
+
+/// Lets the paused VM run again.
+pub fn resume(vm_id: usize) -> HyperResult {
+    let slot = slot(vm_id)?;
+    {
+        let mut state = slot.state.lock();
+        if state.status != VmStatus::Paused {
+            return Err(HyperError::BadState);
+        }
+        state.status = VmStatus::Running;
+    }
+    slot.resume_wq.notify_all(false);
+    Ok(())
+}
+
+fn stop(vm_id: usize, status: VmStatus, exit_code: u32) -> HyperResult {
+    let slot = slot(vm_id)?;
+    let vcpu_count = {
+        let mut state = slot.state.lock();
+        if !matches!(state.status, VmStatus::Running | VmStatus::Paused) {
+            return Err(HyperError::BadState);
+        }
+        state.status = status;
+        state.exit_code = exit_code;
+        state.vcpu_count
+    };
+    slot.resume_wq.notify_all(false);
+    kick_all(vm_id, vcpu_count);
+    Ok(())
+}
+
+/// Stops the running or paused VM, which is then created again from scratch
+/// by the task running it.
+pub fn reset(vm_id: usize) -> HyperResult {
+    stop(vm_id, VmStatus::Resetting, 0)
+}
+
+/// Stops the running or paused VM for good.
+pub fn destroy(vm_id: usize) -> HyperResult {
+    stop(vm_id, VmStatus::Destroying, 0)
+}
+
+/// Powers off the VM on behalf of its guest, with `exit_code` reported by
+/// [`exit_code`]. Ignored if the VM is already stopping.
+pub fn guest_shutdown(vm_id: usize, exit_code: u32) {
+    if stop(vm_id, VmStatus::Shutdown, exit_code).is_err() {
+        debug!("VM {} is already stopping, shutdown ignored", vm_id);
+    }
+}
+
+/// Resets the VM on behalf of its guest. Ignored if the VM is already
+/// stopping.
+pub fn guest_reset(vm_id: usize) {
+    if stop(vm_id, VmStatus::Resetting, 0).is_err() {
+        debug!("VM {} is already stopping, reset ignored", vm_id);
+    }
+}
+
+/// Called by the task running the VM after its vCPUs returned from `run` and
+/// it has been freed. Returns whether the VM should be created again.
+pub fn finish(vm_id: usize) -> bool {
+    let Ok(slot) = slot(vm_id) else {
+        return false;
+    };
+    let mut state = slot.state.lock();
+    state.vcpu_count = 0;
+    if state.status == VmStatus::Resetting {
+        true
+    } else {
+        state.status = VmStatus::Stopped;
+        false
+    }
+}
+
+/// Exit code the guest powered the VM off with, 0 if it was destroyed from
+/// the host. Valid once [`finish`] tells the VM is stopped.
+pub fn exit_code(vm_id: usize) -> u32 {
+    slot(vm_id).map_or(0, |slot| slot.state.lock().exit_code)
+}
+
+/// Pauses `vcpu` or makes it return from `run` if requested. Called at the
+/// end of VM exit handling.
+pub(super) fn check_request(vcpu: &mut VCpu) -> HyperResult {
+    let slot = slot(vcpu.get_vm_id())?;
+    let status = slot.state.lock().status;
+    match status {
+        VmStatus::Paused => {
+            vcpu.clock().pause();
+            slot.resume_wq
+                .wait_until(|| slot.state.lock().status != VmStatus::Paused);
+            vcpu.clock().resume();
+            // Other vCPUs may have been run on this CPU while we were paused.
+            vcpu.load_vmcs()?;
+            if slot.state.lock().status != VmStatus::Running {
+                vcpu.stop();
+            }
+        }
+        VmStatus::Resetting | VmStatus::Destroying | VmStatus::Shutdown => vcpu.stop(),
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Virtual L2 switch connecting the virtio-net devices of the VMs, and
+//! optionally the network stack of the host through the uplink port.
+//!
+//! The switch learns on which port each source MAC address is, and floods
+//! frames to unknown, broadcast and multicast addresses to all other ports.
+
+extern crate alloc;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use hypercraft::{HyperError, HyperResult};
+use spinlock::SpinNoIrq;
+
+use super::device_emu::{all_virt_devices, MAX_VMS};
+use crate::GuestPageTable;
+
+/// A port of the switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchPort {
+    /// The virtio-net device of the VM.
+    Vm(usize),
+    /// The network stack of the host.
+    Uplink,
+}
+
+struct Switch {
+    // VMs with a virtio-net device connected
+    vms: Vec<usize>,
+    uplink: bool,
+    mac_table: BTreeMap<[u8; 6], SwitchPort>,
+}
+
+static SWITCH: SpinNoIrq<Switch> = SpinNoIrq::new(Switch {
+    vms: Vec::new(),
+    uplink: false,
+    mac_table: BTreeMap::new(),
+});
+
+fn is_multicast(mac: &[u8; 6]) -> bool {
+    mac[0] & 1 != 0
+}
+
+/// Connects the virtio-net device of the VM to the switch, with the MAC
+/// address `mac`, and `vector` as its interrupt. The device accesses guest
+/// memory through the nested page table `npt`.
+///
+/// It's called again each time the VM is created after a reset.
+pub fn attach(
+    vm_id: usize,
+    mac: [u8; 6],
+    vector: u8,
+    npt: Arc<SpinNoIrq<GuestPageTable>>,
+) -> HyperResult {
+    if vm_id >= MAX_VMS || is_multicast(&mac) || mac == [0; 6] {
+        return Err(HyperError::InvalidParam);
+    }
+    let mut switch = SWITCH.lock();
+    let taken = switch
+        .vms
+        .iter()
+        .filter(|&&id| id != vm_id)
+        .any(|&id| all_virt_devices(id).find_virtio_net().unwrap().mac_address() == mac);
+    if taken {
+        warn!("VM {} MAC address {:02x?} already used", vm_id, mac);
+        return Err(HyperError::BadState);
+    }
+    all_virt_devices(vm_id)
+        .find_virtio_net()
+        .unwrap()
+        .attach(mac, vector, npt);
+    if !switch.vms.contains(&vm_id) {
+        info!("VM {} connected to the switch as {:02x?}", vm_id, mac);
+        switch.vms.push(vm_id);
+    }
+    Ok(())
+}
+
+/// Disconnects the VM from the switch, before its guest memory is freed.
+pub fn detach(vm_id: usize) {
+    let mut switch = SWITCH.lock();
+    if let Some(pos) = switch.vms.iter().position(|&id| id == vm_id) {
+        switch.vms.remove(pos);
+        switch.mac_table.retain(|_, port| *port != SwitchPort::Vm(vm_id));
+        all_virt_devices(vm_id).find_virtio_net().unwrap().detach();
+    }
+}
+
+/// Forwards an ethernet frame received on the port `from`. Returns `true` if
+/// it's only for a known port, `false` if it's dropped or flooded.
+pub fn forward(from: SwitchPort, frame: &[u8]) -> bool {
+    if frame.len() < 14 {
+        return false;
+    }
+    let dst: [u8; 6] = frame[0..6].try_into().unwrap();
+    let src: [u8; 6] = frame[6..12].try_into().unwrap();
+
+    let mut switch = SWITCH.lock();
+    if !is_multicast(&src) && switch.mac_table.insert(src, from) != Some(from) {
+        debug!("switch: {:02x?} is on {:?}", src, from);
+    }
+    let (ports, unicast) = match switch.mac_table.get(&dst) {
+        Some(&port) if !is_multicast(&dst) => {
+            if port == from {
+                return false;
+            }
+            (alloc::vec![port], true)
+        }
+        _ => {
+            let mut ports: Vec<SwitchPort> = switch.vms.iter().map(|&id| SwitchPort::Vm(id)).collect();
+            if switch.uplink {
+                ports.push(SwitchPort::Uplink);
+            }
+            ports.retain(|&port| port != from);
+            (ports, false)
+        }
+    };
+    drop(switch);
+
+    for port in ports {
+        match port {
+            SwitchPort::Vm(vm_id) => all_virt_devices(vm_id).find_virtio_net().unwrap().receive(frame),
+            #[cfg(feature = "net")]
+            SwitchPort::Uplink => axnet::uplink_receive(frame),
+            #[cfg(not(feature = "net"))]
+            SwitchPort::Uplink => {}
+        }
+    }
+    unicast
+}
+
+/// Connects the network stack of the host to the switch. Frames the host
+/// sends to the VMs go to the switch instead of the NIC.
+#[cfg(feature = "net")]
+pub fn enable_uplink() {
+    fn transmit(frame: &[u8]) -> bool {
+        forward(SwitchPort::Uplink, frame)
+    }
+
+    SWITCH.lock().uplink = true;
+    axnet::set_uplink(transmit);
+    info!("host network stack connected to the switch");
+}
@@ -0,0 +1,108 @@
+//! Host timers backing the virtual APIC timers of vCPUs.
+//!
+//! A CPU can only program its own host timer, so each CPU keeps the virtual
+//! timers of the vCPUs it runs, and they move along with the vCPUs.
+
+extern crate alloc;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spinlock::SpinNoIrq;
+use timer_list::{TimeValue, TimerEvent, TimerList};
+
+use super::vcpu_wait;
+
+struct VTimerEvent {
+    vm_id: usize,
+    vcpu_id: usize,
+}
+
+impl TimerEvent for VTimerEvent {
+    fn callback(self, _now: TimeValue) {
+        vcpu_wait::kick_vcpu(self.vm_id, self.vcpu_id);
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The virtual timers armed on each CPU, fired by its own host timer.
+    static ref VTIMER_LISTS: Vec<SpinNoIrq<TimerList<VTimerEvent>>> = {
+        let mut temp = Vec::new();
+        for _ in 0..axconfig::SMP {
+            temp.push(SpinNoIrq::new(TimerList::new()));
+        }
+        temp
+    };
+}
+
+/// The CPU holding the virtual timer of each armed vCPU, by VM and vCPU IDs,
+/// and its deadline.
+static ARMED_TIMERS: SpinNoIrq<BTreeMap<(usize, usize), (usize, u64)>> =
+    SpinNoIrq::new(BTreeMap::new());
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DEADLINE: AtomicU64 = AtomicU64::new(0);
+/// The deadline of the host one-shot timer currently programmed on each CPU.
+static PROGRAMMED_DEADLINES: [AtomicU64; axconfig::SMP] = [NO_DEADLINE; axconfig::SMP];
+
+/// Arms the virtual timer of the given vCPU at `deadline_ns`, or disarms it
+/// if `deadline_ns` is `None`. It is armed on the current CPU, which must be
+/// the one running the vCPU.
+pub fn set_vcpu_timer(vm_id: usize, vcpu_id: usize, deadline_ns: Option<u64>) {
+    let mut armed = ARMED_TIMERS.lock();
+    if let Some((cpu_id, _)) = armed.remove(&(vm_id, vcpu_id)) {
+        VTIMER_LISTS[cpu_id]
+            .lock()
+            .cancel(|e| e.vm_id == vm_id && e.vcpu_id == vcpu_id);
+    }
+    if let Some(deadline_ns) = deadline_ns {
+        let cpu_id = axhal::cpu::this_cpu_id();
+        armed.insert((vm_id, vcpu_id), (cpu_id, deadline_ns));
+        VTIMER_LISTS[cpu_id]
+            .lock()
+            .set(Duration::from_nanos(deadline_ns), VTimerEvent { vm_id, vcpu_id });
+        // Only bring the host timer forward, the periodic tick must not be delayed.
+        if deadline_ns < PROGRAMMED_DEADLINES[cpu_id].load(Ordering::Relaxed) {
+            PROGRAMMED_DEADLINES[cpu_id].store(deadline_ns, Ordering::Relaxed);
+            axhal::time::set_oneshot_timer(deadline_ns);
+        }
+    }
+}
+
+/// Moves the virtual timer of the given vCPU, if armed, to the current CPU,
+/// after the vCPU moved to it. Otherwise the previous CPU would fire it
+/// without kicking the vCPU out of the guest.
+pub(super) fn move_to_current_cpu(vm_id: usize, vcpu_id: usize) {
+    let deadline_ns = ARMED_TIMERS.lock().get(&(vm_id, vcpu_id)).map(|&(_, ddl)| ddl);
+    if deadline_ns.is_some() {
+        set_vcpu_timer(vm_id, vcpu_id, deadline_ns);
+    }
+}
+
+/// Programs the host one-shot timer of the current CPU at the earlier one of
+/// `next_tick_ns` and the deadline of its earliest virtual timer, called in
+/// the host timer IRQ handler.
+pub fn program_timer(next_tick_ns: u64) {
+    let cpu_id = axhal::cpu::this_cpu_id();
+    let timers = VTIMER_LISTS[cpu_id].lock();
+    let deadline = match timers.next_deadline() {
+        Some(ddl) => next_tick_ns.min(ddl.as_nanos() as u64),
+        None => next_tick_ns,
+    };
+    PROGRAMMED_DEADLINES[cpu_id].store(deadline, Ordering::Relaxed);
+    axhal::time::set_oneshot_timer(deadline);
+}
+
+/// Fires the expired virtual timers of the current CPU, called in the host
+/// timer IRQ handler.
+pub fn check_events() {
+    let timers = &VTIMER_LISTS[axhal::cpu::this_cpu_id()];
+    loop {
+        let now = axhal::time::current_time();
+        let event = timers.lock().expire_one(now);
+        if let Some((_deadline, event)) = event {
+            event.callback(now);
+        } else {
+            break;
+        }
+    }
+}
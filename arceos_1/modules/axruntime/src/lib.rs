@@ -41,6 +41,11 @@ mod hv;
 pub use gpm::GuestPageTable;
 #[cfg(feature = "hv")]
 pub use hv::HyperCraftHalImpl;
+#[cfg(all(feature = "hv", target_arch = "x86_64"))]
+pub use hv::{
+    acpi, console, crash, demand_paging, dirty_log, exit_stats, gdbstub, host_cpus,
+    io_passthrough, notify_vcpu, set_hpet_enabled, shmem, snapshot, vm_control, vswitch,
+};
 
 
 const LOGO: &str = r#"
@@ -188,6 +193,9 @@ pub extern "C" fn rust_main(cpu_id: usize, dtb: usize) -> ! {
         axdisplay::init_display(all_devices.display);
     }
 
+    #[cfg(all(feature = "hv", target_arch = "x86_64"))]
+    hv::host_cpus::enable_current(cpu_id);
+
     #[cfg(feature = "smp")]
     self::mp::start_secondary_cpus(cpu_id);
 
@@ -297,12 +305,45 @@ fn init_interrupt() {
         axhal::time::set_oneshot_timer(deadline);
     }
 
+    // Fire the timer earlier if a virtual timer expires before the next tick.
+    // The periodic tick deadline is not consumed in this case, and `false` is
+    // returned.
+    #[cfg(all(feature = "hv", target_arch = "x86_64"))]
+    fn update_timer_hv() -> bool {
+        hv::check_vtimer_events();
+        let now_ns = axhal::time::current_time_nanos();
+        // Safety: we have disabled preemption in IRQ handler.
+        let next_deadline = unsafe { NEXT_DEADLINE.read_current_raw() };
+        let is_tick = now_ns >= next_deadline.saturating_sub(PERIODIC_INTERVAL_NANOS);
+        if is_tick {
+            update_timer();
+        }
+        let next_tick = unsafe { NEXT_DEADLINE.read_current_raw() } - PERIODIC_INTERVAL_NANOS;
+        hv::program_timer(next_tick);
+        is_tick
+    }
+
     axhal::irq::register_handler(TIMER_IRQ_NUM, || {
-        update_timer();
+        #[cfg(all(feature = "hv", target_arch = "x86_64"))]
+        let is_tick = update_timer_hv();
+        #[cfg(not(all(feature = "hv", target_arch = "x86_64")))]
+        let is_tick = {
+            update_timer();
+            true
+        };
+        // Virtual timers firing between ticks must not shorten time slices.
         #[cfg(feature = "multitask")]
-        axtask::on_timer_tick();
+        if is_tick {
+            axtask::on_timer_tick();
+        }
+        #[cfg(not(feature = "multitask"))]
+        let _ = is_tick;
     });
 
+    // Other CPUs running vCPUs of the same VMs send IPIs, e.g., to flush EPTs.
+    #[cfg(all(feature = "hv", feature = "smp", target_arch = "x86_64"))]
+    axhal::irq::register_handler(axhal::mp::IPI_IRQ_NUM, hv::handle_ipi);
+
     /* 
     #[cfg(all(feature = "hv", target_arch = "aarch64"))]
     {
@@ -47,6 +47,9 @@ pub extern "C" fn rust_main_secondary(cpu_id: usize) -> ! {
 
     axhal::platform_init_secondary();
 
+    #[cfg(all(feature = "hv", target_arch = "x86_64"))]
+    super::hv::host_cpus::enable_current(cpu_id);
+
     #[cfg(feature = "multitask")]
     axtask::init_scheduler_secondary();
 
@@ -5,7 +5,7 @@ use alloc::{string::String, sync::Arc};
 pub(crate) use crate::run_queue::{AxRunQueue, RUN_QUEUE};
 
 #[doc(cfg(feature = "multitask"))]
-pub use crate::task::{CurrentTask, TaskId, TaskInner};
+pub use crate::task::{CurrentTask, MigrationHooks, TaskId, TaskInner};
 #[doc(cfg(feature = "multitask"))]
 pub use crate::wait_queue::WaitQueue;
 
@@ -124,6 +124,32 @@ pub fn set_priority(prio: isize) -> bool {
     RUN_QUEUE.lock().set_current_priority(prio)
 }
 
+/// Pins the current task to the CPU `cpu_id`, or lets it run on any CPU if
+/// `None`. The task is moved to that CPU before returning.
+///
+/// Returns `false` if the CPU does not exist.
+pub fn set_current_affinity(cpu_id: Option<usize>) -> bool {
+    if cpu_id.map_or(false, |id| id >= axconfig::SMP) {
+        return false;
+    }
+    let curr = current();
+    curr.set_cpu_affinity(cpu_id);
+    // Tasks are queued for all CPUs, the target one picks us up.
+    while !curr.can_run_on(axhal::cpu::this_cpu_id()) {
+        yield_now();
+    }
+    true
+}
+
+/// Sets the functions to call as the current task moves between CPUs, or
+/// removes them if `None`.
+///
+/// They are called when the task resumes on another CPU, either after it is
+/// pinned to it by [`set_current_affinity`], or any time if it is not pinned.
+pub fn set_current_migration_hooks(hooks: Option<MigrationHooks>) {
+    current().set_migration_hooks(hooks);
+}
+
 /// Current task gives up the CPU time voluntarily, and switches to another
 /// ready task.
 pub fn yield_now() {
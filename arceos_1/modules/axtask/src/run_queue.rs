@@ -1,5 +1,6 @@
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use lazy_init::LazyInit;
 use scheduler::BaseScheduler;
 use spinlock::SpinNoIrq;
@@ -88,7 +89,8 @@ impl AxRunQueue {
         assert!(!curr.is_idle());
         if curr.is_init() {
             EXITED_TASKS.lock().clear();
-            axhal::misc::terminate();
+            // negative codes are failures too
+            axhal::misc::terminate_with_status(u8::try_from(exit_code).unwrap_or(u8::MAX));
         } else {
             curr.set_state(TaskState::Exited);
             curr.notify_exit(exit_code, self);
@@ -156,13 +158,31 @@ impl AxRunQueue {
                 self.scheduler.put_prev_task(prev.clone(), preempt);
             }
         }
-        let next = self.scheduler.pick_next_task().unwrap_or_else(|| unsafe {
+        let next = self.pick_next_task().unwrap_or_else(|| unsafe {
             // Safety: IRQs must be disabled at this time.
             IDLE_TASK.current_ref_raw().get_unchecked().clone()
         });
         self.switch_to(prev, next);
     }
 
+    /// Picks the next ready task that may run on this CPU. Tasks pinned to
+    /// other CPUs are put back at the end of the queue, for those CPUs to
+    /// pick them up.
+    fn pick_next_task(&mut self) -> Option<AxTaskRef> {
+        let cpu_id = axhal::cpu::this_cpu_id();
+        let mut skipped = Vec::new();
+        let next = loop {
+            match self.scheduler.pick_next_task() {
+                Some(task) if !task.can_run_on(cpu_id) => skipped.push(task),
+                next => break next,
+            }
+        };
+        for task in skipped {
+            self.scheduler.add_task(task);
+        }
+        next
+    }
+
     fn switch_to(&mut self, prev_task: CurrentTask, next_task: AxTaskRef) {
         trace!(
             "context switch: {} -> {}",
@@ -185,9 +205,12 @@ impl AxRunQueue {
             assert!(Arc::strong_count(prev_task.as_task_ref()) > 1);
             assert!(Arc::strong_count(&next_task) >= 1);
 
+            prev_task.leave_cpu(axhal::cpu::this_cpu_id());
             CurrentTask::set_current(prev_task, next_task);
             (*prev_ctx_ptr).switch_to(&*next_ctx_ptr);
         }
+        // switched back to `prev_task`, maybe on another CPU
+        crate::current().enter_cpu(axhal::cpu::this_cpu_id());
     }
 }
 
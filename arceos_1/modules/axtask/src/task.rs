@@ -1,13 +1,11 @@
 use alloc::{boxed::Box, string::String, sync::Arc};
 use core::ops::Deref;
-use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use core::{alloc::Layout, cell::UnsafeCell, fmt, ptr::NonNull};
 
-#[cfg(feature = "preempt")]
-use core::sync::atomic::AtomicUsize;
-
 use axhal::arch::TaskContext;
 use memory_addr::{align_up_4k, VirtAddr};
+use spinlock::SpinNoIrq;
 
 use crate::{AxRunQueue, AxTask, AxTaskRef, WaitQueue};
 
@@ -25,6 +23,26 @@ pub(crate) enum TaskState {
     Exited = 4,
 }
 
+/// Value of [`TaskInner::cpu_affinity`] for tasks not pinned to a CPU.
+const NO_AFFINITY: usize = usize::MAX;
+
+/// Value of `TaskInner::last_cpu` after the task left its CPU.
+const NO_CPU: usize = usize::MAX;
+
+/// Functions called as a task moves between CPUs, to move the states it keeps
+/// in the CPU, e.g., the VMCS of the vCPU it runs.
+#[derive(Debug, Clone, Copy)]
+pub struct MigrationHooks {
+    /// Called with [`MigrationHooks::arg`] on the CPU the task leaves, when it
+    /// is switched out and may resume on another CPU.
+    pub leave_cpu: fn(usize),
+    /// Called with [`MigrationHooks::arg`] on the CPU the task resumes on, if
+    /// it left its previous one.
+    pub enter_cpu: fn(usize),
+    /// The argument of the hooks.
+    pub arg: usize,
+}
+
 /// The inner task structure.
 pub struct TaskInner {
     id: TaskId,
@@ -34,6 +52,11 @@ pub struct TaskInner {
 
     entry: Option<*mut dyn FnOnce()>,
     state: AtomicU8,
+    // the only CPU the task may run on, `NO_AFFINITY` for any
+    cpu_affinity: AtomicUsize,
+    migration_hooks: SpinNoIrq<Option<MigrationHooks>>,
+    // the CPU the task runs on, `NO_CPU` once it left it
+    last_cpu: AtomicUsize,
 
     in_wait_queue: AtomicBool,
     #[cfg(feature = "irq")]
@@ -95,6 +118,14 @@ impl TaskInner {
         alloc::format!("Task({}, {:?})", self.id.as_u64(), self.name)
     }
 
+    /// Gets the CPU the task is pinned to, if any.
+    pub fn cpu_affinity(&self) -> Option<usize> {
+        match self.cpu_affinity.load(Ordering::Acquire) {
+            NO_AFFINITY => None,
+            cpu_id => Some(cpu_id),
+        }
+    }
+
     /// Wait for the task to exit, and return the exit code.
     ///
     /// It will return immediately if the task has already exited (but not dropped).
@@ -115,6 +146,9 @@ impl TaskInner {
             is_init: false,
             entry: None,
             state: AtomicU8::new(TaskState::Ready as u8),
+            cpu_affinity: AtomicUsize::new(NO_AFFINITY),
+            migration_hooks: SpinNoIrq::new(None),
+            last_cpu: AtomicUsize::new(NO_CPU),
             in_wait_queue: AtomicBool::new(false),
             #[cfg(feature = "irq")]
             in_timer_list: AtomicBool::new(false),
@@ -190,6 +224,47 @@ impl TaskInner {
         self.is_idle
     }
 
+    #[inline]
+    pub(crate) fn set_cpu_affinity(&self, cpu_id: Option<usize>) {
+        self.cpu_affinity
+            .store(cpu_id.unwrap_or(NO_AFFINITY), Ordering::Release);
+    }
+
+    /// Whether the task may run on the CPU `cpu_id`.
+    #[inline]
+    pub(crate) fn can_run_on(&self, cpu_id: usize) -> bool {
+        self.cpu_affinity().map_or(true, |id| id == cpu_id)
+    }
+
+    pub(crate) fn set_migration_hooks(&self, hooks: Option<MigrationHooks>) {
+        let mut migration_hooks = self.migration_hooks.lock();
+        self.last_cpu.store(axhal::cpu::this_cpu_id(), Ordering::Release);
+        *migration_hooks = hooks;
+    }
+
+    /// Called before the task is switched out of the CPU `cpu_id`.
+    pub(crate) fn leave_cpu(&self, cpu_id: usize) {
+        if self.cpu_affinity() == Some(cpu_id) {
+            return; // resumes on this CPU
+        }
+        let hooks = *self.migration_hooks.lock();
+        if let Some(hooks) = hooks {
+            (hooks.leave_cpu)(hooks.arg);
+            self.last_cpu.store(NO_CPU, Ordering::Release);
+        }
+    }
+
+    /// Called when the task resumes on the CPU `cpu_id`.
+    pub(crate) fn enter_cpu(&self, cpu_id: usize) {
+        if self.last_cpu.swap(cpu_id, Ordering::AcqRel) == cpu_id {
+            return;
+        }
+        let hooks = *self.migration_hooks.lock();
+        if let Some(hooks) = hooks {
+            (hooks.enter_cpu)(hooks.arg);
+        }
+    }
+
     #[inline]
     pub(crate) fn in_wait_queue(&self) -> bool {
         self.in_wait_queue.load(Ordering::Acquire)
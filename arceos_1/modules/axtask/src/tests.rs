@@ -128,3 +128,54 @@ fn test_task_join() {
         assert_eq!(tasks[i].join(), Some(i as _));
     }
 }
+
+#[test]
+fn test_cpu_affinity() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    assert!(!axtask::set_current_affinity(Some(axconfig::SMP)));
+    assert_eq!(current().cpu_affinity(), None);
+
+    let task = axtask::spawn(|| {
+        assert!(axtask::set_current_affinity(Some(0)));
+        assert_eq!(current().cpu_affinity(), Some(0));
+        axtask::yield_now();
+        assert_eq!(axhal::cpu::this_cpu_id(), 0);
+    });
+    assert_eq!(task.join(), Some(0));
+}
+
+#[test]
+fn test_migration_hooks() {
+    let _lock = SERIAL.lock();
+    INIT.call_once(axtask::init_scheduler);
+
+    static LEFT: AtomicUsize = AtomicUsize::new(0);
+    static ENTERED: AtomicUsize = AtomicUsize::new(0);
+    let hooks = axtask::MigrationHooks {
+        leave_cpu: |arg| assert_eq!(LEFT.fetch_add(arg, Ordering::Relaxed), 0),
+        enter_cpu: |arg| assert_eq!(ENTERED.fetch_add(arg, Ordering::Relaxed), 0),
+        arg: 1,
+    };
+    let task = axtask::spawn(move || {
+        // not pinned: may resume on any CPU
+        axtask::set_current_migration_hooks(Some(hooks));
+        axtask::yield_now();
+        assert_eq!(LEFT.load(Ordering::Relaxed), 1);
+        assert_eq!(ENTERED.load(Ordering::Relaxed), 1);
+
+        // pinned: the hooks are not called
+        assert!(axtask::set_current_affinity(Some(0)));
+        LEFT.store(0, Ordering::Relaxed);
+        ENTERED.store(0, Ordering::Relaxed);
+        axtask::spawn(|| {});
+        axtask::yield_now();
+        axtask::set_current_migration_hooks(None);
+        assert_eq!(LEFT.load(Ordering::Relaxed), 0);
+        assert_eq!(ENTERED.load(Ordering::Relaxed), 0);
+    });
+    // let the task switch to us and back
+    axtask::yield_now();
+    assert_eq!(task.join(), Some(0));
+}
@@ -16,6 +16,7 @@ features-$(FS) += libax/fs
 features-$(NET) += libax/net
 features-$(GRAPHIC) += libax/display
 features-$(HV) += libax/hv 
+features-$(QEMU_DEBUG_EXIT) += libax/qemu-debug-exit
 
 ifeq ($(ARCH), x86_64)
   features-$(HV) += libax/irq
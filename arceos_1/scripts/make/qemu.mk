@@ -35,6 +35,11 @@ qemu_args-x86_64 := \
   -machine q35 \
   -kernel $(OUT_ELF)
 
+# report the exit status to the host, see `axhal::misc::terminate_with_status`
+ifeq ($(QEMU_DEBUG_EXIT), y)
+  qemu_args-x86_64 += -device isa-debug-exit,iobase=0xf4,iosize=0x04
+endif
+
 qemu_args-riscv64 := \
   -machine virt \
   -bios default \
@@ -133,6 +138,11 @@ else
   qemu_args-$(ACCEL) += -cpu host -accel kvm
 endif
 
+# TCG only exposes SVM on request, which the hypervisor detects at runtime
+ifeq ($(SVM)$(ACCEL), yn)
+  qemu_args-y += -cpu qemu64,+svm,+npt,+nrip-save
+endif
+
 define run_qemu
   @printf "    $(CYAN_C)Running$(END_C) $(QEMU) $(qemu_args-y) $(1)\n"
   @sudo $(QEMU) $(qemu_args-y)
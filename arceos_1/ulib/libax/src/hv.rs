@@ -11,3 +11,15 @@ pub use hypercraft::HyperCraftHal;
 pub use hypercraft::{PerCpu, VCpu, VmCpus, VM};
 #[cfg(not(target_arch = "aarch64"))]
 pub use hypercraft::{HyperCallMsg, VmExitInfo, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr};
+#[cfg(target_arch = "x86_64")]
+pub use axruntime::{
+    acpi, console, crash, demand_paging, dirty_log, exit_stats, gdbstub, host_cpus,
+    io_passthrough, notify_vcpu, set_hpet_enabled, shmem, snapshot, vm_control, vswitch,
+};
+#[cfg(target_arch = "x86_64")]
+pub use hypercraft::{
+    core_file_headers, snapshot::{StateReader, StateWriter}, ReplayInput, ReplayLog, VcpuState,
+    CORE_FILE_ALIGN,
+};
+#[cfg(feature = "alloc")]
+pub use axalloc::global_allocator;
@@ -34,6 +34,14 @@ impl TcpStream {
     pub fn shutdown(&self) -> io::Result {
         self.socket.shutdown()
     }
+
+    /// Moves this TCP stream into or out of nonblocking mode.
+    ///
+    /// In nonblocking mode, reads and writes that would block return
+    /// `Err(WouldBlock)` instead.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.socket.set_nonblocking(nonblocking)
+    }
 }
 
 impl Read for TcpStream {
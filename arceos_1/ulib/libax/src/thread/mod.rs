@@ -7,7 +7,7 @@ use axtask::AxTaskRef;
 use core::cell::UnsafeCell;
 
 #[doc(cfg(feature = "multitask"))]
-pub use axtask::{current, set_priority, TaskId as ThreadId};
+pub use axtask::{current, set_current_affinity, set_priority, TaskId as ThreadId};
 
 /// Thread factory, which can be used in order to configure the properties of
 /// a new thread.
@@ -105,6 +105,10 @@ pub fn yield_now() {
 ///
 /// For single-threaded configuration (`multitask` feature is disabled),
 /// it directly terminates the main thread and shutdown.
+///
+/// The system shuts down with `exit_code` as its status once the main thread
+/// exits, where the platform can report it. Codes out of `0..=255` are
+/// reported as 255.
 pub fn exit(exit_code: i32) -> ! {
     axtask::exit(exit_code);
 }
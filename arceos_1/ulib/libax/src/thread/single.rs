@@ -17,9 +17,13 @@ pub fn yield_now() {
 ///
 /// For single-threaded configuration (`multitask` feature is disabled),
 /// it directly terminates the main thread and shutdown.
+///
+/// The system shuts down with `exit_code` as its status once the main thread
+/// exits, where the platform can report it. Codes out of `0..=255` are
+/// reported as 255.
 pub fn exit(exit_code: i32) -> ! {
     axlog::debug!("main task exited: exit_code={}", exit_code);
-    axhal::misc::terminate()
+    axhal::misc::terminate_with_status(u8::try_from(exit_code).unwrap_or(u8::MAX))
 }
 
 /// Current thread is going to sleep for the given duration.